@@ -0,0 +1,88 @@
+/*
+ * SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+ *
+ * Copyleft (c) 2024 James Wong. This file is part of James Wong.
+ * is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * James Wong is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * IMPORTANT: Any software that fully or partially contains or uses materials
+ * covered by this license must also be released under the GNU GPL license.
+ * This includes modifications and derived works.
+ */
+
+use criterion::{ criterion_group, criterion_main, BatchSize, Criterion };
+use mywebnote::config::config_serve::DbProperties;
+use mywebnote::store::users_sqlite::UserSQLiteRepository;
+use mywebnote::store::AsyncRepository;
+use mywebnote::types::user::User;
+use tokio::runtime::Runtime;
+
+const ROWS: usize = 200;
+
+async fn new_repo() -> UserSQLiteRepository {
+    let dir = std::env::temp_dir().join(format!("mywebnote_bench_{}", uuid::Uuid::new_v4()));
+    let mut config = DbProperties::default();
+    config.sqlite.dir = Some(dir.to_string_lossy().to_string());
+    UserSQLiteRepository::new(&config).await.unwrap()
+}
+
+fn new_users() -> Vec<User> {
+    (0..ROWS)
+        .map(|i| {
+            User {
+                name: Some(format!("user-{}", i)),
+                email: Some(format!("user-{}@example.com", i)),
+                ..User::default()
+            }
+        })
+        .collect()
+}
+
+fn insert_per_row(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let repo = rt.block_on(new_repo());
+
+    c.bench_function("sqlite insert per row", |b| {
+        b.iter_batched(
+            new_users,
+            |users| {
+                rt.block_on(async {
+                    for user in users {
+                        repo.insert(user).await.unwrap();
+                    }
+                })
+            },
+            BatchSize::SmallInput
+        )
+    });
+}
+
+fn insert_batch(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let repo = rt.block_on(new_repo());
+
+    c.bench_function("sqlite insert batch", |b| {
+        b.iter_batched(
+            new_users,
+            |users| {
+                rt.block_on(async {
+                    repo.insert_batch(users).await.unwrap();
+                })
+            },
+            BatchSize::SmallInput
+        )
+    });
+}
+
+criterion_group!(benches, insert_per_row, insert_batch);
+criterion_main!(benches);
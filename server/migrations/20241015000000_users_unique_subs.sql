@@ -0,0 +1,38 @@
+-- SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+--
+-- Copyleft (c) 2024 James Wong. This file is part of James Wong.
+-- is free software: you can redistribute it and/or modify it under
+-- the terms of the GNU General Public License as published by the
+-- Free Software Foundation, either version 3 of the License, or
+-- (at your option) any later version.
+--
+-- James Wong is distributed in the hope that it will be useful,
+-- but WITHOUT ANY WARRANTY; without even the implied warranty of
+-- MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+-- GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License
+-- along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+--
+-- IMPORTANT: Any software that fully or partially contains or uses materials
+-- covered by this license must also be released under the GNU GPL license.
+-- This includes modifications and derived works.
+
+-- The provider subjects identify the unique user, so that the concurrent first logins of the same
+-- subject cannot create the duplicated users. (The NULLs are not conflicted in the unique index)
+
+-- The duplicated users created before are soft-deleted except the earliest, otherwise the unique
+-- indexes could not be created.
+update users set del_flag = 1 where del_flag = 0 and oidc_claims_sub is not null and id not in (
+    select min(id) from users where del_flag = 0 and oidc_claims_sub is not null group by oidc_claims_sub);
+update users set del_flag = 1 where del_flag = 0 and github_claims_sub is not null and id not in (
+    select min(id) from users where del_flag = 0 and github_claims_sub is not null group by github_claims_sub);
+update users set del_flag = 1 where del_flag = 0 and google_claims_sub is not null and id not in (
+    select min(id) from users where del_flag = 0 and google_claims_sub is not null group by google_claims_sub);
+update users set del_flag = 1 where del_flag = 0 and ethers_address is not null and id not in (
+    select min(id) from users where del_flag = 0 and ethers_address is not null group by ethers_address);
+
+create unique index if not exists uk_users_oidc_claims_sub on users (oidc_claims_sub) where del_flag = 0;
+create unique index if not exists uk_users_github_claims_sub on users (github_claims_sub) where del_flag = 0;
+create unique index if not exists uk_users_google_claims_sub on users (google_claims_sub) where del_flag = 0;
+create unique index if not exists uk_users_ethers_address on users (ethers_address) where del_flag = 0;
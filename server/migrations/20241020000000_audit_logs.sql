@@ -0,0 +1,38 @@
+-- SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+--
+-- Copyleft (c) 2024 James Wong. This file is part of James Wong.
+-- is free software: you can redistribute it and/or modify it under
+-- the terms of the GNU General Public License as published by the
+-- Free Software Foundation, either version 3 of the License, or
+-- (at your option) any later version.
+--
+-- James Wong is distributed in the hope that it will be useful,
+-- but WITHOUT ANY WARRANTY; without even the implied warranty of
+-- MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+-- GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License
+-- along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+--
+-- IMPORTANT: Any software that fully or partially contains or uses materials
+-- covered by this license must also be released under the GNU GPL license.
+-- This includes modifications and derived works.
+
+-- The audit logs are append only, and queried by the user, event type and range of create time, so
+-- that the indexes are ended with the create_time for the range predicate and ordering.
+create table if not exists audit_logs (
+    id integer primary key not null,
+    uid integer null,
+    event_type varchar(32) null, -- e.g. 'login'
+    detail text null,
+    client_ip varchar(64) null,
+    status integer null default 0,
+    create_by varchar(64) null,
+    create_time integer default current_timestamp,
+    update_by varchar(64) null,
+    update_time integer default current_timestamp,
+    del_flag integer not null default 0
+);
+create index if not exists idx_audit_logs_create_time on audit_logs (create_time);
+create index if not exists idx_audit_logs_uid_create_time on audit_logs (uid, create_time);
+create index if not exists idx_audit_logs_event_type_create_time on audit_logs (event_type, create_time);
@@ -0,0 +1,36 @@
+-- SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+--
+-- Copyleft (c) 2024 James Wong. This file is part of James Wong.
+-- is free software: you can redistribute it and/or modify it under
+-- the terms of the GNU General Public License as published by the
+-- Free Software Foundation, either version 3 of the License, or
+-- (at your option) any later version.
+--
+-- James Wong is distributed in the hope that it will be useful,
+-- but WITHOUT ANY WARRANTY; without even the implied warranty of
+-- MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+-- GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License
+-- along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+--
+-- IMPORTANT: Any software that fully or partially contains or uses materials
+-- covered by this license must also be released under the GNU GPL license.
+-- This includes modifications and derived works.
+
+-- The documents of the users, the soft-deleted (del_flag = 1) are kept in the trash until purged.
+create table if not exists documents (
+    id integer primary key not null,
+    key varchar(64) null,
+    name varchar(64) null,
+    folder_key varchar(64) null,
+    type varchar(16) null, -- 'Board' or 'Note'
+    content text null,
+    status integer null default 0,
+    create_by varchar(64) null,
+    create_time integer default current_timestamp,
+    update_by varchar(64) null,
+    update_time integer default current_timestamp,
+    del_flag integer not null default 0
+);
+create index if not exists idx_documents_create_by on documents (create_by);
@@ -0,0 +1,50 @@
+-- SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+--
+-- Copyleft (c) 2024 James Wong. This file is part of James Wong.
+-- is free software: you can redistribute it and/or modify it under
+-- the terms of the GNU General Public License as published by the
+-- Free Software Foundation, either version 3 of the License, or
+-- (at your option) any later version.
+--
+-- James Wong is distributed in the hope that it will be useful,
+-- but WITHOUT ANY WARRANTY; without even the implied warranty of
+-- MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+-- GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License
+-- along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+--
+-- IMPORTANT: Any software that fully or partially contains or uses materials
+-- covered by this license must also be released under the GNU GPL license.
+-- This includes modifications and derived works.
+
+-- The same columns as the users of sqlite, see: ../migrations/20240710083754_init.sql
+create table if not exists users (
+    id bigint primary key not null,
+    name varchar(64) null,
+    email varchar(64) null,
+    phone varchar(64) null,
+    password varchar(256) null,
+    oidc_claims_sub varchar(64) null,
+    oidc_claims_name varchar(64) null,
+    oidc_claims_email varchar(64) null,
+    github_claims_sub varchar(64) null,
+    github_claims_name varchar(64) null,
+    github_claims_email varchar(64) null,
+    google_claims_sub varchar(64) null,
+    google_claims_name varchar(64) null,
+    google_claims_email varchar(64) null,
+    ethers_address varchar(64) null,
+    lang varchar(64) null,
+    status bigint null default 0,
+    create_by varchar(64) null,
+    create_time bigint null,
+    update_by varchar(64) null,
+    update_time bigint null,
+    del_flag integer not null default 0
+);
+
+create unique index if not exists uk_users_oidc_claims_sub on users (oidc_claims_sub) where del_flag = 0;
+create unique index if not exists uk_users_github_claims_sub on users (github_claims_sub) where del_flag = 0;
+create unique index if not exists uk_users_google_claims_sub on users (google_claims_sub) where del_flag = 0;
+create unique index if not exists uk_users_ethers_address on users (ethers_address) where del_flag = 0;
@@ -22,12 +22,13 @@
 
 use std::ops::Deref;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{ Duration, Instant };
 use std::collections::HashMap;
 
 use anyhow::{ Error, Ok };
 use axum::async_trait;
-use moka::policy::EvictionPolicy;
+use moka::notification::RemovalCause;
+use moka::policy::{ EvictionPolicy, Expiry };
 use moka::future::Cache;
 use regex::Regex;
 
@@ -37,6 +38,21 @@ use super::ICache;
 
 pub struct StringMemoryCache {
     cache: Arc<Cache<String, String>>,
+    // The counters of incr() with the expiration of each entry, which is set when created as the redis.
+    counters: Arc<Cache<String, (i64, Option<Duration>)>>,
+}
+
+struct CounterExpiry;
+
+impl Expiry<String, (i64, Option<Duration>)> for CounterExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &String,
+        value: &(i64, Option<Duration>),
+        _created_at: Instant
+    ) -> Option<Duration> {
+        value.1
+    }
 }
 
 impl StringMemoryCache {
@@ -51,6 +67,11 @@ impl StringMemoryCache {
         if let Some(ttl) = config.ttl {
             builder = builder.time_to_live(Duration::from_millis(ttl));
         }
+        builder = builder.eviction_listener(|key: Arc<String>, _: String, cause| {
+            if cause == RemovalCause::Expired {
+                super::record_expired(&key);
+            }
+        });
         if let Some(eviction_policy) = &config.eviction_policy {
             match eviction_policy.to_uppercase().as_str() {
                 "LRU" => {
@@ -64,8 +85,16 @@ impl StringMemoryCache {
                 }
             }
         }
+        let mut counters = Cache::builder().expire_after(CounterExpiry);
+        if let Some(max_capacity) = config.max_capacity {
+            counters = counters.max_capacity(max_capacity);
+        }
+        if let Some(ttl) = config.ttl {
+            counters = counters.time_to_live(Duration::from_millis(ttl));
+        }
         StringMemoryCache {
             cache: Arc::new(builder.build()),
+            counters: Arc::new(counters.build()),
         }
     }
 
@@ -81,7 +110,12 @@ impl StringMemoryCache {
 #[async_trait]
 impl ICache<String> for StringMemoryCache {
     async fn get(&self, key: String) -> Result<Option<String>, Error> {
-        Ok(self.cache.get(&key).await)
+        let value = match self.cache.get(&key).await {
+            Some(value) => Some(value),
+            None => self.counters.get(&key).await.map(|(count, _)| count.to_string()),
+        };
+        super::record_get(&key, &value);
+        Ok(value)
     }
 
     /// Sets the given key to the specified value.
@@ -96,6 +130,7 @@ impl ICache<String> for StringMemoryCache {
         milliseconds: Option<i32>
     ) -> Result<bool, Error> {
         self.cache.insert(key.clone(), value).await;
+        super::record_set(&key);
         tracing::info!("Inserted to key: {}, expire: {:?}ms", key, milliseconds);
         Ok(true)
     }
@@ -264,8 +299,26 @@ impl ICache<String> for StringMemoryCache {
 
     async fn del(&self, key: String) -> Result<bool, Error> {
         self.cache.invalidate(&key).await;
+        self.counters.invalidate(&key).await;
         Ok(true)
     }
+
+    /// Increments the counter atomically (per key).
+    ///
+    /// # Note
+    /// The expiration of `seconds` is set when created and kept by the increments, the configured ttl is
+    /// the upper bound.
+    async fn incr(&self, key: String, seconds: Option<i32>) -> Result<i64, Error> {
+        let ttl = seconds.filter(|s| *s > 0).map(|s| Duration::from_secs(s as u64));
+        let entry = self.counters
+            .entry(key.clone())
+            .and_upsert_with(|existing| {
+                let count = existing.map(|e| e.into_value().0).unwrap_or(0);
+                std::future::ready((count + 1, ttl))
+            }).await;
+        super::record_set(&key);
+        Ok(entry.into_value().0)
+    }
 }
 
 #[cfg(test)]
@@ -394,6 +447,34 @@ mod tests {
         assert!(cache.get_bit("bitkey".to_string(), 1).await.unwrap());
     }
 
+    #[tokio::test]
+    async fn test_incr_concurrently() {
+        let cache = create_test_cache();
+        let incrs = (0..50).map(|_| cache.incr("counter".to_string(), Some(60)));
+        let mut counts = futures::future::join_all(incrs).await
+            .into_iter()
+            .map(|c| c.unwrap())
+            .collect::<Vec<_>>();
+        counts.sort();
+        assert_eq!(counts, (1..=50).collect::<Vec<_>>());
+        assert_eq!(cache.get("counter".to_string()).await.unwrap(), Some("50".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_incr_expired_by_seconds() {
+        let cache = create_test_cache();
+        assert_eq!(cache.incr("window".to_string(), Some(1)).await.unwrap(), 1);
+        assert_eq!(cache.incr("window".to_string(), Some(1)).await.unwrap(), 2);
+
+        // Expired after the seconds since created, even though the configured ttl is longer.
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(cache.get("window".to_string()).await.unwrap(), None);
+        assert_eq!(cache.incr("window".to_string(), Some(1)).await.unwrap(), 1);
+
+        assert!(cache.del("window".to_string()).await.unwrap());
+        assert_eq!(cache.incr("window".to_string(), Some(1)).await.unwrap(), 1);
+    }
+
     #[tokio::test]
     async fn test_delete() {
         let cache = create_test_cache();
@@ -24,8 +24,11 @@ use std::collections::HashMap;
 
 use anyhow::Error;
 use axum::async_trait;
+use prometheus::IntCounterVec;
+use serde::{ de::DeserializeOwned, Serialize };
 
 use crate::config::config_serve::{ WebServeProperties, CacheProvider };
+use crate::mgmt::apm::metrics::{ CACHE_EXPIRATIONS_TOTAL, CACHE_HITS_TOTAL, CACHE_MISSES_TOTAL, CACHE_SETS_TOTAL };
 
 pub mod memory;
 pub mod redis;
@@ -65,6 +68,73 @@ pub trait ICache<T>: Send + Sync {
     async fn set_bit(&self, key: String, offset: u64, value: bool) -> Result<bool, Error>;
 
     async fn del(&self, key: String) -> Result<bool, Error>;
+
+    // Increments the counter atomically and returns the incremented, the expiration is set when created.
+    async fn incr(&self, key: String, seconds: Option<i32>) -> Result<i64, Error>;
+}
+
+/// The typed error of the cached value that cannot be deserialized, e.g. corrupted or of old format.
+#[derive(Debug, thiserror::Error)]
+#[error("Corrupted cache value of key '{key}': {source}")]
+pub struct CacheValueError {
+    pub key: String,
+    #[source]
+    pub source: serde_json::Error,
+}
+
+/// The typed values layered over the string cache, which are (de)serialized as JSON automatically.
+#[async_trait]
+pub trait JsonCacheExt {
+    async fn set_json<V>(&self, key: String, value: &V, seconds: Option<i32>) -> Result<bool, Error>
+        where V: Serialize + Sync;
+
+    async fn get_json<V>(&self, key: String) -> Result<Option<V>, Error> where V: DeserializeOwned;
+}
+
+#[async_trait]
+impl JsonCacheExt for dyn ICache<String> + '_ {
+    async fn set_json<V>(&self, key: String, value: &V, seconds: Option<i32>) -> Result<bool, Error>
+        where V: Serialize + Sync
+    {
+        let value = serde_json::to_string(value)?;
+        self.set(key, value, seconds).await
+    }
+
+    async fn get_json<V>(&self, key: String) -> Result<Option<V>, Error> where V: DeserializeOwned {
+        match self.get(key.to_owned()).await? {
+            Some(value) =>
+                serde_json
+                    ::from_str(&value)
+                    .map(Some)
+                    .map_err(|source| CacheValueError { key, source }.into()),
+            None => Ok(None),
+        }
+    }
+}
+
+// ----- Cache metrics. -----
+
+// The low cardinality label of the key, e.g. 'auth:nonce' of 'auth:nonce:<nonce>', or 'other' if the
+// key has no prefix of two segments.
+pub(crate) fn key_prefix(key: &str) -> &str {
+    let mut splits = key.match_indices(':').map(|(i, _)| i);
+    match (splits.next(), splits.next()) {
+        (Some(_), Some(end)) => &key[..end],
+        _ => "other",
+    }
+}
+
+pub(crate) fn record_get<T>(key: &str, value: &Option<T>) {
+    let counter: &IntCounterVec = if value.is_some() { &CACHE_HITS_TOTAL } else { &CACHE_MISSES_TOTAL };
+    counter.with_label_values(&[key_prefix(key)]).inc();
+}
+
+pub(crate) fn record_set(key: &str) {
+    CACHE_SETS_TOTAL.with_label_values(&[key_prefix(key)]).inc();
+}
+
+pub(crate) fn record_expired(key: &str) {
+    CACHE_EXPIRATIONS_TOTAL.with_label_values(&[key_prefix(key)]).inc();
 }
 
 pub struct CacheContainer<T> where T: 'static + Send + Sync {
@@ -95,3 +165,71 @@ impl<T> CacheContainer<T> where T: 'static + Send + Sync {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use crate::cache::memory::StringMemoryCache;
+    use crate::config::config_serve::MemoryProperties;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Blacklisted {
+        uid: i64,
+        logout_at: i64,
+    }
+
+    fn new_cache() -> Box<dyn ICache<String>> {
+        Box::new(StringMemoryCache::new(&MemoryProperties::default()))
+    }
+
+    #[tokio::test]
+    async fn test_json_cache_round_trip() {
+        let cache = new_cache();
+        let value = Blacklisted { uid: 1001, logout_at: 1729000000000 };
+        assert!(cache.set_json("k1".to_string(), &value, None).await.unwrap());
+        assert_eq!(cache.get_json::<Blacklisted>("k1".to_string()).await.unwrap(), Some(value));
+        assert_eq!(cache.get_json::<Blacklisted>("missing".to_string()).await.unwrap(), None);
+
+        // The raw string API is kept.
+        assert_eq!(
+            cache.get("k1".to_string()).await.unwrap(),
+            Some(r#"{"uid":1001,"logout_at":1729000000000}"#.to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json_cache_corrupt_value() {
+        let cache = new_cache();
+        cache.set("k1".to_string(), "not a json".to_string(), None).await.unwrap();
+        let err = cache.get_json::<Blacklisted>("k1".to_string()).await.unwrap_err();
+        let err = err.downcast_ref::<CacheValueError>().unwrap();
+        assert_eq!(err.key, "k1");
+    }
+
+    #[test]
+    fn test_cache_key_prefix() {
+        assert_eq!(key_prefix("auth:nonce:abc"), "auth:nonce");
+        assert_eq!(key_prefix("logout:blacklist:eyJ.x:y"), "logout:blacklist");
+        assert_eq!(key_prefix("auth:nonce"), "other");
+        assert_eq!(key_prefix("plain"), "other");
+    }
+
+    #[tokio::test]
+    async fn test_cache_metrics_hit_and_miss() {
+        // The unique prefixes of this test, since the counters are global.
+        let (nonce, blacklist) = ("test:metrics-nonce", "test:metrics-blacklist");
+        let count = |vec: &IntCounterVec, prefix: &str| vec.with_label_values(&[prefix]).get();
+        let cache = new_cache();
+
+        cache.set(format!("{}:k1", nonce), "v1".to_string(), None).await.unwrap();
+        assert!(cache.get(format!("{}:k1", nonce)).await.unwrap().is_some());
+        assert!(cache.get(format!("{}:k1", blacklist)).await.unwrap().is_none());
+
+        assert_eq!(count(&CACHE_SETS_TOTAL, nonce), 1);
+        assert_eq!(count(&CACHE_HITS_TOTAL, nonce), 1);
+        assert_eq!(count(&CACHE_MISSES_TOTAL, nonce), 0);
+        assert_eq!(count(&CACHE_HITS_TOTAL, blacklist), 0);
+        assert_eq!(count(&CACHE_MISSES_TOTAL, blacklist), 1);
+    }
+}
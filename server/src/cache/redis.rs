@@ -33,6 +33,10 @@ use crate::config::config_serve::RedisProperties;
 
 use super::ICache;
 
+// Increments and sets the expiration when created in one round trip, so that the counter never lives forever.
+const INCR_SCRIPT: &str =
+    "local n = redis.call('INCR', KEYS[1]) if n == 1 and tonumber(ARGV[1]) > 0 then redis.call('EXPIRE', KEYS[1], ARGV[1]) end return n";
+
 pub struct StringRedisCache {
     client: Arc<ClusterClient>,
 }
@@ -85,18 +89,21 @@ impl ICache<String> for StringRedisCache {
         let mut con = self.get_async_connection().await?;
         let result: RedisResult<Option<String>> = redis
             ::cmd("GET")
-            .arg(key)
+            .arg(&key)
             .query_async(&mut con).await;
-        Ok(result?)
+        let value = result?;
+        super::record_get(&key, &value);
+        Ok(value)
     }
 
     async fn set(&self, key: String, value: String, seonds: Option<i32>) -> Result<bool, Error> {
         let mut con = self.get_async_connection().await?;
         let result: RedisResult<String> = if let Some(seconds) = seonds {
-            redis::cmd("SETEX").arg(key).arg(seconds).arg(value).query_async(&mut con).await
+            redis::cmd("SETEX").arg(&key).arg(seconds).arg(value).query_async(&mut con).await
         } else {
-            redis::cmd("SET").arg(key).arg(value).query_async(&mut con).await
+            redis::cmd("SET").arg(&key).arg(value).query_async(&mut con).await
         };
+        super::record_set(&key);
         Ok(result.map(|s| s == "OK")?)
     }
 
@@ -218,4 +225,17 @@ impl ICache<String> for StringRedisCache {
         let result: RedisResult<i32> = redis::cmd("DEL").arg(key).query_async(&mut con).await;
         Ok(result.map(|n| n > 0).unwrap_or(false))
     }
+
+    async fn incr(&self, key: String, seconds: Option<i32>) -> Result<i64, Error> {
+        let mut con = self.get_async_connection().await?;
+        let result: RedisResult<i64> = redis
+            ::cmd("EVAL")
+            .arg(INCR_SCRIPT)
+            .arg(1)
+            .arg(&key)
+            .arg(seconds.unwrap_or(0))
+            .query_async(&mut con).await;
+        super::record_set(&key);
+        Ok(result?)
+    }
 }
@@ -21,7 +21,9 @@
  */
 
 use std::env;
+use std::io;
 use std::sync::Arc;
+use std::time::Duration;
 use clap::Arg;
 use clap::Command;
 
@@ -30,29 +32,52 @@ use tower_http::trace::TraceLayer;
 use tracing::info;
 
 use tokio::task::JoinHandle;
-use tokio::net::TcpListener;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{ TcpListener, TcpStream };
 use tokio::sync::oneshot;
 
-use axum::Router;
-use axum::routing::get;
+use hyper_util::rt::{ TokioExecutor, TokioIo, TokioTimer };
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+
+use axum::{ Extension, Router };
+use axum::extract::{ ConnectInfo, DefaultBodyLimit };
+use axum::routing::{ get, post };
 use axum_prometheus::PrometheusMetricLayer;
 
 use crate::config::config_serve;
+use crate::config::config_serve::ServerProperties;
 use crate::config::config_serve::WebServeConfig;
+use crate::config::config_serve::DEFAULT_MAX_BODY_BYTES;
 use crate::config::config_serve::GIT_BUILD_DATE;
 use crate::config::config_serve::GIT_COMMIT_HASH;
 use crate::config::config_serve::GIT_VERSION;
 use crate::config::swagger;
 use crate::context::state::AppState;
+use crate::handler::document::start_purge_deleted_job;
 use crate::mgmt::apm;
+use crate::mgmt::apm::logging::{ handle_logs_level, handle_logs_rotate };
 use crate::mgmt::apm::metrics::handle_metrics;
 use crate::mgmt::health::init as health_router;
-use crate::route::auths::auth_middleware;
+use crate::route::{
+    access_log_middleware,
+    build_cors_layer,
+    cache_control_middleware,
+    degraded_mode_middleware,
+    envelope_version_middleware,
+    make_request_span,
+    request_id_middleware,
+    timezone_middleware,
+};
+use crate::route::auths::{ auth_middleware, ext_authz_middleware };
 use crate::route::auths::init as auth_router;
+use crate::route::config::init as config_router;
 use crate::route::user::init as user_router;
 use crate::route::document::init as document_router;
 use crate::route::folder::init as folder_router;
 use crate::route::settings::init as settings_router;
+use crate::route::attachment::init as attachment_router;
+use crate::route::audit::init as audit_router;
 use crate::route::browser_indexeddb::init as browser_indexeddb_router;
 use crate::route::api_v1::users::init as api_v1_users_router;
 
@@ -69,7 +94,11 @@ async fn start_mgmt_server(
 ) -> JoinHandle<()> {
     let (prometheus_layer, _) = PrometheusMetricLayer::pair();
 
-    let app: Router = Router::new().route("/metrics", get(handle_metrics)).layer(prometheus_layer);
+    let app: Router = Router::new()
+        .route("/metrics", get(handle_metrics))
+        .route("/logs/rotate", post(handle_logs_rotate))
+        .route("/logs/level", post(handle_logs_level))
+        .layer(prometheus_layer);
 
     let bind_addr = config.server.mgmt_bind.clone();
     info!("Starting Management server on {}", bind_addr);
@@ -86,15 +115,19 @@ async fn start_mgmt_server(
 
 async fn start_server(config: &Arc<WebServeConfig>) {
     let app_state = AppState::new(&config).await;
+    start_purge_deleted_job(app_state.clone());
     tracing::info!("Register Web server middlewares ...");
 
     // 1. Merge the biz modules routes.
     let expose_routes = Router::new()
         .merge(auth_router())
+        .merge(config_router())
         .merge(user_router())
         .merge(document_router())
         .merge(folder_router())
         .merge(settings_router())
+        .merge(audit_router())
+        .merge(attachment_router())
         .merge(browser_indexeddb_router())
         .merge(api_v1_users_router());
 
@@ -117,41 +150,136 @@ async fn start_server(config: &Arc<WebServeConfig>) {
     }
 
     // 4. Finally add the (auth) middlewares.
+    let propagator = config.mgmt.otel.propagator.unwrap_or_default();
     // Notice: The settings of middlewares are in order, which will affect the priority of route matching.
     // The later the higher the priority? For example, if auth_middleware is set at the end, it will
     // enter when requesting '/', otherwise it will not enter if it is set at the front, and will
     // directly enter handle_root().
     app_routes = app_routes.layer(
         ServiceBuilder::new()
-            .layer(axum::middleware::from_fn_with_state(app_state, auth_middleware))
-            // Optional: add logs to tracing.
+            // Optional: add logs to tracing. (the outermost, so that the span covers all middlewares)
             .layer(
-                TraceLayer::new_for_http().make_span_with(|request: &axum::http::Request<_>| {
-                    tracing::info_span!(
-                            "http_request",
-                            method = %request.method(),
-                            uri = %request.uri(),
-                        )
+                TraceLayer::new_for_http().make_span_with(move |request: &axum::http::Request<_>| {
+                    make_request_span(request, propagator)
                 })
             )
+            // The limit of buffering the request body, which is honored by the body extractors.
+            .layer(DefaultBodyLimit::max(config.server.max_body_bytes.unwrap_or(DEFAULT_MAX_BODY_BYTES)))
+            // So that the errors of all inner middlewares carry the request id.
+            .layer(axum::middleware::from_fn_with_state(app_state.clone(), request_id_middleware))
+            // So that the requests rejected by the auth are also logged.
+            .layer(axum::middleware::from_fn_with_state(app_state.clone(), access_log_middleware))
+            .layer(axum::middleware::from_fn_with_state(app_state.clone(), envelope_version_middleware))
+            .layer(axum::middleware::from_fn_with_state(app_state.clone(), timezone_middleware))
+            // So that the preflight requests are responded before the auth.
+            .layer(build_cors_layer(&config.server.cors))
+            // The ext authz is inner of the auth, so that the user of request is bound before authorizing.
+            .layer(axum::middleware::from_fn_with_state(app_state.clone(), ext_authz_middleware))
+            .layer(axum::middleware::from_fn_with_state(app_state.clone(), auth_middleware))
+            .layer(axum::middleware::from_fn_with_state(app_state.clone(), degraded_mode_middleware))
+            .layer(axum::middleware::from_fn_with_state(app_state, cache_control_middleware))
     );
     //.route_layer(axum::Extension(app_state));
 
     let bind_addr = &config.server.bind;
     tracing::info!("Starting web server on {}", bind_addr);
 
-    axum::serve(
-        TcpListener::bind(&bind_addr).await.unwrap(),
-        app_routes.into_make_service()
-    ).await.unwrap_or_else(|e| panic!("Error starting API server: {}", e));
+    serve_with_limits(TcpListener::bind(&bind_addr).await.unwrap(), app_routes, &config.server).await;
 
     tracing::info!("Web server is ready");
 }
 
-fn on_panic(info: &std::panic::PanicInfo) {
-    let info = info.to_string().replace('\n', " ");
-    tracing::error!(%info);
-    eprintln!(":: Panic Error ::\n{}", info)
+// Hyper requires the read buffer to be at least 8KB.
+const MIN_MAX_HEADER_BYTES: usize = 8192;
+const DEFAULT_MAX_HEADER_BYTES: usize = 64 * 1024;
+const DEFAULT_HEADER_READ_TIMEOUT: u64 = 30_000;
+const REQUEST_TIMEOUT_RESPONSE: &[u8] =
+    b"HTTP/1.1 408 Request Timeout\r\nconnection: close\r\ncontent-length: 0\r\n\r\n";
+
+// Instead of the axum::serve(), serving each connection with the bounded request header size and
+// header read time, to protect from the oversized headers and slow-loris clients. The oversized
+// headers is responded 431 by hyper, and the header read timeout is responded 408 at here.
+async fn serve_with_limits(listener: TcpListener, app: Router, server: &ServerProperties) {
+    let max_header_bytes = server.max_header_bytes
+        .unwrap_or(DEFAULT_MAX_HEADER_BYTES)
+        .max(MIN_MAX_HEADER_BYTES);
+    let header_read_timeout = Duration::from_millis(
+        server.header_read_timeout.unwrap_or(DEFAULT_HEADER_READ_TIMEOUT)
+    );
+
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) if is_connection_error(&e) => continue,
+            Err(e) => {
+                // e.g. EMFILE/ENFILE, backoff as the axum::serve() does, rather than spinning on accepting.
+                tracing::warn!("Failed to accept connection. reason: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        // The other handle of the connection, so that it can still be written after hyper gives up the connection.
+        let (stream, reply_stream) = match duplicate_stream(stream) {
+            Ok(streams) => streams,
+            Err(e) => {
+                tracing::warn!("Failed to duplicate connection from {}. reason: {}", remote_addr, e);
+                continue;
+            }
+        };
+        // The peer address of the connection, as the axum::serve() with the connect info does, e.g. for the
+        // client ip of throttling which can't be spoofed.
+        let service = TowerToHyperService::new(app.clone().layer(Extension(ConnectInfo(remote_addr))));
+
+        tokio::spawn(async move {
+            // Both of the HTTP/1 and HTTP/2 (prior knowledge), as the axum::serve() does.
+            let mut builder = auto::Builder::new(TokioExecutor::new());
+            builder
+                .http1()
+                .max_buf_size(max_header_bytes)
+                .timer(TokioTimer::new())
+                .header_read_timeout(header_read_timeout);
+            builder.http2().timer(TokioTimer::new()).max_header_list_size(max_header_bytes as u32);
+            let result = builder.serve_connection_with_upgrades(TokioIo::new(stream), service).await;
+
+            if let Err(e) = result {
+                if is_header_read_timeout(&*e) {
+                    tracing::debug!("Timeout reading request headers from {}", remote_addr);
+                    reply_and_close(reply_stream, REQUEST_TIMEOUT_RESPONSE).await;
+                } else {
+                    tracing::debug!("Failed to serve connection from {}. reason: {}", remote_addr, e);
+                }
+            }
+        });
+    }
+}
+
+// The errors of the single connection, which is aborted before accepted, see: axum::serve::is_connection_error()
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset
+    )
+}
+
+// The header read timeout is the only timeout of serving the connection, i.e. the hyper timeout error
+// (which reports the header read timeout since hyper 1.6).
+fn is_header_read_timeout(e: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    e.downcast_ref::<hyper::Error>().is_some_and(|e| e.is_timeout())
+}
+
+// Duplicate the handle (fd) of the connection, the one is owned by hyper, and the other is only registered
+// to the runtime when replying after hyper gives up the connection.
+fn duplicate_stream(stream: TcpStream) -> io::Result<(TcpStream, std::net::TcpStream)> {
+    let stream = stream.into_std()?;
+    let reply_stream = stream.try_clone()?;
+    Ok((TcpStream::from_std(stream)?, reply_stream))
+}
+
+async fn reply_and_close(stream: std::net::TcpStream, response: &[u8]) {
+    if let Ok(mut stream) = TcpStream::from_std(stream) {
+        let _ = stream.write_all(response).await;
+        let _ = stream.shutdown().await;
+    }
 }
 
 fn print_launch_resume(config: &Arc<WebServeConfig>, verbose: bool) {
@@ -219,7 +347,7 @@ pub fn build_cli() -> Command {
 #[allow(unused)]
 #[tokio::main]
 pub async fn handle_cli(matches: &clap::ArgMatches) -> () {
-    std::panic::set_hook(Box::new(on_panic));
+    apm::panic::set_panic_hook();
 
     let verbose = matches.get_flag("verbose");
 
@@ -239,6 +367,8 @@ pub async fn handle_cli(matches: &clap::ArgMatches) -> () {
     start_server(&config).await;
 
     mgmt_handle.await.unwrap();
+
+    apm::logging::shutdown_log_files();
 }
 
 #[cfg(test)]
@@ -275,4 +405,77 @@ mod tests {
         let result = app.try_get_matches_from(vec!["", "invalid"]);
         assert!(result.is_err());
     }
+
+    async fn start_limited_server(max_header_bytes: usize, header_read_timeout: u64) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = ServerProperties {
+            max_header_bytes: Some(max_header_bytes),
+            header_read_timeout: Some(header_read_timeout),
+            ..ServerProperties::default()
+        };
+        let app = Router::new().route("/", get(|| async { "ok" }));
+        tokio::spawn(async move { serve_with_limits(listener, app, &server).await });
+        addr
+    }
+
+    async fn read_response(stream: &mut TcpStream) -> String {
+        use tokio::io::AsyncReadExt;
+        let mut buf = Vec::new();
+        let _ = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buf)).await;
+        String::from_utf8_lossy(&buf).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_serve_with_limits_accepts_bounded_headers() {
+        let addr = start_limited_server(16 * 1024, 1000).await;
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        let request = format!(
+            "GET / HTTP/1.1\r\nhost: localhost\r\nx-large: {}\r\nconnection: close\r\n\r\n",
+            "a".repeat(8 * 1024)
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let response = read_response(&mut stream).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_serve_with_limits_rejects_oversized_headers() {
+        let addr = start_limited_server(16 * 1024, 1000).await;
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        let request = format!(
+            "GET / HTTP/1.1\r\nhost: localhost\r\nx-large: {}\r\n\r\n",
+            "a".repeat(32 * 1024)
+        );
+        let _ = stream.write_all(request.as_bytes()).await;
+
+        let response = read_response(&mut stream).await;
+        assert!(response.starts_with("HTTP/1.1 431"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_serve_with_limits_accepts_http2_prior_knowledge() {
+        use tokio::io::AsyncReadExt;
+        let addr = start_limited_server(16 * 1024, 1000).await;
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        // The connection preface and the empty SETTINGS frame.
+        stream.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").await.unwrap();
+        stream.write_all(&[0, 0, 0, 4, 0, 0, 0, 0, 0]).await.unwrap();
+
+        // Responded with the SETTINGS frame of server rather than the HTTP/1 error.
+        let mut frame_header = [0u8; 9];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut frame_header)).await.unwrap().unwrap();
+        assert_eq!(frame_header[3], 4, "{:?}", frame_header);
+    }
+
+    #[tokio::test]
+    async fn test_serve_with_limits_times_out_stalled_headers() {
+        let addr = start_limited_server(16 * 1024, 200).await;
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nhost: local").await.unwrap();
+
+        let response = read_response(&mut stream).await;
+        assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+    }
 }
@@ -20,11 +20,14 @@
  * This includes modifications and derived works.
  */
 
-use std::{ env, ops::Deref, sync::Arc, time::Duration };
+use std::{ collections::BTreeMap, env, ops::Deref, str::FromStr, sync::Arc };
 
 use anyhow::Ok;
 use arc_swap::ArcSwap;
-use globset::{ Glob, GlobSet, GlobSetBuilder };
+use chrono::FixedOffset;
+use axum::http::HeaderValue;
+use globset::{ Glob, GlobMatcher, GlobSet, GlobSetBuilder };
+use jsonwebtoken::{ Algorithm, DecodingKey, EncodingKey };
 use once_cell::sync::Lazy;
 use serde::{ Deserialize, Serialize };
 // use std::fs::File;
@@ -33,13 +36,18 @@ use serde::{ Deserialize, Serialize };
 use config::Config;
 use validator::Validate;
 
-use crate::mgmt::{ health::HEALTHZ_URI, apm::logging::LogMode };
+use crate::mgmt::{ health::HEALTHZ_URI, apm::{ logging::{ LogMode, LogRotation, LogSink }, otel::TracePropagator } };
+use crate::types::ApiVersion;
+use crate::utils::times;
 
 #[derive(Debug, Serialize, Deserialize, Clone, Validate)]
 pub struct WebServeProperties {
     #[serde(rename = "service-name")]
     #[validate(length(min = 1, max = 32))]
     pub service_name: String,
+    // The running profile, the debugging features (e.g. '/auth/debug/whoami') are only enabled in dev.
+    #[serde(default)]
+    pub profile: RunProfile,
     #[serde(default = "ServerProperties::default")]
     pub server: ServerProperties,
     #[serde(default = "LoggingProperties::default")]
@@ -69,6 +77,115 @@ pub struct ServerProperties {
     pub thread_max_pool: u32,
     #[serde(default = "CorsProperties::default")]
     pub cors: CorsProperties,
+    // The max bytes of the request line and headers, the exceeded request will be rejected with 431.
+    #[serde(rename = "max-header-bytes")]
+    pub max_header_bytes: Option<usize>,
+    // The max millis of reading the request headers, the slow client will be rejected with 408.
+    #[serde(rename = "header-read-timeout")]
+    pub header_read_timeout: Option<u64>,
+    // The max bytes of the (json or msgpack) request body, the exceeded will be rejected with 413.
+    #[serde(rename = "max-body-bytes")]
+    pub max_body_bytes: Option<usize>,
+    // The max nesting depth of the json request body, the exceeded will be rejected with 400.
+    #[serde(rename = "max-json-depth")]
+    pub max_json_depth: Option<usize>,
+    // The max elements of each array in the json request body, the exceeded will be rejected with 400.
+    #[serde(rename = "max-json-array-len")]
+    pub max_json_array_len: Option<usize>,
+    // The Cache-Control policies of response by path glob, the first matched is used and the unmatched
+    // is 'no-store' for safety.
+    #[serde(rename = "cache-control", default = "ServerProperties::default_cache_control")]
+    pub cache_control: Vec<CacheControlPolicy>,
+    // The latency budgets (SLO) of routes by path glob, the first matched is used and the exceeded
+    // request is warned. Default is no budget.
+    #[serde(rename = "latency-budgets", default)]
+    pub latency_budgets: Vec<LatencyBudget>,
+    // The response envelope version when the request has no (or unknown) Accept-Version header.
+    #[serde(rename = "api-version", default)]
+    pub api_version: ApiVersion,
+    // The max concurrent validating of the batch import items, the writes are serialized anyway.
+    #[serde(rename = "import-concurrency")]
+    pub import_concurrency: Option<usize>,
+    // The max page size of the audit logs query, the larger (or unbounded) request is capped to it.
+    #[serde(rename = "audit-max-page-size")]
+    pub audit_max_page_size: Option<u32>,
+    // Whether the debug responses (e.g. '/auth/debug/whoami') are pretty printed by default, could be
+    // overridden by the '?pretty=' query, which is only honored in the dev profile.
+    #[serde(rename = "debug-pretty-json")]
+    pub debug_pretty_json: Option<bool>,
+    // Whether the error responses carry the request id (and the trace id when tracing is active), so
+    // that the user could report it. The 'X-Request-Id' response header is always set.
+    #[serde(rename = "error-correlation-ids")]
+    pub error_correlation_ids: Option<bool>,
+    // The timezone of rendering the timestamps in responses, 'UTC' or the fixed offset e.g. '+08:00', the
+    // timestamps are always stored in UTC. Default none, i.e. not rendered.
+    pub timezone: Option<String>,
+    // How to scrub the settings and audit logs of the user on the right to erasure, the user itself is
+    // always scrubbed and soft-deleted.
+    #[serde(rename = "erasure-strategy", default)]
+    pub erasure_strategy: ErasureStrategy,
+    // Whether to degrade on the write outage of the primary db, i.e. the writes are rejected by 503 with
+    // the 'Retry-After' while the reads keep serving (from the replica or cache), and recovered once the
+    // probing write succeeds.
+    #[serde(rename = "degraded-mode")]
+    pub degraded_mode: Option<bool>,
+    // The seconds of rejecting the writes since the last failed write, then a write is let through to probe.
+    #[serde(rename = "degraded-retry-after")]
+    pub degraded_retry_after: Option<u64>,
+    // The proxies (IPs) whose 'X-Forwarded-For' is trusted to get the client ip, e.g. for the login throttle
+    // and rate limits. Default none, i.e. the peer address, because the headers could be spoofed by clients.
+    #[serde(rename = "trusted-proxies", default)]
+    pub trusted_proxies: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RunProfile {
+    Dev,
+    #[default]
+    Prod,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountMergeStrategy {
+    #[default]
+    None,
+    // Link the identity to the existing user whose email (or any provider email) is the same.
+    VerifiedEmail,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErasureStrategy {
+    // Keep the rows but scrub the personal data, so that the audit counts of the user are preserved.
+    #[default]
+    Anonymize,
+    // Delete the rows permanently.
+    Delete,
+}
+
+// The 'SameSite' attribute of the auth cookies, notice: The 'none' requires the 'cookie-secure'.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CookieSameSite {
+    #[default]
+    Strict,
+    Lax,
+    None,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LatencyBudget {
+    pub path: String,
+    // The budget of millis.
+    pub budget: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CacheControlPolicy {
+    pub path: String,
+    pub value: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -76,12 +193,66 @@ pub struct CorsProperties {
     pub hosts: Vec<String>,
     pub headers: Vec<String>,
     pub methods: Vec<String>,
+    // The seconds of the preflight response could be cached by the browser (Access-Control-Max-Age).
+    #[serde(rename = "max-age")]
+    pub max_age: Option<u64>,
+    // Whether to allow the credentials (cookies), which is only emitted for the explicitly allowed
+    // hosts, never for the wildcard '*'.
+    #[serde(rename = "allow-credentials")]
+    pub allow_credentials: Option<bool>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LoggingProperties {
     pub mode: LogMode,
     pub level: String,
+    #[serde(rename = "access-log", default = "AccessLogProperties::default")]
+    pub access_log: AccessLogProperties,
+    // The active sinks of logs, at least one is required.
+    #[serde(default = "LoggingProperties::default_sinks")]
+    pub sinks: Vec<LogSink>,
+    #[serde(default = "LogFileProperties::default")]
+    pub file: LogFileProperties,
+    // The max full backtraces captured of panics per minute, the exceeded panics are logged with the
+    // location only, so that the overhead is bounded under the frequent panics (e.g. crash loop).
+    #[serde(rename = "panic-backtraces-per-minute")]
+    pub panic_backtraces_per_minute: Option<u32>,
+    // Whether to include the 'trace_id' and 'span_id' of current span (from the OpenTelemetry context)
+    // in the log lines, so that the logs could be correlated to the traces in the backend.
+    #[serde(rename = "trace-correlation")]
+    pub trace_correlation: Option<bool>,
+}
+
+// The buffered log files (of the 'file' and 'err_file' sinks), which could be flushed and rotated on
+// demand by the management endpoint.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LogFileProperties {
+    pub dir: Option<String>,
+    // The current file is '<prefix>.log', and the rotated is '<prefix>.<yyyyMMddHHmmssSSS>.log'.
+    pub prefix: Option<String>,
+    // The period of rotating the files automatically (on the first write after the period boundary), default
+    // never, i.e. rotated on demand only.
+    pub rotation: Option<LogRotation>,
+    // The max rotated files kept of each sink (i.e. the 'file' and 'err_file' respectively), the oldest are
+    // deleted on rotation, default unlimited.
+    #[serde(rename = "max-files")]
+    pub max_files: Option<usize>,
+    // Whether to gzip the rotated files to '<prefix>.<yyyyMMddHHmmssSSS>.log.gz', which are also counted by the
+    // 'max-files', the current file is never compressed.
+    #[serde(rename = "compress-rotated")]
+    pub compress_rotated: Option<bool>,
+}
+
+// The sampling of per-request access logs, which is independent of the tracing spans sampling.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AccessLogProperties {
+    pub enabled: Option<bool>,
+    // The ratio in [0, 1] of the successful requests to be logged, e.g. 0.01 is about 1%.
+    #[serde(rename = "sample-ratio")]
+    pub sample_ratio: Option<f64>,
+    // Whether to always log the error (4xx/5xx) requests regardless of the sample ratio.
+    #[serde(rename = "always-log-errors")]
+    pub always_log_errors: Option<bool>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -90,6 +261,53 @@ pub struct DbProperties {
     pub db_type: DbType,
     pub sqlite: SqliteProperties,
     pub mongo: MongoProperties,
+    // Notice: Only the users are backed by the postgres for now (requires the 'postgres' feature), the
+    // others are still backed by the sqlite.
+    #[serde(default)]
+    pub postgres: PostgresProperties,
+    // The optional read replica, the select queries are directed to the replica and the writes to the
+    // primary, fallback to the primary when not configured or unavailable.
+    // Notice: The replica may lag behind, so that the reads within the max lag after a write are still
+    // directed to the primary.
+    #[serde(rename = "read-replica")]
+    pub read_replica: Option<ReadReplicaProperties>,
+    // Whether to check the table columns against the entity fields at startup, and fail fast if drifted.
+    #[serde(rename = "schema-audit")]
+    pub schema_audit: Option<bool>,
+    // Whether to ping the pooled connection before acquiring, and discard it if broken (sqlite only).
+    #[serde(rename = "test-on-acquire")]
+    pub test_on_acquire: Option<bool>,
+    // The number of consecutive connection errors to recreate all the pooled connections (sqlite only).
+    // Notice: The mongo driver monitors the servers and reconnects by itself.
+    #[serde(rename = "reconnect-error-threshold")]
+    pub reconnect_error_threshold: Option<u32>,
+    // The max concurrent transactions of each repository, the excess wait for at most the timeout (ms)
+    // and then fail as the storage unavailable, instead of exhausting the pool (sqlite only).
+    #[serde(rename = "max-transactions")]
+    pub max_transactions: Option<usize>,
+    #[serde(rename = "transaction-acquire-timeout")]
+    pub transaction_acquire_timeout: Option<u64>,
+    // The soft-deleted documents (in trash) are purged permanently after the days, 0 to disable.
+    #[serde(rename = "purge-deleted-after-days")]
+    pub purge_deleted_after_days: Option<u32>,
+    // Whether the deleting by id soft-deletes (del_flag = 1) the rows, which are kept in the table and could
+    // be purged later, or hard deletes them otherwise. Notice: Only the users honor it for now.
+    #[serde(rename = "soft-delete")]
+    pub soft_delete: Option<bool>,
+    // The pragmas applied on each new sqlite connection (primary and replica), e.g. the WAL and busy timeout
+    // reduce the 'SQLITE_BUSY' of the concurrent writes dramatically. Only the allowlisted are accepted.
+    #[serde(rename = "sqlite-pragmas")]
+    pub sqlite_pragmas: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReadReplicaProperties {
+    pub sqlite: Option<SqliteProperties>,
+    // The max replication lag (ms) of the replica, the reads within it after the last write are directed
+    // to the primary, so that they are not stale.
+    #[serde(rename = "max-lag")]
+    pub max_lag: Option<u64>,
+    // Notice: The mongo replica reads is configured by the 'readPreference' of the mongo url.
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
@@ -97,6 +315,7 @@ pub struct DbProperties {
 pub enum DbType {
     Sqlite,
     Mongo,
+    Postgres,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -104,6 +323,13 @@ pub struct SqliteProperties {
     pub dir: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PostgresProperties {
+    pub url: Option<String>,
+    #[serde(rename = "max-connections")]
+    pub max_connections: Option<u32>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MongoProperties {
     pub url: Option<String>,
@@ -164,8 +390,69 @@ pub struct AuthProperties {
     pub jwt_validity_rk: Option<u64>,
     #[serde(rename = "jwt-secret")]
     pub jwt_secret: Option<String>,
+    // The previous secrets are still accepted to validate the tokens (but not to sign), so that the
+    // issued tokens keep valid in the grace period of rotating the jwt-secret.
+    #[serde(rename = "jwt-previous-secrets")]
+    pub jwt_previous_secrets: Option<Vec<String>>,
+    // The 'iss' and 'aud' claims of the issued tokens, which are also required to match on validating
+    // when configured, e.g. to isolate the services of multi-service deployments.
+    #[serde(rename = "jwt-issuer")]
+    pub jwt_issuer: Option<String>,
+    #[serde(rename = "jwt-audience")]
+    pub jwt_audience: Option<String>,
+    // The signing algorithm of JWT, default HS256 signed by the 'jwt-secret'. The asymmetric (e.g. RS256,
+    // ES256) are signed by the private key, so that other services could verify by the public key only.
+    #[serde(rename = "jwt-algorithm")]
+    pub jwt_algorithm: Option<String>,
+    #[serde(rename = "jwt-private-key-pem")]
+    pub jwt_private_key_pem: Option<String>,
+    #[serde(rename = "jwt-public-key-pem")]
+    pub jwt_public_key_pem: Option<String>,
+    // The max bytes of each string claim (e.g. uname/email) in JWT, the overlong will be truncated.
+    #[serde(rename = "jwt-claim-max-bytes")]
+    pub jwt_claim_max_bytes: Option<usize>,
+    // The max bytes of the whole JWT, the oversized will be rejected before decoding.
+    #[serde(rename = "jwt-max-bytes")]
+    pub jwt_max_bytes: Option<usize>,
+    // Whether to issue the new refresh token (and revoke the old) on refreshing the access token.
+    #[serde(rename = "jwt-rotate-rk")]
+    pub jwt_rotate_rk: Option<bool>,
+    // The absolute lifetime (seconds) of the login session since the initial login, the refreshing
+    // beyond it is rejected and the re-authentication is required. Default unlimited.
+    #[serde(rename = "max-session-secs")]
+    pub max_session_secs: Option<u64>,
+    // The window (ms) before the access token expired, in which the responses carry the remaining seconds
+    // by the 'X-Token-Expiring' header, so that the client could refresh proactively, 0 to disable.
+    #[serde(rename = "jwt-expiring-window")]
+    pub jwt_expiring_window: Option<u64>,
+    // The attributes of the auth (access/refresh token) cookies, e.g. the 'cookie-secure' should be disabled for
+    // the local http development, and 'cookie-same-site: lax' for SPAs required the cross-site redirects.
+    #[serde(rename = "cookie-secure")]
+    pub cookie_secure: Option<bool>,
+    #[serde(rename = "cookie-same-site", default)]
+    pub cookie_same_site: CookieSameSite,
+    #[serde(rename = "cookie-domain")]
+    pub cookie_domain: Option<String>,
+    #[serde(rename = "cookie-path")]
+    pub cookie_path: Option<String>,
     #[serde(rename = "anonymous-paths")]
     pub anonymous_paths: Option<Vec<String>>,
+    // The uids of the administrators, who could act on behalf of the other users, e.g. export their data.
+    #[serde(rename = "admin-uids")]
+    pub admin_uids: Option<Vec<i64>>,
+    // Whether to create the user automatically when first login by provider (oidc/github).
+    #[serde(rename = "auto-register")]
+    pub auto_register: Option<bool>,
+    // How to merge the provider identity into the existing user of the same email at first login by
+    // provider, default 'none' creates another user. Notice: Only the verified email is trusted to link.
+    #[serde(rename = "account-merge", default)]
+    pub account_merge: AccountMergeStrategy,
+    #[serde(rename = "login-throttle", default = "LoginThrottleProperties::default")]
+    pub login_throttle: LoginThrottleProperties,
+    #[serde(rename = "validate-rate-limit", default = "ValidateRateLimitProperties::default")]
+    pub validate_rate_limit: ValidateRateLimitProperties,
+    #[serde(rename = "ext-authz", default = "ExtAuthzProperties::default")]
+    pub ext_authz: ExtAuthzProperties,
     pub oidc: OidcProperties,
     pub github: GithubProperties,
     #[serde(rename = "login-url")]
@@ -176,6 +463,42 @@ pub struct AuthProperties {
     pub unauthz_url: Option<String>,
 }
 
+// Temporarily lock out the password login of an account/IP after too many failures.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LoginThrottleProperties {
+    pub enabled: Option<bool>,
+    #[serde(rename = "max-failures")]
+    pub max_failures: Option<u32>,
+    // The window (ms) in which the failures are counted.
+    #[serde(rename = "failure-window")]
+    pub failure_window: Option<u64>,
+    // The lockout duration (ms) after the failures reached max.
+    #[serde(rename = "lockout-duration")]
+    pub lockout_duration: Option<u64>,
+}
+
+// Limit the requests of validating token per client IP, to prevent it from being abused as an oracle.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ValidateRateLimitProperties {
+    #[serde(rename = "max-requests")]
+    pub max_requests: Option<u32>,
+    // The window (ms) in which the requests are counted.
+    pub window: Option<u64>,
+}
+
+// Authorize the authenticated requests by the external service (e.g. OPA), which is POSTed with the
+// request metadata and responded with '{"allow": true|false}'.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ExtAuthzProperties {
+    pub enabled: Option<bool>,
+    pub url: Option<String>,
+    // The timeout (ms) of the authorizer callout, the timed out is denied.
+    pub timeout: Option<u64>,
+    // The paths (globs) bypass the external authorization.
+    #[serde(rename = "bypass-paths")]
+    pub bypass_paths: Option<Vec<String>>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct OidcProperties {
     pub enabled: Option<bool>,
@@ -189,6 +512,14 @@ pub struct OidcProperties {
     pub redirect_url: Option<String>,
     #[serde(rename = "scope")]
     pub scope: Option<String>,
+    // Whether to protect the authorization code by PKCE (S256), see:https://datatracker.ietf.org/doc/html/rfc7636
+    pub pkce: Option<bool>,
+    // The discovery document (and JWKS) is served from the cache within the TTL (ms), and the stale one is served
+    // while refreshing in the background within the hard TTL (ms), beyond which is refreshed blocking.
+    #[serde(rename = "discovery-ttl")]
+    pub discovery_ttl: Option<u64>,
+    #[serde(rename = "discovery-hard-ttl")]
+    pub discovery_hard_ttl: Option<u64>,
 }
 
 // see:https://github.com/settings/developers
@@ -279,22 +610,48 @@ pub struct PyroscopeAgentProperties {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct OtelProperties {
     pub enabled: bool,
+    // The collector endpoint, the 'http://' is prepended if without the scheme (e.g. 'collector:4317'), and the
+    // default of the protocol if empty.
     pub endpoint: String,
+    // The protocol of exporter, i.e. 'grpc' (default), 'http/protobuf' and 'http/json', the HTTP endpoint is the
+    // base URL of collector (e.g. http://localhost:4318) without the signal path '/v1/traces'.
     pub protocol: String,
     pub timeout: Option<u64>,
+    // Whether to fail the startup when the OTLP tracer install failed, otherwise continue without OTLP.
+    pub required: Option<bool>,
+    // The propagation format of the trace context in the HTTP headers, i.e. 'w3c' (default), 'b3' and 'b3_multi',
+    // the B3 is extracted from either the single or multiple headers.
+    pub propagator: Option<TracePropagator>,
+    // The sampling ratios of the traces by the request path, the first matched rule is applied, and the unmatched
+    // (or the empty rules) are sampled by the default sampler, e.g. to downsample the noisy health checks.
+    #[serde(rename = "sample-rules", default)]
+    pub sample_rules: Vec<TraceSampleRule>,
     // Notice: More OTEL custom configuration use to environment: OTEL_SPAN_xxx, see to: opentelemetry_sdk::trace::config::default()
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TraceSampleRule {
+    // The glob patterns of the request path (including the context path), e.g. '/healthz' and '/sys/**'
+    #[serde(rename = "path-patterns")]
+    pub path_patterns: Vec<String>,
+    // The ratio in [0, 1] of the traces to be sampled.
+    pub ratio: f64,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct WebNoteProperties {
     pub indexeddb_name: String,
     pub indexeddb_store_names: Vec<String>,
+    // The storage root of the attachments, which are sandboxed under the directory of each user.
+    #[serde(rename = "attachment-dir", default = "WebNoteProperties::default_attachment_dir")]
+    pub attachment_dir: String,
 }
 
 impl WebServeProperties {
     pub fn default() -> WebServeProperties {
         WebServeProperties {
             service_name: String::from("the-mywebnote"),
+            profile: RunProfile::default(),
             server: ServerProperties::default(),
             logging: LoggingProperties::default(),
             db: DbProperties::default(),
@@ -308,6 +665,12 @@ impl WebServeProperties {
 
     pub fn validate(self) -> Result<WebServeProperties, anyhow::Error> {
         //self.validate();
+        if self.logging.sinks.is_empty() {
+            return Err(anyhow::anyhow!("At least one of the logging sinks must be enabled."));
+        }
+        if self.db.db_type == DbType::Postgres && self.db.postgres.url.as_deref().unwrap_or_default().is_empty() {
+            return Err(anyhow::anyhow!("The 'db.postgres.url' must be configured for the postgres db type."));
+        }
         Ok(self)
     }
 
@@ -339,16 +702,54 @@ impl Default for ServerProperties {
             context_path: None,
             thread_max_pool: 4,
             cors: CorsProperties::default(),
+            max_header_bytes: Some(64 * 1024),
+            header_read_timeout: Some(30_000),
+            max_body_bytes: Some(DEFAULT_MAX_BODY_BYTES),
+            max_json_depth: Some(DEFAULT_MAX_JSON_DEPTH),
+            max_json_array_len: Some(DEFAULT_MAX_JSON_ARRAY_LEN),
+            cache_control: ServerProperties::default_cache_control(),
+            latency_budgets: Vec::new(),
+            api_version: ApiVersion::default(),
+            import_concurrency: Some(DEFAULT_IMPORT_CONCURRENCY),
+            audit_max_page_size: Some(DEFAULT_AUDIT_MAX_PAGE_SIZE),
+            debug_pretty_json: Some(false),
+            error_correlation_ids: Some(true),
+            timezone: None,
+            erasure_strategy: ErasureStrategy::default(),
+            degraded_mode: Some(false),
+            degraded_retry_after: Some(DEFAULT_DEGRADED_RETRY_AFTER),
+            trusted_proxies: vec![],
         }
     }
 }
 
+impl ServerProperties {
+    pub fn default_cache_control() -> Vec<CacheControlPolicy> {
+        vec![
+            CacheControlPolicy {
+                path: String::from("/auth/**"),
+                value: String::from(DEFAULT_CACHE_CONTROL),
+            },
+            CacheControlPolicy {
+                path: String::from("/sys/settings/query"),
+                value: String::from("private, max-age=60"),
+            },
+            CacheControlPolicy {
+                path: String::from("/static/**"),
+                value: String::from("public, max-age=3600"),
+            }
+        ]
+    }
+}
+
 impl Default for CorsProperties {
     fn default() -> Self {
         CorsProperties {
             hosts: vec!["*".to_string()],
             headers: vec!["*".to_string()],
             methods: vec!["*".to_string()],
+            max_age: Some(DEFAULT_CORS_MAX_AGE),
+            allow_credentials: Some(false),
         }
     }
 }
@@ -358,6 +759,39 @@ impl Default for LoggingProperties {
         LoggingProperties {
             mode: LogMode::Json,
             level: "info".to_string(),
+            access_log: AccessLogProperties::default(),
+            sinks: LoggingProperties::default_sinks(),
+            file: LogFileProperties::default(),
+            panic_backtraces_per_minute: Some(DEFAULT_PANIC_BACKTRACES_PER_MINUTE),
+            trace_correlation: Some(true),
+        }
+    }
+}
+
+impl LoggingProperties {
+    pub fn default_sinks() -> Vec<LogSink> {
+        vec![LogSink::Stderr, LogSink::Otlp]
+    }
+}
+
+impl Default for LogFileProperties {
+    fn default() -> Self {
+        LogFileProperties {
+            dir: Some("/tmp/mywebnote/log".to_string()),
+            prefix: Some("mywebnote".to_string()),
+            rotation: Some(LogRotation::Never),
+            max_files: None,
+            compress_rotated: Some(false),
+        }
+    }
+}
+
+impl Default for AccessLogProperties {
+    fn default() -> Self {
+        AccessLogProperties {
+            enabled: Some(true),
+            sample_ratio: Some(1.0),
+            always_log_errors: Some(true),
         }
     }
 }
@@ -368,10 +802,29 @@ impl Default for DbProperties {
             db_type: DbType::Sqlite,
             sqlite: SqliteProperties::default(),
             mongo: MongoProperties::default(),
+            postgres: PostgresProperties::default(),
+            read_replica: None,
+            schema_audit: Some(true),
+            test_on_acquire: Some(true),
+            reconnect_error_threshold: Some(DEFAULT_DB_RECONNECT_ERROR_THRESHOLD),
+            max_transactions: Some(DEFAULT_DB_MAX_TRANSACTIONS),
+            transaction_acquire_timeout: Some(DEFAULT_DB_TRANSACTION_ACQUIRE_TIMEOUT),
+            purge_deleted_after_days: Some(DEFAULT_DB_PURGE_DELETED_AFTER_DAYS),
+            soft_delete: Some(true),
+            sqlite_pragmas: Some(DbProperties::default_sqlite_pragmas()),
         }
     }
 }
 
+impl DbProperties {
+    pub fn default_sqlite_pragmas() -> BTreeMap<String, String> {
+        BTreeMap::from([
+            (String::from("journal_mode"), String::from("WAL")),
+            (String::from("busy_timeout"), DEFAULT_DB_SQLITE_BUSY_TIMEOUT.to_string()),
+        ])
+    }
+}
+
 impl Default for SqliteProperties {
     fn default() -> Self {
         SqliteProperties {
@@ -380,6 +833,15 @@ impl Default for SqliteProperties {
     }
 }
 
+impl Default for PostgresProperties {
+    fn default() -> Self {
+        PostgresProperties {
+            url: Some(String::from("postgres://postgres@localhost:5432/mywebnote")),
+            max_connections: Some(DEFAULT_DB_POSTGRES_MAX_CONNECTIONS),
+        }
+    }
+}
+
 impl Default for MongoProperties {
     fn default() -> Self {
         MongoProperties {
@@ -389,6 +851,37 @@ impl Default for MongoProperties {
     }
 }
 
+impl Default for ValidateRateLimitProperties {
+    fn default() -> Self {
+        ValidateRateLimitProperties {
+            max_requests: Some(60),
+            window: Some(60_000),
+        }
+    }
+}
+
+impl Default for LoginThrottleProperties {
+    fn default() -> Self {
+        LoginThrottleProperties {
+            enabled: Some(true),
+            max_failures: Some(5),
+            failure_window: Some(900_000),
+            lockout_duration: Some(900_000),
+        }
+    }
+}
+
+impl Default for ExtAuthzProperties {
+    fn default() -> Self {
+        ExtAuthzProperties {
+            enabled: Some(false),
+            url: None,
+            timeout: Some(3000),
+            bypass_paths: None,
+        }
+    }
+}
+
 impl Default for OidcProperties {
     fn default() -> Self {
         OidcProperties {
@@ -398,6 +891,9 @@ impl Default for OidcProperties {
             issue_url: None,
             redirect_url: None,
             scope: Some("openid profile email".to_string()),
+            pkce: Some(true),
+            discovery_ttl: Some(DEFAULT_OIDC_DISCOVERY_TTL),
+            discovery_hard_ttl: Some(DEFAULT_OIDC_DISCOVERY_HARD_TTL),
         }
     }
 }
@@ -464,6 +960,12 @@ impl Default for RedisProperties {
     }
 }
 
+impl AuthProperties {
+    pub fn is_admin(&self, uid: i64) -> bool {
+        self.admin_uids.as_ref().is_some_and(|uids| uids.contains(&uid))
+    }
+}
+
 impl Default for AuthProperties {
     fn default() -> Self {
         AuthProperties {
@@ -472,7 +974,28 @@ impl Default for AuthProperties {
             jwt_validity_ak: Some(3600_000),
             jwt_validity_rk: Some(86400_000),
             jwt_secret: Some("changeit".to_string()),
+            jwt_previous_secrets: None,
+            jwt_issuer: None,
+            jwt_audience: None,
+            jwt_algorithm: None,
+            jwt_private_key_pem: None,
+            jwt_public_key_pem: None,
+            jwt_claim_max_bytes: Some(128),
+            jwt_max_bytes: Some(8192),
+            jwt_rotate_rk: Some(true),
+            max_session_secs: None,
+            jwt_expiring_window: Some(DEFAULT_JWT_EXPIRING_WINDOW),
+            cookie_secure: Some(false),
+            cookie_same_site: CookieSameSite::default(),
+            cookie_domain: None,
+            cookie_path: Some(String::from("/")),
             anonymous_paths: None,
+            admin_uids: None,
+            auto_register: Some(true),
+            account_merge: AccountMergeStrategy::default(),
+            login_throttle: LoginThrottleProperties::default(),
+            validate_rate_limit: ValidateRateLimitProperties::default(),
+            ext_authz: ExtAuthzProperties::default(),
             oidc: OidcProperties::default(),
             github: GithubProperties::default(),
             login_url: Some(String::from("/static/login.html")),
@@ -539,18 +1062,92 @@ impl Default for OtelProperties {
     fn default() -> Self {
         OtelProperties {
             enabled: true,
-            endpoint: String::from("http://localhost:4317"),
+            endpoint: DEFAULT_OTLP_ENDPOINT.to_string(),
             protocol: String::from("grpc"),
-            timeout: Some(Duration::from_secs(10).as_millis() as u64),
+            timeout: Some(DEFAULT_OTLP_TIMEOUT),
+            required: Some(false),
+            propagator: Some(TracePropagator::W3c),
+            sample_rules: Vec::new(),
         }
     }
 }
 
+pub const DEFAULT_CORS_MAX_AGE: u64 = 600;
+pub const DEFAULT_OTLP_TIMEOUT: u64 = 10_000;
+pub const DEFAULT_OTLP_ENDPOINT: &str = "http://localhost:4317";
+pub const DEFAULT_OTLP_HTTP_ENDPOINT: &str = "http://localhost:4318";
+pub const DEFAULT_MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+pub const DEFAULT_MAX_JSON_DEPTH: usize = 32;
+pub const DEFAULT_MAX_JSON_ARRAY_LEN: usize = 10_000;
+pub const DEFAULT_IMPORT_CONCURRENCY: usize = 4;
+pub const DEFAULT_AUDIT_MAX_PAGE_SIZE: u32 = 100;
+pub const DEFAULT_DEGRADED_RETRY_AFTER: u64 = 30;
+pub const DEFAULT_PANIC_BACKTRACES_PER_MINUTE: u32 = 10;
+pub const DEFAULT_DB_RECONNECT_ERROR_THRESHOLD: u32 = 3;
+pub const DEFAULT_DB_MAX_TRANSACTIONS: usize = 4;
+pub const DEFAULT_DB_TRANSACTION_ACQUIRE_TIMEOUT: u64 = 5000;
+pub const DEFAULT_DB_PURGE_DELETED_AFTER_DAYS: u32 = 30;
+pub const DEFAULT_DB_SQLITE_BUSY_TIMEOUT: u64 = 5000;
+pub const DEFAULT_DB_READ_REPLICA_MAX_LAG: u64 = 5000;
+pub const DEFAULT_DB_POSTGRES_MAX_CONNECTIONS: u32 = 10;
+pub const DEFAULT_CACHE_CONTROL: &str = "no-store";
+pub const DEFAULT_JWT_EXPIRING_WINDOW: u64 = 300_000;
+pub const DEFAULT_OIDC_DISCOVERY_TTL: u64 = 3_600_000;
+pub const DEFAULT_OIDC_DISCOVERY_HARD_TTL: u64 = 86_400_000;
+
 pub struct WebServeConfig {
     pub inner: WebServeProperties,
     pub auth_jwt_ak_name: String,
     pub auth_jwt_rk_name: String,
     pub auth_anonymous_glob_matcher: Option<GlobSet>,
+    pub cache_control_matchers: Vec<(GlobMatcher, HeaderValue)>,
+    pub ext_authz_bypass_matcher: Option<GlobSet>,
+    pub latency_budget_matchers: Vec<(GlobMatcher, u64)>,
+    // The keys of the asymmetric jwt algorithm, none for the HMAC (by the 'jwt-secret').
+    pub jwt_asymmetric_keys: Option<JwtAsymmetricKeys>,
+    // The parsed 'server.timezone' of rendering the timestamps in responses.
+    pub timezone: Option<FixedOffset>,
+}
+
+#[derive(Clone)]
+pub struct JwtAsymmetricKeys {
+    pub algorithm: Algorithm,
+    pub encoding_key: EncodingKey,
+    pub decoding_key: DecodingKey,
+}
+
+impl JwtAsymmetricKeys {
+    // Load the keys of configured algorithm, none if not configured or the HMAC algorithms.
+    fn load(auth: &AuthProperties) -> Option<Self> {
+        let algorithm = auth.jwt_algorithm
+            .as_deref()
+            .map(|alg| Algorithm::from_str(&alg.to_uppercase()).expect("Invalid jwt-algorithm"))?;
+        match algorithm {
+            Algorithm::HS256 => {
+                return None;
+            }
+            Algorithm::HS384 | Algorithm::HS512 => panic!("Unsupported jwt-algorithm {:?}, the HMAC is HS256 only", algorithm),
+            _ => {}
+        }
+        let private_pem = auth.jwt_private_key_pem.as_deref().expect("Missing jwt-private-key-pem configure");
+        let public_pem = auth.jwt_public_key_pem.as_deref().expect("Missing jwt-public-key-pem configure");
+        let (encoding_key, decoding_key) = match algorithm {
+            Algorithm::ES256 | Algorithm::ES384 =>
+                (EncodingKey::from_ec_pem(private_pem.as_bytes()), DecodingKey::from_ec_pem(public_pem.as_bytes())),
+            Algorithm::EdDSA =>
+                (EncodingKey::from_ed_pem(private_pem.as_bytes()), DecodingKey::from_ed_pem(public_pem.as_bytes())),
+            _ =>
+                (
+                    EncodingKey::from_rsa_pem(private_pem.as_bytes()),
+                    DecodingKey::from_rsa_pem(public_pem.as_bytes()),
+                ),
+        };
+        Some(JwtAsymmetricKeys {
+            algorithm,
+            encoding_key: encoding_key.expect("Invalid jwt-private-key-pem"),
+            decoding_key: decoding_key.expect("Invalid jwt-public-key-pem"),
+        })
+    }
 }
 
 impl Deref for WebServeConfig {
@@ -583,6 +1180,32 @@ impl WebServeConfig {
             globset = Some(builder.build().unwrap());
         }
 
+        // Build to the cache control matchers, in order of configured.
+        let cache_control_matchers = config.server.cache_control
+            .iter()
+            .map(|policy| {
+                (
+                    Glob::new(&policy.path).unwrap().compile_matcher(),
+                    HeaderValue::from_str(&policy.value).expect("Invalid cache-control value"),
+                )
+            })
+            .collect();
+
+        // Build to the latency budget matchers, in order of configured.
+        let latency_budget_matchers = config.server.latency_budgets
+            .iter()
+            .map(|b| (Glob::new(&b.path).unwrap().compile_matcher(), b.budget))
+            .collect();
+
+        // Build to the external authorization bypass matcher.
+        let ext_authz_bypass_matcher = config.auth.ext_authz.bypass_paths.as_ref().map(|paths| {
+            let mut builder = GlobSetBuilder::new();
+            for path in paths {
+                builder.add(Glob::new(path).unwrap());
+            }
+            builder.build().unwrap()
+        });
+
         Arc::new(WebServeConfig {
             inner: config.clone(),
             auth_jwt_ak_name: config.auth.jwt_ak_name
@@ -594,6 +1217,13 @@ impl WebServeConfig {
                 .unwrap_or(String::from("_rk"))
                 .to_string(),
             auth_anonymous_glob_matcher: globset,
+            cache_control_matchers,
+            ext_authz_bypass_matcher,
+            latency_budget_matchers,
+            jwt_asymmetric_keys: JwtAsymmetricKeys::load(&config.auth),
+            timezone: config.server.timezone
+                .as_deref()
+                .map(|tz| times::parse_timezone(tz).unwrap_or_else(|e| panic!("{}", e))),
         })
     }
 }
@@ -608,10 +1238,17 @@ impl Default for WebNoteProperties {
                 String::from("menu"),
                 String::from("blob")
             ],
+            attachment_dir: Self::default_attachment_dir(),
         }
     }
 }
 
+impl WebNoteProperties {
+    fn default_attachment_dir() -> String {
+        String::from("/tmp/mywebnote/attachments")
+    }
+}
+
 #[allow(unused)]
 fn init() -> Arc<WebServeConfig> {
     env::var("APP_CFG_PATH")
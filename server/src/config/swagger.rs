@@ -41,11 +41,17 @@ use crate::{
             __path_handle_connect_github,
             __path_handle_connect_oidc,
             __path_handle_logout,
+            __path_handle_logout_all,
+            __path_handle_refresh_token,
             __path_handle_password_pubkey,
             __path_handle_password_verify,
+            __path_handle_password_login,
+            __path_handle_validate_token,
         },
         user::{
             __path_handle_delete_user,
+            __path_handle_erase_user,
+            __path_handle_export_user_data,
             __path_handle_get_current_user,
             __path_handle_post_current_user,
             __path_handle_query_users,
@@ -53,7 +59,9 @@ use crate::{
         },
         document::{
             __path_handle_delete_document,
+            __path_handle_purge_document,
             __path_handle_query_documents,
+            __path_handle_query_deleted_documents,
             __path_handle_save_document,
         },
         folder::{
@@ -63,9 +71,14 @@ use crate::{
         },
         settings::{
             __path_handle_delete_settings,
+            __path_handle_diff_settings,
+            __path_handle_import_settings,
             __path_handle_query_settings,
             __path_handle_save_settings,
         },
+        audit::__path_handle_query_audit_logs,
+        attachment::{ __path_handle_download_attachment, __path_handle_upload_attachment },
+        config::__path_handle_get_features,
         browser_indexeddb::{
             __path_handle_browser_indexeddb_get,
             __path_handle_browser_indexeddb_get_all,
@@ -78,17 +91,24 @@ use crate::{
     utils::auths,
 };
 
+use crate::errors::ErrorEnvelope;
 use crate::types::{
     BaseBean,
+    OperationAction,
+    OperationOutcome,
     PageRequest,
     PageResponse,
+    SortOrder,
     auth::{
-        CallbackGithubRequest,
-        CallbackOidcRequest,
+        OAuthCallbackParams,
         PasswordPubKeyRequest,
         PasswordPubKeyResponse,
         PasswordLoginRequest,
+        EmailLoginRequest,
         LogoutRequest,
+        RefreshTokenRequest,
+        TokenInvalidReason,
+        ValidateTokenResponse,
     },
     user::{
         User,
@@ -99,6 +119,11 @@ use crate::types::{
         SaveUserResponse,
         DeleteUserRequest,
         DeleteUserResponse,
+        ExportUserDataRequest,
+        UserDataExport,
+        EraseUserRequest,
+        EraseUserResponse,
+        ErasureOutcome,
     },
     api_v1::users::{
         QueryUserApiV1Request,
@@ -112,6 +137,8 @@ use crate::types::{
         Document,
         QueryDocumentRequest,
         QueryDocumentResponse,
+        DeletedDocument,
+        QueryDeletedDocumentResponse,
         SaveDocumentRequest,
         SaveDocumentResponse,
         DeleteDocumentRequest,
@@ -135,7 +162,13 @@ use crate::types::{
         SaveSettingsResponse,
         DeleteSettingsRequest,
         DeleteSettingsResponse,
+        ImportSettingsRequest,
+        ImportSettingsResponse,
+        DiffSettingsRequest,
+        DiffSettingsResponse,
     },
+    audit::{ AuditLog, QueryAuditLogsRequest, QueryAuditLogsResponse },
+    config::{ FeatureFlags, LoginProviderFlags },
     browser_indexeddb::{
         IndexedValue,
         GetIndexedRecordRequest,
@@ -174,20 +207,28 @@ use crate::types::{
         handle_callback_oidc,
         handle_password_pubkey,
         handle_password_verify,
+        handle_password_login,
         handle_logout,
+        handle_logout_all,
+        handle_refresh_token,
+        handle_validate_token,
         // User
         handle_get_current_user,
         handle_post_current_user,
         handle_query_users,
         handle_save_user,
         handle_delete_user,
+        handle_export_user_data,
+        handle_erase_user,
         handle_apiv1_get_users,
         handle_apiv1_save_user,
         handle_apiv1_delete_user,
         // Document
         handle_query_documents,
+        handle_query_deleted_documents,
         handle_save_document,
         handle_delete_document,
+        handle_purge_document,
         // Folder
         handle_query_folders,
         handle_save_folder,
@@ -196,6 +237,15 @@ use crate::types::{
         handle_query_settings,
         handle_save_settings,
         handle_delete_settings,
+        handle_import_settings,
+        handle_diff_settings,
+        // Audit
+        handle_query_audit_logs,
+        // Attachment
+        handle_upload_attachment,
+        handle_download_attachment,
+        // Config
+        handle_get_features,
         // Browser IndexedDB
         handle_browser_indexeddb_get,
         handle_browser_indexeddb_get_all,
@@ -210,13 +260,20 @@ use crate::types::{
             BaseBean,
             PageRequest,
             PageResponse,
+            SortOrder,
+            ErrorEnvelope,
+            OperationOutcome,
+            OperationAction,
             // Module of Auth
-            CallbackOidcRequest,
-            CallbackGithubRequest,
+            OAuthCallbackParams,
             PasswordPubKeyRequest,
             PasswordPubKeyResponse,
             PasswordLoginRequest,
+            EmailLoginRequest,
             LogoutRequest,
+            RefreshTokenRequest,
+            TokenInvalidReason,
+            ValidateTokenResponse,
             // Module of User
             User,
             QueryUserRequest,
@@ -226,6 +283,11 @@ use crate::types::{
             SaveUserResponse,
             DeleteUserRequest,
             DeleteUserResponse,
+            ExportUserDataRequest,
+            UserDataExport,
+            EraseUserRequest,
+            EraseUserResponse,
+            ErasureOutcome,
             QueryUserApiV1Request,
             QueryUserApiV1Response,
             SaveUserApiV1Request,
@@ -236,6 +298,8 @@ use crate::types::{
             Document,
             QueryDocumentRequest,
             QueryDocumentResponse,
+            DeletedDocument,
+            QueryDeletedDocumentResponse,
             SaveDocumentRequest,
             SaveDocumentResponse,
             DeleteDocumentRequest,
@@ -257,6 +321,17 @@ use crate::types::{
             SaveSettingsResponse,
             DeleteSettingsRequest,
             DeleteSettingsResponse,
+            ImportSettingsRequest,
+            ImportSettingsResponse,
+            DiffSettingsRequest,
+            DiffSettingsResponse,
+            // Module of Audit
+            AuditLog,
+            QueryAuditLogsRequest,
+            QueryAuditLogsResponse,
+            // Module of Config
+            FeatureFlags,
+            LoginProviderFlags,
             // Module of Browser IndexedDB
             IndexedValue,
             GetIndexedRecordRequest,
@@ -0,0 +1,42 @@
+/*
+ * SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+ *
+ * Copyleft (c) 2024 James Wong. This file is part of James Wong.
+ * is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * James Wong is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * IMPORTANT: Any software that fully or partially contains or uses materials
+ * covered by this license must also be released under the GNU GPL license.
+ * This includes modifications and derived works.
+ */
+
+use anyhow::Error;
+use axum::async_trait;
+
+use crate::types::user::User;
+
+/// The hook of user registration, e.g. sending the welcome email or calling the webhook.
+#[async_trait]
+pub trait RegistrationHook: Send + Sync {
+    /// Invoked after the new user has been created by the login callbacks (not on updates).
+    async fn on_user_registered(&self, user: &User) -> Result<(), Error>;
+}
+
+pub struct NoopRegistrationHook;
+
+#[async_trait]
+impl RegistrationHook for NoopRegistrationHook {
+    async fn on_user_registered(&self, _user: &User) -> Result<(), Error> {
+        Ok(())
+    }
+}
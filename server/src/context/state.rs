@@ -21,6 +21,7 @@
  */
 
 use std::sync::Arc;
+use std::time::Duration;
 use oauth2::basic::BasicClient;
 use tokio::sync::Mutex;
 
@@ -28,13 +29,18 @@ use crate::cache::memory::StringMemoryCache;
 use crate::cache::redis::StringRedisCache;
 use crate::cache::CacheContainer;
 // use crate::monitoring::health::{ MongoChecker, RedisClusterChecker, SQLiteChecker };
+use crate::types::audit::AuditLog;
 use crate::types::document::Document;
 use crate::types::folder::Folder;
 use crate::types::settings::Settings;
 use crate::types::user::User;
-use crate::config::config_serve::WebServeConfig;
+use crate::config::config_serve::{ DbProperties, DbType, WebServeConfig, DEFAULT_DEGRADED_RETRY_AFTER };
+use crate::context::hooks::{ NoopRegistrationHook, RegistrationHook };
+use crate::mgmt::health::WriteHealth;
 use crate::store::{
     RepositoryContainer,
+    audit_logs_sqlite::AuditLogSQLiteRepository,
+    audit_logs_mongo::AuditLogMongoRepository,
     documents_sqlite::DocumentSQLiteRepository,
     documents_mongo::DocumentMongoRepository,
     folders_sqlite::FolderSQLiteRepository,
@@ -44,14 +50,17 @@ use crate::store::{
     users_sqlite::UserSQLiteRepository,
     users_mongo::UserMongoRepository,
 };
-use crate::utils::{ self, httpclients };
+#[cfg(feature = "postgres")]
+use crate::store::users_postgres::UserPgRepository;
+use crate::types::PageResponse;
+use crate::utils::{ self, httpclients, singleflight::SingleFlight, swr::SwrCache };
 
 #[derive(Clone)]
 pub struct AppState {
     pub config: Arc<WebServeConfig>,
     // The basic operators.
     pub string_cache: Arc<CacheContainer<String>>,
-    pub oidc_client: Option<Arc<openidconnect::core::CoreClient>>,
+    pub oidc_client: Option<Arc<SwrCache<openidconnect::core::CoreClient>>>,
     pub github_client: Option<Arc<BasicClient>>,
     pub default_http_client: Arc<reqwest::Client>,
     // The modules repositories.
@@ -59,6 +68,13 @@ pub struct AppState {
     pub document_repo: Arc<Mutex<RepositoryContainer<Document>>>,
     pub folder_repo: Arc<Mutex<RepositoryContainer<Folder>>>,
     pub settings_repo: Arc<Mutex<RepositoryContainer<Settings>>>,
+    pub audit_log_repo: Arc<Mutex<RepositoryContainer<AuditLog>>>,
+    // The extension hooks.
+    pub registration_hook: Arc<dyn RegistrationHook>,
+    // The coalescing of concurrent identical settings reads (by user and query).
+    pub settings_flight: Arc<SingleFlight<(PageResponse, Vec<Settings>)>>,
+    // The health of the writes to the primary db, which drives the degraded mode.
+    pub write_health: Arc<WriteHealth>,
     // // The health checker.
     // pub sqlite_checker: SQLiteChecker,
     // pub mongo_checker: MongoChecker,
@@ -94,6 +110,7 @@ impl AppState {
             Box::new(UserSQLiteRepository::new(&db_config).await.unwrap()),
             Box::new(UserMongoRepository::new(&db_config).await.unwrap())
         );
+        let user_repo_container = Self::with_postgres_users(user_repo_container, db_config).await;
         let document_repo_container = RepositoryContainer::new(
             Box::new(DocumentSQLiteRepository::new(&db_config).await.unwrap()),
             Box::new(DocumentMongoRepository::new(&db_config).await.unwrap())
@@ -106,6 +123,10 @@ impl AppState {
             Box::new(SettingsSQLiteRepository::new(&db_config).await.unwrap()),
             Box::new(SettingsMongoRepository::new(&db_config).await.unwrap())
         );
+        let audit_log_repo_container = RepositoryContainer::new(
+            Box::new(AuditLogSQLiteRepository::new(db_config).await.unwrap()),
+            Box::new(AuditLogMongoRepository::new(db_config).await.unwrap())
+        );
 
         let app_state = AppState {
             // Notice: Arc object clone only increments the reference counter, and does not copy the actual data block.
@@ -120,6 +141,16 @@ impl AppState {
             document_repo: Arc::new(Mutex::new(document_repo_container)),
             folder_repo: Arc::new(Mutex::new(folder_repo_container)),
             settings_repo: Arc::new(Mutex::new(settings_repo_container)),
+            audit_log_repo: Arc::new(Mutex::new(audit_log_repo_container)),
+            // The extension hooks.
+            registration_hook: Arc::new(NoopRegistrationHook),
+            // The coalescing of concurrent identical reads.
+            settings_flight: Arc::new(SingleFlight::new()),
+            write_health: Arc::new(
+                WriteHealth::new(
+                    Duration::from_secs(config.server.degraded_retry_after.unwrap_or(DEFAULT_DEGRADED_RETRY_AFTER))
+                )
+            ),
             // // The health checker.
             // sqlite_checker: SQLiteChecker::new(),
             // mongo_checker: MongoChecker::new(),
@@ -132,4 +163,43 @@ impl AppState {
 
         app_state
     }
+
+    // Only connect to the postgres when it's selected, the others modules are still backed by the sqlite.
+    #[cfg(feature = "postgres")]
+    async fn with_postgres_users(
+        container: RepositoryContainer<User>,
+        db_config: &DbProperties
+    ) -> RepositoryContainer<User> {
+        if db_config.db_type != DbType::Postgres {
+            return container;
+        }
+        tracing::warn!("Only the users are backed by the postgres for now, the others are backed by the sqlite.");
+        container.with_postgres(Box::new(UserPgRepository::new(db_config).await.unwrap()))
+    }
+
+    #[cfg(not(feature = "postgres"))]
+    async fn with_postgres_users(
+        container: RepositoryContainer<User>,
+        db_config: &DbProperties
+    ) -> RepositoryContainer<User> {
+        if db_config.db_type == DbType::Postgres {
+            panic!("The postgres db type requires the 'postgres' feature enabled");
+        }
+        container
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::config_serve::WebServeProperties;
+
+    // Build the app state with a fresh sqlite db in the temp directory.
+    pub(crate) async fn new_test_state(customize: impl FnOnce(&mut WebServeProperties)) -> AppState {
+        let dir = std::env::temp_dir().join(format!("mywebnote_test_{}", uuid::Uuid::new_v4()));
+        let mut properties = WebServeProperties::default();
+        properties.db.sqlite.dir = Some(dir.to_string_lossy().to_string());
+        customize(&mut properties);
+        AppState::new(&properties.to_config()).await
+    }
 }
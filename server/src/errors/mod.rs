@@ -0,0 +1,272 @@
+/*
+ * SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+ *
+ * Copyleft (c) 2024 James Wong. This file is part of James Wong.
+ * is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * James Wong is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * IMPORTANT: Any software that fully or partially contains or uses materials
+ * covered by this license must also be released under the GNU GPL license.
+ * This includes modifications and derived works.
+ */
+
+use axum::{ http::StatusCode, response::{ IntoResponse, Response }, Json };
+use serde::{ Deserialize, Serialize };
+
+use crate::mgmt::apm::otel::{ current_trace_id, record_error_chain };
+use crate::store::{ is_unique_violation, StoreError };
+use crate::types::{ attach_correlation_ids, build_envelope, ApiVersion, RequestCorrelation };
+
+/// The extension of errors that carries the HTTP status to respond.
+pub trait ErrorExt: std::error::Error {
+    fn status_code(&self) -> StatusCode;
+
+    /// The message that is safe to be responded to the client.
+    fn output_msg(&self) -> String {
+        self.to_string()
+    }
+
+    /// Converts to the plain JSON response '{"code": <status>, "message": <output_msg>}', which is for the errors
+    /// not responded by the handlers envelope, e.g. the errors of the middlewares.
+    fn to_http_response(&self) -> Response {
+        let status = self.status_code();
+        let body = serde_json::json!({ "code": status.as_u16() as u32, "message": self.output_msg() });
+        (status, Json(body)).into_response()
+    }
+}
+
+/// The stable JSON shape of errors for the frontend, e.g.
+/// '{"code": 400, "status": "Bad Request", "message": "...", "trace_id": "..."}'.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
+pub struct ErrorEnvelope {
+    pub code: u32,
+    // The canonical reason of the status code.
+    pub status: String,
+    // The masked message, i.e. the output_msg() of the error.
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+}
+
+impl ErrorEnvelope {
+    pub fn from_error(err: &dyn ErrorExt) -> Self {
+        let status = err.status_code();
+        Self {
+            code: status.as_u16() as u32,
+            status: status.canonical_reason().unwrap_or("Unknown").to_string(),
+            message: err.output_msg(),
+            trace_id: current_trace_id(),
+        }
+    }
+}
+
+/// The unified error of handlers, which is rendered as the JSON envelope '{"errcode": <status>, "errmsg": "..."}'.
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    #[error("Unauthorized: {0}")]
+    Auth(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    #[error("Invalid parameter: {0}")]
+    Validation(String),
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    // The temporary unavailable, e.g. the writes during the degraded mode, which could be retried later.
+    #[error("Service unavailable: {0}")]
+    Unavailable(String),
+    #[error("Storage error: {0}")]
+    Storage(#[source] anyhow::Error),
+    #[error("Internal error: {0}")]
+    Internal(#[source] anyhow::Error),
+}
+
+impl AppError {
+    /// Wraps the error of repositories, the unique constraint violation (and purging the active row) is
+    /// recognized as conflict and the missing row as not found.
+    pub fn storage(e: anyhow::Error) -> Self {
+        if is_unique_violation(&e) {
+            AppError::Conflict(e.to_string())
+        } else if let Some(StoreError::NotFound(..)) = e.downcast_ref::<StoreError>() {
+            AppError::NotFound(e.to_string())
+        } else if let Some(StoreError::NotDeleted(..) | StoreError::Conflict(..)) = e.downcast_ref::<StoreError>() {
+            AppError::Conflict(e.to_string())
+        } else if let Some(StoreError::InvalidCursor(msg)) = e.downcast_ref::<StoreError>() {
+            AppError::Validation(msg.clone())
+        } else {
+            AppError::Storage(e)
+        }
+    }
+}
+
+impl ErrorExt for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::Auth(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::Validation(_) => StatusCode::BAD_REQUEST,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Storage(_) | AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn output_msg(&self) -> String {
+        match self {
+            // Don't leak the details of internal errors to client.
+            AppError::Storage(_) => "Storage error".to_string(),
+            AppError::Internal(_) => "Internal error".to_string(),
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for AppError {
+    fn from(e: anyhow::Error) -> Self {
+        AppError::Internal(e)
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status_code();
+        let correlation = RequestCorrelation::current();
+        let request_id = correlation.as_ref().map(|c| c.request_id.as_str()).unwrap_or_default();
+        let trace_id = current_trace_id();
+        let trace_id_field = trace_id.as_deref().unwrap_or_default();
+        let storage_fault = matches!(self, AppError::Storage(_));
+        match &self {
+            // The chain is recorded as the span events when traced, otherwise logged as is.
+            AppError::Storage(e) | AppError::Internal(e) if tracing::Span::current().is_none() => {
+                tracing::error!(request_id, trace_id = trace_id_field, "Failed to handle request. reason: {:?}", e);
+            }
+            AppError::Storage(e) | AppError::Internal(e) => record_error_chain(status, e),
+            _ => tracing::debug!(request_id, trace_id = trace_id_field, "Failed to handle request. reason: {}", self),
+        }
+        let version = ApiVersion::current();
+        let mut body = build_envelope(version, status.as_u16() as i64, &self.output_msg());
+        if correlation.as_ref().is_some_and(|c| c.expose) {
+            attach_correlation_ids(&mut body, version, request_id, trace_id.as_deref());
+        }
+        let mut response = (status, Json(body)).into_response();
+        if storage_fault {
+            response.extensions_mut().insert(StorageFault);
+        }
+        response
+    }
+}
+
+// The marker of the responses failed by the storage, so that the outer middlewares could tell the storage
+// outage from the others, e.g. the degraded mode.
+#[derive(Clone, Copy, Debug)]
+pub struct StorageFault;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use serde_json::json;
+
+    async fn render(err: AppError) -> (StatusCode, serde_json::Value) {
+        let response = err.into_response();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_app_error_renders_status_and_envelope() {
+        let cases = vec![
+            (AppError::Auth("no token".to_string()), StatusCode::UNAUTHORIZED, "Unauthorized: no token"),
+            (AppError::Forbidden("user 2".to_string()), StatusCode::FORBIDDEN, "Forbidden: user 2"),
+            (AppError::Validation("name".to_string()), StatusCode::BAD_REQUEST, "Invalid parameter: name"),
+            (AppError::NotFound("settings 1".to_string()), StatusCode::NOT_FOUND, "Not found: settings 1"),
+            (AppError::Conflict("name".to_string()), StatusCode::CONFLICT, "Conflict: name"),
+            (AppError::Unavailable("writes".to_string()), StatusCode::SERVICE_UNAVAILABLE, "Service unavailable: writes"),
+            (AppError::Storage(anyhow!("disk I/O")), StatusCode::INTERNAL_SERVER_ERROR, "Storage error"),
+            (AppError::Internal(anyhow!("oops")), StatusCode::INTERNAL_SERVER_ERROR, "Internal error")
+        ];
+        for (err, status, errmsg) in cases {
+            let (actual_status, body) = render(err).await;
+            assert_eq!(actual_status, status);
+            assert_eq!(body, json!({ "errcode": status.as_u16(), "errmsg": errmsg }));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_ext_to_http_response() {
+        let cases = vec![
+            (AppError::Validation("name".to_string()), StatusCode::BAD_REQUEST, "Invalid parameter: name"),
+            (AppError::Internal(anyhow!("secret")), StatusCode::INTERNAL_SERVER_ERROR, "Internal error"),
+            (AppError::Storage(anyhow!("disk I/O")), StatusCode::INTERNAL_SERVER_ERROR, "Storage error")
+        ];
+        for (err, status, message) in cases {
+            let response = err.to_http_response();
+            assert_eq!(response.status(), status);
+            let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(body, json!({ "code": status.as_u16(), "message": message }));
+        }
+    }
+
+    #[test]
+    fn test_error_envelope_round_trip() {
+        let envelope = ErrorEnvelope::from_error(&AppError::Validation("name".to_string()));
+        assert_eq!(envelope, ErrorEnvelope {
+            code: 400,
+            status: "Bad Request".to_string(),
+            message: "Invalid parameter: name".to_string(),
+            trace_id: None,
+        });
+        let json = serde_json::to_value(&envelope).unwrap();
+        assert_eq!(json, json!({ "code": 400, "status": "Bad Request", "message": "Invalid parameter: name" }));
+        assert_eq!(serde_json::from_value::<ErrorEnvelope>(json).unwrap(), envelope);
+
+        let traced = ErrorEnvelope { trace_id: Some("4bf92f3577b34da6a3ce929d0e0e4736".to_string()), ..envelope };
+        let json = serde_json::to_string(&traced).unwrap();
+        assert_eq!(serde_json::from_str::<ErrorEnvelope>(&json).unwrap(), traced);
+    }
+
+    #[test]
+    fn test_error_envelope_masks_internal_errors() {
+        for err in [AppError::Internal(anyhow!("password=secret")), AppError::Storage(anyhow!("disk /dev/sda1"))] {
+            let envelope = ErrorEnvelope::from_error(&err);
+            assert_eq!(envelope.code, 500);
+            assert_eq!(envelope.status, "Internal Server Error");
+            assert!(!envelope.message.contains("secret") && !envelope.message.contains("sda1"), "{}", envelope.message);
+        }
+    }
+
+    #[test]
+    fn test_app_error_from_anyhow_is_internal() {
+        let err: AppError = anyhow!("boom").into();
+        assert!(matches!(err, AppError::Internal(_)));
+        assert!(matches!(AppError::storage(anyhow!("boom")), AppError::Storage(_)));
+    }
+
+    #[test]
+    fn test_app_error_storage_not_found_and_unavailable() {
+        let not_found = AppError::storage(StoreError::NotFound("user", 1).into());
+        assert_eq!(not_found.status_code(), StatusCode::NOT_FOUND);
+        let not_deleted = AppError::storage(StoreError::NotDeleted("document", 1).into());
+        assert_eq!(not_deleted.status_code(), StatusCode::CONFLICT);
+        let conflict = AppError::storage(StoreError::Conflict("user", 1).into());
+        assert_eq!(conflict.status_code(), StatusCode::CONFLICT);
+        let invalid_cursor = AppError::storage(StoreError::InvalidCursor("Invalid page cursor: x".to_string()).into());
+        assert_eq!(invalid_cursor.status_code(), StatusCode::BAD_REQUEST);
+        let unavailable = AppError::storage(StoreError::StorageUnavailable(anyhow!("closed")).into());
+        assert_eq!(unavailable.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,161 @@
+/*
+ * SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+ *
+ * Copyleft (c) 2024 James Wong. This file is part of James Wong.
+ * is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * James Wong is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * IMPORTANT: Any software that fully or partially contains or uses materials
+ * covered by this license must also be released under the GNU GPL license.
+ * This includes modifications and derived works.
+ */
+
+use axum::async_trait;
+
+use crate::config::config_serve::DEFAULT_AUDIT_MAX_PAGE_SIZE;
+use crate::context::state::AppState;
+use crate::errors::AppError;
+use crate::types::audit::{ AuditLog, QueryAuditLogsRequest };
+use crate::types::{ PageRequest, PageResponse };
+
+#[async_trait]
+pub trait IAuditHandler: Send {
+    async fn record(&self, audit_log: AuditLog) -> Result<i64, AppError>;
+
+    // Find the audit logs by filters in order of create time desc, the page size is capped to the
+    // configured max page size.
+    async fn find(
+        &self,
+        param: QueryAuditLogsRequest,
+        page: PageRequest
+    ) -> Result<(PageResponse, Vec<AuditLog>), AppError>;
+}
+
+pub struct AuditHandler<'a> {
+    state: &'a AppState,
+}
+
+impl<'a> AuditHandler<'a> {
+    pub fn new(state: &'a AppState) -> Self {
+        Self { state }
+    }
+
+    fn cap_page(&self, mut page: PageRequest) -> PageRequest {
+        let max_page_size = self.state.config.server.audit_max_page_size.unwrap_or(DEFAULT_AUDIT_MAX_PAGE_SIZE);
+        page.limit = Some(page.get_limit().min(max_page_size.max(1)));
+        page
+    }
+}
+
+#[async_trait]
+impl<'a> IAuditHandler for AuditHandler<'a> {
+    async fn record(&self, audit_log: AuditLog) -> Result<i64, AppError> {
+        let repo = self.state.audit_log_repo.lock().await;
+        repo.get(&self.state.config).insert(audit_log).await.map_err(AppError::storage)
+    }
+
+    async fn find(
+        &self,
+        param: QueryAuditLogsRequest,
+        page: PageRequest
+    ) -> Result<(PageResponse, Vec<AuditLog>), AppError> {
+        if let (Some(start_time), Some(end_time)) = (param.start_time, param.end_time) {
+            if start_time > end_time {
+                return Err(AppError::Validation(format!("start_time {} is after end_time {}", start_time, end_time)));
+            }
+        }
+        let page = self.cap_page(page);
+        let repo = self.state.audit_log_repo.lock().await;
+        repo.get(&self.state.config).select(param.to_audit_log(), page).await.map_err(AppError::storage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::state::tests::new_test_state;
+    use crate::types::audit::AUDIT_EVENT_LOGIN;
+
+    // Record the audit log, and wait for the next millis so that the create time is ascending.
+    async fn record_next(handler: &AuditHandler<'_>, uid: i64, event_type: &str) {
+        handler.record(AuditLog::new(Some(uid), Some(event_type.to_string()), None, None)).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+    }
+
+    fn query(uid: Option<i64>, event_type: Option<&str>, start_time: Option<i64>, end_time: Option<i64>) -> QueryAuditLogsRequest {
+        QueryAuditLogsRequest { uid, event_type: event_type.map(|t| t.to_string()), start_time, end_time }
+    }
+
+    fn page(num: u32, limit: Option<u32>) -> PageRequest {
+        PageRequest { num: Some(num), limit, ..PageRequest::default() }
+    }
+
+    #[tokio::test]
+    async fn test_find_audit_logs_filtered() {
+        let state = new_test_state(|_| {}).await;
+        let handler = AuditHandler::new(&state);
+        for (uid, event_type) in [(1, AUDIT_EVENT_LOGIN), (1, "export"), (2, AUDIT_EVENT_LOGIN), (1, AUDIT_EVENT_LOGIN)] {
+            record_next(&handler, uid, event_type).await;
+        }
+        let (_, all) = handler.find(query(None, None, None, None), page(1, None)).await.unwrap();
+        // In order of create time desc.
+        let times = all.iter().map(|l| l.base.create_time.unwrap()).collect::<Vec<_>>();
+        assert_eq!(times.len(), 4);
+        assert!(times.windows(2).all(|w| w[0] > w[1]), "{:?}", times);
+
+        let (resp, data) = handler.find(query(Some(1), None, None, None), page(1, None)).await.unwrap();
+        assert_eq!(resp.total, Some(3));
+        assert!(data.iter().all(|l| l.uid == Some(1)));
+
+        let (resp, data) = handler.find(query(None, Some(AUDIT_EVENT_LOGIN), None, None), page(1, None)).await.unwrap();
+        assert_eq!(resp.total, Some(3));
+        assert!(data.iter().all(|l| l.event_type.as_deref() == Some(AUDIT_EVENT_LOGIN)));
+
+        // The range of create time is inclusive.
+        let (resp, data) = handler.find(query(None, None, Some(times[2]), Some(times[1])), page(1, None)).await.unwrap();
+        assert_eq!(resp.total, Some(2));
+        assert_eq!(data.iter().map(|l| l.base.create_time.unwrap()).collect::<Vec<_>>(), vec![times[1], times[2]]);
+
+        let (resp, data) = handler
+            .find(query(Some(1), Some(AUDIT_EVENT_LOGIN), Some(times[2]), None), page(1, None)).await
+            .unwrap();
+        assert_eq!(resp.total, Some(1));
+        assert_eq!(data[0].base.create_time, Some(times[0]));
+
+        let err = handler.find(query(None, None, Some(times[0]), Some(times[3])), page(1, None)).await.unwrap_err();
+        assert!(matches!(err, AppError::Validation(_)));
+    }
+
+    #[tokio::test]
+    async fn test_find_audit_logs_capped_to_max_page_size() {
+        let state = new_test_state(|p| {
+            p.server.audit_max_page_size = Some(5);
+        }).await;
+        let handler = AuditHandler::new(&state);
+        for _ in 0..12 {
+            record_next(&handler, 1, AUDIT_EVENT_LOGIN).await;
+        }
+
+        let (resp, data) = handler.find(query(None, None, None, None), page(1, Some(u32::MAX))).await.unwrap();
+        assert_eq!(resp.total, Some(12));
+        assert_eq!(resp.limit, Some(5));
+        assert_eq!(data.len(), 5);
+
+        // The later pages are paginated by the capped size.
+        let (_, data) = handler.find(query(None, None, None, None), page(3, Some(1000))).await.unwrap();
+        assert_eq!(data.len(), 2);
+        let (_, second) = handler.find(query(None, None, None, None), page(2, None)).await.unwrap();
+        assert_eq!(second.len(), 5);
+        assert!(second[4].base.create_time > data[0].base.create_time);
+    }
+}
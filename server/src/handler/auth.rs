@@ -5,33 +5,47 @@ use hyper::{ header, StatusCode };
 use lazy_static::lazy_static;
 use anyhow::{ anyhow, Error, Ok };
 use chrono::Utc;
-use openidconnect::{ core::CoreUserInfoClaims, LanguageTag };
+use openidconnect::{ core::CoreUserInfoClaims, LanguageTag, PkceCodeChallenge, PkceCodeVerifier };
 use serde::{ Deserialize, Serialize };
-use tower_cookies::cookie::{ time::Duration, CookieBuilder, SameSite };
+use tower_cookies::cookie::time::Duration;
 
 use ethers::types::{ Address, Signature };
 
 use crate::{
-    config::config_serve::WebServeConfig,
+    config::config_serve::{ AccountMergeStrategy, WebServeConfig },
     context::state::AppState,
     types::{
+        audit::{ AuditLog, AUDIT_EVENT_LOGIN },
         auth::{
             EthersWalletLoginRequest,
             GithubUserInfo,
+            GoogleUserInfo,
             LogoutRequest,
             PasswordLoginRequest,
             PasswordPubKeyRequest,
         },
         user::{ SaveUserRequest, User },
+        OperationAction,
+        PageRequest,
     },
-    utils::{ self, auths, rsa_ciphers::RSACipher },
+    utils::{ self, auths, rsa_ciphers::RSACipher, webs },
 };
 
+use super::audit::{ AuditHandler, IAuditHandler };
 use super::user::{ IUserHandler, UserHandler };
 
 pub const AUTH_NONCE_PREFIX: &'static str = "auth:nonce:";
+pub const AUTH_STATE_PREFIX: &str = "auth:state:";
+pub const AUTH_PKCE_PREFIX: &str = "auth:pkce:";
+pub const AUTH_LINK_PREFIX: &str = "auth:link:";
+// The oauth2 state only lives for the round-trip of the provider authorization.
+pub const AUTH_STATE_EXPIRE_SECS: i32 = 300;
 pub const LOGIN_PRIVATE_KEY_PREFIX: &'static str = "login:privatekey:";
 pub const LOGOUT_BLACKLIST_PREFIX: &'static str = "logout:blacklist:";
+pub const AUTH_LOGOUT_ALL_PREFIX: &str = "auth:logout:all:";
+pub const LOGIN_FAILURES_PREFIX: &str = "login:failures:";
+pub const LOGIN_LOCKED_PREFIX: &str = "login:locked:";
+pub const VALIDATE_RATE_PREFIX: &str = "auth:validate:rate:";
 
 lazy_static! {
     pub static ref LANG_CLAIMS_NAME_KEY: LanguageTag = LanguageTag::new("name".to_owned());
@@ -42,22 +56,137 @@ pub enum PrincipalType {
     Password,
     OIDC,
     Github,
+    Google,
     EtherWallet,
 }
 
+// The user info of the third-party login providers, which maps to the provider claims of the user.
+pub trait ProviderUserInfo: Send + Sync {
+    fn provider(&self) -> PrincipalType;
+
+    fn subject(&self) -> Option<String>;
+
+    fn display_name(&self) -> Option<String>;
+
+    fn email(&self) -> Option<String>;
+
+    // Whether the email has been verified by the provider, only the verified email is trusted to link.
+    fn email_verified(&self) -> bool;
+}
+
+impl ProviderUserInfo for CoreUserInfoClaims {
+    fn provider(&self) -> PrincipalType {
+        PrincipalType::OIDC
+    }
+
+    fn subject(&self) -> Option<String> {
+        Some(CoreUserInfoClaims::subject(self).to_string())
+    }
+
+    fn display_name(&self) -> Option<String> {
+        self.preferred_username().map(|c| c.to_string())
+    }
+
+    fn email(&self) -> Option<String> {
+        CoreUserInfoClaims::email(self).map(|c| c.to_string())
+    }
+
+    fn email_verified(&self) -> bool {
+        CoreUserInfoClaims::email_verified(self).unwrap_or(false)
+    }
+}
+
+impl ProviderUserInfo for GithubUserInfo {
+    fn provider(&self) -> PrincipalType {
+        PrincipalType::Github
+    }
+
+    fn subject(&self) -> Option<String> {
+        self.id.map(|id| id.to_string())
+    }
+
+    fn display_name(&self) -> Option<String> {
+        self.login.clone()
+    }
+
+    fn email(&self) -> Option<String> {
+        self.email.clone()
+    }
+
+    // The public email of github user info isn't guaranteed to be verified.
+    fn email_verified(&self) -> bool {
+        false
+    }
+}
+
+impl ProviderUserInfo for GoogleUserInfo {
+    fn provider(&self) -> PrincipalType {
+        PrincipalType::Google
+    }
+
+    fn subject(&self) -> Option<String> {
+        self.sub.clone()
+    }
+
+    fn display_name(&self) -> Option<String> {
+        self.name.clone()
+    }
+
+    fn email(&self) -> Option<String> {
+        self.email.clone()
+    }
+
+    fn email_verified(&self) -> bool {
+        self.email_verified.unwrap_or(false)
+    }
+}
+
 #[async_trait]
 pub trait IAuthHandler: Send {
     async fn handle_password_pubkey(&self, param: PasswordPubKeyRequest) -> Result<String, Error>;
 
     async fn handle_password_verify(&self, param: PasswordLoginRequest) -> Result<Arc<User>, Error>;
 
+    /// Logins by the email and plaintext password, which is verified against the stored hash.
+    async fn handle_login_password(
+        &self,
+        email: &str,
+        password: &str,
+        client_ip: Option<String>,
+        headers: &header::HeaderMap
+    ) -> Result<hyper::Response<axum::body::Body>, Error>;
+
     async fn handle_auth_create_nonce(&self, sid: &str, nonce: String) -> Result<(), Error>;
 
     async fn handle_auth_get_nonce(&self, sid: &str) -> Result<Option<String>, Error>;
 
-    async fn handle_auth_callback_oidc(&self, userinfo: CoreUserInfoClaims) -> Result<i64, Error>;
+    /// Caches the oauth2 'state' of the authorization session, for verifying the callback against CSRF.
+    async fn handle_auth_create_state(&self, sid: &str, state: String) -> Result<(), Error>;
+
+    /// Verifies the callback 'state' is the one cached for the session, which is single-use and deleted
+    /// whether it matches or not.
+    async fn handle_auth_verify_state(&self, sid: &str, state: Option<&str>) -> Result<(), Error>;
+
+    /// Creates the PKCE code verifier of the authorization session, and returns the S256 code challenge.
+    async fn handle_auth_create_pkce(&self, sid: &str) -> Result<PkceCodeChallenge, Error>;
+
+    /// Takes (single-use) the PKCE code verifier of the authorization session for exchanging the code.
+    async fn handle_auth_take_pkce_verifier(&self, sid: &str) -> Result<Option<PkceCodeVerifier>, Error>;
+
+    /// Remembers the authenticated user of the authorization session, whom the provider identity of the
+    /// callback is linked to, instead of login by it.
+    async fn handle_auth_create_link(&self, sid: &str, uid: i64) -> Result<(), Error>;
 
-    async fn handle_auth_callback_github(&self, userinfo: GithubUserInfo) -> Result<i64, Error>;
+    /// Takes (single-use) the user id to link of the authorization session, if any.
+    async fn handle_auth_take_link(&self, sid: &str) -> Result<Option<i64>, Error>;
+
+    async fn handle_provider_callback(&self, userinfo: &dyn ProviderUserInfo) -> Result<i64, Error>;
+
+    /// Attaches the provider identity (subject/name/email claims) to the existing user, which fails if the
+    /// identity has been linked to another user. Returns the user id.
+    async fn handle_link_identity(&self, user_id: i64, userinfo: &dyn ProviderUserInfo) -> Result<i64, Error>;
+
+    async fn handle_auth_callback_google(&self, userinfo: GoogleUserInfo) -> Result<i64, Error>;
 
     async fn handle_wallet_verify_ethers(
         &self,
@@ -71,16 +200,57 @@ pub trait IAuthHandler: Send {
         uid: i64,
         uname: &str,
         email: &str,
+        client_ip: Option<String>,
         headers: &header::HeaderMap
-    ) -> hyper::Response<axum::body::Body>;
+    ) -> Result<hyper::Response<axum::body::Body>, Error>;
 
     async fn handle_logout(&self, param: LogoutRequest) -> Result<(), Error>;
 
+    /// Logout everywhere, i.e. revoke all the outstanding (access and refresh) tokens of the user, by
+    /// recording the min valid issued at, which the tokens issued before are rejected.
+    async fn handle_logout_all(&self, user_id: &str) -> Result<(), Error>;
+
+    /// Exchanges the valid refresh token for the new access token (and the rotated refresh token if
+    /// enabled), the tokens minted as access tokens or revoked are rejected.
+    async fn handle_refresh_token(
+        &self,
+        refresh_token: &str,
+        headers: &header::HeaderMap
+    ) -> Result<hyper::Response<axum::body::Body>, Error>;
+
+    /// Returns the remaining lockout seconds if any of the subjects (e.g. account/IP) is locked out.
+    async fn handle_login_throttle_check(&self, subjects: &[String]) -> Result<Option<u64>, Error>;
+
+    /// Records a failed login for the subjects, returns the lockout seconds if it has been locked out.
+    async fn handle_login_throttle_failure(
+        &self,
+        subjects: &[String]
+    ) -> Result<Option<u64>, Error>;
+
+    async fn handle_login_throttle_reset(&self, subjects: &[String]) -> Result<(), Error>;
+
+    /// Counts a token validating request of the subject (e.g. IP), returns the retry seconds if it is limited.
+    async fn handle_validate_rate_limit(&self, subject: &str) -> Result<Option<u64>, Error>;
+
     fn build_auth_nonce_key(&self, nonce: &str) -> String;
 
+    fn build_auth_state_key(&self, sid: &str) -> String;
+
+    fn build_auth_pkce_key(&self, sid: &str) -> String;
+
+    fn build_auth_link_key(&self, sid: &str) -> String;
+
     fn build_login_private_key(&self, fingerprint_token: &str) -> String;
 
     fn build_logout_blacklist_key(&self, access_token: &str) -> String;
+
+    fn build_logout_all_key(&self, user_id: &str) -> String;
+
+    fn build_login_failures_key(&self, subject: &str) -> String;
+
+    fn build_login_locked_key(&self, subject: &str) -> String;
+
+    fn build_validate_rate_key(&self, subject: &str) -> String;
 }
 
 pub struct AuthHandler<'a> {
@@ -91,6 +261,188 @@ impl<'a> AuthHandler<'a> {
     pub fn new(state: &'a AppState) -> Self {
         Self { state }
     }
+
+    fn is_auto_register(&self) -> bool {
+        self.state.config.auth.auto_register.unwrap_or(true)
+    }
+
+    fn is_login_throttle_enabled(&self) -> bool {
+        self.state.config.auth.login_throttle.enabled.unwrap_or(true)
+    }
+
+    // Save the user of provider login, and notify the registration hook if it is created.
+    async fn save_provider_user(&self, save_param: SaveUserRequest) -> Result<i64, Error> {
+        let mut user = save_param.to_user();
+        let outcome = UserHandler::new(self.state).save(save_param).await?;
+        if outcome.action == OperationAction::Created {
+            user.base.id = outcome.id;
+            // The hook failure should not fail the login.
+            if let Err(e) = self.state.registration_hook.on_user_registered(&user).await {
+                tracing::warn!("Failed to notify the registration of user {:?}. cause: {}", outcome.id, e);
+            }
+        }
+        Ok(outcome.id.unwrap_or(-1))
+    }
+
+    // Verify the password of the user of email against the stored hash, the unknown email and the wrong
+    // password are failed indistinguishably, both by the error and by the time (always running scrypt).
+    async fn verify_user_password(&self, email: &str, password: &str) -> Result<Arc<User>, Error> {
+        let user = UserHandler::new(self.state)
+            .get(None, None, Some(email.to_string()), None, None, None, None, None).await
+            .map_err(|e| {
+                tracing::error!("Failed to get user of {}, cause: {}", email, e);
+                e
+            })?;
+        let stored = user.as_ref().and_then(|u| u.password.to_owned()).unwrap_or_default();
+        let legacy = !stored.is_empty() && !auths::is_password_hashed(&stored);
+        let verified = if auths::is_password_hashed(&stored) {
+            auths::verify_password(password, &stored)
+        } else {
+            // The legacy password stored before hashing is compared as is, and rehashed once verified.
+            auths::verify_dummy_password(password);
+            legacy && auths::constant_time_eq(password.as_bytes(), stored.as_bytes())
+        };
+        match user {
+            Some(user) if verified => {
+                tracing::debug!("Login success for: {}", email);
+                if legacy {
+                    self.rehash_user_password(&user, password).await;
+                }
+                Ok(user)
+            }
+            _ => {
+                tracing::warn!("Login failed for: {}", email);
+                Err(anyhow!("Invalid email or password"))
+            }
+        }
+    }
+
+    // Migrate the legacy (not hashed) password of the user to the hashed, the failure should not fail the login,
+    // which is retried on the next login.
+    async fn rehash_user_password(&self, user: &User, password: &str) {
+        let save_param = SaveUserRequest {
+            id: user.base.id,
+            password: Some(password.to_string()),
+            ..SaveUserRequest::default()
+        };
+        match UserHandler::new(self.state).save(save_param).await {
+            std::result::Result::Ok(_) => tracing::info!("Rehashed the legacy password of user {:?}", user.base.id),
+            Err(e) => tracing::warn!("Failed to rehash the legacy password of user {:?}. cause: {}", user.base.id, e),
+        }
+    }
+
+    // Get the user by the subject of provider.
+    async fn get_provider_user(&self, provider: &PrincipalType, sub: &str) -> Result<Option<Arc<User>>, Error> {
+        let handler = UserHandler::new(self.state);
+        let sub = Some(sub.to_owned());
+        match provider {
+            PrincipalType::OIDC => handler.get(None, None, None, None, sub, None, None, None).await,
+            PrincipalType::Github => handler.get(None, None, None, None, None, sub, None, None).await,
+            PrincipalType::Google => handler.get(None, None, None, None, None, None, sub, None).await,
+            _ => Err(anyhow!("Unsupported provider user of {:?}", provider)),
+        }
+    }
+
+    // Find the existing user to link the provider identity by the same verified email, see 'account-merge'. Both
+    // sides must be verified, i.e. the stored email of the user had been verified by a provider, otherwise the
+    // pre-registered account of the victim's email would take over the victim's provider logins.
+    async fn find_merge_user(&self, userinfo: &dyn ProviderUserInfo) -> Result<Option<User>, Error> {
+        if self.state.config.auth.account_merge != AccountMergeStrategy::VerifiedEmail || !userinfo.email_verified() {
+            return Ok(None);
+        }
+        let email = match userinfo.email() {
+            Some(email) if !email.trim().is_empty() => email,
+            _ => {
+                return Ok(None);
+            }
+        };
+        let provider = userinfo.provider();
+        let param = User { verified_email: Some(email), ..User::default() };
+        let repo = self.state.user_repo.lock().await;
+        let (_, users) = repo.get(&self.state.config).select(param, PageRequest::default()).await?;
+        // Don't take over the user which has been bound to another identity of the same provider.
+        Ok(
+            users.into_iter().find(|user| {
+                match provider {
+                    PrincipalType::OIDC => user.oidc_claims_sub.is_none(),
+                    PrincipalType::Github => user.github_claims_sub.is_none(),
+                    PrincipalType::Google => user.google_claims_sub.is_none(),
+                    _ => false,
+                }
+            })
+        )
+    }
+
+    // The lockout of subject is stored as the 'locked_until' (ms), which is checked by ourselves because
+    // the memory cache does not support expiration.
+    async fn get_login_locked_until(&self, subject: &str) -> Result<i64, Error> {
+        let cache = self.state.string_cache.get(&self.state.config);
+        let value = cache.get(self.build_login_locked_key(subject)).await?;
+        Ok(value.and_then(|v| v.parse::<i64>().ok()).unwrap_or(0))
+    }
+
+    // The failed logins are counted in the fixed window, i.e. the key suffixed by the window index, so that
+    // the counter is restarted by the next window without the expiration.
+    fn build_login_failures_window_key(&self, subject: &str, window: i64, now: i64) -> String {
+        format!("{}:{}", self.build_login_failures_key(subject), now / window)
+    }
+}
+
+// The remaining seconds until the time (ms), rounded up, so that it would never be told to retry before.
+fn remaining_secs(until: i64, now: i64) -> Option<u64> {
+    if until > now {
+        Some(((until - now + 999) / 1000) as u64)
+    } else {
+        None
+    }
+}
+
+// Build the saving user of provider login, only the claims of the provider are set.
+fn build_provider_save_param(id: Option<i64>, sub: String, userinfo: &dyn ProviderUserInfo) -> SaveUserRequest {
+    let name = userinfo.display_name();
+    let email = userinfo.email();
+    let mut save_param = SaveUserRequest {
+        id,
+        name: name.to_owned(),
+        email: None,
+        phone: None,
+        password: None,
+        oidc_claims_sub: None,
+        oidc_claims_name: None,
+        oidc_claims_email: None,
+        github_claims_sub: None,
+        github_claims_name: None,
+        github_claims_email: None,
+        google_claims_sub: None,
+        google_claims_name: None,
+        google_claims_email: None,
+        verified_email: None,
+        ethers_address: None,
+        lang: None,
+    };
+    match userinfo.provider() {
+        PrincipalType::OIDC => {
+            save_param.oidc_claims_sub = Some(sub);
+            save_param.oidc_claims_name = name;
+            save_param.oidc_claims_email = email.clone();
+        }
+        PrincipalType::Github => {
+            save_param.github_claims_sub = Some(sub);
+            save_param.github_claims_name = name;
+            save_param.github_claims_email = email.clone();
+        }
+        PrincipalType::Google => {
+            save_param.google_claims_sub = Some(sub);
+            save_param.google_claims_name = name;
+            save_param.google_claims_email = email.clone();
+        }
+        _ => {}
+    }
+    // Keep the last verified email, so that the unverified doesn't overwrite it.
+    if userinfo.email_verified() {
+        save_param.verified_email = email;
+    }
+    save_param
 }
 
 #[async_trait]
@@ -140,44 +492,11 @@ impl<'a> IAuthHandler for AuthHandler<'a> {
                             }
                         };
 
-                        // Getting user from database.
-                        let handler = UserHandler::new(self.state);
-                        match handler.get(None, None, None, None, None, None, None, None).await {
-                            std::result::Result::Ok(user) => {
-                                match user {
-                                    Some(user) => {
-                                        let store_hashed_password = user.password
-                                            .clone()
-                                            .unwrap_or_default()
-                                            .into_bytes();
-                                        if
-                                            utils::auths::constant_time_eq(
-                                                &hashed_password,
-                                                &store_hashed_password
-                                            )
-                                        {
-                                            tracing::debug!("Login success for: {:?}", param);
-                                            Ok(user)
-                                        } else {
-                                            tracing::error!("Login failed for: {:?}", param);
-                                            Err(anyhow!("Invalid password"))
-                                        }
-                                    }
-                                    None => {
-                                        let errmsg = format!(
-                                            "No login user, Please confirm that the login account is correct. {:?}",
-                                            param
-                                        );
-                                        tracing::error!(errmsg);
-                                        Err(anyhow!(errmsg))
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                tracing::error!("Failed to get user. {:?}, cause: {}", param, e);
-                                Err(e.into())
-                            }
-                        }
+                        // Verifying the password of user from database.
+                        let password = String::from_utf8(hashed_password).map_err(|e|
+                            anyhow!("Unable decode password. {:?}", e.to_string())
+                        )?;
+                        self.verify_user_password(&param.username, &password).await
                     }
                     None => {
                         let errmsg = format!(
@@ -196,10 +515,29 @@ impl<'a> IAuthHandler for AuthHandler<'a> {
         }
     }
 
+    async fn handle_login_password(
+        &self,
+        email: &str,
+        password: &str,
+        client_ip: Option<String>,
+        headers: &header::HeaderMap
+    ) -> Result<hyper::Response<axum::body::Body>, Error> {
+        let user = self.verify_user_password(email, password).await?;
+        self.handle_login_success(
+            &self.state.config,
+            PrincipalType::Password,
+            user.base.id.unwrap(),
+            &user.name.to_owned().unwrap_or_default(),
+            &user.email.to_owned().unwrap_or_default(),
+            client_ip,
+            headers
+        ).await
+    }
+
     async fn handle_auth_create_nonce(&self, sid: &str, nonce: String) -> Result<(), Error> {
         let cache = self.state.string_cache.get(&self.state.config);
 
-        let key = self.build_logout_blacklist_key(sid);
+        let key = self.build_auth_nonce_key(sid);
         let value = nonce;
 
         // TODO: using expires config? To ensure safety, expire as soon as possible. 10s
@@ -218,7 +556,7 @@ impl<'a> IAuthHandler for AuthHandler<'a> {
     async fn handle_auth_get_nonce(&self, sid: &str) -> Result<Option<String>, Error> {
         let cache = self.state.string_cache.get(&self.state.config);
 
-        let key = self.build_logout_blacklist_key(sid);
+        let key = self.build_auth_nonce_key(sid);
 
         match cache.get(key).await {
             std::result::Result::Ok(nonce) => {
@@ -232,127 +570,141 @@ impl<'a> IAuthHandler for AuthHandler<'a> {
         }
     }
 
-    async fn handle_auth_callback_oidc(&self, userinfo: CoreUserInfoClaims) -> Result<i64, Error> {
-        let oidc_sub = userinfo.subject().as_str();
-        // let oidc_uname = userinfo.name().map(|n| n.get(Some(&LANG_CLAIMS_NAME_KEY)).map(|u| u.to_string()).unwrap_or_default());
-        let oidc_preferred_name = userinfo.preferred_username().map(|c| c.to_string());
-        let oidc_email = userinfo.email().map(|c| c.to_string());
+    async fn handle_auth_create_state(&self, sid: &str, state: String) -> Result<(), Error> {
+        let cache = self.state.string_cache.get(&self.state.config);
 
-        let handler = UserHandler::new(self.state);
+        let key = self.build_auth_state_key(sid);
+        match cache.set(key, state, Some(AUTH_STATE_EXPIRE_SECS)).await {
+            std::result::Result::Ok(_) => {
+                tracing::info!("Created auth state for {}", sid);
+                Ok(())
+            }
+            Err(e) => {
+                tracing::error!("Created auth state failed for {}, cause: {}", sid, e);
+                Err(e)
+            }
+        }
+    }
 
-        // 1. Get user by oidc uid
-        let user = handler
-            .get(None, None, None, None, Some(oidc_sub.to_string()), None, None, None).await
-            .unwrap();
+    async fn handle_auth_verify_state(&self, sid: &str, state: Option<&str>) -> Result<(), Error> {
+        let cache = self.state.string_cache.get(&self.state.config);
 
-        // 2. If user exists, update user github subject ID.
-        let save_param;
-        if user.is_some() {
-            save_param = SaveUserRequest {
-                id: user.unwrap().base.id,
-                name: oidc_preferred_name.to_owned(),
-                email: None,
-                phone: None,
-                password: None,
-                oidc_claims_sub: Some(oidc_sub.to_string()),
-                oidc_claims_name: oidc_preferred_name,
-                oidc_claims_email: oidc_email,
-                github_claims_sub: None,
-                github_claims_name: None,
-                github_claims_email: None,
-                google_claims_sub: None,
-                google_claims_name: None,
-                google_claims_email: None,
-                ethers_address: None,
-                lang: None,
-            };
-        } else {
-            // 3. If user not exists, create user by github login, which auto register user.
-            save_param = SaveUserRequest {
-                id: None,
-                name: oidc_preferred_name.to_owned(),
-                email: None,
-                phone: None,
-                password: None,
-                oidc_claims_sub: Some(oidc_sub.to_string()),
-                oidc_claims_name: oidc_preferred_name,
-                oidc_claims_email: oidc_email,
-                github_claims_sub: None,
-                github_claims_name: None,
-                github_claims_email: None,
-                google_claims_sub: None,
-                google_claims_name: None,
-                google_claims_email: None,
-                ethers_address: None,
-                lang: None,
-            };
-        }
-
-        match handler.save(save_param).await {
-            std::result::Result::Ok(uid) => Ok(uid),
-            Err(e) => Err(e),
-        }
-    }
-
-    async fn handle_auth_callback_github(&self, userinfo: GithubUserInfo) -> Result<i64, Error> {
-        let github_sub = userinfo.id.expect("github uid is None");
-        let github_uname = userinfo.login.expect("github uname is None");
-        let github_email = userinfo.email;
+        let key = self.build_auth_state_key(sid);
+        let cached = cache.get(key.to_owned()).await?;
+        // Single-use, so that the state cannot be replayed or guessed by retries.
+        if cached.is_some() {
+            cache.del(key).await?;
+        }
+        let state = state.filter(|s| !s.is_empty()).ok_or_else(|| anyhow!("Missing state"))?;
+        match cached {
+            Some(expected) if auths::constant_time_eq(expected.as_bytes(), state.as_bytes()) => Ok(()),
+            Some(_) => Err(anyhow!("Mismatched state")),
+            None => Err(anyhow!("Expired or used state")),
+        }
+    }
 
-        let handler = UserHandler::new(self.state);
+    async fn handle_auth_create_pkce(&self, sid: &str) -> Result<PkceCodeChallenge, Error> {
+        let cache = self.state.string_cache.get(&self.state.config);
 
-        // 1. Get user by github_uid
-        let user = handler
-            .get(None, None, None, None, None, Some(github_sub.to_string()), None, None).await
-            .unwrap();
+        let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
+        let key = self.build_auth_pkce_key(sid);
+        cache.set(key, verifier.secret().to_owned(), Some(AUTH_STATE_EXPIRE_SECS)).await?;
+        tracing::info!("Created auth pkce for {}", sid);
+        Ok(challenge)
+    }
 
-        // 2. If user exists, update user github subject ID.
-        let save_param;
-        if user.is_some() {
-            save_param = SaveUserRequest {
-                id: user.unwrap().base.id,
-                name: Some(github_uname.to_string()),
-                email: None,
-                phone: None,
-                password: None,
-                oidc_claims_sub: None,
-                oidc_claims_name: None,
-                oidc_claims_email: None,
-                github_claims_sub: Some(github_sub.to_string()),
-                github_claims_name: Some(github_uname.to_string()),
-                github_claims_email: github_email,
-                google_claims_sub: None,
-                google_claims_name: None,
-                google_claims_email: None,
-                ethers_address: None,
-                lang: None,
-            };
-        } else {
-            // 3. If user not exists, create user by github login, which auto register user.
-            save_param = SaveUserRequest {
-                id: None,
-                name: Some(github_uname.to_string()),
-                email: None,
-                phone: None,
-                password: None,
-                oidc_claims_sub: None,
-                oidc_claims_name: None,
-                oidc_claims_email: None,
-                github_claims_sub: Some(github_sub.to_string()),
-                github_claims_name: Some(github_uname.to_string()),
-                github_claims_email: github_email,
-                google_claims_sub: None,
-                google_claims_name: None,
-                google_claims_email: None,
-                ethers_address: None,
-                lang: None,
-            };
-        }
-
-        match handler.save(save_param).await {
-            std::result::Result::Ok(uid) => Ok(uid),
-            Err(e) => Err(e),
+    async fn handle_auth_take_pkce_verifier(&self, sid: &str) -> Result<Option<PkceCodeVerifier>, Error> {
+        let cache = self.state.string_cache.get(&self.state.config);
+
+        let key = self.build_auth_pkce_key(sid);
+        let verifier = cache.get(key.to_owned()).await?;
+        if verifier.is_some() {
+            cache.del(key).await?;
+        }
+        Ok(verifier.map(PkceCodeVerifier::new))
+    }
+
+    async fn handle_auth_create_link(&self, sid: &str, uid: i64) -> Result<(), Error> {
+        let cache = self.state.string_cache.get(&self.state.config);
+
+        let key = self.build_auth_link_key(sid);
+        cache.set(key, uid.to_string(), Some(AUTH_STATE_EXPIRE_SECS)).await?;
+        tracing::info!("Created auth link of user {} for {}", uid, sid);
+        Ok(())
+    }
+
+    async fn handle_auth_take_link(&self, sid: &str) -> Result<Option<i64>, Error> {
+        let cache = self.state.string_cache.get(&self.state.config);
+
+        let key = self.build_auth_link_key(sid);
+        let uid = cache.get(key.to_owned()).await?;
+        if uid.is_some() {
+            cache.del(key).await?;
         }
+        Ok(uid.and_then(|uid| uid.parse().ok()))
+    }
+
+    async fn handle_provider_callback(&self, userinfo: &dyn ProviderUserInfo) -> Result<i64, Error> {
+        let provider = userinfo.provider();
+        let sub = userinfo
+            .subject()
+            .ok_or_else(|| anyhow!("Missing the subject of {:?} user", provider))?;
+
+        // 1. Get user by the provider subject.
+        let user = self.get_provider_user(&provider, &sub).await?;
+
+        // 2. If user exists, update the user provider claims, otherwise link to the existing user of the
+        // same verified email if configured, or create user which auto register user.
+        let (id, linked) = match user {
+            Some(user) => (user.base.id, false),
+            None =>
+                match self.find_merge_user(userinfo).await? {
+                    Some(user) => {
+                        tracing::info!("Linked the {:?} user {} to the existing user {:?} by email", provider, sub, user.base.id);
+                        (user.base.id, true)
+                    }
+                    None => {
+                        if !self.is_auto_register() {
+                            return Err(anyhow!("Account not provisioned for {:?} user: {}", provider, sub));
+                        }
+                        (None, false)
+                    }
+                }
+        };
+        let mut save_param = build_provider_save_param(id, sub, userinfo);
+        if linked {
+            // Keep the name of the existing user.
+            save_param.name = None;
+        }
+        self.save_provider_user(save_param).await
+    }
+
+    async fn handle_link_identity(&self, user_id: i64, userinfo: &dyn ProviderUserInfo) -> Result<i64, Error> {
+        let provider = userinfo.provider();
+        let sub = userinfo
+            .subject()
+            .ok_or_else(|| anyhow!("Missing the subject of {:?} user", provider))?;
+
+        if let Some(linked) = self.get_provider_user(&provider, &sub).await? {
+            if linked.base.id != Some(user_id) {
+                return Err(anyhow!("The {:?} user {} has been linked to another user", provider, sub));
+            }
+        }
+        let handler = UserHandler::new(self.state);
+        if handler.get(Some(user_id), None, None, None, None, None, None, None).await?.is_none() {
+            return Err(anyhow!("Not found user by id: {}", user_id));
+        }
+
+        let mut save_param = build_provider_save_param(Some(user_id), sub.to_owned(), userinfo);
+        // Keep the name of the existing user.
+        save_param.name = None;
+        handler.save(save_param).await?;
+        tracing::info!("Linked the {:?} user {} to the user {}", provider, sub, user_id);
+        Ok(user_id)
+    }
+
+    async fn handle_auth_callback_google(&self, userinfo: GoogleUserInfo) -> Result<i64, Error> {
+        self.handle_provider_callback(&userinfo).await
     }
 
     async fn handle_wallet_verify_ethers(
@@ -373,9 +725,16 @@ impl<'a> IAuthHandler for AuthHandler<'a> {
                     let uname = param.address;
 
                     let handler = UserHandler::new(self.state);
-                    let user = handler
-                        .get(None, None, None, None, None, None, None, Some(uname.to_owned())).await
-                        .unwrap();
+                    let user = handler.get(
+                        None,
+                        None,
+                        None,
+                        None,
+                        None,
+                        None,
+                        None,
+                        Some(uname.to_owned())
+                    ).await?;
 
                     // 3. If user exists, update user github subject ID.
                     let save_param;
@@ -395,6 +754,7 @@ impl<'a> IAuthHandler for AuthHandler<'a> {
                             google_claims_sub: None,
                             google_claims_name: None,
                             google_claims_email: None,
+                            verified_email: None,
                             ethers_address: Some(uname),
                             lang: None,
                         };
@@ -415,16 +775,14 @@ impl<'a> IAuthHandler for AuthHandler<'a> {
                             google_claims_sub: None,
                             google_claims_name: None,
                             google_claims_email: None,
+                            verified_email: None,
                             ethers_address: Some(uname),
                             lang: None,
                         };
                     }
 
                     // 5. save user info
-                    match handler.save(save_param).await {
-                        std::result::Result::Ok(uid) => Ok(uid),
-                        Err(e) => Err(e),
-                    }
+                    self.save_provider_user(save_param).await
                 } else {
                     tracing::error!("Failed to verify wallet signature.");
                     Err(anyhow!(StatusCode::UNAUTHORIZED))
@@ -444,36 +802,61 @@ impl<'a> IAuthHandler for AuthHandler<'a> {
         uid: i64,
         uname: &str,
         email: &str,
+        client_ip: Option<String>,
         headers: &header::HeaderMap
-    ) -> hyper::Response<axum::body::Body> {
+    ) -> Result<hyper::Response<axum::body::Body>, Error> {
+        let audit_log = AuditLog::new(
+            Some(uid),
+            Some(AUDIT_EVENT_LOGIN.to_string()),
+            Some(format!("{:?}", ptype)),
+            client_ip
+        );
+        // The audit failure should not fail the login.
+        if let Err(e) = AuditHandler::new(self.state).record(audit_log).await {
+            tracing::warn!("Failed to record the login audit of user {}. cause: {}", uid, e);
+        }
+
         // TODO: 附加更多自定义 JWT 信息
         let extra_claims = HashMap::new();
-        let ak = auths::create_jwt(config, &ptype, uid, uname, email, false, Some(extra_claims));
-        let rk = auths::create_jwt(config, &ptype, uid, uname, email, true, None);
-
-        let ak_cookie = CookieBuilder::new(&config.auth_jwt_ak_name, ak)
-            .path("/")
-            .max_age(Duration::milliseconds(config.auth.jwt_validity_ak.unwrap() as i64))
-            //.secure(true) // true: indicates that only https requests will carry
-            .http_only(true)
-            .same_site(SameSite::Strict)
-            .build();
-
-        let rk_cookie = CookieBuilder::new(&config.auth_jwt_rk_name, rk)
-            .path("/")
-            .max_age(Duration::milliseconds(config.auth.jwt_validity_rk.unwrap() as i64))
-            //.secure(true) // true: indicates that only https requests will carry
-            .http_only(true)
-            .same_site(SameSite::Strict)
-            .build();
-
-        utils::auths::auth_resp_redirect_or_json(
-            &config,
-            headers,
-            config.auth.success_url.to_owned().unwrap().as_str(),
-            StatusCode::OK,
-            "Authenticated",
-            Some((Some(ak_cookie), Some(rk_cookie), None))
+        let claims = auths::AuthUserClaims {
+            ptype,
+            uid,
+            uname: uname.to_owned(),
+            email: email.to_owned(),
+            exp: 0,
+            iat: 0,
+            iat_ms: None,
+            iss: None,
+            aud: None,
+            auth_time: None,
+            ext: Some(extra_claims),
+            refresh: false,
+            jti: None,
+        };
+        let pair = auths::create_token_pair(config, &claims)?;
+
+        let ak_cookie = webs::build_auth_cookie(
+            config,
+            &config.auth_jwt_ak_name,
+            &pair.access_token,
+            Duration::milliseconds(pair.access_expires_in as i64)
+        );
+        let rk_cookie = webs::build_auth_cookie(
+            config,
+            &config.auth_jwt_rk_name,
+            &pair.refresh_token,
+            Duration::milliseconds(pair.refresh_expires_in as i64)
+        );
+
+        Ok(
+            utils::auths::auth_resp_redirect_or_json(
+                &config,
+                headers,
+                config.auth.success_url.to_owned().unwrap().as_str(),
+                StatusCode::OK,
+                "Authenticated",
+                Some((Some(ak_cookie), Some(rk_cookie), None))
+            )
         )
     }
 
@@ -487,8 +870,13 @@ impl<'a> IAuthHandler for AuthHandler<'a> {
                 return Err(Error::msg("access_token is None"));
             }
         };
-        let key = self.build_logout_blacklist_key(ak.as_str());
         let value = Utc::now().timestamp_millis().to_string();
+        // Revoke the refresh token too, so that it cannot be exchanged for the new access token.
+        if let Some(rk) = &param.refresh_token {
+            let validity_secs = (self.state.config.auth.jwt_validity_rk.unwrap() / 1000) as i32;
+            cache.set(self.build_logout_blacklist_key(rk), value.to_owned(), Some(validity_secs)).await?;
+        }
+        let key = self.build_logout_blacklist_key(ak.as_str());
         match cache.set(key, value, Some(3600_000)).await {
             std::result::Result::Ok(_) => {
                 tracing::info!("Logout success for {}", ak);
@@ -501,10 +889,194 @@ impl<'a> IAuthHandler for AuthHandler<'a> {
         }
     }
 
+    async fn handle_logout_all(&self, user_id: &str) -> Result<(), Error> {
+        let cache = self.state.string_cache.get(&self.state.config);
+
+        // Kept until all the tokens issued before are expired, i.e. the validity of refresh token.
+        let validity_secs = (self.state.config.auth.jwt_validity_rk.unwrap() / 1000) as i32;
+        let min_iat_ms = Utc::now().timestamp_millis().to_string();
+        cache.set(self.build_logout_all_key(user_id), min_iat_ms, Some(validity_secs)).await?;
+        tracing::info!("Logout everywhere for user {}", user_id);
+        Ok(())
+    }
+
+    async fn handle_refresh_token(
+        &self,
+        refresh_token: &str,
+        headers: &header::HeaderMap
+    ) -> Result<hyper::Response<axum::body::Body>, Error> {
+        let config = &self.state.config;
+        let claims = auths::validate_refresh_jwt_with_blacklist(self.state, refresh_token).await
+            .map_err(|reason| anyhow!("Invalid refresh token: {:?}", reason))?;
+        if !claims.refresh {
+            return Err(anyhow!("Not a refresh token"));
+        }
+        if let (Some(max_session_secs), Some(auth_time)) = (config.auth.max_session_secs, claims.auth_time) {
+            if Utc::now().timestamp() - (auth_time as i64) > (max_session_secs as i64) {
+                return Err(anyhow!("Session lifetime exceeded, re-authentication is required"));
+            }
+        }
+
+        let (ak_cookie, rk_cookie) = if config.auth.jwt_rotate_rk.unwrap_or(true) {
+            let pair = auths::create_token_pair(config, &claims)?;
+            // Revoke the old refresh token, so that it cannot be exchanged again.
+            let validity_secs = (config.auth.jwt_validity_rk.unwrap() / 1000) as i32;
+            self.state.string_cache
+                .get(config)
+                .set(
+                    self.build_logout_blacklist_key(refresh_token),
+                    Utc::now().timestamp_millis().to_string(),
+                    Some(validity_secs)
+                ).await?;
+            (
+                webs::build_auth_cookie(
+                    config,
+                    &config.auth_jwt_ak_name,
+                    &pair.access_token,
+                    Duration::milliseconds(pair.access_expires_in as i64)
+                ),
+                Some(
+                    webs::build_auth_cookie(
+                        config,
+                        &config.auth_jwt_rk_name,
+                        &pair.refresh_token,
+                        Duration::milliseconds(pair.refresh_expires_in as i64)
+                    )
+                ),
+            )
+        } else {
+            let access_token = auths::create_jwt(
+                config,
+                &claims.ptype,
+                claims.uid,
+                &claims.uname,
+                &claims.email,
+                false,
+                None
+            );
+            let validity = config.auth.jwt_validity_ak.unwrap();
+            (
+                webs::build_auth_cookie(
+                    config,
+                    &config.auth_jwt_ak_name,
+                    &access_token,
+                    Duration::milliseconds(validity as i64)
+                ),
+                None,
+            )
+        };
+        tracing::info!("Refreshed the access token of user {}", claims.uid);
+
+        Ok(
+            utils::auths::auth_resp_redirect_or_json(
+                config,
+                headers,
+                config.auth.success_url.to_owned().unwrap().as_str(),
+                StatusCode::OK,
+                "Refreshed",
+                Some((Some(ak_cookie), rk_cookie, None))
+            )
+        )
+    }
+
+    async fn handle_login_throttle_check(&self, subjects: &[String]) -> Result<Option<u64>, Error> {
+        if !self.is_login_throttle_enabled() {
+            return Ok(None);
+        }
+        let now = Utc::now().timestamp_millis();
+        let mut retry_after = None;
+        for subject in subjects {
+            let locked_until = self.get_login_locked_until(subject).await?;
+            retry_after = retry_after.max(remaining_secs(locked_until, now));
+        }
+        Ok(retry_after)
+    }
+
+    async fn handle_login_throttle_failure(
+        &self,
+        subjects: &[String]
+    ) -> Result<Option<u64>, Error> {
+        if !self.is_login_throttle_enabled() {
+            return Ok(None);
+        }
+        let throttle = &self.state.config.auth.login_throttle;
+        let max_failures = throttle.max_failures.unwrap_or(5).max(1);
+        let window = throttle.failure_window.unwrap_or(900_000).max(1) as i64;
+        let lockout = throttle.lockout_duration.unwrap_or(900_000) as i64;
+
+        let cache = self.state.string_cache.get(&self.state.config);
+        let now = Utc::now().timestamp_millis();
+        let mut retry_after = None;
+        for subject in subjects {
+            let locked_until = self.get_login_locked_until(subject).await?;
+            if remaining_secs(locked_until, now).is_some() {
+                retry_after = retry_after.max(remaining_secs(locked_until, now));
+                continue;
+            }
+            // Counted by the atomic increment, so that the concurrent failures are never lost.
+            let key = self.build_login_failures_window_key(subject, window, now);
+            let count = cache.incr(key.to_owned(), Some(((window + 999) / 1000) as i32)).await?;
+            // The failure reaching the max is locked out already, i.e. at most max failures are verified.
+            if count >= (max_failures as i64) {
+                tracing::warn!("Too many login failures for {}, locked out for {}ms", subject, lockout);
+                cache.set(
+                    self.build_login_locked_key(subject),
+                    (now + lockout).to_string(),
+                    Some(((lockout + 999) / 1000) as i32)
+                ).await?;
+                // Restart counting after the lockout expired.
+                cache.del(key).await?;
+                retry_after = retry_after.max(remaining_secs(now + lockout, now));
+            }
+        }
+        Ok(retry_after)
+    }
+
+    async fn handle_login_throttle_reset(&self, subjects: &[String]) -> Result<(), Error> {
+        let window = self.state.config.auth.login_throttle.failure_window.unwrap_or(900_000).max(1) as i64;
+        let cache = self.state.string_cache.get(&self.state.config);
+        let now = Utc::now().timestamp_millis();
+        for subject in subjects {
+            cache.del(self.build_login_failures_window_key(subject, window, now)).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_validate_rate_limit(&self, subject: &str) -> Result<Option<u64>, Error> {
+        let limit = &self.state.config.auth.validate_rate_limit;
+        let max_requests = limit.max_requests.unwrap_or(60).max(1);
+        let window = limit.window.unwrap_or(60_000).max(1) as i64;
+
+        // The fixed window counter by the atomic increment of the key suffixed by the window index, so that
+        // the concurrent requests are never lost, and it's restarted by the next window without expiration.
+        let cache = self.state.string_cache.get(&self.state.config);
+        let now = Utc::now().timestamp_millis();
+        let key = format!("{}:{}", self.build_validate_rate_key(subject), now / window);
+        let count = cache.incr(key, Some(((window + 999) / 1000) as i32)).await?;
+        if count > (max_requests as i64) {
+            return Ok(remaining_secs((now / window + 1) * window, now));
+        }
+        Ok(None)
+    }
+
     fn build_auth_nonce_key(&self, nonce: &str) -> String {
-        format!("{}:{}", AUTH_NONCE_PREFIX, nonce)
+        format!("{}{}", AUTH_NONCE_PREFIX, nonce)
     }
 
+    fn build_auth_state_key(&self, sid: &str) -> String {
+        format!("{}{}", AUTH_STATE_PREFIX, sid)
+    }
+
+    fn build_auth_pkce_key(&self, sid: &str) -> String {
+        format!("{}{}", AUTH_PKCE_PREFIX, sid)
+    }
+
+    fn build_auth_link_key(&self, sid: &str) -> String {
+        format!("{}{}", AUTH_LINK_PREFIX, sid)
+    }
+
+    // Notice: The keys of login private key and logout blacklist are kept in the original format (i.e. with the
+    // doubled separator), so that the keys written before upgrading are still found until expired.
     fn build_login_private_key(&self, fingerprint_token: &str) -> String {
         format!("{}:{}", LOGIN_PRIVATE_KEY_PREFIX, fingerprint_token)
     }
@@ -512,4 +1084,782 @@ impl<'a> IAuthHandler for AuthHandler<'a> {
     fn build_logout_blacklist_key(&self, access_token: &str) -> String {
         format!("{}:{}", LOGOUT_BLACKLIST_PREFIX, access_token)
     }
+
+    fn build_logout_all_key(&self, user_id: &str) -> String {
+        format!("{}{}", AUTH_LOGOUT_ALL_PREFIX, user_id)
+    }
+
+    fn build_login_failures_key(&self, subject: &str) -> String {
+        format!("{}{}", LOGIN_FAILURES_PREFIX, subject)
+    }
+
+    fn build_login_locked_key(&self, subject: &str) -> String {
+        format!("{}{}", LOGIN_LOCKED_PREFIX, subject)
+    }
+
+    fn build_validate_rate_key(&self, subject: &str) -> String {
+        format!("{}{}", VALIDATE_RATE_PREFIX, subject)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::state::tests::new_test_state;
+    use crate::utils::auths::AuthUserClaims;
+
+    fn github_userinfo(id: i64, login: &str) -> GithubUserInfo {
+        serde_json::from_value(serde_json::json!({ "id": id, "login": login })).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_callback_github_rejects_unknown_user_without_auto_register() {
+        let state = new_test_state(|p| {
+            p.auth.auto_register = Some(false);
+        }).await;
+
+        let result = AuthHandler::new(&state).handle_provider_callback(
+            &github_userinfo(10001, "unknown")
+        ).await;
+        assert!(result.unwrap_err().to_string().contains("Account not provisioned"));
+
+        let user = UserHandler::new(&state)
+            .get(None, None, None, None, None, Some("10001".to_string()), None, None).await
+            .unwrap();
+        assert!(user.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_callback_github_login_known_user_without_auto_register() {
+        let state = new_test_state(|p| {
+            p.auth.auto_register = Some(false);
+        }).await;
+
+        let user = User {
+            name: Some("known".to_string()),
+            github_claims_sub: Some("10002".to_string()),
+            ..User::default()
+        };
+        let uid = state.user_repo.lock().await.get(&state.config).insert(user).await.unwrap();
+        assert!(uid > 0);
+
+        let result = AuthHandler::new(&state).handle_provider_callback(
+            &github_userinfo(10002, "known")
+        ).await;
+        assert_eq!(result.unwrap(), uid);
+    }
+
+    #[tokio::test]
+    async fn test_callback_github_rejects_malformed_user_info() {
+        let state = new_test_state(|_| {}).await;
+        let userinfo: GithubUserInfo = serde_json::from_value(serde_json::json!({ "login": "octocat" })).unwrap();
+        assert_eq!(userinfo.id, None);
+
+        let result = AuthHandler::new(&state).handle_provider_callback(&userinfo).await;
+        assert_eq!(result.unwrap_err().to_string(), "Missing the subject of Github user");
+        let (_, users) = state.user_repo.lock().await
+            .get(&state.config)
+            .select(User::default(), PageRequest::default()).await
+            .unwrap();
+        assert!(users.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_callback_github_auto_register_by_default() {
+        let state = new_test_state(|_| {}).await;
+
+        let uid = AuthHandler::new(&state)
+            .handle_provider_callback(&github_userinfo(10003, "newbie")).await
+            .unwrap();
+        assert!(uid > 0);
+    }
+
+    async fn get_by_name(state: &AppState, name: &str) -> Arc<User> {
+        UserHandler::new(state)
+            .get(None, Some(name.to_string()), None, None, None, None, None, None).await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_provider_callback_maps_oidc_user_info() {
+        let state = new_test_state(|_| {}).await;
+        let userinfo = CoreUserInfoClaims::new(
+            openidconnect::StandardClaims
+                ::new(openidconnect::SubjectIdentifier::new("oidc-1".to_string()))
+                .set_preferred_username(Some(openidconnect::EndUserUsername::new("olivia".to_string())))
+                .set_email(Some(openidconnect::EndUserEmail::new("o@example.com".to_string()))),
+            openidconnect::EmptyAdditionalClaims {}
+        );
+
+        let uid = AuthHandler::new(&state).handle_provider_callback(&userinfo).await.unwrap();
+        let user = get_by_name(&state, "olivia").await;
+        assert_eq!(user.base.id, Some(uid));
+        assert_eq!(user.oidc_claims_sub.as_deref(), Some("oidc-1"));
+        assert_eq!(user.oidc_claims_name.as_deref(), Some("olivia"));
+        assert_eq!(user.oidc_claims_email.as_deref(), Some("o@example.com"));
+        assert_eq!(user.github_claims_sub, None);
+        assert_eq!(user.google_claims_sub, None);
+    }
+
+    #[tokio::test]
+    async fn test_provider_callback_maps_github_user_info() {
+        let state = new_test_state(|_| {}).await;
+        let userinfo: GithubUserInfo = serde_json
+            ::from_value(serde_json::json!({ "id": 20001, "login": "octocat", "email": "g@example.com" }))
+            .unwrap();
+
+        let uid = AuthHandler::new(&state).handle_provider_callback(&userinfo).await.unwrap();
+        let user = get_by_name(&state, "octocat").await;
+        assert_eq!(user.base.id, Some(uid));
+        assert_eq!(user.github_claims_sub.as_deref(), Some("20001"));
+        assert_eq!(user.github_claims_name.as_deref(), Some("octocat"));
+        assert_eq!(user.github_claims_email.as_deref(), Some("g@example.com"));
+        assert_eq!(user.oidc_claims_sub, None);
+        assert_eq!(user.google_claims_sub, None);
+    }
+
+    #[tokio::test]
+    async fn test_provider_callback_maps_google_user_info() {
+        let state = new_test_state(|_| {}).await;
+        let userinfo = GoogleUserInfo {
+            sub: Some("google-1".to_string()),
+            name: Some("gina".to_string()),
+            email: Some("gina@example.com".to_string()),
+            ..Default::default()
+        };
+
+        let handler = AuthHandler::new(&state);
+        let uid = handler.handle_provider_callback(&userinfo).await.unwrap();
+        let user = get_by_name(&state, "gina").await;
+        assert_eq!(user.base.id, Some(uid));
+        assert_eq!(user.google_claims_sub.as_deref(), Some("google-1"));
+        assert_eq!(user.google_claims_name.as_deref(), Some("gina"));
+        assert_eq!(user.google_claims_email.as_deref(), Some("gina@example.com"));
+        assert_eq!(user.oidc_claims_sub, None);
+        assert_eq!(user.github_claims_sub, None);
+
+        // The same subject logins to the same user.
+        assert_eq!(handler.handle_provider_callback(&userinfo).await.unwrap(), uid);
+    }
+
+    fn google_userinfo(sub: &str, name: &str) -> GoogleUserInfo {
+        serde_json::from_value(serde_json::json!({ "sub": sub, "name": name, "email": "x@gmail.com" })).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_callback_google_auto_register_new_user() {
+        let state = new_test_state(|_| {}).await;
+
+        let uid = AuthHandler::new(&state)
+            .handle_auth_callback_google(google_userinfo("google-10001", "newbie")).await
+            .unwrap();
+        assert!(uid > 0);
+
+        let user = UserHandler::new(&state)
+            .get(None, None, None, None, None, None, Some("google-10001".to_string()), None).await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.base.id, Some(uid));
+        assert_eq!(user.google_claims_name.as_deref(), Some("newbie"));
+        assert_eq!(user.google_claims_email.as_deref(), Some("x@gmail.com"));
+        assert_eq!(user.github_claims_sub, None);
+        assert_eq!(user.oidc_claims_sub, None);
+    }
+
+    #[tokio::test]
+    async fn test_callback_google_updates_existing_user() {
+        let state = new_test_state(|p| {
+            p.auth.auto_register = Some(false);
+        }).await;
+
+        let user = User {
+            name: Some("known".to_string()),
+            google_claims_sub: Some("google-10002".to_string()),
+            ..User::default()
+        };
+        let uid = state.user_repo.lock().await.get(&state.config).insert(user).await.unwrap();
+
+        let result = AuthHandler::new(&state)
+            .handle_auth_callback_google(google_userinfo("google-10002", "renamed")).await
+            .unwrap();
+        assert_eq!(result, uid);
+
+        let user = UserHandler::new(&state)
+            .get(Some(uid), None, None, None, None, None, None, None).await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.google_claims_name.as_deref(), Some("renamed"));
+        assert_eq!(user.github_claims_sub, None);
+        assert_eq!(user.oidc_claims_sub, None);
+    }
+
+    #[tokio::test]
+    async fn test_provider_callback_rejects_missing_subject() {
+        let state = new_test_state(|_| {}).await;
+        let userinfo = GoogleUserInfo { name: Some("nobody".to_string()), ..Default::default() };
+
+        let result = AuthHandler::new(&state).handle_provider_callback(&userinfo).await;
+        assert!(result.unwrap_err().to_string().contains("Missing the subject"));
+    }
+
+    #[tokio::test]
+    async fn test_login_throttle_locks_out_after_max_failures() {
+        let state = new_test_state(|p| {
+            p.auth.login_throttle.max_failures = Some(3);
+            p.auth.login_throttle.lockout_duration = Some(60_000);
+        }).await;
+        let handler = AuthHandler::new(&state);
+        let subjects = vec!["account:alice".to_string(), "ip:10.0.0.1".to_string()];
+
+        // Exactly at the limit, i.e. the 3rd failure is locked out, and the attempt before it is allowed.
+        for _ in 0..2 {
+            assert_eq!(handler.handle_login_throttle_failure(&subjects).await.unwrap(), None);
+            assert_eq!(handler.handle_login_throttle_check(&subjects).await.unwrap(), None);
+        }
+        let retry_after = handler.handle_login_throttle_failure(&subjects).await.unwrap();
+        assert_eq!(retry_after, Some(60));
+        assert_eq!(handler.handle_login_throttle_check(&subjects).await.unwrap(), Some(60));
+
+        // The next attempt is rejected, even from another ip.
+        let retry_after = handler
+            .handle_login_throttle_check(&["account:alice".to_string()]).await
+            .unwrap();
+        assert!(matches!(retry_after, Some(secs) if secs > 0 && secs <= 60));
+        let others = handler.handle_login_throttle_check(&["account:bob".to_string()]).await;
+        assert_eq!(others.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_login_throttle_counts_concurrent_failures() {
+        let state = new_test_state(|p| {
+            p.auth.login_throttle.max_failures = Some(5);
+        }).await;
+        let handler = AuthHandler::new(&state);
+        let subjects = vec!["account:erin".to_string()];
+
+        // None of the concurrent failures is lost, so that the 5th of them is locked out.
+        let failures = (0..5).map(|_| handler.handle_login_throttle_failure(&subjects));
+        let locked = futures::future::join_all(failures).await
+            .into_iter()
+            .filter(|r| r.as_ref().unwrap().is_some())
+            .count();
+        assert_eq!(locked, 1);
+        assert!(handler.handle_login_throttle_check(&subjects).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_login_throttle_reset_on_success() {
+        let state = new_test_state(|p| {
+            p.auth.login_throttle.max_failures = Some(2);
+        }).await;
+        let handler = AuthHandler::new(&state);
+        let subjects = vec!["account:carol".to_string()];
+
+        assert_eq!(handler.handle_login_throttle_failure(&subjects).await.unwrap(), None);
+        handler.handle_login_throttle_reset(&subjects).await.unwrap();
+
+        // The counter is restarted, so that one more failure does not lock out.
+        assert_eq!(handler.handle_login_throttle_failure(&subjects).await.unwrap(), None);
+        assert_eq!(handler.handle_login_throttle_check(&subjects).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_login_throttle_disabled() {
+        let state = new_test_state(|p| {
+            p.auth.login_throttle.enabled = Some(false);
+            p.auth.login_throttle.max_failures = Some(1);
+        }).await;
+        let handler = AuthHandler::new(&state);
+        let subjects = vec!["account:dave".to_string()];
+
+        assert_eq!(handler.handle_login_throttle_failure(&subjects).await.unwrap(), None);
+        assert_eq!(handler.handle_login_throttle_check(&subjects).await.unwrap(), None);
+    }
+
+    struct RecordingHook {
+        registered: std::sync::Mutex<Vec<Option<i64>>>,
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl crate::context::hooks::RegistrationHook for RecordingHook {
+        async fn on_user_registered(&self, user: &User) -> Result<(), Error> {
+            self.registered.lock().unwrap().push(user.base.id);
+            if self.fail {
+                return Err(anyhow!("webhook unavailable"));
+            }
+            Ok(())
+        }
+    }
+
+    async fn new_state_with_hook(fail: bool) -> (AppState, Arc<RecordingHook>) {
+        let mut state = new_test_state(|_| {}).await;
+        let hook = Arc::new(RecordingHook { registered: std::sync::Mutex::new(Vec::new()), fail });
+        state.registration_hook = hook.clone();
+        (state, hook)
+    }
+
+    #[tokio::test]
+    async fn test_registration_hook_fires_once_on_first_login() {
+        let (state, hook) = new_state_with_hook(false).await;
+        let handler = AuthHandler::new(&state);
+
+        let uid = handler.handle_provider_callback(&github_userinfo(10004, "first")).await.unwrap();
+        let again = handler.handle_provider_callback(&github_userinfo(10004, "first")).await.unwrap();
+        assert_eq!(again, uid);
+        assert_eq!(*hook.registered.lock().unwrap(), vec![Some(uid)]);
+    }
+
+    #[tokio::test]
+    async fn test_registration_hook_failure_does_not_fail_login() {
+        let (state, hook) = new_state_with_hook(true).await;
+
+        let uid = AuthHandler::new(&state)
+            .handle_provider_callback(&github_userinfo(10005, "unlucky")).await
+            .unwrap();
+        assert!(uid > 0);
+        assert_eq!(hook.registered.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_nonce_and_logout_blacklist_keys_do_not_collide() {
+        let state = new_test_state(|_| {}).await;
+        let handler = AuthHandler::new(&state);
+        assert_eq!(handler.build_auth_nonce_key("abc"), "auth:nonce:abc");
+        assert_eq!(handler.build_logout_blacklist_key("abc"), "logout:blacklist::abc");
+        assert_eq!(handler.build_login_private_key("abc"), "login:privatekey::abc");
+
+        // The raw value of the nonce sid and the logged out token are the same.
+        let raw = "colliding-value";
+        handler.handle_auth_create_nonce(raw, "nonce-1".to_string()).await.unwrap();
+        handler
+            .handle_logout(LogoutRequest { access_token: Some(raw.to_string()), refresh_token: None }).await
+            .unwrap();
+        assert_eq!(handler.handle_auth_get_nonce(raw).await.unwrap(), Some("nonce-1".to_string()));
+
+        let cache = state.string_cache.get(&state.config);
+        let blacklisted = cache.get(handler.build_logout_blacklist_key(raw)).await.unwrap().unwrap();
+        assert!(blacklisted.parse::<i64>().is_ok(), "{}", blacklisted);
+
+        // The nonce does not satisfy the blacklist of another token of the same value.
+        let other = "other-value";
+        handler.handle_auth_create_nonce(other, "nonce-2".to_string()).await.unwrap();
+        assert_eq!(cache.get(handler.build_logout_blacklist_key(other)).await.unwrap(), None);
+    }
+
+    // Login by github first, and then by google of the same email, returns both the user ids.
+    async fn login_github_then_google(merge: AccountMergeStrategy, email_verified: bool) -> (AppState, i64, i64) {
+        let state = new_test_state(|p| {
+            p.auth.account_merge = merge;
+        }).await;
+        let handler = AuthHandler::new(&state);
+        let mut github = github_userinfo(10001, "octocat");
+        github.email = Some("alice@example.com".to_string());
+        let github_uid = handler.handle_provider_callback(&github).await.unwrap();
+
+        let mut google = google_userinfo("google-10001", "Alice");
+        google.email = Some("alice@example.com".to_string());
+        google.email_verified = Some(email_verified);
+        let google_uid = handler.handle_auth_callback_google(google).await.unwrap();
+        (state, github_uid, google_uid)
+    }
+
+    #[tokio::test]
+    async fn test_account_merge_links_verified_email() {
+        // The stored email of the github user is never verified, so that the verified google login of the same
+        // email is not linked to it, i.e. the pre-registered account could not take over the victim's login.
+        let (state, github_uid, google_uid) = login_github_then_google(AccountMergeStrategy::VerifiedEmail, true).await;
+        assert_ne!(github_uid, google_uid);
+        let user = UserHandler::new(&state).get(Some(github_uid), None, None, None, None, None, None, None).await;
+        assert_eq!(user.unwrap().unwrap().verified_email, None);
+
+        // Both the stored and incoming emails are verified.
+        let oidc = CoreUserInfoClaims::new(
+            openidconnect::StandardClaims
+                ::new(openidconnect::SubjectIdentifier::new("oidc-10001".to_string()))
+                .set_preferred_username(Some(openidconnect::EndUserUsername::new("olivia".to_string())))
+                .set_email(Some(openidconnect::EndUserEmail::new("alice@example.com".to_string())))
+                .set_email_verified(Some(true)),
+            openidconnect::EmptyAdditionalClaims {}
+        );
+        let oidc_uid = AuthHandler::new(&state).handle_provider_callback(&oidc).await.unwrap();
+        assert_eq!(oidc_uid, google_uid);
+
+        let user = UserHandler::new(&state)
+            .get(None, None, None, None, Some("oidc-10001".to_string()), None, None, None).await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.base.id, Some(google_uid));
+        assert_eq!(user.google_claims_sub.as_deref(), Some("google-10001"));
+        assert_eq!(user.oidc_claims_email.as_deref(), Some("alice@example.com"));
+        assert_eq!(user.verified_email.as_deref(), Some("alice@example.com"));
+        assert_eq!(user.name.as_deref(), Some("Alice"));
+    }
+
+    #[tokio::test]
+    async fn test_account_merge_skips_unverified_email() {
+        let (_, github_uid, google_uid) = login_github_then_google(AccountMergeStrategy::VerifiedEmail, false).await;
+        assert_ne!(github_uid, google_uid);
+    }
+
+    #[tokio::test]
+    async fn test_account_merge_disabled_by_default() {
+        let (_, github_uid, google_uid) = login_github_then_google(AccountMergeStrategy::default(), true).await;
+        assert_ne!(github_uid, google_uid);
+    }
+
+    fn refresh_claims() -> AuthUserClaims {
+        AuthUserClaims {
+            ptype: PrincipalType::Github,
+            uname: "octocat".to_string(),
+            refresh: true,
+            ..AuthUserClaims::for_test(10001, "octocat@example.com")
+        }
+    }
+
+    // Extract the values of cookies from the response.
+    fn response_cookie(resp: &hyper::Response<axum::body::Body>, name: &str) -> Option<String> {
+        resp.headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .find_map(|v| webs::get_cookie_from_str(v, name))
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_reissues_access_token() {
+        let state = new_test_state(|_| {}).await;
+        let handler = AuthHandler::new(&state);
+        let pair = auths::create_token_pair(&state.config, &refresh_claims()).unwrap();
+
+        let resp = handler.handle_refresh_token(&pair.refresh_token, &header::HeaderMap::new()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let ak = response_cookie(&resp, &state.config.auth_jwt_ak_name).unwrap();
+        let claims = auths::validate_jwt(&state.config, &ak).unwrap();
+        assert_eq!(claims.uid, 10001);
+        assert!(!claims.refresh);
+
+        // The refresh token is rotated, and the old one is revoked.
+        let rk = response_cookie(&resp, &state.config.auth_jwt_rk_name).unwrap();
+        assert!(auths::validate_jwt(&state.config, &rk).unwrap().refresh);
+        let result = handler.handle_refresh_token(&pair.refresh_token, &header::HeaderMap::new()).await;
+        assert!(result.unwrap_err().to_string().contains("Revoked"));
+        assert!(handler.handle_refresh_token(&rk, &header::HeaderMap::new()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_without_rotation() {
+        let state = new_test_state(|p| {
+            p.auth.jwt_rotate_rk = Some(false);
+        }).await;
+        let handler = AuthHandler::new(&state);
+        let pair = auths::create_token_pair(&state.config, &refresh_claims()).unwrap();
+
+        let resp = handler.handle_refresh_token(&pair.refresh_token, &header::HeaderMap::new()).await.unwrap();
+        assert!(response_cookie(&resp, &state.config.auth_jwt_ak_name).is_some());
+        assert!(response_cookie(&resp, &state.config.auth_jwt_rk_name).is_none());
+        assert!(handler.handle_refresh_token(&pair.refresh_token, &header::HeaderMap::new()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_rejects_expired_token() {
+        let state = new_test_state(|_| {}).await;
+        let expired = AuthUserClaims { exp: (Utc::now().timestamp() - 10) as usize, ..refresh_claims() };
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &expired,
+            &jsonwebtoken::EncodingKey::from_secret(state.config.auth.jwt_secret.to_owned().unwrap().as_ref())
+        ).unwrap();
+
+        let result = AuthHandler::new(&state).handle_refresh_token(&token, &header::HeaderMap::new()).await;
+        assert!(result.unwrap_err().to_string().contains("Expired"));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_rejects_logged_out_token() {
+        let state = new_test_state(|_| {}).await;
+        let handler = AuthHandler::new(&state);
+        let pair = auths::create_token_pair(&state.config, &refresh_claims()).unwrap();
+        handler
+            .handle_logout(LogoutRequest {
+                access_token: Some(pair.access_token.to_owned()),
+                refresh_token: Some(pair.refresh_token.to_owned()),
+            }).await
+            .unwrap();
+
+        let result = handler.handle_refresh_token(&pair.refresh_token, &header::HeaderMap::new()).await;
+        assert!(result.unwrap_err().to_string().contains("Revoked"));
+    }
+
+    #[tokio::test]
+    async fn test_logout_all_rejects_tokens_issued_before() {
+        let state = new_test_state(|_| {}).await;
+        let handler = AuthHandler::new(&state);
+        let old = auths::create_token_pair(&state.config, &refresh_claims()).unwrap();
+        let other = auths::create_token_pair(&state.config, &AuthUserClaims { uid: 10002, ..refresh_claims() }).unwrap();
+        assert!(auths::validate_jwt_with_blacklist(&state, &old.access_token).await.is_ok());
+
+        // The iat_ms is of millis, so that the old tokens only need to be issued in the previous millis.
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        handler.handle_logout_all("10001").await.unwrap();
+
+        for token in [&old.access_token, &old.refresh_token] {
+            let result = auths::validate_jwt_with_blacklist(&state, token).await;
+            assert_eq!(result.unwrap_err(), crate::types::auth::TokenInvalidReason::Revoked);
+        }
+        let result = handler.handle_refresh_token(&old.refresh_token, &header::HeaderMap::new()).await;
+        assert!(result.unwrap_err().to_string().contains("Revoked"));
+        // The tokens of the other users and issued after are still valid.
+        assert!(auths::validate_jwt_with_blacklist(&state, &other.access_token).await.is_ok());
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        let fresh = auths::create_token_pair(&state.config, &refresh_claims()).unwrap();
+        assert!(auths::validate_jwt_with_blacklist(&state, &fresh.access_token).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_within_max_session_lifetime() {
+        let state = new_test_state(|p| {
+            p.auth.max_session_secs = Some(3600);
+        }).await;
+        let handler = AuthHandler::new(&state);
+        let auth_time = (Utc::now().timestamp() - 600) as usize;
+        let pair = auths::create_token_pair(
+            &state.config,
+            &AuthUserClaims { auth_time: Some(auth_time), ..refresh_claims() }
+        ).unwrap();
+
+        let resp = handler.handle_refresh_token(&pair.refresh_token, &header::HeaderMap::new()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        // The auth time of the initial login is kept on the rotated tokens.
+        let rk = response_cookie(&resp, &state.config.auth_jwt_rk_name).unwrap();
+        assert_eq!(auths::validate_jwt(&state.config, &rk).unwrap().auth_time, Some(auth_time));
+        let ak = response_cookie(&resp, &state.config.auth_jwt_ak_name).unwrap();
+        assert_eq!(auths::validate_jwt(&state.config, &ak).unwrap().auth_time, Some(auth_time));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_rejects_exceeded_max_session_lifetime() {
+        let state = new_test_state(|p| {
+            p.auth.max_session_secs = Some(3600);
+        }).await;
+        let handler = AuthHandler::new(&state);
+        let auth_time = (Utc::now().timestamp() - 3601) as usize;
+        let pair = auths::create_token_pair(
+            &state.config,
+            &AuthUserClaims { auth_time: Some(auth_time), ..refresh_claims() }
+        ).unwrap();
+
+        let result = handler.handle_refresh_token(&pair.refresh_token, &header::HeaderMap::new()).await;
+        assert!(result.unwrap_err().to_string().contains("re-authentication is required"));
+
+        // Unlimited when unset.
+        let state = new_test_state(|_| {}).await;
+        let pair = auths::create_token_pair(
+            &state.config,
+            &AuthUserClaims { auth_time: Some(auth_time), ..refresh_claims() }
+        ).unwrap();
+        assert!(AuthHandler::new(&state).handle_refresh_token(&pair.refresh_token, &header::HeaderMap::new()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_rejects_access_token() {
+        let state = new_test_state(|_| {}).await;
+        let pair = auths::create_token_pair(&state.config, &refresh_claims()).unwrap();
+
+        let result = AuthHandler::new(&state).handle_refresh_token(&pair.access_token, &header::HeaderMap::new()).await;
+        assert!(result.unwrap_err().to_string().contains("Not a refresh token"));
+    }
+
+    #[tokio::test]
+    async fn test_auth_state_verified_once() {
+        let state = new_test_state(|_| {}).await;
+        let handler = AuthHandler::new(&state);
+        handler.handle_auth_create_state("sid-1", "state-1".to_string()).await.unwrap();
+
+        assert!(handler.handle_auth_verify_state("sid-1", Some("state-1")).await.is_ok());
+        // The reused state is rejected.
+        let result = handler.handle_auth_verify_state("sid-1", Some("state-1")).await;
+        assert_eq!(result.unwrap_err().to_string(), "Expired or used state");
+    }
+
+    #[tokio::test]
+    async fn test_auth_state_rejects_missing() {
+        let state = new_test_state(|_| {}).await;
+        let handler = AuthHandler::new(&state);
+        handler.handle_auth_create_state("sid-1", "state-1".to_string()).await.unwrap();
+
+        let result = handler.handle_auth_verify_state("sid-1", None).await;
+        assert_eq!(result.unwrap_err().to_string(), "Missing state");
+        // The state is deleted even if the verification failed.
+        assert!(handler.handle_auth_verify_state("sid-1", Some("state-1")).await.is_err());
+
+        let result = handler.handle_auth_verify_state("unknown-sid", Some("state-1")).await;
+        assert_eq!(result.unwrap_err().to_string(), "Expired or used state");
+    }
+
+    #[tokio::test]
+    async fn test_auth_state_rejects_mismatched() {
+        let state = new_test_state(|_| {}).await;
+        let handler = AuthHandler::new(&state);
+        handler.handle_auth_create_state("sid-1", "state-1".to_string()).await.unwrap();
+        handler.handle_auth_create_state("sid-2", "state-2".to_string()).await.unwrap();
+
+        let result = handler.handle_auth_verify_state("sid-1", Some("state-2")).await;
+        assert_eq!(result.unwrap_err().to_string(), "Mismatched state");
+        assert!(handler.handle_auth_verify_state("sid-2", Some("state-2")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_pkce_challenge_is_s256_of_verifier() {
+        use base64::Engine;
+        use sha2::Digest;
+
+        let state = new_test_state(|_| {}).await;
+        let handler = AuthHandler::new(&state);
+        let challenge = handler.handle_auth_create_pkce("sid-1").await.unwrap();
+        assert_eq!(challenge.method().as_str(), "S256");
+
+        let verifier = handler.handle_auth_take_pkce_verifier("sid-1").await.unwrap().unwrap();
+        let digest = sha2::Sha256::digest(verifier.secret().as_bytes());
+        let expected = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(digest);
+        assert_eq!(challenge.as_str(), expected);
+        assert!(!challenge.as_str().contains('='));
+
+        // The verifier is single-use.
+        assert!(handler.handle_auth_take_pkce_verifier("sid-1").await.unwrap().is_none());
+    }
+
+    async fn save_password_user(state: &AppState, email: &str, password: &str) -> i64 {
+        let save_param = SaveUserRequest {
+            id: None,
+            name: None,
+            email: Some(email.to_string()),
+            phone: None,
+            password: Some(password.to_string()),
+            oidc_claims_sub: None,
+            oidc_claims_name: None,
+            oidc_claims_email: None,
+            github_claims_sub: None,
+            github_claims_name: None,
+            github_claims_email: None,
+            google_claims_sub: None,
+            google_claims_name: None,
+            google_claims_email: None,
+            verified_email: None,
+            ethers_address: None,
+            lang: None,
+        };
+        UserHandler::new(state).save(save_param).await.unwrap().id.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_link_identity_attaches_to_existing_user() {
+        let state = new_test_state(|_| {}).await;
+        let handler = AuthHandler::new(&state);
+        let uid = handler.handle_auth_callback_google(google_userinfo("google-10001", "Alice")).await.unwrap();
+
+        let linked_uid = handler.handle_link_identity(uid, &github_userinfo(10001, "octocat")).await.unwrap();
+        assert_eq!(linked_uid, uid);
+
+        let user = UserHandler::new(&state)
+            .get(None, None, None, None, None, Some("10001".to_string()), None, None).await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.base.id, Some(uid));
+        assert_eq!(user.google_claims_sub.as_deref(), Some("google-10001"));
+        assert_eq!(user.github_claims_sub.as_deref(), Some("10001"));
+        assert_eq!(user.github_claims_name.as_deref(), Some("octocat"));
+        assert_eq!(user.name.as_deref(), Some("Alice"));
+
+        // The later login by the linked identity is the same user.
+        let login_uid = handler.handle_provider_callback(&github_userinfo(10001, "octocat")).await.unwrap();
+        assert_eq!(login_uid, uid);
+    }
+
+    #[tokio::test]
+    async fn test_link_identity_rejects_linked_to_another_user() {
+        let state = new_test_state(|_| {}).await;
+        let handler = AuthHandler::new(&state);
+        let uid = handler.handle_auth_callback_google(google_userinfo("google-10001", "Alice")).await.unwrap();
+        let other_uid = handler.handle_provider_callback(&github_userinfo(10001, "octocat")).await.unwrap();
+        assert_ne!(uid, other_uid);
+
+        let result = handler.handle_link_identity(uid, &github_userinfo(10001, "octocat")).await;
+        assert!(result.unwrap_err().to_string().contains("linked to another user"));
+        // Relinking to the same user is idempotent.
+        assert_eq!(handler.handle_link_identity(other_uid, &github_userinfo(10001, "octocat")).await.unwrap(), other_uid);
+    }
+
+    #[tokio::test]
+    async fn test_auth_link_taken_once() {
+        let state = new_test_state(|_| {}).await;
+        let handler = AuthHandler::new(&state);
+        handler.handle_auth_create_link("sid-1", 101).await.unwrap();
+
+        assert_eq!(handler.handle_auth_take_link("sid-1").await.unwrap(), Some(101));
+        assert_eq!(handler.handle_auth_take_link("sid-1").await.unwrap(), None);
+        assert_eq!(handler.handle_auth_take_link("sid-2").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_login_password_success() {
+        let state = new_test_state(|_| {}).await;
+        let uid = save_password_user(&state, "alice@example.com", "secret").await;
+
+        // The password is stored hashed.
+        let user = UserHandler::new(&state).get(Some(uid), None, None, None, None, None, None, None).await.unwrap().unwrap();
+        assert!(auths::is_password_hashed(user.password.as_deref().unwrap()));
+
+        let resp = AuthHandler::new(&state)
+            .handle_login_password("alice@example.com", "secret", None, &header::HeaderMap::new()).await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let ak = response_cookie(&resp, &state.config.auth_jwt_ak_name).unwrap();
+        assert_eq!(auths::validate_jwt(&state.config, &ak).unwrap().uid, uid);
+    }
+
+    #[tokio::test]
+    async fn test_login_password_rejects_wrong_password() {
+        let state = new_test_state(|_| {}).await;
+        save_password_user(&state, "alice@example.com", "secret").await;
+
+        let result = AuthHandler::new(&state)
+            .handle_login_password("alice@example.com", "wrong", None, &header::HeaderMap::new()).await;
+        assert_eq!(result.unwrap_err().to_string(), "Invalid email or password");
+    }
+
+    #[tokio::test]
+    async fn test_login_password_rejects_unknown_email() {
+        let state = new_test_state(|_| {}).await;
+        save_password_user(&state, "alice@example.com", "secret").await;
+
+        let result = AuthHandler::new(&state)
+            .handle_login_password("bob@example.com", "secret", None, &header::HeaderMap::new()).await;
+        assert_eq!(result.unwrap_err().to_string(), "Invalid email or password");
+    }
+
+    #[tokio::test]
+    async fn test_login_password_rehashes_legacy_password() {
+        let state = new_test_state(|_| {}).await;
+        let user = User {
+            email: Some("legacy@example.com".to_string()),
+            password: Some("legacy-secret".to_string()),
+            ..User::default()
+        };
+        let uid = state.user_repo.lock().await.get(&state.config).insert(user).await.unwrap();
+
+        let handler = AuthHandler::new(&state);
+        let result = handler.handle_login_password("legacy@example.com", "wrong", None, &header::HeaderMap::new()).await;
+        assert!(result.is_err());
+        let resp = handler
+            .handle_login_password("legacy@example.com", "legacy-secret", None, &header::HeaderMap::new()).await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // The legacy password is migrated to the hashed, and still verified.
+        let user = UserHandler::new(&state).get(Some(uid), None, None, None, None, None, None, None).await.unwrap().unwrap();
+        assert!(auths::is_password_hashed(user.password.as_deref().unwrap()));
+        assert!(handler.handle_login_password("legacy@example.com", "legacy-secret", None, &header::HeaderMap::new()).await.is_ok());
+    }
 }
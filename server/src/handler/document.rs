@@ -1,7 +1,10 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{ Error, Ok };
 use axum::async_trait;
+use tokio::task::JoinHandle;
+use crate::config::config_serve::DEFAULT_DB_PURGE_DELETED_AFTER_DAYS;
 use crate::context::state::AppState;
 use crate::types::document::{
     DeleteDocumentRequest,
@@ -9,7 +12,8 @@ use crate::types::document::{
     SaveDocumentRequest,
     Document,
 };
-use crate::types::{ PageRequest, PageResponse };
+use crate::types::{ BaseBean, PageRequest, PageResponse };
+use crate::utils::{ auths::AuthUserClaims, times };
 
 #[async_trait]
 pub trait IDocumentHandler: Send {
@@ -21,9 +25,19 @@ pub trait IDocumentHandler: Send {
         page: PageRequest
     ) -> Result<(PageResponse, Vec<Document>), Error>;
 
+    // Find the soft-deleted documents (the trash) owned by the principal.
+    async fn find_deleted(&self, principal: &AuthUserClaims, page: PageRequest) -> Result<(PageResponse, Vec<Document>), Error>;
+
     async fn save(&self, param: SaveDocumentRequest) -> Result<i64, Error>;
 
+    // Soft-delete the document, i.e. move it to the trash, which could be purged later.
     async fn delete(&self, param: DeleteDocumentRequest) -> Result<u64, Error>;
+
+    // Purge (hard delete) the soft-deleted document, the active is rejected, which must be deleted first.
+    async fn purge(&self, param: DeleteDocumentRequest) -> Result<u64, Error>;
+
+    // Purge all the documents soft-deleted before the time (millis).
+    async fn purge_deleted_before(&self, update_time: i64) -> Result<u64, Error>;
 }
 
 pub struct DocumentHandler<'a> {
@@ -63,6 +77,12 @@ impl<'a> IDocumentHandler for DocumentHandler<'a> {
         repo.get(&self.state.config).select(param.to_document(), page).await
     }
 
+    async fn find_deleted(&self, principal: &AuthUserClaims, page: PageRequest) -> Result<(PageResponse, Vec<Document>), Error> {
+        let param = new_owned_document(principal, None);
+        let repo = self.state.document_repo.lock().await;
+        repo.get(&self.state.config).select_deleted(param, page).await
+    }
+
     async fn save(&self, param: SaveDocumentRequest) -> Result<i64, Error> {
         let repo = self.state.document_repo.lock().await;
         if param.id.is_some() {
@@ -74,6 +94,126 @@ impl<'a> IDocumentHandler for DocumentHandler<'a> {
 
     async fn delete(&self, param: DeleteDocumentRequest) -> Result<u64, Error> {
         let repo = self.state.document_repo.lock().await;
-        repo.get(&self.state.config).delete_by_id(param.id).await
+        repo.get(&self.state.config).soft_delete_by_id(param.id).await
+    }
+
+    async fn purge(&self, param: DeleteDocumentRequest) -> Result<u64, Error> {
+        let repo = self.state.document_repo.lock().await;
+        repo.get(&self.state.config).purge_by_id(param.id).await
+    }
+
+    async fn purge_deleted_before(&self, update_time: i64) -> Result<u64, Error> {
+        let repo = self.state.document_repo.lock().await;
+        repo.get(&self.state.config).purge_deleted_before(update_time).await
+    }
+}
+
+// The query param of the documents owned by the principal, the owner is the creator, see: BaseBean::pre_insert()
+fn new_owned_document(principal: &AuthUserClaims, id: Option<i64>) -> Document {
+    Document {
+        base: BaseBean::new(id, Some(principal.email.clone()), None),
+        key: None,
+        name: None,
+        folder_key: None,
+        doc_type: None,
+        content: None,
+    }
+}
+
+const PURGE_DELETED_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Starts the maintenance job of purging the documents soft-deleted more than the configured days ago
+/// (see 'db.purge-deleted-after-days') hourly, returns none if disabled.
+pub fn start_purge_deleted_job(state: AppState) -> Option<JoinHandle<()>> {
+    let days = state.config.db.purge_deleted_after_days.unwrap_or(DEFAULT_DB_PURGE_DELETED_AFTER_DAYS);
+    if days == 0 {
+        return None;
+    }
+    Some(
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PURGE_DELETED_INTERVAL);
+            loop {
+                interval.tick().await;
+                let before = times::now_millis() - (days as i64) * 86_400_000;
+                match DocumentHandler::new(&state).purge_deleted_before(before).await {
+                    std::result::Result::Ok(purged) => {
+                        tracing::info!("Purged {} documents deleted {} days ago.", purged, days);
+                    }
+                    Err(e) => tracing::warn!("Failed to purge the deleted documents. reason: {:?}", e),
+                }
+            }
+        })
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::document::DocumentType;
+    use crate::context::state::tests::new_test_state;
+    use crate::store::StoreError;
+
+    fn new_save_request(name: &str) -> SaveDocumentRequest {
+        SaveDocumentRequest {
+            id: None,
+            key: Some(name.to_string()),
+            name: Some(name.to_string()),
+            folder_key: None,
+            doc_type: Some(DocumentType::Note),
+            content: Some("hello".to_string()),
+        }
+    }
+
+    // Save the document and returns the owner principal of it, i.e. the creator stamped on inserting.
+    async fn save_owned(handler: &DocumentHandler<'_>, state: &AppState, name: &str) -> (i64, AuthUserClaims) {
+        let id = handler.save(new_save_request(name)).await.unwrap();
+        let repo = state.document_repo.lock().await;
+        let stored = repo.get(&state.config).select_by_id(id).await.unwrap().unwrap();
+        (id, AuthUserClaims::for_test(1001, &stored.base.create_by.unwrap()))
+    }
+
+    #[tokio::test]
+    async fn test_delete_document_moves_to_trash() {
+        let state = new_test_state(|_| {}).await;
+        let handler = DocumentHandler::new(&state);
+        let (id, owner) = save_owned(&handler, &state, "trash-1").await;
+        let (kept, _) = save_owned(&handler, &state, "trash-2").await;
+
+        assert_eq!(handler.delete(DeleteDocumentRequest { id }).await.unwrap(), 1);
+        // Deleting again affects nothing, since it's in the trash already.
+        assert_eq!(handler.delete(DeleteDocumentRequest { id }).await.unwrap(), 0);
+
+        // The deleted is excluded from the queries, but remains in the trash with the deletion time.
+        let repo = state.document_repo.lock().await;
+        assert!(repo.get(&state.config).select_by_id(id).await.unwrap().is_none());
+        assert!(repo.get(&state.config).select_by_id(kept).await.unwrap().is_some());
+        drop(repo);
+        let (_, trash) = handler.find_deleted(&owner, PageRequest::default()).await.unwrap();
+        let deleted = trash.iter().find(|d| d.base.id == Some(id)).expect("not in trash");
+        assert!(deleted.base.update_time.is_some());
+        assert!(trash.iter().all(|d| d.base.id != Some(kept)));
+
+        // The trash of others is invisible.
+        let other = AuthUserClaims::for_test(1001, "trash-other@example.com");
+        let (_, trash) = handler.find_deleted(&other, PageRequest::default()).await.unwrap();
+        assert!(trash.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_purge_document_in_trash_only() {
+        let state = new_test_state(|_| {}).await;
+        let handler = DocumentHandler::new(&state);
+        let (id, owner) = save_owned(&handler, &state, "purge-1").await;
+        let param = || DeleteDocumentRequest { id };
+
+        let err = handler.purge(param()).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::NotDeleted(..))), "{:?}", err);
+
+        handler.delete(param()).await.unwrap();
+        assert_eq!(handler.purge(param()).await.unwrap(), 1);
+        let (_, trash) = handler.find_deleted(&owner, PageRequest::default()).await.unwrap();
+        assert!(trash.iter().all(|d| d.base.id != Some(id)));
+        let err = handler.purge(param()).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::NotFound(..))), "{:?}", err);
     }
 }
@@ -4,4 +4,5 @@ pub mod user;
 pub mod browser_indexeddb_v2;
 pub mod document;
 pub mod settings;
+pub mod audit;
 pub mod folder;
@@ -1,29 +1,66 @@
 use std::sync::Arc;
 
-use anyhow::{ Error, Ok };
 use axum::async_trait;
+use futures::StreamExt;
+use validator::Validate;
+use crate::config::config_serve::{ AuthProperties, DEFAULT_IMPORT_CONCURRENCY };
 use crate::context::state::AppState;
+use crate::errors::AppError;
+use crate::store::select_all;
 use crate::types::settings::{
     DeleteSettingsRequest,
+    DiffSettingsRequest,
+    DiffSettingsResponse,
+    ImportSettingsRequest,
+    ImportSettingsResponse,
     QuerySettingsRequest,
     SaveSettingsRequest,
     Settings,
+    SETTINGS_SCOPE_GLOBAL,
+    SETTINGS_SCOPE_GROUP,
+    SETTINGS_SCOPE_USER,
 };
-use crate::types::{ PageRequest, PageResponse };
+use crate::types::{ BaseBean, OperationOutcome, PageRequest, PageResponse };
+use crate::utils::auths::AuthUserClaims;
+
+// The key of user claims extension that carries the group id of user.
+pub const CLAIMS_EXT_GROUP_KEY: &str = "group";
+
+// The items of each concurrent validating task of import.
+const IMPORT_VALIDATE_CHUNK_SIZE: usize = 256;
 
 #[async_trait]
 pub trait ISettingsHandler: Send {
-    async fn get(&self, name: Option<String>) -> Result<Option<Arc<Settings>>, Error>;
+    async fn get(&self, name: Option<String>, principal: Option<AuthUserClaims>) -> Result<Option<Arc<Settings>>, AppError>;
 
+    // Resolve the effective settings by merging the layers of global, group and user in order, the
+    // later overrides the former, and the missing layer is skipped.
+    async fn resolve(
+        &self,
+        name: &str,
+        principal: Option<&AuthUserClaims>,
+        user_only: bool
+    ) -> Result<Option<Settings>, AppError>;
+
+    // Find the settings, the settings of specified name is resolved to the effective of the principal.
     async fn find(
         &self,
         param: QuerySettingsRequest,
-        page: PageRequest
-    ) -> Result<(PageResponse, Vec<Settings>), Error>;
+        page: PageRequest,
+        principal: Option<AuthUserClaims>
+    ) -> Result<(PageResponse, Vec<Settings>), AppError>;
+
+    // Save the settings of the layer writable by the principal, see: authorize_layer().
+    async fn save(&self, principal: &AuthUserClaims, param: SaveSettingsRequest) -> Result<OperationOutcome, AppError>;
 
-    async fn save(&self, param: SaveSettingsRequest) -> Result<i64, Error>;
+    async fn delete(&self, principal: &AuthUserClaims, param: DeleteSettingsRequest) -> Result<OperationOutcome, AppError>;
 
-    async fn delete(&self, param: DeleteSettingsRequest) -> Result<u64, Error>;
+    // Import the items all or nothing, which are validated concurrently and written in a transaction.
+    async fn import(&self, principal: &AuthUserClaims, param: ImportSettingsRequest) -> Result<ImportSettingsResponse, AppError>;
+
+    // Write only the items changed against the stored of the layer in a transaction, so that the
+    // unchanged rows are neither rewritten nor invalidated.
+    async fn diff(&self, principal: &AuthUserClaims, param: DiffSettingsRequest) -> Result<DiffSettingsResponse, AppError>;
 }
 
 pub struct SettingsHandler<'a> {
@@ -34,15 +71,132 @@ impl<'a> SettingsHandler<'a> {
     pub fn new(state: &'a AppState) -> Self {
         Self { state }
     }
+
+    async fn select_layer(
+        &self,
+        name: &str,
+        scope: &str,
+        owner: Option<String>
+    ) -> Result<Option<Settings>, AppError> {
+        let param = Settings::new(Some(name.to_string()), Some(scope.to_string()), owner);
+        let repo = self.state.settings_repo.lock().await;
+        let (_, res) = repo
+            .get(&self.state.config)
+            .select(param, PageRequest::default()).await
+            .map_err(AppError::storage)?;
+        Ok(res.into_iter().next())
+    }
+
+    // Check the stored settings of id is writable by the principal, none if not exists.
+    async fn authorize_stored(&self, principal: &AuthUserClaims, id: i64) -> Result<Option<Settings>, AppError> {
+        let repo = self.state.settings_repo.lock().await;
+        let stored = repo.get(&self.state.config).select_by_id(id).await.map_err(AppError::storage)?;
+        match stored {
+            Some(stored) if !can_write_stored(&self.state.config.auth, principal, &stored) => {
+                Err(AppError::Forbidden(format!("writing the settings {} of the other owner", id)))
+            }
+            stored => Ok(stored),
+        }
+    }
+
+    async fn find_uncoalesced(
+        &self,
+        param: QuerySettingsRequest,
+        page: PageRequest,
+        principal: Option<AuthUserClaims>
+    ) -> Result<(PageResponse, Vec<Settings>), AppError> {
+        // The settings of specified name is resolved to the effective of current user.
+        if let Some(name) = param.name.as_deref().filter(|name| !name.is_empty()) {
+            let user_only = param.user_only.unwrap_or(false);
+            let data = self.resolve(name, principal.as_ref(), user_only).await?.into_iter().collect::<Vec<_>>();
+            let page = PageResponse::new(Some(data.len() as i64), Some(page.get_offset()), Some(page.get_limit()));
+            return Ok((page, data));
+        }
+
+        let repo = self.state.settings_repo.lock().await;
+        repo.get(&self.state.config).select(param.to_settings(), page).await.map_err(AppError::storage)
+    }
+}
+
+// Authorize the principal to write the layer of scope and owner, returns the (scope, owner) to write. The
+// user layer is of the principal itself (the owner defaults to it), and the layers of the other users, the
+// groups and the global are of the admins only.
+fn authorize_layer(
+    auth: &AuthProperties,
+    principal: &AuthUserClaims,
+    scope: Option<&str>,
+    owner: Option<&str>
+) -> Result<(String, Option<String>), AppError> {
+    let uid = principal.uid.to_string();
+    let is_admin = auth.is_admin(principal.uid);
+    match scope.unwrap_or(SETTINGS_SCOPE_USER) {
+        SETTINGS_SCOPE_USER =>
+            match owner {
+                None => Ok((SETTINGS_SCOPE_USER.to_string(), Some(uid))),
+                Some(owner) if owner == uid || is_admin => Ok((SETTINGS_SCOPE_USER.to_string(), Some(owner.to_string()))),
+                Some(owner) => Err(AppError::Forbidden(format!("writing the settings of user {} requires admin", owner))),
+            }
+        SETTINGS_SCOPE_GLOBAL if is_admin => Ok((SETTINGS_SCOPE_GLOBAL.to_string(), None)),
+        SETTINGS_SCOPE_GROUP if is_admin =>
+            match owner {
+                Some(owner) => Ok((SETTINGS_SCOPE_GROUP.to_string(), Some(owner.to_string()))),
+                None => Err(AppError::Validation("The owner of group settings is required".to_string())),
+            }
+        scope @ (SETTINGS_SCOPE_GLOBAL | SETTINGS_SCOPE_GROUP) => {
+            Err(AppError::Forbidden(format!("writing the {} settings requires admin", scope)))
+        }
+        scope => Err(AppError::Validation(format!("Unknown settings scope: {}", scope))),
+    }
+}
+
+// The group of the principal carried by the claims ext, see: CLAIMS_EXT_GROUP_KEY
+fn principal_group(principal: Option<&AuthUserClaims>) -> Option<&str> {
+    principal
+        .and_then(|p| p.ext.as_ref())
+        .and_then(|ext| ext.get(CLAIMS_EXT_GROUP_KEY))
+        .map(String::as_str)
+}
+
+// Whether the stored settings is writable by the principal, i.e. the own user layer, or by the admins.
+fn can_write_stored(auth: &AuthProperties, principal: &AuthUserClaims, stored: &Settings) -> bool {
+    auth.is_admin(principal.uid) ||
+        (stored.scope.as_deref() == Some(SETTINGS_SCOPE_USER) && stored.owner.as_deref() == Some(principal.uid.to_string().as_str()))
+}
+
+// Merge the items of JSON object shallowly, the non-object value of upper layer replaces the lower.
+fn merge_settings_value(lower: Option<String>, upper: Option<String>) -> Option<String> {
+    let parse = |v: &Option<String>| v.as_deref().and_then(|v| serde_json::from_str::<serde_json::Value>(v).ok());
+    match (parse(&lower), parse(&upper)) {
+        (Some(serde_json::Value::Object(mut lower)), Some(serde_json::Value::Object(upper))) => {
+            lower.extend(upper);
+            Some(serde_json::Value::Object(lower).to_string())
+        }
+        _ => upper.or(lower),
+    }
+}
+
+// Whether the settings values are equal, the JSON values are compared semantically.
+fn settings_value_eq(stored: Option<&str>, value: &str) -> bool {
+    let Some(stored) = stored else {
+        return false;
+    };
+    if stored == value {
+        return true;
+    }
+    match (serde_json::from_str::<serde_json::Value>(stored), serde_json::from_str::<serde_json::Value>(value)) {
+        (Ok(stored), Ok(value)) => stored == value,
+        _ => false,
+    }
 }
 
 #[async_trait]
 impl<'a> ISettingsHandler for SettingsHandler<'a> {
-    async fn get(&self, name: Option<String>) -> Result<Option<Arc<Settings>>, Error> {
+    async fn get(&self, name: Option<String>, principal: Option<AuthUserClaims>) -> Result<Option<Arc<Settings>>, AppError> {
         let param = QuerySettingsRequest {
             name,
+            user_only: None,
         };
-        let res = self.find(param, PageRequest::default()).await.unwrap().1;
+        let res = self.find(param, PageRequest::default(), principal).await?.1;
         if res.len() > 0 {
             let settings = Arc::new(res.get(0).unwrap().clone());
             return Ok(Some(settings));
@@ -51,26 +205,606 @@ impl<'a> ISettingsHandler for SettingsHandler<'a> {
         }
     }
 
+    async fn resolve(
+        &self,
+        name: &str,
+        principal: Option<&AuthUserClaims>,
+        user_only: bool
+    ) -> Result<Option<Settings>, AppError> {
+        let uid = principal.map(|p| p.uid.to_string());
+        let group = principal_group(principal).map(str::to_string);
+
+        let mut layers = Vec::new();
+        if !user_only {
+            layers.push((SETTINGS_SCOPE_GLOBAL, None));
+            if group.is_some() {
+                layers.push((SETTINGS_SCOPE_GROUP, group));
+            }
+        }
+        if uid.is_some() {
+            layers.push((SETTINGS_SCOPE_USER, uid));
+        }
+
+        let mut effective: Option<Settings> = None;
+        for (scope, owner) in layers {
+            if let Some(layer) = self.select_layer(name, scope, owner).await? {
+                effective = Some(match effective {
+                    Some(lower) => Settings { value: merge_settings_value(lower.value, layer.value.clone()), ..layer },
+                    None => layer,
+                });
+            }
+        }
+        Ok(effective)
+    }
+
     async fn find(
         &self,
         param: QuerySettingsRequest,
-        page: PageRequest
-    ) -> Result<(PageResponse, Vec<Settings>), Error> {
+        page: PageRequest,
+        principal: Option<AuthUserClaims>
+    ) -> Result<(PageResponse, Vec<Settings>), AppError> {
+        // The effective settings are resolved by both the user and group, and the anonymous is keyed apart.
+        let key = format!(
+            "{}:{}:{}:{}:{}:{}:{}",
+            principal.as_ref().map(|p| p.uid.to_string()).unwrap_or_else(|| "-".to_string()),
+            principal_group(principal.as_ref()).unwrap_or_default(),
+            param.name.as_deref().unwrap_or_default(),
+            param.user_only.unwrap_or(false),
+            page.get_offset(),
+            page.get_limit(),
+            page.cursor.as_deref().unwrap_or_default()
+        );
+        self.state.settings_flight.execute(&key, || self.find_uncoalesced(param, page, principal)).await
+    }
+
+    async fn save(&self, principal: &AuthUserClaims, param: SaveSettingsRequest) -> Result<OperationOutcome, AppError> {
+        let (scope, owner) = authorize_layer(&self.state.config.auth, principal, param.scope.as_deref(), param.owner.as_deref())?;
+        if let Some(id) = param.id {
+            self.authorize_stored(principal, id).await?;
+        }
+        let param = SaveSettingsRequest { scope: Some(scope), owner, ..param };
+
         let repo = self.state.settings_repo.lock().await;
-        repo.get(&self.state.config).select(param.to_settings(), page).await
+        let config = &self.state.config;
+        match param.id {
+            Some(id) => {
+                if repo.get(config).update(param.to_settings()).await.map_err(AppError::storage)? > 0 {
+                    return Ok(OperationOutcome::updated(id));
+                }
+                // Nothing is updated, because of either unchanged or not exists.
+                let exists = Settings { base: BaseBean::new_default(Some(id)), ..Settings::new(None, None, None) };
+                if repo.get(config).count_by(exists, &[]).await.map_err(AppError::storage)? > 0 {
+                    Ok(OperationOutcome::noop(Some(id)))
+                } else {
+                    Err(AppError::NotFound(format!("settings by id: {}", id)))
+                }
+            }
+            None => {
+                let id = repo.get(config).insert(param.to_settings()).await.map_err(AppError::storage)?;
+                if id > 0 { Ok(OperationOutcome::created(id)) } else { Ok(OperationOutcome::noop(None)) }
+            }
+        }
     }
 
-    async fn save(&self, param: SaveSettingsRequest) -> Result<i64, Error> {
+    async fn delete(&self, principal: &AuthUserClaims, param: DeleteSettingsRequest) -> Result<OperationOutcome, AppError> {
+        self.authorize_stored(principal, param.id).await?;
         let repo = self.state.settings_repo.lock().await;
-        if param.id.is_some() {
-            repo.get(&self.state.config).update(param.to_settings()).await
+        let affected = repo.get(&self.state.config).delete_by_id(param.id).await.map_err(AppError::storage)?;
+        if affected > 0 {
+            Ok(OperationOutcome::deleted(param.id, affected))
         } else {
-            repo.get(&self.state.config).insert(param.to_settings()).await
+            Ok(OperationOutcome::noop(Some(param.id)))
+        }
+    }
+
+    async fn import(&self, principal: &AuthUserClaims, mut param: ImportSettingsRequest) -> Result<ImportSettingsResponse, AppError> {
+        let total = param.items.len();
+        let concurrency = self.state.config.server.import_concurrency.unwrap_or(DEFAULT_IMPORT_CONCURRENCY).max(1);
+
+        // 0. Authorize all the items as saving (same as save), the first unauthorized item fails the whole import.
+        let auth = &self.state.config.auth;
+        for (i, item) in param.items.iter_mut().enumerate() {
+            let (scope, owner) = authorize_layer(auth, principal, item.scope.as_deref(), item.owner.as_deref())
+                .map_err(|e| match e {
+                    AppError::Forbidden(msg) => AppError::Forbidden(format!("items[{}]: {}", i, msg)),
+                    AppError::Validation(msg) => AppError::Validation(format!("items[{}]: {}", i, msg)),
+                    e => e,
+                })?;
+            item.scope = Some(scope);
+            item.owner = owner;
+        }
+        let ids = param.items.iter().filter_map(|item| item.id).collect::<Vec<_>>();
+        if !ids.is_empty() {
+            let repo = self.state.settings_repo.lock().await;
+            let stored = repo.get(&self.state.config).select_by_ids(ids).await.map_err(AppError::storage)?;
+            if let Some(s) = stored.iter().find(|s| !can_write_stored(auth, principal, s)) {
+                return Err(AppError::Forbidden(format!("writing the settings {:?} of the other owner", s.base.id)));
+            }
+        }
+
+        // 1. Validate the chunks of items concurrently, the first invalid item fails the whole import.
+        let chunks: Vec<Vec<SaveSettingsRequest>> = param.items
+            .chunks(IMPORT_VALIDATE_CHUNK_SIZE)
+            .map(<[SaveSettingsRequest]>::to_vec)
+            .collect();
+        let mut validated = futures::stream
+            ::iter(chunks.into_iter().enumerate())
+            .map(|(n, chunk)| {
+                tokio::task::spawn_blocking(move || {
+                    chunk
+                        .iter()
+                        .enumerate()
+                        .map(|(i, item)| {
+                            item.validate()
+                                .map(|_| item.to_settings())
+                                .map_err(|e| format!("items[{}]: {}", n * IMPORT_VALIDATE_CHUNK_SIZE + i, e))
+                        })
+                        .collect::<Result<Vec<_>, _>>()
+                })
+            })
+            .buffered(concurrency);
+        let mut items = Vec::with_capacity(total);
+        while let Some(result) = validated.next().await {
+            let chunk = result.map_err(|e| AppError::Internal(e.into()))?.map_err(AppError::Validation)?;
+            items.extend(chunk);
+            tracing::info!("Validated {}/{} of importing settings", items.len(), total);
+        }
+
+        // 2. Write all the items in a transaction serially.
+        let creating = items.iter().map(|item| item.base.id.is_none()).collect::<Vec<_>>();
+        let repo = self.state.settings_repo.lock().await;
+        let ids = repo.get(&self.state.config).save_all(items).await.map_err(AppError::storage)?;
+
+        let mut resp = ImportSettingsResponse { total: total as u64, created: 0, updated: 0, unchanged: 0 };
+        for (id, creating) in ids.iter().zip(creating) {
+            match (*id > 0, creating) {
+                (true, true) => resp.created += 1,
+                (true, false) => resp.updated += 1,
+                (false, _) => resp.unchanged += 1,
+            }
         }
+        tracing::info!("Imported settings: {:?}", resp);
+        Ok(resp)
     }
 
-    async fn delete(&self, param: DeleteSettingsRequest) -> Result<u64, Error> {
+    async fn diff(&self, principal: &AuthUserClaims, param: DiffSettingsRequest) -> Result<DiffSettingsResponse, AppError> {
+        let (scope, owner) = authorize_layer(&self.state.config.auth, principal, param.scope.as_deref(), param.owner.as_deref())?;
+        let param = DiffSettingsRequest { scope: Some(scope), owner, ..param };
+        for (name, value) in param.items.iter() {
+            if name.is_empty() || name.len() > 64 || value.is_empty() || value.len() > 65535 {
+                return Err(AppError::Validation(format!("items[{}]: invalid length of name or value", name)));
+            }
+        }
+        let config = &self.state.config;
+        let layer = Settings::new(None, param.scope.clone(), param.owner.clone());
+        let stored = select_all(&self.state.settings_repo, config, layer).await.map_err(AppError::storage)?;
+
+        let mut resp = DiffSettingsResponse { total: param.items.len() as u64, created: 0, updated: 0, unchanged: 0 };
+        let mut changed = Vec::new();
+        for (name, value) in param.items {
+            match stored.iter().find(|s| s.name.as_deref() == Some(name.as_str())) {
+                Some(s) if settings_value_eq(s.value.as_deref(), &value) => {
+                    resp.unchanged += 1;
+                }
+                Some(s) => changed.push(Settings { value: Some(value), ..s.clone() }),
+                None => {
+                    let settings = Settings::new(Some(name), param.scope.clone(), param.owner.clone());
+                    changed.push(Settings { value: Some(value), ..settings });
+                }
+            }
+        }
+        if changed.is_empty() {
+            return Ok(resp);
+        }
+
+        let creating = changed.iter().map(|item| item.base.id.is_none()).collect::<Vec<_>>();
         let repo = self.state.settings_repo.lock().await;
-        repo.get(&self.state.config).delete_by_id(param.id).await
+        let ids = repo.get(config).save_all(changed).await.map_err(AppError::storage)?;
+        for (id, creating) in ids.iter().zip(creating) {
+            match (*id > 0, creating) {
+                (true, true) => resp.created += 1,
+                (true, false) => resp.updated += 1,
+                (false, _) => resp.unchanged += 1,
+            }
+        }
+        tracing::info!("Diff updated settings: {:?}", resp);
+        Ok(resp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{ BTreeMap, HashMap };
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+    use crate::store::{ AsyncRepository, RepositoryContainer };
+    use crate::store::settings_mongo::SettingsMongoRepository;
+    use crate::store::settings_sqlite::SettingsSQLiteRepository;
+    use crate::config::config_serve::WebServeProperties;
+    use crate::context::state::tests::new_test_state;
+
+    fn new_principal(uid: i64, group: Option<&str>) -> AuthUserClaims {
+        AuthUserClaims {
+            ext: group.map(|g| HashMap::from([(CLAIMS_EXT_GROUP_KEY.to_string(), g.to_string())])),
+            ..AuthUserClaims::for_test(uid, "a@b.com")
+        }
+    }
+
+    async fn save_layer(handler: &SettingsHandler<'_>, scope: &str, owner: Option<&str>, value: &str) {
+        let param = SaveSettingsRequest {
+            id: None,
+            name: Some("editor".to_string()),
+            scope: Some(scope.to_string()),
+            owner: owner.map(|o| o.to_string()),
+            value: Some(value.to_string()),
+        };
+        handler.save(&new_principal(ADMIN_UID, None), param).await.unwrap();
+    }
+
+    const ADMIN_UID: i64 = 9001;
+
+    async fn new_admin_state() -> AppState {
+        new_test_state(|p: &mut WebServeProperties| {
+            p.auth.admin_uids = Some(vec![ADMIN_UID]);
+        }).await
+    }
+
+    fn value_of(settings: Option<Settings>) -> serde_json::Value {
+        serde_json::from_str(&settings.unwrap().value.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_resolve_settings_layers_precedence() {
+        let state = new_admin_state().await;
+        let handler = SettingsHandler::new(&state);
+        save_layer(&handler, SETTINGS_SCOPE_GLOBAL, None, r#"{"theme":"light","font":12,"tab":2}"#).await;
+        save_layer(&handler, SETTINGS_SCOPE_GROUP, Some("g1"), r#"{"theme":"dark","font":14}"#).await;
+        save_layer(&handler, SETTINGS_SCOPE_USER, Some("1001"), r#"{"font":16}"#).await;
+
+        let principal = new_principal(1001, Some("g1"));
+        let effective = handler.resolve("editor", Some(&principal), false).await.unwrap();
+        assert_eq!(value_of(effective), serde_json::json!({ "theme": "dark", "font": 16, "tab": 2 }));
+
+        let user_only = handler.resolve("editor", Some(&principal), true).await.unwrap();
+        assert_eq!(value_of(user_only), serde_json::json!({ "font": 16 }));
+
+        // The anonymous is resolved to the global only.
+        let global = handler.resolve("editor", None, false).await.unwrap();
+        assert_eq!(value_of(global), serde_json::json!({ "theme": "light", "font": 12, "tab": 2 }));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_settings_skips_missing_group() {
+        let state = new_admin_state().await;
+        let handler = SettingsHandler::new(&state);
+        save_layer(&handler, SETTINGS_SCOPE_GLOBAL, None, r#"{"theme":"light","font":12}"#).await;
+        save_layer(&handler, SETTINGS_SCOPE_USER, Some("1001"), r#"{"font":16}"#).await;
+
+        // Neither the group claim nor the group layer.
+        for principal in [new_principal(1001, None), new_principal(1001, Some("g2"))] {
+            let effective = handler.resolve("editor", Some(&principal), false).await.unwrap();
+            assert_eq!(value_of(effective), serde_json::json!({ "theme": "light", "font": 16 }));
+        }
+        assert!(handler.resolve("editor", Some(&new_principal(2002, None)), true).await.unwrap().is_none());
+        assert!(handler.resolve("missing", Some(&new_principal(1001, None)), false).await.unwrap().is_none());
+    }
+
+    fn new_save_request(scope: Option<&str>, owner: Option<&str>) -> SaveSettingsRequest {
+        SaveSettingsRequest {
+            id: None,
+            name: Some("editor".to_string()),
+            scope: scope.map(|s| s.to_string()),
+            owner: owner.map(|o| o.to_string()),
+            value: Some(r#"{"font":16}"#.to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_save_settings_authorized_by_principal() {
+        let state = new_admin_state().await;
+        let handler = SettingsHandler::new(&state);
+        let alice = new_principal(1001, Some("g1"));
+
+        // The owner of user layer is derived from the principal.
+        let created = handler.save(&alice, new_save_request(None, None)).await.unwrap();
+        let stored = handler_select(&state, "editor").await.unwrap();
+        assert_eq!(stored.scope.as_deref(), Some(SETTINGS_SCOPE_USER));
+        assert_eq!(stored.owner.as_deref(), Some("1001"));
+
+        // The non admin writes neither the other user, the group nor the global layers.
+        for (scope, owner) in [
+            (Some(SETTINGS_SCOPE_USER), Some("2002")),
+            (Some(SETTINGS_SCOPE_GROUP), Some("g1")),
+            (Some(SETTINGS_SCOPE_GLOBAL), None),
+        ] {
+            let err = handler.save(&alice, new_save_request(scope, owner)).await.unwrap_err();
+            assert!(matches!(err, AppError::Forbidden(_)), "{:?} {:?}: {:?}", scope, owner, err);
+        }
+        let err = handler.save(&alice, new_save_request(Some("other"), None)).await.unwrap_err();
+        assert!(matches!(err, AppError::Validation(_)), "{:?}", err);
+
+        // Nor overwrites or deletes the stored settings of the other owner by id.
+        let bob = new_principal(2002, None);
+        let param = SaveSettingsRequest { id: created.id, ..new_save_request(None, None) };
+        assert!(matches!(handler.save(&bob, param).await, Err(AppError::Forbidden(_))));
+        let param = DeleteSettingsRequest { id: created.id.unwrap() };
+        assert!(matches!(handler.delete(&bob, param).await, Err(AppError::Forbidden(_))));
+
+        // The admin writes the global layer, the owner is dropped.
+        let admin = new_principal(ADMIN_UID, None);
+        let param = SaveSettingsRequest { name: Some("global".to_string()), ..new_save_request(Some(SETTINGS_SCOPE_GLOBAL), Some("1001")) };
+        handler.save(&admin, param).await.unwrap();
+        let stored = handler_select(&state, "global").await.unwrap();
+        assert_eq!(stored.owner, None);
+        let param = DeleteSettingsRequest { id: created.id.unwrap() };
+        assert!(handler.delete(&admin, param).await.is_ok());
+    }
+
+    fn new_import_item(i: usize) -> SaveSettingsRequest {
+        SaveSettingsRequest {
+            id: None,
+            name: Some(format!("import-{}", i)),
+            scope: Some(SETTINGS_SCOPE_USER.to_string()),
+            owner: Some((i % 10).to_string()),
+            value: Some(format!(r#"{{"n":{}}}"#, i)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_import_settings_concurrently_all_or_nothing() {
+        let state = new_test_state(|p: &mut WebServeProperties| {
+            p.server.import_concurrency = Some(4);
+            p.auth.admin_uids = Some(vec![ADMIN_UID]);
+        }).await;
+        let handler = SettingsHandler::new(&state);
+
+        let total = 3000;
+        let started = std::time::Instant::now();
+        let items = (0..total).map(new_import_item).collect::<Vec<_>>();
+        let resp = handler.import(&new_principal(ADMIN_UID, None), ImportSettingsRequest { items }).await.unwrap();
+        assert!(started.elapsed() < std::time::Duration::from_secs(30), "too slow: {:?}", started.elapsed());
+        assert_eq!(resp, ImportSettingsResponse { total: total as u64, created: total as u64, updated: 0, unchanged: 0 });
+
+        let repo = state.settings_repo.lock().await;
+        let param = Settings::new(None, Some(SETTINGS_SCOPE_USER.to_string()), None);
+        assert_eq!(repo.get(&state.config).count_by(param, &[]).await.unwrap(), total as i64);
+        drop(repo);
+        let imported = handler_select(&state, "import-1234").await.unwrap();
+        assert_eq!(imported.owner.as_deref(), Some("4"));
+        assert_eq!(imported.value.as_deref(), Some(r#"{"n":1234}"#));
+
+        // The invalid item fails the whole import, nothing is written.
+        let mut items = (total..total + 1000).map(new_import_item).collect::<Vec<_>>();
+        items[700].name = Some("x".repeat(65));
+        let err = handler.import(&new_principal(ADMIN_UID, None), ImportSettingsRequest { items }).await.unwrap_err();
+        assert!(matches!(&err, AppError::Validation(msg) if msg.contains("items[700]")), "{:?}", err);
+        assert!(handler_select(&state, &format!("import-{}", total)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_import_settings_authorized_by_principal() {
+        let state = new_admin_state().await;
+        let handler = SettingsHandler::new(&state);
+        let bob = new_principal(2002, None);
+
+        // The item of the other owner fails the whole import, nothing is written.
+        let items = vec![new_save_request(None, None), new_save_request(Some(SETTINGS_SCOPE_GLOBAL), None)];
+        let err = handler.import(&bob, ImportSettingsRequest { items }).await.unwrap_err();
+        assert!(matches!(&err, AppError::Forbidden(msg) if msg.contains("items[1]")), "{:?}", err);
+        assert!(handler_select(&state, "editor").await.is_none());
+
+        // The items of own are written with the derived owner.
+        let items = vec![new_save_request(None, None)];
+        let resp = handler.import(&bob, ImportSettingsRequest { items }).await.unwrap();
+        assert_eq!(resp.created, 1);
+        let stored = handler_select(&state, "editor").await.unwrap();
+        assert_eq!(stored.owner.as_deref(), Some("2002"));
+
+        // Nor overwrites the stored settings of the other owner by id.
+        let alice = new_principal(1001, None);
+        let items = vec![SaveSettingsRequest { id: stored.base.id, ..new_save_request(None, None) }];
+        let err = handler.import(&alice, ImportSettingsRequest { items }).await.unwrap_err();
+        assert!(matches!(err, AppError::Forbidden(_)), "{:?}", err);
+    }
+
+    async fn select_layer_by_name(state: &AppState, owner: &str) -> BTreeMap<String, Settings> {
+        let layer = Settings::new(None, Some(SETTINGS_SCOPE_USER.to_string()), Some(owner.to_string()));
+        let stored = select_all(&state.settings_repo, &state.config, layer).await.unwrap();
+        stored
+            .into_iter()
+            .map(|s| (s.name.clone().unwrap(), s))
+            .collect()
+    }
+
+    fn new_diff_request(items: BTreeMap<String, String>) -> DiffSettingsRequest {
+        DiffSettingsRequest {
+            scope: Some(SETTINGS_SCOPE_USER.to_string()),
+            owner: Some("1001".to_string()),
+            items,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_diff_settings_writes_only_changed_rows() {
+        let state = new_test_state(|_: &mut WebServeProperties| {}).await;
+        let handler = SettingsHandler::new(&state);
+        let items = (0..100)
+            .map(|i| (format!("key-{}", i), format!(r#"{{"n":{},"on":true}}"#, i)))
+            .collect::<BTreeMap<_, _>>();
+        let resp = handler.diff(&new_principal(1001, None), new_diff_request(items.clone())).await.unwrap();
+        assert_eq!(resp, DiffSettingsResponse { total: 100, created: 100, updated: 0, unchanged: 0 });
+        let before = select_layer_by_name(&state, "1001").await;
+
+        // The full set with one key changed, and the semantically equal JSON is unchanged.
+        let mut full = items.clone();
+        full.insert("key-7".to_string(), r#"{"n":700,"on":true}"#.to_string());
+        full.insert("key-8".to_string(), r#"{ "on": true, "n": 8 }"#.to_string());
+        let resp = handler.diff(&new_principal(1001, None), new_diff_request(full)).await.unwrap();
+        assert_eq!(resp, DiffSettingsResponse { total: 100, created: 0, updated: 1, unchanged: 99 });
+
+        let after = select_layer_by_name(&state, "1001").await;
+        assert_eq!(after.len(), 100);
+        for (name, settings) in after.iter() {
+            let origin = &before[name];
+            if name == "key-7" {
+                assert_eq!(settings.value.as_deref(), Some(r#"{"n":700,"on":true}"#));
+                assert!(settings.base.update_time.is_some());
+                assert_ne!(settings.base.update_time, origin.base.update_time);
+            } else {
+                assert_eq!(settings.value, origin.value, "{}", name);
+                assert_eq!(settings.base.update_time, origin.base.update_time, "{}", name);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_diff_settings_changed_keys_only() {
+        let state = new_test_state(|_: &mut WebServeProperties| {}).await;
+        let handler = SettingsHandler::new(&state);
+        let items = BTreeMap::from([("a".to_string(), "1".to_string()), ("b".to_string(), "2".to_string())]);
+        handler.diff(&new_principal(1001, None), new_diff_request(items)).await.unwrap();
+
+        let changed = BTreeMap::from([("b".to_string(), "3".to_string()), ("c".to_string(), "4".to_string())]);
+        let resp = handler.diff(&new_principal(1001, None), new_diff_request(changed)).await.unwrap();
+        assert_eq!(resp, DiffSettingsResponse { total: 2, created: 1, updated: 1, unchanged: 0 });
+
+        // The keys absent are kept as is.
+        let stored = select_layer_by_name(&state, "1001").await;
+        let values = stored
+            .iter()
+            .map(|(name, s)| (name.as_str(), s.value.as_deref().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![("a", "1"), ("b", "3"), ("c", "4")]);
+
+        let invalid = BTreeMap::from([("x".repeat(65), "1".to_string())]);
+        assert!(matches!(handler.diff(&new_principal(1001, None), new_diff_request(invalid)).await, Err(AppError::Validation(_))));
+    }
+
+    #[tokio::test]
+    async fn test_diff_settings_authorized_by_principal() {
+        let state = new_admin_state().await;
+        let handler = SettingsHandler::new(&state);
+        let items = BTreeMap::from([("a".to_string(), "1".to_string())]);
+
+        // The non admin diffs neither the other user nor the global layers.
+        let bob = new_principal(2002, None);
+        let err = handler.diff(&bob, new_diff_request(items.clone())).await.unwrap_err();
+        assert!(matches!(err, AppError::Forbidden(_)), "{:?}", err);
+        let global = DiffSettingsRequest { scope: Some(SETTINGS_SCOPE_GLOBAL.to_string()), owner: None, items: items.clone() };
+        let err = handler.diff(&bob, global.clone()).await.unwrap_err();
+        assert!(matches!(err, AppError::Forbidden(_)), "{:?}", err);
+        assert!(select_layer_by_name(&state, "1001").await.is_empty());
+
+        // The owner is derived from the principal when absent.
+        let own = DiffSettingsRequest { scope: None, owner: None, items: items.clone() };
+        handler.diff(&bob, own).await.unwrap();
+        assert_eq!(select_layer_by_name(&state, "2002").await.len(), 1);
+
+        // The admin diffs the global layer and the other user.
+        let admin = new_principal(ADMIN_UID, None);
+        let resp = handler.diff(&admin, global).await.unwrap();
+        assert_eq!(resp.created, 1);
+        handler.diff(&admin, new_diff_request(items)).await.unwrap();
+        assert_eq!(select_layer_by_name(&state, "1001").await.len(), 1);
+    }
+
+    // The settings repository counting the selects, which delegates to the sqlite, and the select is slowed
+    // down so that the concurrent reads overlap.
+    struct CountingSettingsRepository {
+        inner: SettingsSQLiteRepository,
+        selects: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl AsyncRepository<Settings> for CountingSettingsRepository {
+        async fn select(&self, param: Settings, page: PageRequest) -> Result<(PageResponse, Vec<Settings>), anyhow::Error> {
+            self.selects.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            self.inner.select(param, page).await
+        }
+        async fn select_by_id(&self, id: i64) -> Result<Option<Settings>, anyhow::Error> {
+            self.inner.select_by_id(id).await
+        }
+        async fn select_by_ids(&self, ids: Vec<i64>) -> Result<Vec<Settings>, anyhow::Error> {
+            self.inner.select_by_ids(ids).await
+        }
+        async fn select_deleted(&self, param: Settings, page: PageRequest) -> Result<(PageResponse, Vec<Settings>), anyhow::Error> {
+            self.inner.select_deleted(param, page).await
+        }
+        async fn count_by(&self, param: Settings, not_null_fields: &[&str]) -> Result<i64, anyhow::Error> {
+            self.inner.count_by(param, not_null_fields).await
+        }
+        async fn insert(&self, param: Settings) -> Result<i64, anyhow::Error> {
+            self.inner.insert(param).await
+        }
+        async fn update(&self, param: Settings) -> Result<i64, anyhow::Error> {
+            self.inner.update(param).await
+        }
+        async fn save_all(&self, params: Vec<Settings>) -> Result<Vec<i64>, anyhow::Error> {
+            self.inner.save_all(params).await
+        }
+        async fn delete_all(&self) -> Result<u64, anyhow::Error> {
+            self.inner.delete_all().await
+        }
+        async fn delete_by_id(&self, id: i64) -> Result<u64, anyhow::Error> {
+            self.inner.delete_by_id(id).await
+        }
+        async fn purge_by_id(&self, id: i64) -> Result<u64, anyhow::Error> {
+            self.inner.purge_by_id(id).await
+        }
+        async fn purge_deleted_before(&self, update_time: i64) -> Result<u64, anyhow::Error> {
+            self.inner.purge_deleted_before(update_time).await
+        }
+    }
+
+    async fn new_counting_state() -> (AppState, Arc<AtomicUsize>) {
+        let state = new_admin_state().await;
+        let selects = Arc::new(AtomicUsize::new(0));
+        let counting = CountingSettingsRepository {
+            inner: SettingsSQLiteRepository::new(&state.config.db).await.unwrap(),
+            selects: selects.clone(),
+        };
+        let mongo = SettingsMongoRepository::new(&state.config.db).await.unwrap();
+        *state.settings_repo.lock().await = RepositoryContainer::new(Box::new(counting), Box::new(mongo));
+        (state, selects)
+    }
+
+    #[tokio::test]
+    async fn test_find_settings_coalesces_concurrent_reads() {
+        let (state, selects) = new_counting_state().await;
+        let handler = SettingsHandler::new(&state);
+        save_layer(&handler, SETTINGS_SCOPE_USER, Some("1001"), r#"{"font":16}"#).await;
+
+        let principal = new_principal(1001, Some("g1"));
+        let param = || QuerySettingsRequest { name: None, user_only: None };
+        let reads = (0..20).map(|_| handler.find(param(), PageRequest::default(), Some(principal.clone())));
+        let results = futures::future::join_all(reads).await;
+        assert_eq!(selects.load(Ordering::SeqCst), 1);
+        for result in results {
+            assert_eq!(result.unwrap().1.len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_find_settings_not_coalesced_across_groups() {
+        let (state, _) = new_counting_state().await;
+        let handler = SettingsHandler::new(&state);
+        save_layer(&handler, SETTINGS_SCOPE_GROUP, Some("g1"), r#"{"theme":"dark"}"#).await;
+        save_layer(&handler, SETTINGS_SCOPE_GROUP, Some("g2"), r#"{"theme":"light"}"#).await;
+
+        // The same user switched the group concurrently sees the settings of each group.
+        let param = || QuerySettingsRequest { name: Some("editor".to_string()), user_only: None };
+        let (g1, g2) = tokio::join!(
+            handler.find(param(), PageRequest::default(), Some(new_principal(1001, Some("g1")))),
+            handler.find(param(), PageRequest::default(), Some(new_principal(1001, Some("g2"))))
+        );
+        assert_eq!(value_of(g1.unwrap().1.into_iter().next()), serde_json::json!({ "theme": "dark" }));
+        assert_eq!(value_of(g2.unwrap().1.into_iter().next()), serde_json::json!({ "theme": "light" }));
+    }
+
+    async fn handler_select(state: &AppState, name: &str) -> Option<Settings> {
+        let param = Settings::new(Some(name.to_string()), None, None);
+        let repo = state.settings_repo.lock().await;
+        repo.get(&state.config).select(param, PageRequest::default()).await.unwrap().1.into_iter().next()
     }
 }
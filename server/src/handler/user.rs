@@ -1,16 +1,22 @@
 use std::sync::Arc;
 
-use anyhow::{ Error, Ok };
+use anyhow::{ anyhow, Error, Ok };
 use axum::async_trait;
 use crate::context::state::AppState;
+use crate::store::{ is_unique_violation, select_all };
+use crate::types::audit::AuditLog;
+use crate::types::settings::{ Settings, SETTINGS_SCOPE_USER };
 use crate::types::user::{
     DeleteUserRequest,
     QueryUserRequest,
     SaveUserRequest,
     SaveUserRequestWith,
+    EraseUserResponse,
     User,
+    UserDataExport,
 };
-use crate::types::{ BaseBean, PageRequest, PageResponse };
+use crate::types::{ BaseBean, OperationOutcome, PageRequest, PageResponse };
+use crate::utils::{ auths, times };
 
 #[async_trait]
 pub trait IUserHandler: Send {
@@ -45,9 +51,17 @@ pub trait IUserHandler: Send {
         page: PageRequest
     ) -> Result<(PageResponse, Vec<User>), Error>;
 
-    async fn save(&self, param: SaveUserRequest) -> Result<i64, Error>;
+    async fn save(&self, param: SaveUserRequest) -> Result<OperationOutcome, Error>;
 
-    async fn delete(&self, param: DeleteUserRequest) -> Result<u64, Error>;
+    async fn delete(&self, param: DeleteUserRequest) -> Result<OperationOutcome, Error>;
+
+    // Export the user record (redacted) with all the settings and audit logs of the user, none if the
+    // user not found.
+    async fn export(&self, uid: i64) -> Result<Option<UserDataExport>, Error>;
+
+    // Erase the user (right to erasure), i.e. scrub the personal data and soft-delete the user, with the
+    // settings and audit logs deleted or anonymized by the configured strategy, all in a transaction.
+    async fn erase(&self, uid: i64) -> Result<EraseUserResponse, Error>;
 }
 
 pub struct UserHandler<'a> {
@@ -58,6 +72,30 @@ impl<'a> UserHandler<'a> {
     pub fn new(state: &'a AppState) -> Self {
         Self { state }
     }
+
+    // The user of same subject has been inserted concurrently (e.g. the first logins of provider
+    // callbacks), so read the existing and update it instead, to converge to the one user.
+    async fn save_after_conflict(
+        &self,
+        param: SaveUserRequest,
+        cause: Error
+    ) -> Result<OperationOutcome, Error> {
+        tracing::info!("Conflicted to insert user, retrying as update. cause: {}", cause);
+        let existing = self.get(
+            None,
+            None,
+            None,
+            None,
+            param.oidc_claims_sub.to_owned(),
+            param.github_claims_sub.to_owned(),
+            param.google_claims_sub.to_owned(),
+            param.ethers_address.to_owned()
+        ).await?;
+        match existing.and_then(|user| user.base.id) {
+            Some(id) => self.save(SaveUserRequest { id: Some(id), ..param }).await,
+            None => Err(cause),
+        }
+    }
 }
 
 #[async_trait]
@@ -88,6 +126,7 @@ impl<'a> IUserHandler for UserHandler<'a> {
             google_claims_sub,
             google_claims_name: None,
             google_claims_email: None,
+            verified_email: None,
             ethers_address,
             lang: None,
         };
@@ -148,6 +187,7 @@ impl<'a> IUserHandler for UserHandler<'a> {
                     google_claims_sub: param.google_claims_sub,
                     google_claims_name: param.google_claims_name,
                     google_claims_email: param.google_claims_email,
+                    verified_email: None,
                     ethers_address: param.ethers_address,
                     lang: param.lang,
                 };
@@ -155,9 +195,7 @@ impl<'a> IUserHandler for UserHandler<'a> {
                     save_param.id = user.base.id;
                 }
                 match self.save(save_param).await {
-                    std::result::Result::Ok(id) => {
-                        if id > 0 { Ok(()) } else { Err(anyhow::Error::msg("Failed to save user")) }
-                    }
+                    std::result::Result::Ok(_) => Ok(()),
                     Err(e) => Err(e),
                 }
             }
@@ -177,12 +215,13 @@ impl<'a> IUserHandler for UserHandler<'a> {
                     google_claims_sub: param.google_claims_sub,
                     google_claims_name: param.google_claims_name,
                     google_claims_email: param.google_claims_email,
+                    verified_email: None,
                     ethers_address: param.ethers_address,
                     lang: param.lang,
                 };
                 match self.save(save_param).await {
-                    std::result::Result::Ok(id) => {
-                        if id > 0 {
+                    std::result::Result::Ok(outcome) => {
+                        if outcome.id.is_some() {
                             Ok(())
                         } else {
                             Err(anyhow::Error::msg("Failed to save user, because no found user"))
@@ -205,17 +244,277 @@ impl<'a> IUserHandler for UserHandler<'a> {
     }
 
     //#[common_log_macro::biz_log("创建/更新了用户信息: id: {param.base.id}, name: {param.name}")]
-    async fn save(&self, param: SaveUserRequest) -> Result<i64, Error> {
+    async fn save(&self, param: SaveUserRequest) -> Result<OperationOutcome, Error> {
+        // Never store the plaintext password.
+        let param = match param.password.as_deref() {
+            Some(password) if !auths::is_password_hashed(password) => {
+                SaveUserRequest { password: Some(auths::hash_password(password)?), ..param }
+            }
+            _ => param,
+        };
+        let repo = self.state.user_repo.lock().await;
+        let config = &self.state.config;
+        match param.id {
+            Some(id) => {
+                if repo.get(config).update(param.to_user()).await? > 0 {
+                    return Ok(OperationOutcome::updated(id));
+                }
+                // Nothing is updated, because of either unchanged or not exists.
+                let mut exists = User::default();
+                exists.base.id = Some(id);
+                if repo.get(config).count_by(exists, &[]).await? > 0 {
+                    Ok(OperationOutcome::noop(Some(id)))
+                } else {
+                    Err(anyhow!("Not found user by id: {}", id))
+                }
+            }
+            None => {
+                let result = repo.get(config).insert(param.to_user()).await;
+                // Release the lock, the conflict retrying will acquire it again.
+                drop(repo);
+                match result {
+                    std::result::Result::Ok(id) if id > 0 => Ok(OperationOutcome::created(id)),
+                    std::result::Result::Ok(_) => Ok(OperationOutcome::noop(None)),
+                    Err(e) if is_unique_violation(&e) => self.save_after_conflict(param, e).await,
+                    Err(e) => Err(e),
+                }
+            }
+        }
+    }
+
+    async fn delete(&self, param: DeleteUserRequest) -> Result<OperationOutcome, Error> {
         let repo = self.state.user_repo.lock().await;
-        if param.id.is_some() {
-            repo.get(&self.state.config).update(param.to_user()).await
+        let affected = repo.get(&self.state.config).delete_by_id(param.id).await?;
+        if affected > 0 {
+            Ok(OperationOutcome::deleted(param.id, affected))
         } else {
-            repo.get(&self.state.config).insert(param.to_user()).await
+            Ok(OperationOutcome::noop(Some(param.id)))
         }
     }
 
-    async fn delete(&self, param: DeleteUserRequest) -> Result<u64, Error> {
+    async fn export(&self, uid: i64) -> Result<Option<UserDataExport>, Error> {
+        let config = &self.state.config;
+        let user = {
+            let repo = self.state.user_repo.lock().await;
+            match repo.get(config).select_by_id(uid).await? {
+                Some(user) => user.redacted(),
+                None => {
+                    return Ok(None);
+                }
+            }
+        };
+        let settings_param = Settings::new(None, Some(SETTINGS_SCOPE_USER.to_string()), Some(uid.to_string()));
+        let settings = select_all(&self.state.settings_repo, config, settings_param).await?;
+        let audit_log_param = AuditLog::new(Some(uid), None, None, None);
+        let audit_logs = select_all(&self.state.audit_log_repo, config, audit_log_param).await?;
+        Ok(Some(UserDataExport { user, settings, audit_logs, export_time: times::now_millis() }))
+    }
+
+    async fn erase(&self, uid: i64) -> Result<EraseUserResponse, Error> {
+        let strategy = self.state.config.server.erasure_strategy;
         let repo = self.state.user_repo.lock().await;
-        repo.get(&self.state.config).delete_by_id(param.id).await
+        let outcome = repo.get(&self.state.config).erase(uid, strategy).await?;
+        Ok(EraseUserResponse { uid, strategy, outcome })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::config_serve::WebServeProperties;
+    use crate::config::config_serve::ErasureStrategy;
+    use crate::context::state::tests::new_test_state;
+    use crate::handler::audit::{ AuditHandler, IAuditHandler };
+    use crate::types::audit::QueryAuditLogsRequest;
+    use crate::types::user::{ ErasureOutcome, ERASED_TOMBSTONE };
+    use crate::types::OperationAction;
+
+    fn new_save_request(id: Option<i64>, name: &str) -> SaveUserRequest {
+        SaveUserRequest {
+            id,
+            name: Some(name.to_string()),
+            email: None,
+            phone: None,
+            password: None,
+            oidc_claims_sub: None,
+            oidc_claims_name: None,
+            oidc_claims_email: None,
+            github_claims_sub: None,
+            github_claims_name: None,
+            github_claims_email: None,
+            google_claims_sub: None,
+            google_claims_name: None,
+            google_claims_email: None,
+            verified_email: None,
+            ethers_address: None,
+            lang: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_save_reports_created_updated_and_noop() {
+        let state = new_test_state(|_: &mut WebServeProperties| {}).await;
+        let handler = UserHandler::new(&state);
+
+        let created = handler.save(new_save_request(None, "alice")).await.unwrap();
+        assert_eq!(created.action, OperationAction::Created);
+        let id = created.id.unwrap();
+
+        let updated = handler.save(new_save_request(Some(id), "bob")).await.unwrap();
+        assert_eq!(updated, OperationOutcome::updated(id));
+
+        let noop = handler.save(new_save_request(Some(id), "bob")).await.unwrap();
+        assert_eq!(noop, OperationOutcome::noop(Some(id)));
+    }
+
+    #[tokio::test]
+    async fn test_save_not_found_and_delete_noop() {
+        let state = new_test_state(|_: &mut WebServeProperties| {}).await;
+        let handler = UserHandler::new(&state);
+
+        assert!(handler.save(new_save_request(Some(999_999), "nobody")).await.is_err());
+
+        let deleted = handler.delete(DeleteUserRequest { id: 999_999 }).await.unwrap();
+        assert_eq!(deleted.action, OperationAction::NoOp);
+        assert_eq!(deleted.affected, 0);
+    }
+
+    #[tokio::test]
+    async fn test_save_concurrent_insert_same_subject_converges() {
+        let state = new_test_state(|_: &mut WebServeProperties| {}).await;
+        let new_request = || {
+            let mut param = new_save_request(None, "racer");
+            param.github_claims_sub = Some("20001".to_string());
+            param
+        };
+
+        let (handler1, handler2) = (UserHandler::new(&state), UserHandler::new(&state));
+        let (first, second) = tokio::join!(handler1.save(new_request()), handler2.save(new_request()));
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert_eq!(first.id, second.id);
+        assert!(first.action == OperationAction::Created || second.action == OperationAction::Created);
+
+        let param = User { github_claims_sub: Some("20001".to_string()), ..User::default() };
+        let repo = state.user_repo.lock().await;
+        assert_eq!(repo.get(&state.config).count_by(param, &[]).await.unwrap(), 1);
+    }
+
+    // Insert the settings (named 'editor-<i>') and login audit logs of the user.
+    async fn seed_user_data(state: &AppState, uid: i64, settings_count: usize, audit_logs_count: usize) {
+        for i in 0..settings_count {
+            let mut settings = Settings::new(
+                Some(format!("editor-{}", i)),
+                Some(SETTINGS_SCOPE_USER.to_string()),
+                Some(uid.to_string())
+            );
+            settings.value = Some(r#"{"theme":"dark"}"#.to_string());
+            let repo = state.settings_repo.lock().await;
+            repo.get(&state.config).insert(settings).await.unwrap();
+        }
+        for _ in 0..audit_logs_count {
+            let audit_log = AuditLog::new(Some(uid), Some("login".to_string()), None, Some("10.0.0.1".to_string()));
+            let repo = state.audit_log_repo.lock().await;
+            repo.get(&state.config).insert(audit_log).await.unwrap();
+        }
+    }
+
+    async fn find_audit_logs(state: &AppState, uid: i64) -> Vec<AuditLog> {
+        let param = QueryAuditLogsRequest { uid: Some(uid), event_type: None, start_time: None, end_time: None };
+        AuditHandler::new(state).find(param, PageRequest::default()).await.unwrap().1
+    }
+
+    async fn count_user_data(state: &AppState, uid: i64) -> (i64, usize) {
+        let settings = Settings::new(None, Some(SETTINGS_SCOPE_USER.to_string()), Some(uid.to_string()));
+        let settings = state.settings_repo.lock().await.get(&state.config).count_by(settings, &[]).await.unwrap();
+        (settings, find_audit_logs(state, uid).await.len())
+    }
+
+    #[tokio::test]
+    async fn test_export_includes_settings_and_audit_logs_without_password() {
+        let state = new_test_state(|_: &mut WebServeProperties| {}).await;
+        let handler = UserHandler::new(&state);
+
+        let mut param = new_save_request(None, "alice");
+        param.password = Some("secret123".to_string());
+        let uid = handler.save(param).await.unwrap().id.unwrap();
+        let other = handler.save(new_save_request(None, "bob")).await.unwrap().id.unwrap();
+        let hashed = {
+            let repo = state.user_repo.lock().await;
+            repo.get(&state.config).select_by_id(uid).await.unwrap().unwrap().password.unwrap()
+        };
+
+        seed_user_data(&state, uid, 2, 3).await;
+        seed_user_data(&state, other, 1, 1).await;
+
+        let export = handler.export(uid).await.unwrap().unwrap();
+        assert_eq!(export.user.base.id, Some(uid));
+        assert_eq!(export.user.name.as_deref(), Some("alice"));
+        assert!(export.user.password.is_none());
+        assert_eq!(export.settings.len(), 2);
+        assert!(export.settings.iter().all(|s| s.owner == Some(uid.to_string())));
+        assert_eq!(export.audit_logs.len(), 3);
+        assert!(export.audit_logs.iter().all(|l| l.uid == Some(uid)));
+
+        let json = serde_json::to_string(&export).unwrap();
+        assert!(!json.contains(&hashed));
+        assert!(json.contains("editor-1"));
+
+        assert!(handler.export(999_999).await.unwrap().is_none());
+    }
+
+    async fn erase_with(strategy: ErasureStrategy) -> (AppState, i64, i64, EraseUserResponse) {
+        let state = new_test_state(|p: &mut WebServeProperties| {
+            p.server.erasure_strategy = strategy;
+        }).await;
+        let handler = UserHandler::new(&state);
+        let mut param = new_save_request(None, "alice");
+        param.email = Some("alice@example.com".to_string());
+        param.phone = Some("13800000000".to_string());
+        param.github_claims_sub = Some("30001".to_string());
+        let uid = handler.save(param).await.unwrap().id.unwrap();
+        let other = handler.save(new_save_request(None, "bob")).await.unwrap().id.unwrap();
+        seed_user_data(&state, uid, 2, 3).await;
+        seed_user_data(&state, other, 1, 1).await;
+
+        let erased = handler.erase(uid).await.unwrap();
+        (state, uid, other, erased)
+    }
+
+    async fn assert_scrubbed(state: &AppState, uid: i64) {
+        let repo = state.user_repo.lock().await;
+        // The erased user is soft-deleted, i.e. excluded from the queries but kept in the trash.
+        assert!(repo.get(&state.config).select_by_id(uid).await.unwrap().is_none());
+        let param = User { base: BaseBean::new(Some(uid), None, None), ..User::default() };
+        let (_, deleted) = repo.get(&state.config).select_deleted(param, PageRequest::default()).await.unwrap();
+        let user = deleted.into_iter().next().unwrap();
+        assert_eq!(user.name.as_deref(), Some(ERASED_TOMBSTONE));
+        assert!(user.email.is_none() && user.phone.is_none() && user.github_claims_sub.is_none());
+        assert_eq!(user.base.del_flag, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_erase_anonymize_scrubs_pii_and_keeps_audit_counts() {
+        let (state, uid, other, erased) = erase_with(ErasureStrategy::Anonymize).await;
+        assert_eq!(erased.outcome, ErasureOutcome { users: 1, settings: 2, audit_logs: 3 });
+        assert_scrubbed(&state, uid).await;
+
+        // The audit logs are kept (referenced to the tombstoned user), but the client IPs are scrubbed.
+        assert_eq!(count_user_data(&state, uid).await, (0, 3));
+        assert!(find_audit_logs(&state, uid).await.iter().all(|l| l.client_ip.is_none() && l.detail.is_none()));
+        // The other user is untouched.
+        assert_eq!(count_user_data(&state, other).await, (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_erase_delete_removes_settings_and_audit_logs() {
+        let (state, uid, other, erased) = erase_with(ErasureStrategy::Delete).await;
+        assert_eq!(erased.outcome, ErasureOutcome { users: 1, settings: 2, audit_logs: 3 });
+        assert_scrubbed(&state, uid).await;
+        assert_eq!(count_user_data(&state, uid).await, (0, 0));
+        assert_eq!(count_user_data(&state, other).await, (1, 1));
+
+        // The missing user is not found, and nothing is erased.
+        let err = UserHandler::new(&state).erase(999_999).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<crate::store::StoreError>(), Some(crate::store::StoreError::NotFound(..))));
     }
 }
@@ -20,14 +20,40 @@
  * This includes modifications and derived works.
  */
 
-use std::{ fmt::{ self, Display }, io::LineWriter, str::FromStr, sync::Arc };
+use std::{
+    collections::BTreeMap,
+    fmt::{ self, Display },
+    fs::{ self, File, OpenOptions },
+    io::{ self, BufWriter, LineWriter, Write },
+    path::{ Path, PathBuf },
+    str::FromStr,
+    sync::{ mpsc, Arc, Mutex },
+};
 
-use tracing::level_filters::LevelFilter;
-use tracing_subscriber::{ filter::Targets, EnvFilter, Layer };
+use axum::{ http::StatusCode, response::IntoResponse, Json };
+use flate2::{ write::GzEncoder, Compression };
+use once_cell::sync::Lazy;
+use opentelemetry::trace::{ SpanId, TraceContextExt, TraceId };
+use tracing::{ level_filters::LevelFilter, Event, Subscriber };
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::{
+    filter::Targets,
+    fmt::{
+        format::{ DefaultFields, Format, JsonFields, Writer },
+        writer::{ BoxMakeWriter, MakeWriterExt },
+        FmtContext,
+        FormatEvent,
+        FormatFields,
+        MakeWriter,
+    },
+    registry::LookupSpan,
+    EnvFilter,
+    Layer,
+};
 
 use serde::{ Deserialize, Serialize };
 
-use crate::config::config_serve::WebServeConfig;
+use crate::config::config_serve::{ AccessLogProperties, WebServeConfig };
 
 pub type LogRouteHandle = tracing_subscriber::reload::Handle<
     LogRouteType,
@@ -85,6 +111,45 @@ impl FromStr for LogMode {
     }
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogSink {
+    Stdout,
+    Stderr,
+    File,
+    // The file of error logs only.
+    ErrFile,
+    // The spans and events exported to the OpenTelemetry collector.
+    Otlp,
+}
+
+// The period of rotating the log files automatically.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    Daily,
+    // Rotated on demand only, see: handle_logs_rotate
+    #[default]
+    Never,
+}
+
+impl LogRotation {
+    /// The start of the next period after the time, none if never rotated automatically.
+    pub fn next_boundary(&self, now: chrono::DateTime<chrono::Local>) -> Option<chrono::DateTime<chrono::Local>> {
+        use chrono::Timelike;
+        let minute = now.with_second(0)?.with_nanosecond(0)?;
+        match self {
+            LogRotation::Minutely => Some(minute + chrono::Duration::minutes(1)),
+            LogRotation::Hourly => Some(minute.with_minute(0)? + chrono::Duration::hours(1)),
+            LogRotation::Daily =>
+                now.date_naive().succ_opt()?.and_hms_opt(0, 0, 0)?.and_local_timezone(chrono::Local).earliest(),
+            LogRotation::Never => None,
+        }
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 #[error("Unsupported log mode level `{0}`. Supported values are `HUMAN` and `JSON`.")]
 pub struct LogModeError(String);
@@ -151,21 +216,17 @@ pub(super) fn default_log_route_layer() -> LogRouteType {
     None.with_filter(tracing_subscriber::filter::Targets::new().with_target("", LevelFilter::OFF))
 }
 
+// The console layer of the stdout and/or stderr sinks, which is no-op if neither is active.
 pub(super) fn default_log_stderr_layer(config: &Arc<WebServeConfig>) -> LogStderrType {
-    let layer = tracing_subscriber::fmt
-        ::layer()
-        .with_writer(|| LineWriter::new(std::io::stderr()))
-        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE);
-
-    let layer = match config.logging.mode {
-        LogMode::Human =>
-            Box::new(layer) as Box<
-                dyn tracing_subscriber::Layer<SubscriberForSecondLayer> + Send + Sync
-            >,
-        LogMode::Json =>
-            Box::new(layer.json()) as Box<
-                dyn tracing_subscriber::Layer<SubscriberForSecondLayer> + Send + Sync
-            >,
+    let layer = match console_make_writer(&config.logging.sinks) {
+        Some(writer) => {
+            let layer = tracing_subscriber::fmt
+                ::layer()
+                .with_writer(writer)
+                .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE);
+            new_fmt_layer(layer, config)
+        }
+        None => Box::new(None::<tracing_subscriber::fmt::Layer<SubscriberForSecondLayer>>),
     };
 
     layer.with_filter(
@@ -175,6 +236,17 @@ pub(super) fn default_log_stderr_layer(config: &Arc<WebServeConfig>) -> LogStder
     )
 }
 
+fn console_make_writer(sinks: &[LogSink]) -> Option<BoxMakeWriter> {
+    let stdout = || LineWriter::new(io::stdout());
+    let stderr = || LineWriter::new(io::stderr());
+    match (sinks.contains(&LogSink::Stdout), sinks.contains(&LogSink::Stderr)) {
+        (true, true) => Some(BoxMakeWriter::new(stdout.and(stderr))),
+        (true, false) => Some(BoxMakeWriter::new(stdout)),
+        (false, true) => Some(BoxMakeWriter::new(stderr)),
+        (false, false) => None,
+    }
+}
+
 pub(super) fn default_log_levels_layer() -> EnvFilter {
     EnvFilter::try_from_default_env()
         .unwrap_or_else(|_| "debug".into())
@@ -183,3 +255,914 @@ pub(super) fn default_log_levels_layer() -> EnvFilter {
         .add_directive("hyper=warn".parse().unwrap())
         .add_directive("tokio=trace".parse().unwrap()) // Notice: Must be at trace level to collect
 }
+
+// ----- File log appender. -----
+
+// The global file writers, which are used by the management endpoint to flush and rotate.
+static LOG_FILE_WRITERS: Lazy<Mutex<Vec<RollingFileWriter>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// The buffered log file writer, which could be flushed and rotated on demand. The writes and the
+/// rotation are serialized by the lock, so that no in-flight message is lost across the rotation.
+#[derive(Clone)]
+pub struct RollingFileWriter {
+    inner: Arc<Mutex<RollingFileInner>>,
+}
+
+struct RollingFileInner {
+    writer: BufWriter<File>,
+    rotation: LogRotation,
+    // The time of the next automatic rotation, none if never.
+    next_rotation: Option<chrono::DateTime<chrono::Local>>,
+    archiver: RotatedFileArchiver,
+}
+
+// The single worker of archiving the rotated files of all the writers, so that the compressions and the prunes
+// never overlap, e.g. a prune never deletes the file being compressed.
+static LOG_ARCHIVE_WORKER: Lazy<Mutex<mpsc::Sender<ArchiveJob>>> = Lazy::new(|| {
+    let (sender, receiver) = mpsc::channel::<ArchiveJob>();
+    std::thread::Builder
+        ::new()
+        .name("log-archiver".to_string())
+        .spawn(move || {
+            for job in receiver {
+                let archived = job.archiver.archive(job.rotated);
+                if let Some(reply) = job.reply {
+                    let _ = reply.send(archived);
+                }
+            }
+        })
+        .expect("Failed to spawn the log archiver");
+    Mutex::new(sender)
+});
+
+struct ArchiveJob {
+    archiver: RotatedFileArchiver,
+    rotated: PathBuf,
+    // Replied with the archived file path if waiting for it.
+    reply: Option<mpsc::Sender<io::Result<PathBuf>>>,
+}
+
+// The compression and retention of the rotated files, which is done out of the writer lock, so that the writes
+// are not blocked by the (slow) compression.
+#[derive(Clone)]
+struct RotatedFileArchiver {
+    dir: PathBuf,
+    prefix: String,
+    // The max rotated files kept, none if unlimited.
+    max_files: Option<usize>,
+    // Whether to gzip the rotated file to '<prefix>.<timestamp>.log.gz'
+    compress: bool,
+}
+
+impl RollingFileWriter {
+    pub fn new(dir: &str, prefix: &str) -> io::Result<Self> {
+        Self::with_rotation(dir, prefix, LogRotation::Never)
+    }
+
+    pub fn with_rotation(dir: &str, prefix: &str, rotation: LogRotation) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let dir = PathBuf::from(dir);
+        let writer = Self::open(&dir, prefix)?;
+        let next_rotation = rotation.next_boundary(chrono::Local::now());
+        let archiver = RotatedFileArchiver { dir, prefix: prefix.to_string(), max_files: None, compress: false };
+        Ok(Self {
+            inner: Arc::new(Mutex::new(RollingFileInner { writer, rotation, next_rotation, archiver })),
+        })
+    }
+
+    /// Keep the max rotated files at most, the oldest are deleted on rotation.
+    pub fn with_max_files(self, max_files: Option<usize>) -> Self {
+        self.inner.lock().unwrap().archiver.max_files = max_files;
+        self
+    }
+
+    /// Gzip the rotated files, the current file is never compressed.
+    pub fn with_compress(self, compress: bool) -> Self {
+        self.inner.lock().unwrap().archiver.compress = compress;
+        self
+    }
+
+    fn open(dir: &Path, prefix: &str) -> io::Result<BufWriter<File>> {
+        let file = OpenOptions::new().create(true).append(true).open(dir.join(format!("{}.log", prefix)))?;
+        Ok(BufWriter::new(file))
+    }
+
+    pub fn current_path(&self) -> PathBuf {
+        let inner = self.inner.lock().unwrap();
+        inner.archiver.dir.join(format!("{}.log", inner.archiver.prefix))
+    }
+
+    /// Flush the buffered messages and rotate the current file, returns the rotated (or compressed) file path.
+    pub fn rotate(&self) -> io::Result<PathBuf> {
+        let (rotated, archiver) = {
+            let mut inner = self.inner.lock().unwrap();
+            (inner.rotate()?, inner.archiver.clone())
+        };
+        let (reply, archived) = mpsc::channel();
+        archiver.submit(rotated, Some(reply));
+        archived.recv().unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "The log archiver stopped")))
+    }
+}
+
+impl RollingFileInner {
+    fn rotate(&mut self) -> io::Result<PathBuf> {
+        self.writer.flush()?;
+
+        let (dir, prefix) = (&self.archiver.dir, &self.archiver.prefix);
+        let current = dir.join(format!("{}.log", prefix));
+        let timestamp = chrono::Local::now().format("%Y%m%d%H%M%S%3f");
+        let mut rotated = dir.join(format!("{}.{}.log", prefix, timestamp));
+        let mut seq = 1;
+        while rotated.exists() || rotated.with_extension("log.gz").exists() {
+            rotated = dir.join(format!("{}.{}-{}.log", prefix, timestamp, seq));
+            seq += 1;
+        }
+        fs::rename(&current, &rotated)?;
+        self.writer = RollingFileWriter::open(dir, prefix)?;
+        self.next_rotation = self.rotation.next_boundary(chrono::Local::now());
+        Ok(rotated)
+    }
+
+    // Rotate before writing if the period boundary has passed, the write goes on even if failed to rotate.
+    fn rotate_if_due(&mut self) {
+        let due = self.next_rotation.is_some_and(|next| chrono::Local::now() >= next);
+        if !due {
+            return;
+        }
+        match self.rotate() {
+            Ok(rotated) => self.archiver.submit(rotated, None),
+            Err(e) => {
+                eprintln!("Failed to rotate the log file of {}. {}", self.archiver.prefix, e);
+                self.next_rotation = self.rotation.next_boundary(chrono::Local::now());
+            }
+        }
+    }
+}
+
+impl RotatedFileArchiver {
+    // Archive by the single worker in the background, the rotated file is kept as is if the worker stopped.
+    fn submit(&self, rotated: PathBuf, reply: Option<mpsc::Sender<io::Result<PathBuf>>>) {
+        let job = ArchiveJob { archiver: self.clone(), rotated, reply };
+        if let Err(e) = LOG_ARCHIVE_WORKER.lock().unwrap().send(job) {
+            eprintln!("Failed to archive the rotated log file {:?}, the log archiver stopped.", e.0.rotated);
+        }
+    }
+
+    // Compress the rotated file if enabled and then prune the oldest, returns the archived file path, the
+    // rotated file is kept uncompressed if failed to compress.
+    fn archive(&self, rotated: PathBuf) -> io::Result<PathBuf> {
+        let archived = if self.compress {
+            match Self::compress(&rotated) {
+                Ok(compressed) => compressed,
+                Err(e) => {
+                    eprintln!("Failed to compress the rotated log file {:?}. {}", rotated, e);
+                    rotated
+                }
+            }
+        } else {
+            rotated
+        };
+        if let Err(e) = self.prune() {
+            eprintln!("Failed to prune the rotated log files of {}. {}", self.prefix, e);
+        }
+        Ok(archived)
+    }
+
+    // Compress to the temporary file and then rename, so that the partial '.gz' is never seen as archived.
+    fn compress(rotated: &Path) -> io::Result<PathBuf> {
+        let compressed = rotated.with_extension("log.gz");
+        let temporary = rotated.with_extension("log.gz.tmp");
+        let result = (|| {
+            let mut encoder = GzEncoder::new(File::create(&temporary)?, Compression::default());
+            io::copy(&mut File::open(rotated)?, &mut encoder)?;
+            encoder.finish()?.sync_all()?;
+            fs::rename(&temporary, &compressed)
+        })();
+        if let Err(e) = result {
+            let _ = fs::remove_file(&temporary);
+            return Err(e);
+        }
+        fs::remove_file(rotated)?;
+        Ok(compressed)
+    }
+
+    // Delete the oldest rotated (compressed or not) files beyond the max files, the current file is never deleted.
+    // The segment of both the uncompressed and compressed (e.g. interrupted before removing the uncompressed) is
+    // counted once, and the temporary files of compressing are skipped.
+    fn prune(&self) -> io::Result<Vec<PathBuf>> {
+        let max_files = match self.max_files {
+            Some(max_files) => max_files,
+            None => {
+                return Ok(Vec::new());
+            }
+        };
+        let mut rotated: BTreeMap<(String, u32), Vec<PathBuf>> = BTreeMap::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            if name.ends_with(".tmp") {
+                continue;
+            }
+            if let Some(key) = self.rotated_key(&name) {
+                rotated.entry(key).or_default().push(path);
+            }
+        }
+        // The newest first, i.e. ordered by the timestamp and then the sequence of the same timestamp.
+        let mut deleted = Vec::new();
+        for (_, paths) in rotated.into_iter().rev().skip(max_files) {
+            for path in paths {
+                fs::remove_file(&path)?;
+                deleted.push(path);
+            }
+        }
+        Ok(deleted)
+    }
+
+    // The (timestamp, sequence) of the rotated file name '<prefix>.<timestamp>[-<seq>].log[.gz]', none if not the
+    // rotated file of this prefix, e.g. the current file or the files of the other prefixes ('<prefix>.err').
+    fn rotated_key(&self, name: &str) -> Option<(String, u32)> {
+        let name = name.strip_suffix(".gz").unwrap_or(name);
+        let rest = name.strip_prefix(&self.prefix)?.strip_prefix('.')?.strip_suffix(".log")?;
+        let (timestamp, seq) = match rest.split_once('-') {
+            Some((timestamp, seq)) => (timestamp, seq.parse().ok()?),
+            None => (rest, 0),
+        };
+        if timestamp.is_empty() || !timestamp.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        Some((timestamp.to_string(), seq))
+    }
+}
+
+impl Write for RollingFileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.rotate_if_due();
+        inner.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.lock().unwrap().writer.flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for RollingFileWriter {
+    type Writer = RollingFileWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+// The layers of the file and err_file sinks, none if neither is active.
+pub(super) fn default_log_file_layer<S>(
+    config: &Arc<WebServeConfig>
+) -> Option<Box<dyn Layer<S> + Send + Sync>>
+    where S: Subscriber + for<'a> LookupSpan<'a> + 'static
+{
+    let sinks = &config.logging.sinks;
+    let prefix = config.logging.file.prefix.as_deref().unwrap_or("mywebnote");
+    let mut layers = Vec::new();
+    if sinks.contains(&LogSink::File) {
+        let level = LevelFilter::from_str(&config.logging.level.to_string()).unwrap();
+        layers.extend(new_file_layer(config, prefix.to_string(), level, true));
+    }
+    if sinks.contains(&LogSink::ErrFile) {
+        layers.extend(new_file_layer(config, format!("{}.err", prefix), LevelFilter::ERROR, false));
+    }
+    if layers.is_empty() { None } else { Some(layers.boxed()) }
+}
+
+// The layer of the file sink with the level, which could be reloaded at runtime if reloadable, see:
+// set_global_log_level
+fn new_file_layer<S>(
+    config: &Arc<WebServeConfig>,
+    prefix: String,
+    level: LevelFilter,
+    reloadable: bool
+) -> Option<Box<dyn Layer<S> + Send + Sync>>
+    where S: Subscriber + for<'a> LookupSpan<'a> + 'static
+{
+    let dir = config.logging.file.dir.as_deref().unwrap_or("/tmp/mywebnote/log");
+    let rotation = config.logging.file.rotation.unwrap_or_default();
+    let writer = match RollingFileWriter::with_rotation(dir, &prefix, rotation) {
+        Ok(writer) =>
+            writer
+                .with_max_files(config.logging.file.max_files)
+                .with_compress(config.logging.file.compress_rotated.unwrap_or(false)),
+        Err(e) => {
+            eprintln!("Failed to create log file writer of {}, disabled it. {}", prefix, e);
+            return None;
+        }
+    };
+    LOG_FILE_WRITERS.lock().unwrap().push(writer.clone());
+
+    // Flush periodically until shutdown, because of the buffered writes.
+    spawn_periodic_flush(writer.clone(), LOG_FLUSH_STOP.subscribe());
+
+    let layer = new_fmt_layer(tracing_subscriber::fmt::layer().with_writer(writer).with_ansi(false), config);
+    let targets = Targets::new().with_target("", level);
+    if !reloadable {
+        return Some(layer.with_filter(targets).boxed());
+    }
+    let (targets, handle) = tracing_subscriber::reload::Layer::new(targets);
+    register_log_level_reloader(Box::new(move |targets| handle.reload(targets.clone())));
+    Some(layer.with_filter(targets).boxed())
+}
+
+// The signal of stopping the periodic flushes of the log files, i.e. on shutdown.
+static LOG_FLUSH_STOP: Lazy<tokio::sync::watch::Sender<bool>> = Lazy::new(|| tokio::sync::watch::channel(false).0);
+
+fn spawn_periodic_flush(
+    mut writer: RollingFileWriter,
+    mut stop: tokio::sync::watch::Receiver<bool>
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let _ = writer.flush();
+                }
+                _ = stop.wait_for(|stopped| *stopped) => {
+                    let _ = writer.flush();
+                    break;
+                }
+            }
+        }
+    })
+}
+
+/// Stop the periodic flushes of the log files, and flush the remaining buffered logs on shutdown.
+pub fn shutdown_log_files() {
+    LOG_FLUSH_STOP.send_replace(true);
+    for mut writer in LOG_FILE_WRITERS.lock().unwrap().clone() {
+        let _ = writer.flush();
+    }
+}
+
+// ----- Log level reloading. -----
+
+type LogLevelReloader = Box<dyn Fn(&Targets) -> Result<(), tracing_subscriber::reload::Error> + Send + Sync>;
+
+// The reloaders of the level filters of the stderr and file sinks, the err_file sink is always error level.
+static LOG_LEVEL_RELOADERS: Lazy<Mutex<Vec<LogLevelReloader>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+fn register_log_level_reloader(reloader: LogLevelReloader) {
+    LOG_LEVEL_RELOADERS.lock().unwrap().push(reloader);
+}
+
+// Register the reload handle of the stderr (console) layer, so that its level could be swapped at runtime.
+pub(super) fn register_log_stderr_handle(handle: LogStderrHandle) {
+    register_log_level_reloader(
+        Box::new(move |targets| handle.modify(|layer| *layer.filter_mut() = targets.clone()))
+    );
+}
+
+/// Swap the level filters of the stderr and file sinks at runtime without restarting, e.g. 'debug' or
+/// 'info,mywebnote=debug'. Notice: The events are still bounded by the global 'RUST_LOG' env filter.
+pub fn set_global_log_level(new_targets: &str) -> anyhow::Result<()> {
+    let targets = Targets::from_str(new_targets).map_err(|e|
+        anyhow::anyhow!("Invalid log level targets '{}'. {}", new_targets, e)
+    )?;
+    let mut reloaders = LOG_LEVEL_RELOADERS.lock().unwrap();
+    let mut error = None;
+    // The reloaders of the dropped subscribers are removed.
+    reloaders.retain(|reload| {
+        match reload(&targets) {
+            Ok(()) => true,
+            Err(e) if e.is_dropped() => false,
+            Err(e) => {
+                error.get_or_insert(e);
+                true
+            }
+        }
+    });
+    match error {
+        Some(e) => Err(anyhow::anyhow!("Failed to reload the log level to '{}'. {}", new_targets, e)),
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogLevelRequest {
+    // The level targets, e.g. 'debug' or 'info,mywebnote=debug'
+    pub targets: String,
+}
+
+pub async fn handle_logs_level(Json(request): Json<LogLevelRequest>) -> impl IntoResponse {
+    match set_global_log_level(&request.targets) {
+        Ok(()) => {
+            tracing::info!("Reloaded the log level to '{}'", request.targets);
+            (StatusCode::OK, Json(serde_json::json!({ "targets": request.targets }))).into_response()
+        }
+        Err(e) => {
+            tracing::warn!("Failed to reload the log level. {}", e);
+            (StatusCode::BAD_REQUEST, e.to_string()).into_response()
+        }
+    }
+}
+
+// ----- Trace correlation. -----
+
+// Apply the event format of the configured log mode, with the trace correlation if enabled, the json lines
+// are flattened, i.e. the 'timestamp', 'level', 'target' and the event fields (e.g. 'message') are top level,
+// so that they could be ingested (e.g. Loki, Elasticsearch) without the nested parsing.
+fn new_fmt_layer<S, W>(
+    layer: tracing_subscriber::fmt::Layer<S, DefaultFields, Format, W>,
+    config: &Arc<WebServeConfig>
+) -> Box<dyn Layer<S> + Send + Sync>
+    where S: Subscriber + for<'a> LookupSpan<'a>, W: for<'w> MakeWriter<'w> + Send + Sync + 'static
+{
+    let enabled = config.logging.trace_correlation.unwrap_or(true);
+    match config.logging.mode {
+        LogMode::Human => layer.event_format(TraceCorrelatedFormat::new(Format::default(), enabled, false)).boxed(),
+        LogMode::Json =>
+            layer
+                .fmt_fields(JsonFields::new())
+                .event_format(TraceCorrelatedFormat::new(Format::default().json().flatten_event(true), enabled, true))
+                .boxed(),
+    }
+}
+
+/// The event formatter which includes the 'trace_id' and 'span_id' of the current span in the log
+/// lines, pulling from the OpenTelemetry context. It's a no-op if there's no OpenTelemetry layer
+/// (e.g. the otlp is disabled), since the spans have no OpenTelemetry data then.
+pub struct TraceCorrelatedFormat<F> {
+    inner: F,
+    enabled: bool,
+    json: bool,
+}
+
+impl<F> TraceCorrelatedFormat<F> {
+    pub fn new(inner: F, enabled: bool, json: bool) -> Self {
+        Self { inner, enabled, json }
+    }
+
+    fn current_ids<S, N>(&self, ctx: &FmtContext<'_, S, N>) -> Option<(TraceId, SpanId)>
+        where S: Subscriber + for<'a> LookupSpan<'a>, N: for<'w> FormatFields<'w> + 'static
+    {
+        if !self.enabled {
+            return None;
+        }
+        let span = ctx.event_scope()?.next()?;
+        let extensions = span.extensions();
+        let otel = extensions.get::<OtelData>()?;
+        // Only the root span has the trace id in the builder, otherwise inherits from the parent.
+        let trace_id = otel.builder.trace_id.unwrap_or_else(||
+            otel.parent_cx.span().span_context().trace_id()
+        );
+        Some((trace_id, otel.builder.span_id?))
+    }
+}
+
+impl<S, N, F> FormatEvent<S, N> for TraceCorrelatedFormat<F>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+        N: for<'w> FormatFields<'w> + 'static,
+        F: FormatEvent<S, N>
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let (trace_id, span_id) = match self.current_ids(ctx) {
+            Some(ids) => ids,
+            None => {
+                return self.inner.format_event(ctx, writer, event);
+            }
+        };
+        if !self.json {
+            write!(writer, "trace_id={} span_id={} ", trace_id, span_id)?;
+            return self.inner.format_event(ctx, writer, event);
+        }
+        // Insert the ids as the leading fields of the json object.
+        let mut buf = String::new();
+        self.inner.format_event(ctx, Writer::new(&mut buf), event)?;
+        match buf.strip_prefix('{') {
+            Some(rest) => write!(writer, "{{\"trace_id\":\"{}\",\"span_id\":\"{}\",{}", trace_id, span_id, rest),
+            None => writer.write_str(&buf),
+        }
+    }
+}
+
+/// Flush and rotate the log files, returns the rotated file paths or empty if no file sink is active.
+pub fn flush_and_rotate_logs() -> io::Result<Vec<PathBuf>> {
+    let writers = LOG_FILE_WRITERS.lock().unwrap().clone();
+    writers
+        .iter()
+        .map(|writer| writer.rotate())
+        .collect()
+}
+
+pub async fn handle_logs_rotate() -> impl IntoResponse {
+    // The rotation waits for the archived files, so it's run on the blocking pool.
+    let result = tokio::task::spawn_blocking(flush_and_rotate_logs).await
+        .unwrap_or_else(|e| Err(io::Error::new(io::ErrorKind::Other, e)));
+    match result {
+        Ok(rotated) if rotated.is_empty() => {
+            (StatusCode::NOT_FOUND, "No file sink of logging is active").into_response()
+        }
+        Ok(rotated) => {
+            tracing::info!("Rotated the log files to {:?}", rotated);
+            (StatusCode::OK, Json(serde_json::json!({ "rotated": rotated }))).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Failed to rotate the log file. {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+/// Samples with the probability in [0, 1], e.g. 0.01 is about 1%.
+pub fn sample_based_on_probability(probability: f64) -> bool {
+    probability >= 1.0 || (probability > 0.0 && rand::random::<f64>() < probability)
+}
+
+/// Whether to log the access of the request with the response status.
+pub fn should_log_access(access_log: &AccessLogProperties, status: StatusCode) -> bool {
+    if !access_log.enabled.unwrap_or(true) {
+        return false;
+    }
+    let is_error = status.is_client_error() || status.is_server_error();
+    if is_error && access_log.always_log_errors.unwrap_or(true) {
+        return true;
+    }
+    sample_based_on_probability(access_log.sample_ratio.unwrap_or(1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn new_access_log(sample_ratio: f64, always_log_errors: bool) -> AccessLogProperties {
+        AccessLogProperties {
+            enabled: Some(true),
+            sample_ratio: Some(sample_ratio),
+            always_log_errors: Some(always_log_errors),
+        }
+    }
+
+    #[test]
+    fn test_should_log_access_always_logs_errors() {
+        let access_log = new_access_log(0.0, true);
+        for _ in 0..100 {
+            assert!(should_log_access(&access_log, StatusCode::INTERNAL_SERVER_ERROR));
+            assert!(should_log_access(&access_log, StatusCode::NOT_FOUND));
+            assert!(!should_log_access(&access_log, StatusCode::OK));
+        }
+        assert!(!should_log_access(&new_access_log(0.0, false), StatusCode::BAD_GATEWAY));
+    }
+
+    #[test]
+    fn test_should_log_access_samples_success_at_ratio() {
+        let access_log = new_access_log(0.2, true);
+        let total = 20_000;
+        let logged = (0..total)
+            .filter(|_| should_log_access(&access_log, StatusCode::OK))
+            .count();
+        let ratio = (logged as f64) / (total as f64);
+        assert!((0.18..0.22).contains(&ratio), "unexpected sampled ratio: {}", ratio);
+
+        assert!(should_log_access(&new_access_log(1.0, false), StatusCode::OK));
+    }
+
+    #[test]
+    fn test_should_log_access_disabled() {
+        let mut access_log = new_access_log(1.0, true);
+        access_log.enabled = Some(false);
+        assert!(!should_log_access(&access_log, StatusCode::OK));
+        assert!(!should_log_access(&access_log, StatusCode::INTERNAL_SERVER_ERROR));
+    }
+
+    #[test]
+    fn test_rotate_writes_buffered_messages_to_disk() {
+        let dir = std::env::temp_dir().join(format!("mywebnote_log_{}", uuid::Uuid::new_v4()));
+        let writer = RollingFileWriter::new(dir.to_str().unwrap(), "test").unwrap();
+        let subscriber = tracing_subscriber
+            ::registry()
+            .with(tracing_subscriber::fmt::layer().with_writer(writer.clone()).with_ansi(false));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("before rotation");
+        });
+        // Still in the buffer.
+        assert_eq!(fs::read_to_string(writer.current_path()).unwrap(), "");
+
+        let rotated = writer.rotate().unwrap();
+        assert!(fs::read_to_string(&rotated).unwrap().contains("before rotation"));
+        assert_eq!(fs::read_to_string(writer.current_path()).unwrap(), "");
+
+        // The later messages are written to the new file.
+        let subscriber = tracing_subscriber
+            ::registry()
+            .with(tracing_subscriber::fmt::layer().with_writer(writer.clone()).with_ansi(false));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("after rotation");
+        });
+        let rotated2 = writer.rotate().unwrap();
+        assert_ne!(rotated, rotated2);
+        assert!(fs::read_to_string(&rotated2).unwrap().contains("after rotation"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_periodic_flush_stops_on_shutdown() {
+        let dir = std::env::temp_dir().join(format!("mywebnote_log_{}", uuid::Uuid::new_v4()));
+        let mut writer = RollingFileWriter::new(dir.to_str().unwrap(), "test").unwrap();
+        let (stop_sender, stop) = tokio::sync::watch::channel(false);
+        let flushing = spawn_periodic_flush(writer.clone(), stop);
+
+        writer.write_all(b"before shutdown\n").unwrap();
+        stop_sender.send_replace(true);
+        tokio::time::timeout(std::time::Duration::from_secs(5), flushing).await.unwrap().unwrap();
+        // The remaining buffered logs are flushed on stopped.
+        assert_eq!(fs::read_to_string(writer.current_path()).unwrap(), "before shutdown\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotation_next_boundary() {
+        use chrono::TimeZone;
+        let now = chrono::Local.with_ymd_and_hms(2024, 12, 31, 23, 45, 30).unwrap();
+        let at = |h, m| chrono::Local.with_ymd_and_hms(2024, 12, 31, h, m, 0).unwrap();
+        assert_eq!(LogRotation::Minutely.next_boundary(now), Some(at(23, 46)));
+        assert_eq!(LogRotation::Hourly.next_boundary(now), Some(at(23, 0) + chrono::Duration::hours(1)));
+        let tomorrow = chrono::Local.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).single();
+        assert_eq!(LogRotation::Daily.next_boundary(now), tomorrow);
+        assert_eq!(LogRotation::Never.next_boundary(now), None);
+
+        let rotation: LogRotation = serde_json::from_str(r#""daily""#).unwrap();
+        assert_eq!(rotation, LogRotation::Daily);
+        assert_eq!(LogRotation::default(), LogRotation::Never);
+    }
+
+    #[test]
+    fn test_rotate_automatically_on_period_boundary() {
+        for rotation in [LogRotation::Minutely, LogRotation::Hourly, LogRotation::Daily, LogRotation::Never] {
+            let dir = std::env::temp_dir().join(format!("mywebnote_log_{}", uuid::Uuid::new_v4()));
+            let mut writer = RollingFileWriter::with_rotation(dir.to_str().unwrap(), "test", rotation).unwrap();
+            writer.write_all(b"first period\n").unwrap();
+            assert_eq!(fs::read_dir(&dir).unwrap().count(), 1, "{:?}", rotation);
+
+            // Passed the period boundary.
+            let passed = writer.inner.lock().unwrap().next_rotation.map(|_| chrono::Local::now());
+            writer.inner.lock().unwrap().next_rotation = passed;
+            writer.write_all(b"second period\n").unwrap();
+            writer.flush().unwrap();
+
+            let current = fs::read_to_string(writer.current_path()).unwrap();
+            if rotation == LogRotation::Never {
+                assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+                assert_eq!(current, "first period\nsecond period\n");
+            } else {
+                assert_eq!(fs::read_dir(&dir).unwrap().count(), 2, "{:?}", rotation);
+                assert_eq!(current, "second period\n");
+                assert!(writer.inner.lock().unwrap().next_rotation.unwrap() > chrono::Local::now());
+            }
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_rotate_prunes_oldest_files_beyond_max_files() {
+        let dir = std::env::temp_dir().join(format!("mywebnote_log_{}", uuid::Uuid::new_v4()));
+        let writer = RollingFileWriter::new(dir.to_str().unwrap(), "app").unwrap().with_max_files(Some(3));
+        let others = ["app.err.20200101000000000.log", "app.err.log", "application.20200101000000000.log"];
+        let olds = ["app.20200101000000000.log", "app.20200101000000000-1.log", "app.20200102000000000.log"];
+        for name in others.iter().chain(olds.iter()) {
+            fs::write(dir.join(name), name).unwrap();
+        }
+
+        let rotated = writer.rotate().unwrap();
+        let mut names = fs
+            ::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with("app.2"))
+            .collect::<Vec<_>>();
+        names.sort();
+        let rotated = rotated.file_name().unwrap().to_string_lossy().to_string();
+        assert_eq!(names, vec!["app.20200101000000000-1.log", "app.20200102000000000.log", &rotated]);
+        // Neither the current file nor the files of the other prefixes are deleted.
+        assert!(writer.current_path().exists());
+        assert!(others.iter().all(|name| dir.join(name).exists()));
+
+        let rotated = writer.rotate().unwrap();
+        assert!(!dir.join("app.20200101000000000-1.log").exists());
+        assert!(rotated.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_prune_skips_compressing_and_counts_segment_once() {
+        let dir = std::env::temp_dir().join(format!("mywebnote_log_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let archiver = RotatedFileArchiver { dir: dir.clone(), prefix: "app".to_string(), max_files: Some(2), compress: true };
+        let names = [
+            "app.20200101000000000.log",
+            "app.20200102000000000.log",
+            "app.20200102000000000.log.gz",
+            "app.20200103000000000.log",
+            "app.20200103000000000.log.gz.tmp",
+        ];
+        for name in names {
+            fs::write(dir.join(name), name).unwrap();
+        }
+
+        let mut deleted = archiver
+            .prune()
+            .unwrap()
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        deleted.sort();
+        assert_eq!(deleted, vec!["app.20200101000000000.log"]);
+        assert!(names[1..].iter().all(|name| dir.join(name).exists()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_compresses_previous_segment() {
+        use std::io::Read;
+        let dir = std::env::temp_dir().join(format!("mywebnote_log_{}", uuid::Uuid::new_v4()));
+        let mut writer = RollingFileWriter::new(dir.to_str().unwrap(), "app")
+            .unwrap()
+            .with_compress(true)
+            .with_max_files(Some(2));
+        for i in 0..3 {
+            writer.write_all(format!("segment {}\n", i).as_bytes()).unwrap();
+            let compressed = writer.rotate().unwrap();
+            assert!(compressed.to_string_lossy().ends_with(".log.gz"), "{:?}", compressed);
+            assert!(!compressed.with_extension("").exists(), "the uncompressed is removed");
+            assert!(!compressed.with_extension("gz.tmp").exists(), "the temporary is renamed");
+
+            let mut content = String::new();
+            flate2::read::GzDecoder::new(File::open(&compressed).unwrap()).read_to_string(&mut content).unwrap();
+            assert_eq!(content, format!("segment {}\n", i));
+        }
+        // The active file is never compressed, and the compressed are counted by the retention.
+        writer.write_all(b"active\n").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(fs::read_to_string(writer.current_path()).unwrap(), "active\n");
+        let compressed = fs
+            ::read_dir(&dir)
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().ends_with(".gz"))
+            .count();
+        assert_eq!(compressed, 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn new_sinks_config(sinks: Vec<LogSink>, dir: &std::path::Path) -> Arc<WebServeConfig> {
+        let mut properties = crate::config::config_serve::WebServeProperties::default();
+        properties.logging.sinks = sinks;
+        properties.logging.file.dir = Some(dir.to_string_lossy().to_string());
+        properties.to_config()
+    }
+
+    #[tokio::test]
+    async fn test_log_sinks_stdout_only() {
+        let dir = std::env::temp_dir().join(format!("mywebnote_log_{}", uuid::Uuid::new_v4()));
+        let config = new_sinks_config(vec![LogSink::Stdout], &dir);
+        assert!(console_make_writer(&config.logging.sinks).is_some());
+        assert!(default_log_file_layer::<tracing_subscriber::Registry>(&config).is_none());
+        assert!(!dir.exists());
+    }
+
+    #[tokio::test]
+    async fn test_log_sinks_file_only() {
+        let dir = std::env::temp_dir().join(format!("mywebnote_log_{}", uuid::Uuid::new_v4()));
+        let config = new_sinks_config(vec![LogSink::File, LogSink::ErrFile], &dir);
+        assert!(console_make_writer(&config.logging.sinks).is_none());
+        assert!(default_log_file_layer::<tracing_subscriber::Registry>(&config).is_some());
+        assert!(dir.join("mywebnote.log").exists());
+        assert!(dir.join("mywebnote.err.log").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_log_file_json_mode() {
+        let dir = std::env::temp_dir().join(format!("mywebnote_log_{}", uuid::Uuid::new_v4()));
+        let mut properties = crate::config::config_serve::WebServeProperties::default();
+        properties.logging.mode = LogMode::Json;
+        properties.logging.sinks = vec![LogSink::File, LogSink::ErrFile];
+        properties.logging.file.dir = Some(dir.to_string_lossy().to_string());
+        properties.logging.file.prefix = Some("json".to_string());
+        let config = properties.to_config();
+
+        let subscriber = tracing_subscriber::registry().with(default_log_file_layer(&config));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(user_id = 1001, "info in json");
+            tracing::error!("error in json");
+        });
+        let read_lines = |name: &str| -> Vec<serde_json::Value> {
+            let writer = LOG_FILE_WRITERS.lock().unwrap().iter().find(|w| w.current_path() == dir.join(name)).cloned();
+            let rotated = writer.unwrap().rotate().unwrap();
+            fs::read_to_string(rotated).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+        };
+
+        let lines = read_lines("json.log");
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(lines[0]["message"], "info in json");
+        assert_eq!(lines[0]["user_id"], 1001);
+        assert!(lines[0]["timestamp"].is_string());
+        // The error file follows the same format.
+        let lines = read_lines("json.err.log");
+        assert_eq!(lines.len(), 1);
+        assert_eq!((lines[0]["level"].as_str(), lines[0]["message"].as_str()), (Some("ERROR"), Some("error in json")));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_set_global_log_level_at_runtime() {
+        let dir = std::env::temp_dir().join(format!("mywebnote_log_{}", uuid::Uuid::new_v4()));
+        let mut properties = crate::config::config_serve::WebServeProperties::default();
+        properties.logging.mode = LogMode::Human;
+        properties.logging.level = "info".to_string();
+        properties.logging.sinks = vec![LogSink::File];
+        properties.logging.file.dir = Some(dir.to_string_lossy().to_string());
+        let config = properties.to_config();
+
+        let subscriber = tracing_subscriber::registry().with(default_log_file_layer(&config));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug!("debug before reloaded");
+            tracing::info!("info before reloaded");
+            set_global_log_level("debug").unwrap();
+            tracing::debug!("debug after reloaded");
+        });
+        let writer = LOG_FILE_WRITERS.lock().unwrap().iter().find(|w| w.current_path().starts_with(&dir)).cloned();
+        let logs = fs::read_to_string(writer.unwrap().rotate().unwrap()).unwrap();
+        assert!(!logs.contains("debug before reloaded"), "{}", logs);
+        assert!(logs.contains("info before reloaded"), "{}", logs);
+        assert!(logs.contains("debug after reloaded"), "{}", logs);
+
+        assert!(set_global_log_level("mywebnote=not-a-level").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_log_sinks_at_least_one() {
+        let mut properties = crate::config::config_serve::WebServeProperties::default();
+        properties.logging.sinks = vec![];
+        assert!(properties.validate().is_err());
+
+        let sinks: Vec<LogSink> = serde_json::from_str(r#"["stdout","err_file","otlp"]"#).unwrap();
+        assert_eq!(sinks, vec![LogSink::Stdout, LogSink::ErrFile, LogSink::Otlp]);
+    }
+
+    // Log an event inside and outside of a span, and returns the captured logs with the trace id.
+    fn capture_correlated_logs(mode: LogMode, with_otel: bool) -> (String, String) {
+        use opentelemetry::trace::TracerProvider as _;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let mut properties = crate::config::config_serve::WebServeProperties::default();
+        properties.logging.mode = mode;
+        let config = properties.to_config();
+
+        let logs = crate::mgmt::apm::panic::tests::CapturedLogs::default();
+        let writer = logs.clone();
+        let layer = tracing_subscriber::fmt::layer().with_writer(move || writer.clone()).with_ansi(false);
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let otel = with_otel.then(|| tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let subscriber = tracing_subscriber::registry().with(otel).with(new_fmt_layer(layer, &config));
+
+        let trace_id = tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("correlated");
+            let trace_id = span.context().span().span_context().trace_id().to_string();
+            span.in_scope(|| tracing::info!("inside the span"));
+            tracing::info!("outside the span");
+            trace_id
+        });
+        (logs.contents(), trace_id)
+    }
+
+    #[test]
+    fn test_trace_correlation_human() {
+        let (logs, trace_id) = capture_correlated_logs(LogMode::Human, true);
+        let lines: Vec<&str> = logs.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with(&format!("trace_id={} span_id=", trace_id)), "{}", lines[0]);
+        assert!(lines[0].contains("inside the span"));
+        assert!(!lines[1].contains("trace_id="), "{}", lines[1]);
+    }
+
+    #[test]
+    fn test_trace_correlation_json() {
+        let (logs, trace_id) = capture_correlated_logs(LogMode::Json, true);
+        let lines: Vec<serde_json::Value> = logs
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["trace_id"], trace_id);
+        assert_eq!(lines[0]["span_id"].as_str().unwrap().len(), 16);
+        assert_eq!(lines[0]["message"], "inside the span");
+        assert!(lines[1].get("trace_id").is_none());
+    }
+
+    #[test]
+    fn test_trace_correlation_noop_without_otel() {
+        let (logs, _) = capture_correlated_logs(LogMode::Human, false);
+        assert_eq!(logs.lines().count(), 2);
+        assert!(!logs.contains("trace_id="), "{}", logs);
+    }
+}
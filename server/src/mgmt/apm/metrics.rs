@@ -20,12 +20,25 @@
  * This includes modifications and derived works.
  */
 
-use std::sync::Arc;
+use std::sync::{ Arc, Once };
+use std::time::Instant;
 
+use axum::{ http::header::CONTENT_TYPE, response::IntoResponse };
 use lazy_static::lazy_static;
-use prometheus::{ Registry, Counter, Histogram, Encoder, TextEncoder };
+use prometheus::{
+    Registry,
+    Counter,
+    Gauge,
+    IntCounter,
+    IntCounterVec,
+    IntGaugeVec,
+    Histogram,
+    Encoder,
+    Opts,
+    TextEncoder,
+};
 
-use crate::config::config_serve::WebServeConfig;
+use crate::config::config_serve::{ WebServeConfig, GIT_BUILD_DATE, GIT_COMMIT_HASH, GIT_VERSION };
 
 lazy_static! {
     pub static ref REGISTRY: Registry = Registry::new();
@@ -41,27 +54,129 @@ lazy_static! {
             "My HTTP request duration in seconds"
         )
     ).expect("My metric can be created");
+
+    pub static ref PANIC_COUNTER: IntCounter = IntCounter::new(
+        "panics_total",
+        "Total number of panics"
+    ).expect("My metric can be created");
+
+    // The cache metrics labeled by the key prefix, e.g. 'auth:nonce' and 'logout:blacklist'.
+    pub static ref CACHE_HITS_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new("cache_hits_total", "Total number of cache hits"),
+        &["prefix"]
+    ).expect("My metric can be created");
+
+    pub static ref CACHE_MISSES_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new("cache_misses_total", "Total number of cache misses"),
+        &["prefix"]
+    ).expect("My metric can be created");
+
+    pub static ref CACHE_SETS_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new("cache_sets_total", "Total number of cache sets"),
+        &["prefix"]
+    ).expect("My metric can be created");
+
+    // Notice: Only the memory cache reports the expirations, the redis expires silently.
+    pub static ref CACHE_EXPIRATIONS_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new("cache_expirations_total", "Total number of cache expirations"),
+        &["prefix"]
+    ).expect("My metric can be created");
+    // The baseline metrics of the process.
+    pub static ref PROCESS_START: Instant = Instant::now();
+
+    pub static ref PROCESS_UPTIME_SECONDS: Gauge = Gauge::new(
+        "process_uptime_seconds",
+        "The seconds since the process started"
+    ).expect("My metric can be created");
+
+    // The constant 1 labeled by the build info, e.g. to tell the versions of instances in rolling upgrade.
+    pub static ref BUILD_INFO: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("build_info", "The build info of version, commit and date"),
+        &["version", "commit", "build_date"]
+    ).expect("My metric can be created");
     // Register more metrics...
 }
 
-#[allow(unused)]
-pub async fn handle_metrics() -> String {
+static REGISTER_METRICS: Once = Once::new();
+
+/// Render the metrics of the registry in the Prometheus text format.
+pub fn gather() -> String {
+    PROCESS_UPTIME_SECONDS.set(PROCESS_START.elapsed().as_secs_f64());
     let encoder = TextEncoder::new();
     let mut buffer = Vec::new();
     encoder.encode(&REGISTRY.gather(), &mut buffer).unwrap();
     String::from_utf8(buffer).unwrap()
 }
 
+#[allow(unused)]
+pub async fn handle_metrics() -> impl IntoResponse {
+    ([(CONTENT_TYPE, TextEncoder::new().format_type().to_string())], gather())
+}
+
 #[allow(unused)]
 pub async fn init_metrics(config: &Arc<WebServeConfig>) {
     if config.mgmt.enabled {
         tracing::info!("Custom metrics starting ...");
+        register_metrics();
+    }
+}
+
+// Register the metrics to the registry once, since the duplicated registration fails.
+fn register_metrics() {
+    REGISTER_METRICS.call_once(|| {
+        lazy_static::initialize(&PROCESS_START);
+        BUILD_INFO.with_label_values(&[GIT_VERSION, GIT_COMMIT_HASH, GIT_BUILD_DATE]).set(1);
+
         REGISTRY.register(Box::new(MY_HTTP_REQUESTS_TOTAL.clone())).expect(
             "collector can be registered"
         );
         REGISTRY.register(Box::new(MY_HTTP_REQUEST_DURATION.clone())).expect(
             "collector can be registered"
         );
+        REGISTRY.register(Box::new(PANIC_COUNTER.clone())).expect("collector can be registered");
+        REGISTRY.register(Box::new(CACHE_HITS_TOTAL.clone())).expect("collector can be registered");
+        REGISTRY.register(Box::new(CACHE_MISSES_TOTAL.clone())).expect("collector can be registered");
+        REGISTRY.register(Box::new(CACHE_SETS_TOTAL.clone())).expect("collector can be registered");
+        REGISTRY.register(Box::new(CACHE_EXPIRATIONS_TOTAL.clone())).expect("collector can be registered");
+        REGISTRY.register(Box::new(PROCESS_UPTIME_SECONDS.clone())).expect("collector can be registered");
+        REGISTRY.register(Box::new(BUILD_INFO.clone())).expect("collector can be registered");
         // Register more metrics...
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The value of the sample line '<name> <value>' of the scraped metrics.
+    fn scraped_value(scraped: &str, name: &str) -> Option<f64> {
+        scraped
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .and_then(|value| value.parse().ok())
+    }
+
+    #[tokio::test]
+    async fn test_scrape_metrics() {
+        register_metrics();
+        // Notice: The other tests of panics increase the counter concurrently.
+        PANIC_COUNTER.inc();
+        let before = PANIC_COUNTER.get() as f64;
+        let scraped = gather();
+        let panics = scraped_value(&scraped, "panics_total").unwrap();
+        assert!(panics >= before && panics <= (PANIC_COUNTER.get() as f64), "{}", scraped);
+        assert!(scraped.contains("# TYPE panics_total counter"));
+
+        assert!(scraped_value(&scraped, "process_uptime_seconds").unwrap() >= 0.0);
+        let build_info = format!(
+            "build_info{{build_date=\"{}\",commit=\"{}\",version=\"{}\"}}",
+            GIT_BUILD_DATE,
+            GIT_COMMIT_HASH,
+            GIT_VERSION
+        );
+        assert_eq!(scraped_value(&scraped, &build_info), Some(1.0), "{}", scraped);
+
+        let response = handle_metrics().await.into_response();
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/plain; version=0.0.4");
     }
 }
@@ -22,15 +22,18 @@
 
 use std::sync::Arc;
 
+use opentelemetry::trace::TraceError;
+use opentelemetry_sdk::trace::Tracer;
 use tracing_opentelemetry::OpenTelemetryLayer;
-use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::{ layer::SubscriberExt, registry::LookupSpan };
 
-use crate::config::config_serve::WebServeConfig;
+use crate::config::config_serve::{ WebServeConfig, DEFAULT_PANIC_BACKTRACES_PER_MINUTE };
 use crate::mgmt::apm::otel::create_otel_tracer;
 
 pub mod logging;
 pub mod metrics;
 pub mod otel;
+pub mod panic;
 pub mod profiling;
 
 pub async fn init_components(config: &Arc<WebServeConfig>) {
@@ -38,19 +41,22 @@ pub async fn init_components(config: &Arc<WebServeConfig>) {
     let (route_layer, _) = tracing_subscriber::reload::Layer::new(
         logging::default_log_route_layer()
     );
-    let (stderr_layer, _) = tracing_subscriber::reload::Layer::new(
+    let (stderr_layer, stderr_handle) = tracing_subscriber::reload::Layer::new(
         logging::default_log_stderr_layer(config)
     );
+    logging::register_log_stderr_handle(stderr_handle);
     let level_layer = logging::default_log_levels_layer();
 
-    let subscriber = tracing_subscriber
-        ::registry()
-        .with(route_layer)
-        .with(stderr_layer)
-        .with(level_layer);
+    let subscriber = tracing_subscriber::registry().with(route_layer).with(stderr_layer);
+    let file_layer = logging::default_log_file_layer(config);
+    let subscriber = subscriber.with(file_layer).with(level_layer);
 
     // Create OpenTelemetry layer if tracer is available.
-    let otel_layer = create_otel_tracer(config).await.map(OpenTelemetryLayer::new);
+    let (otel_layer, otel_error) = if config.logging.sinks.contains(&logging::LogSink::Otlp) {
+        build_otel_layer(config).await
+    } else {
+        (None, None)
+    };
     // Add OpenTelemetry layer if available.
     let subscriber = subscriber.with(otel_layer);
 
@@ -80,9 +86,75 @@ pub async fn init_components(config: &Arc<WebServeConfig>) {
         tracing::subscriber::set_global_default(subscriber).unwrap();
     }
 
+    // Report after the subscriber is set, so that it is logged to the other sinks.
+    if let Some(e) = otel_error {
+        tracing::error!("Failed to install OpenTelemetry tracer, continue without OTLP. cause: {}", e);
+    }
+
+    // Setup the backtraces sampling of panics.
+    panic::set_panic_backtrace_limit(
+        config.logging.panic_backtraces_per_minute.unwrap_or(DEFAULT_PANIC_BACKTRACES_PER_MINUTE)
+    );
+
     // Setup custom metrics.
     metrics::init_metrics(config).await;
 
     // Setup profiling.
     profiling::init_profiling(config).await;
 }
+
+// Build the OpenTelemetry layer, the tracer install failure is tolerated (returned to be reported
+// later) unless OTLP is required, because tracing is non-essential to serve.
+async fn build_otel_layer<S>(config: &Arc<WebServeConfig>) -> (Option<OpenTelemetryLayer<S, Tracer>>, Option<TraceError>)
+    where S: tracing::Subscriber + for<'span> LookupSpan<'span>
+{
+    match create_otel_tracer(config).await {
+        Ok(tracer) => (tracer.map(OpenTelemetryLayer::new), None),
+        Err(e) if config.mgmt.otel.required.unwrap_or(false) => {
+            panic!("Failed to install OpenTelemetry tracer. cause: {}", e)
+        }
+        Err(e) => (None, Some(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::config_serve::WebServeProperties;
+
+    fn new_unreachable_otlp_config(required: bool) -> Arc<WebServeConfig> {
+        let mut properties = WebServeProperties::default();
+        properties.mgmt.enabled = true;
+        properties.mgmt.otel.enabled = true;
+        properties.mgmt.otel.endpoint = "not a valid endpoint".to_string();
+        properties.mgmt.otel.required = Some(required);
+        properties.to_config()
+    }
+
+    #[tokio::test]
+    async fn test_otel_install_failure_tolerated_when_not_required() {
+        let config = new_unreachable_otlp_config(false);
+        let (layer, error) = build_otel_layer::<tracing_subscriber::Registry>(&config).await;
+        assert!(layer.is_none());
+        assert!(error.is_some());
+
+        // The logging is still up without the OTLP layer.
+        let dir = std::env::temp_dir().join(format!("mywebnote_log_{}", uuid::Uuid::new_v4()));
+        let writer = logging::RollingFileWriter::new(dir.to_str().unwrap(), "test").unwrap();
+        let subscriber = tracing_subscriber
+            ::registry()
+            .with(layer)
+            .with(tracing_subscriber::fmt::layer().with_writer(writer.clone()).with_ansi(false));
+        tracing::subscriber::with_default(subscriber, || tracing::info!("logging is up"));
+        let rotated = writer.rotate().unwrap();
+        assert!(std::fs::read_to_string(rotated).unwrap().contains("logging is up"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "Failed to install OpenTelemetry tracer")]
+    async fn test_otel_install_failure_panics_when_required() {
+        let config = new_unreachable_otlp_config(true);
+        let _ = build_otel_layer::<tracing_subscriber::Registry>(&config).await;
+    }
+}
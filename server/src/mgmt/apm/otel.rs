@@ -23,47 +23,62 @@
 use std::sync::Arc;
 use std::time::Duration;
 
-use opentelemetry::{ global, KeyValue };
-use opentelemetry_sdk::trace::Config;
+use axum::http::{ HeaderMap, HeaderName, HeaderValue, StatusCode };
+use opentelemetry::{
+    global,
+    propagation::{ Extractor, Injector, TextMapPropagator },
+    trace::{ TraceContextExt, TraceError },
+    KeyValue,
+};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_zipkin::{ B3Encoding, Propagator as B3Propagator };
+use serde::{ Deserialize, Serialize };
+use globset::{ Glob, GlobSet, GlobSetBuilder };
+use opentelemetry::trace::{ Link, SamplingResult, SpanKind, TraceId };
+use opentelemetry_sdk::trace::{ Config, Sampler, ShouldSample };
 use opentelemetry_sdk::Resource;
 use opentelemetry_sdk::runtime::Tokio;
 use opentelemetry_sdk::trace::Tracer;
 use opentelemetry_otlp::{ new_exporter, ExportConfig, Protocol };
 use opentelemetry_otlp::WithExportConfig;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
-use crate::config::config_serve::WebServeConfig;
+use crate::config::config_serve::{
+    OtelProperties,
+    TraceSampleRule,
+    WebServeConfig,
+    DEFAULT_OTLP_ENDPOINT,
+    DEFAULT_OTLP_HTTP_ENDPOINT,
+    DEFAULT_OTLP_TIMEOUT,
+};
 
-pub async fn create_otel_tracer(config: &Arc<WebServeConfig>) -> Option<Tracer> {
+pub async fn create_otel_tracer(config: &Arc<WebServeConfig>) -> Result<Option<Tracer>, TraceError> {
     let mut tracer = None;
 
     if config.mgmt.enabled && config.mgmt.otel.enabled {
-        let _tracer = opentelemetry_otlp
+        let export_config = new_otlp_export_config(&config.mgmt.otel);
+        let pipeline = opentelemetry_otlp
             ::new_pipeline()
             .tracing()
-            .with_exporter(
-                new_exporter()
-                    .tonic()
-                    .with_export_config(ExportConfig {
-                        endpoint: config.mgmt.otel.endpoint.to_string(),
-                        protocol: match config.mgmt.otel.protocol.to_lowercase().as_str() {
-                            "http/protobuf" => Protocol::HttpBinary,
-                            "grpc" => Protocol::Grpc,
-                            "http/json" => Protocol::HttpJson,
-                            _ => Protocol::HttpBinary,
-                        },
-                        timeout: Duration::from_millis(config.mgmt.otel.timeout.unwrap()),
-                    })
-            )
-            .with_trace_config(
+            .with_trace_config({
                 // Notice: More OTEL custom configuration use to environment: OTEL_SPAN_xxx, see to: opentelemetry_sdk::trace::config::default()
-                Config::default().with_resource(
+                let trace_config = Config::default().with_resource(
                     Resource::new(
                         vec![KeyValue::new("service.name", config.service_name.to_string())]
                     )
-                )
-            )
-            .install_batch(Tokio)
-            .expect("Failed to install OpenTelemetry tracer.");
+                );
+                match PathSampler::new(&config.mgmt.otel.sample_rules) {
+                    Some(sampler) => trace_config.with_sampler(sampler),
+                    None => trace_config,
+                }
+            });
+        // The gRPC is exported by tonic, and the others (http/protobuf, http/json) by the HTTP client, e.g.
+        // through the proxy of collector which doesn't speak gRPC.
+        let pipeline = match export_config.protocol {
+            Protocol::Grpc => pipeline.with_exporter(new_exporter().tonic().with_export_config(export_config)),
+            _ => pipeline.with_exporter(new_exporter().http().with_export_config(export_config)),
+        };
+        let _tracer = pipeline.install_batch(Tokio)?;
 
         // Get a tracer from the provider
         tracer = Some(_tracer);
@@ -73,5 +88,439 @@ pub async fn create_otel_tracer(config: &Arc<WebServeConfig>) -> Option<Tracer>
         global::shutdown_tracer_provider();
     }
 
-    tracer
+    Ok(tracer)
+}
+
+// The protocol of the OTLP exporter, i.e. 'grpc' (default), 'http/protobuf' (or 'http') and 'http/json'.
+pub fn parse_otlp_protocol(protocol: &str) -> Protocol {
+    match protocol.trim().to_lowercase().as_str() {
+        "grpc" => Protocol::Grpc,
+        "http/protobuf" | "http" => Protocol::HttpBinary,
+        "http/json" => Protocol::HttpJson,
+        other => {
+            eprintln!("Unsupported OTLP protocol '{}', fallback to grpc.", other);
+            Protocol::Grpc
+        }
+    }
+}
+
+// The endpoint with the scheme, i.e. the 'http://' is prepended only if the scheme is absent (e.g. 'host:port'),
+// and the explicit 'http://' or 'https://' is preserved, the default of the protocol if unset.
+pub fn resolve_otlp_endpoint(endpoint: &str, protocol: Protocol) -> String {
+    let endpoint = endpoint.trim();
+    if endpoint.is_empty() {
+        return match protocol {
+            Protocol::Grpc => DEFAULT_OTLP_ENDPOINT.to_string(),
+            _ => DEFAULT_OTLP_HTTP_ENDPOINT.to_string(),
+        };
+    }
+    if endpoint.contains("://") { endpoint.to_string() } else { format!("http://{}", endpoint) }
+}
+
+// The export config of the OTLP exporter, the endpoint of HTTP protocols is the base URL of collector, i.e.
+// the signal path '/v1/traces' is appended by the exporter.
+pub fn new_otlp_export_config(otel: &OtelProperties) -> ExportConfig {
+    let protocol = parse_otlp_protocol(&otel.protocol);
+    let endpoint = resolve_otlp_endpoint(&otel.endpoint, protocol);
+    let endpoint = match protocol {
+        Protocol::Grpc => endpoint,
+        _ => endpoint.trim_end_matches('/').trim_end_matches("/v1/traces").to_string(),
+    };
+    ExportConfig {
+        endpoint,
+        protocol,
+        timeout: Duration::from_millis(otel.timeout.unwrap_or(DEFAULT_OTLP_TIMEOUT)),
+    }
+}
+
+// The sampler of the root spans by the 'path' attribute of the request span (see: make_request_span), the
+// first matched rule of the path is applied by its ratio of trace id, and the others (i.e. the child spans,
+// the unmatched paths and the spans without path) are sampled by the default (parent based) sampler.
+#[derive(Debug, Clone)]
+pub struct PathSampler {
+    rules: Vec<(GlobSet, Sampler)>,
+    fallback: Sampler,
+}
+
+impl PathSampler {
+    // None if the rules are empty, i.e. sampled by the default sampler.
+    pub fn new(rules: &[TraceSampleRule]) -> Option<Self> {
+        if rules.is_empty() {
+            return None;
+        }
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let mut builder = GlobSetBuilder::new();
+                for pattern in &rule.path_patterns {
+                    builder.add(Glob::new(pattern).unwrap());
+                }
+                (builder.build().unwrap(), Sampler::TraceIdRatioBased(rule.ratio))
+            })
+            .collect();
+        Some(Self { rules, fallback: Sampler::ParentBased(Box::new(Sampler::AlwaysOn)) })
+    }
+}
+
+impl ShouldSample for PathSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&opentelemetry::Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &[KeyValue],
+        links: &[Link]
+    ) -> SamplingResult {
+        let is_root = parent_context.map_or(true, |cx| !cx.span().span_context().is_valid());
+        let path = attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == "path")
+            .map(|kv| kv.value.as_str());
+        let sampler = match path {
+            Some(path) if is_root => {
+                self.rules
+                    .iter()
+                    .find(|(globset, _)| globset.is_match(path.as_ref()))
+                    .map(|(_, sampler)| sampler)
+            }
+            _ => None,
+        };
+        sampler
+            .unwrap_or(&self.fallback)
+            .should_sample(parent_context, trace_id, name, span_kind, attributes, links)
+    }
+}
+
+// The propagation format of the trace context in the HTTP headers.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TracePropagator {
+    // The W3C 'traceparent' and 'tracestate' headers.
+    #[default]
+    W3c,
+    // The B3 (Zipkin) single 'b3' header.
+    B3,
+    // The B3 (Zipkin) multiple 'X-B3-*' headers.
+    B3Multi,
+}
+
+impl TracePropagator {
+    // The B3 is extracted from either the single or multiple headers, and injected as the configured.
+    fn new_propagator(&self, inject: bool) -> Box<dyn TextMapPropagator + Send + Sync> {
+        match self {
+            TracePropagator::W3c => Box::new(TraceContextPropagator::new()),
+            TracePropagator::B3 if inject => Box::new(B3Propagator::with_encoding(B3Encoding::SingleHeader)),
+            TracePropagator::B3Multi if inject => Box::new(B3Propagator::with_encoding(B3Encoding::MultipleHeader)),
+            _ => Box::new(B3Propagator::with_encoding(B3Encoding::SingleAndMultiHeader)),
+        }
+    }
+}
+
+// The trace context headers of the incoming request.
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .keys()
+            .map(|k| k.as_str())
+            .collect()
+    }
+}
+
+// The trace context headers of the outgoing request.
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl<'a> Injector for HeaderInjector<'a> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(key.as_bytes()), HeaderValue::from_str(&value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+// Extract the remote trace context of the headers in the propagation format, none if absent or invalid.
+pub fn extract_trace_context(headers: &HeaderMap, propagator: TracePropagator) -> Option<opentelemetry::Context> {
+    let context = propagator.new_propagator(false).extract(&HeaderExtractor(headers));
+    if context.span().span_context().is_valid() { Some(context) } else { None }
+}
+
+// Inject the trace context into the headers in the propagation format, e.g. to call the downstream services.
+pub fn inject_trace_context(context: &opentelemetry::Context, propagator: TracePropagator) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    propagator.new_propagator(true).inject_context(context, &mut HeaderInjector(&mut headers));
+    headers
+}
+
+// Record each layer of the error chain as an event of the current span. Only the server errors (5xx)
+// are recorded at the error level which also marks the span status as error, the client errors (4xx)
+// are recorded as warnings. It's no-op when there is no span active.
+pub fn record_error_chain(status: StatusCode, err: &anyhow::Error) {
+    if tracing::Span::current().is_none() {
+        return;
+    }
+    for (layer, cause) in err.chain().enumerate() {
+        if status.is_server_error() {
+            tracing::error!(
+                status_code = status.as_u16() as i64,
+                error.layer = layer as i64,
+                error.message = %cause,
+                "error"
+            );
+        } else {
+            tracing::warn!(
+                status_code = status.as_u16() as i64,
+                error.layer = layer as i64,
+                error.message = %cause,
+                "error"
+            );
+        }
+    }
+}
+
+// The trace id of the current span, or none when there is no (sampled or remote) trace active.
+pub fn current_trace_id() -> Option<String> {
+    let context = tracing::Span::current().context();
+    let span_context = context.span().span_context().clone();
+    if span_context.is_valid() {
+        Some(span_context.trace_id().to_string())
+    } else {
+        None
+    }
+}
+
+// Record the exceeded latency budget (SLO) of the route as a warning, and tag the current span
+// with 'slo.violated = true'.
+pub fn record_slo_violation(route: &str, budget_ms: u64, elapsed_ms: u64) {
+    tracing::warn!(
+        route = route,
+        budget_ms = budget_ms as i64,
+        elapsed_ms = elapsed_ms as i64,
+        overage_ms = elapsed_ms.saturating_sub(budget_ms) as i64,
+        "latency budget exceeded"
+    );
+    tracing::Span::current().set_attribute("slo.violated", true);
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use opentelemetry::trace::{ Status, TracerProvider as _ };
+    use opentelemetry::Value;
+    use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+    use opentelemetry_sdk::trace::TracerProvider;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    // Build the subscriber exporting the spans to memory, the finished spans are got after flushed.
+    pub(crate) fn new_in_memory_subscriber() -> (
+        impl tracing::Subscriber + Send + Sync,
+        TracerProvider,
+        InMemorySpanExporter,
+    ) {
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder().with_simple_exporter(exporter.clone()).build();
+        let subscriber = tracing_subscriber
+            ::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        (subscriber, provider, exporter)
+    }
+
+    fn with_in_memory_tracer(f: impl FnOnce()) -> Vec<opentelemetry_sdk::export::trace::SpanData> {
+        let (subscriber, provider, exporter) = new_in_memory_subscriber();
+        tracing::subscriber::with_default(subscriber, f);
+        provider.force_flush();
+        exporter.get_finished_spans().unwrap()
+    }
+
+    #[test]
+    fn test_record_error_chain_as_span_events() {
+        let spans = with_in_memory_tracer(|| {
+            let span = tracing::info_span!("http_request");
+            let _enter = span.enter();
+            let err = anyhow::Error::msg("connection refused").context("Failed to query user");
+            record_error_chain(StatusCode::INTERNAL_SERVER_ERROR, &err);
+        });
+
+        assert_eq!(spans.len(), 1);
+        let span = &spans[0];
+        assert_eq!(span.status, Status::error(""));
+        assert_eq!(span.events.len(), 2);
+
+        let attr = |i: usize, key: &str| {
+            span.events[i].attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == key)
+                .map(|kv| kv.value.clone())
+        };
+        assert_eq!(attr(0, "status_code"), Some(Value::I64(500)));
+        assert_eq!(attr(0, "error.message"), Some(Value::from("Failed to query user")));
+        assert_eq!(attr(1, "error.message"), Some(Value::from("connection refused")));
+    }
+
+    #[test]
+    fn test_record_error_chain_of_client_error_not_mark_span() {
+        let spans = with_in_memory_tracer(|| {
+            let span = tracing::info_span!("http_request");
+            let _enter = span.enter();
+            record_error_chain(StatusCode::BAD_REQUEST, &anyhow::Error::msg("invalid"));
+        });
+
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].status, Status::Unset);
+        assert_eq!(spans[0].events.len(), 1);
+    }
+
+    #[test]
+    fn test_record_error_chain_without_span() {
+        let spans = with_in_memory_tracer(|| {
+            record_error_chain(StatusCode::BAD_REQUEST, &anyhow::Error::msg("invalid"));
+        });
+        assert!(spans.is_empty());
+    }
+
+    #[test]
+    fn test_record_slo_violation() {
+        let spans = with_in_memory_tracer(|| {
+            let span = tracing::info_span!("http_request");
+            let _enter = span.enter();
+            record_slo_violation("/sys/user/query", 100, 250);
+        });
+
+        assert_eq!(spans.len(), 1);
+        let span = &spans[0];
+        let violated = span.attributes.iter().find(|kv| kv.key.as_str() == "slo.violated");
+        assert_eq!(violated.map(|kv| kv.value.clone()), Some(Value::Bool(true)));
+        let overage = span.events[0].attributes.iter().find(|kv| kv.key.as_str() == "overage_ms");
+        assert_eq!(overage.map(|kv| kv.value.clone()), Some(Value::I64(150)));
+    }
+
+    fn new_otel(endpoint: &str, protocol: &str) -> OtelProperties {
+        OtelProperties { endpoint: endpoint.to_string(), protocol: protocol.to_string(), ..OtelProperties::default() }
+    }
+
+    #[test]
+    fn test_otlp_export_config_of_protocols() {
+        let grpc = new_otlp_export_config(&new_otel("http://collector:4317", "grpc"));
+        assert_eq!((grpc.protocol, grpc.endpoint.as_str()), (Protocol::Grpc, "http://collector:4317"));
+        assert_eq!(grpc.timeout, Duration::from_millis(DEFAULT_OTLP_TIMEOUT));
+
+        for protocol in ["http/protobuf", "HTTP"] {
+            let http = new_otlp_export_config(&new_otel("https://collector:4318/", protocol));
+            assert_eq!((http.protocol, http.endpoint.as_str()), (Protocol::HttpBinary, "https://collector:4318"));
+        }
+        // The signal path is appended by the exporter.
+        let http = new_otlp_export_config(&new_otel("http://proxy/otlp/v1/traces", "http/json"));
+        assert_eq!((http.protocol, http.endpoint.as_str()), (Protocol::HttpJson, "http://proxy/otlp"));
+
+        assert_eq!(parse_otlp_protocol("unknown"), Protocol::Grpc);
+    }
+
+    #[test]
+    fn test_resolve_otlp_endpoint_prefixes_scheme_only_if_absent() {
+        for protocol in [Protocol::Grpc, Protocol::HttpBinary] {
+            assert_eq!(resolve_otlp_endpoint("collector:4317", protocol), "http://collector:4317");
+            assert_eq!(resolve_otlp_endpoint(" http://collector:4317 ", protocol), "http://collector:4317");
+            assert_eq!(resolve_otlp_endpoint("https://collector:4317", protocol), "https://collector:4317");
+        }
+        assert_eq!(resolve_otlp_endpoint("", Protocol::Grpc), DEFAULT_OTLP_ENDPOINT);
+        assert_eq!(resolve_otlp_endpoint(" ", Protocol::HttpJson), DEFAULT_OTLP_HTTP_ENDPOINT);
+
+        let http = new_otlp_export_config(&new_otel("collector:4318/v1/traces", "http/protobuf"));
+        assert_eq!(http.endpoint, "http://collector:4318");
+    }
+
+    // The multi thread runtime, because the shutdown of the batch exporter blocks the current thread.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_create_otel_tracer_over_http() {
+        let mut properties = crate::config::config_serve::WebServeProperties::default();
+        properties.mgmt.enabled = true;
+        properties.mgmt.otel = new_otel("http://localhost:4318", "http/protobuf");
+        let tracer = create_otel_tracer(&properties.to_config()).await.unwrap();
+        assert!(tracer.is_some());
+    }
+
+    // Inject the remote span context and extract it back in the propagation format.
+    fn round_trip(propagator: TracePropagator) -> (HeaderMap, opentelemetry::trace::SpanContext) {
+        use opentelemetry::trace::{ SpanContext, SpanId, TraceFlags, TraceId, TraceState };
+        let span_context = SpanContext::new(
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+            SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default()
+        );
+        let context = opentelemetry::Context::new().with_remote_span_context(span_context);
+        let headers = inject_trace_context(&context, propagator);
+        let extracted = extract_trace_context(&headers, propagator).unwrap();
+        let extracted = extracted.span().span_context().clone();
+        (headers, extracted)
+    }
+
+    #[test]
+    fn test_trace_context_round_trip_of_propagators() {
+        for (propagator, header) in [
+            (TracePropagator::W3c, "traceparent"),
+            (TracePropagator::B3, "b3"),
+            (TracePropagator::B3Multi, "x-b3-traceid"),
+        ] {
+            let (headers, extracted) = round_trip(propagator);
+            assert!(headers.contains_key(header), "{:?}: {:?}", propagator, headers);
+            assert_eq!(extracted.trace_id().to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+            assert_eq!(extracted.span_id().to_string(), "00f067aa0ba902b7");
+            assert!(extracted.is_sampled() && extracted.is_remote());
+        }
+
+        // The B3 is extracted from either the single or multiple headers.
+        let (headers, _) = round_trip(TracePropagator::B3Multi);
+        assert!(extract_trace_context(&headers, TracePropagator::B3).is_some());
+        assert!(extract_trace_context(&headers, TracePropagator::W3c).is_none());
+        assert!(extract_trace_context(&HeaderMap::new(), TracePropagator::B3).is_none());
+    }
+
+    fn sampled_ratio(sampler: &PathSampler, path: &str, parent: Option<&opentelemetry::Context>) -> f64 {
+        let total = 10_000;
+        let sampled = (0..total)
+            .filter(|_| {
+                let trace_id = TraceId::from_bytes(rand::random::<u128>().to_be_bytes());
+                let attributes = [KeyValue::new("path", path.to_string())];
+                let kind = SpanKind::Server;
+                let result = sampler.should_sample(parent, trace_id, "http_request", &kind, &attributes, &[]);
+                result.decision == opentelemetry::trace::SamplingDecision::RecordAndSample
+            })
+            .count();
+        (sampled as f64) / (total as f64)
+    }
+
+    #[test]
+    fn test_path_sampler_by_rules() {
+        let rules = vec![
+            TraceSampleRule { path_patterns: vec!["/healthz".to_string(), "/static/**".to_string()], ratio: 0.01 },
+            TraceSampleRule { path_patterns: vec!["/api/**".to_string()], ratio: 1.0 }
+        ];
+        let sampler = PathSampler::new(&rules).unwrap();
+        assert!(sampled_ratio(&sampler, "/healthz", None) < 0.03);
+        assert!(sampled_ratio(&sampler, "/static/app.js", None) < 0.03);
+        assert_eq!(sampled_ratio(&sampler, "/api/v1/users", None), 1.0);
+        // The unmatched is sampled by the default sampler.
+        assert_eq!(sampled_ratio(&sampler, "/sys/user/query", None), 1.0);
+
+        // The child spans follow the parent.
+        use opentelemetry::trace::{ SpanContext, SpanId, TraceFlags, TraceState };
+        let parent = SpanContext::new(
+            TraceId::from_u128(1),
+            SpanId::from_u64(1),
+            TraceFlags::default(),
+            false,
+            TraceState::default()
+        );
+        let parent = opentelemetry::Context::new().with_remote_span_context(parent);
+        assert_eq!(sampled_ratio(&sampler, "/api/v1/users", Some(&parent)), 0.0);
+
+        assert!(PathSampler::new(&[]).is_none());
+    }
 }
@@ -0,0 +1,230 @@
+/*
+ * SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+ *
+ * Copyleft (c) 2024 James Wong. This file is part of James Wong.
+ * is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * James Wong is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * IMPORTANT: Any software that fully or partially contains or uses materials
+ * covered by this license must also be released under the GNU GPL license.
+ * This includes modifications and derived works.
+ */
+
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::panic::PanicInfo;
+use std::sync::atomic::{ AtomicU32, Ordering };
+use std::sync::{ Mutex, Once };
+use std::time::{ Duration, Instant };
+
+use lazy_static::lazy_static;
+
+use crate::config::config_serve::DEFAULT_PANIC_BACKTRACES_PER_MINUTE;
+use crate::mgmt::apm::metrics::PANIC_COUNTER;
+
+// The window of limiting the full backtraces captured.
+const BACKTRACE_SAMPLING_WINDOW: Duration = Duration::from_secs(60);
+
+static SET_PANIC_HOOK: Once = Once::new();
+
+lazy_static! {
+    static ref BACKTRACE_SAMPLER: BacktraceSampler = BacktraceSampler::new(DEFAULT_PANIC_BACKTRACES_PER_MINUTE);
+}
+
+// The fixed window limiter of capturing the full backtraces, i.e. the first N panics per window.
+pub(crate) struct BacktraceSampler {
+    limit: AtomicU32,
+    // The start and the captured count of current window.
+    window: Mutex<Option<(Instant, u32)>>,
+}
+
+impl BacktraceSampler {
+    pub(crate) fn new(limit: u32) -> Self {
+        Self { limit: AtomicU32::new(limit), window: Mutex::new(None) }
+    }
+
+    // Whether the full backtrace should be captured at now, which is counted if true.
+    pub(crate) fn try_acquire(&self, now: Instant) -> bool {
+        // Notice: The lock may be poisoned by the panic of other thread, but the state is still consistent.
+        let mut window = self.window.lock().unwrap_or_else(|e| e.into_inner());
+        let (start, count) = match *window {
+            Some((start, count)) if now.saturating_duration_since(start) < BACKTRACE_SAMPLING_WINDOW => (start, count),
+            _ => (now, 0),
+        };
+        let acquired = count < self.limit.load(Ordering::Relaxed);
+        *window = Some((start, if acquired { count + 1 } else { count }));
+        acquired
+    }
+}
+
+// Set the max full backtraces captured of panics per minute.
+pub fn set_panic_backtrace_limit(limit: u32) {
+    BACKTRACE_SAMPLER.limit.store(limit, Ordering::Relaxed);
+}
+
+// Set the panic hook to log the panic with structured fields, and then delegate to the default hook
+// (e.g. printing to stderr), it's set only once even if called repeatedly.
+pub fn set_panic_hook() {
+    SET_PANIC_HOOK.call_once(|| {
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(
+            Box::new(move |info| {
+                on_panic(info);
+                default_hook(info);
+            })
+        );
+    });
+}
+
+fn on_panic(info: &PanicInfo) {
+    PANIC_COUNTER.inc();
+
+    let thread = std::thread::current();
+    let thread_name = thread.name().unwrap_or("<unnamed>");
+    let location = info
+        .location()
+        .map(|l| l.to_string())
+        .unwrap_or_default();
+    // The full backtrace is expensive, which is captured only if sampled, the others are location only.
+    if BACKTRACE_SAMPLER.try_acquire(Instant::now()) {
+        let backtrace = Backtrace::force_capture();
+        tracing::error!(
+            thread = thread_name,
+            location,
+            backtrace = %backtrace,
+            "Panicked: {}",
+            panic_message(info.payload())
+        );
+    } else {
+        tracing::error!(thread = thread_name, location, "Panicked: {}", panic_message(info.payload()));
+    }
+}
+
+// Extract the readable message of panic payload, which is the '&str' or 'String' generally, e.g.
+// 'panic!("literal")' or 'panic!("{}", arg)'.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.as_str()
+    } else {
+        "<non-string panic payload>"
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{ Arc, Mutex };
+
+    #[derive(Clone, Default)]
+    pub(crate) struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl CapturedLogs {
+        pub(crate) fn contents(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).to_string()
+        }
+    }
+
+    // Serialize the capturing, because the backtraces are sampled by the global sampler.
+    static CAPTURE_PANICS_LOCK: Mutex<()> = Mutex::new(());
+
+    // Run the panicking function with the panic hook set, and returns the captured logs, the backtrace sampling
+    // window is restarted before running.
+    pub(crate) fn capture_panics(f: impl Fn() + std::panic::RefUnwindSafe, times: usize) -> String {
+        let _guard = CAPTURE_PANICS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        *BACKTRACE_SAMPLER.window.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        set_panic_hook();
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber
+            ::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..times {
+                assert!(std::panic::catch_unwind(&f).is_err());
+            }
+        });
+        logs.contents()
+    }
+
+    #[test]
+    fn test_panic_message_of_payloads() {
+        assert_eq!(panic_message(&"literal"), "literal");
+        assert_eq!(panic_message(&"formatted".to_string()), "formatted");
+        assert_eq!(panic_message(&42), "<non-string panic payload>");
+    }
+
+    #[test]
+    fn test_panic_hook_logs_string_payload() {
+        let before = PANIC_COUNTER.get();
+        let logs = std::thread::Builder
+            ::new()
+            .name("panicking-worker".to_string())
+            .spawn(|| capture_panics(|| std::panic::panic_any(format!("boom of {}", "string payload")), 1))
+            .unwrap()
+            .join()
+            .unwrap();
+
+        assert!(logs.contains("Panicked: boom of string payload"), "{}", logs);
+        assert!(logs.contains("thread=\"panicking-worker\""), "{}", logs);
+        assert!(logs.contains("location=\"src/mgmt/apm/panic.rs:"), "{}", logs);
+        assert!(logs.contains("backtrace="), "{}", logs);
+        assert!(PANIC_COUNTER.get() > before);
+    }
+
+    #[test]
+    fn test_backtrace_sampler_limits_per_window() {
+        let sampler = BacktraceSampler::new(3);
+        let start = Instant::now();
+
+        let acquired = (0..100).filter(|i| sampler.try_acquire(start + Duration::from_millis(*i))).count();
+        assert_eq!(acquired, 3);
+        assert!(!sampler.try_acquire(start + BACKTRACE_SAMPLING_WINDOW - Duration::from_millis(1)));
+
+        // The next window is restarted.
+        assert!(sampler.try_acquire(start + BACKTRACE_SAMPLING_WINDOW));
+        assert_eq!((0..10).filter(|_| sampler.try_acquire(start + BACKTRACE_SAMPLING_WINDOW)).count(), 2);
+
+        let disabled = BacktraceSampler::new(0);
+        assert!(!disabled.try_acquire(start));
+    }
+
+    #[test]
+    fn test_panic_hook_samples_backtraces_under_rapid_panics() {
+        let before = PANIC_COUNTER.get();
+        let times = (DEFAULT_PANIC_BACKTRACES_PER_MINUTE as usize) * 5;
+        let logs = capture_panics(|| panic!("crash loop"), times);
+
+        // Every panic is counted and logged with the location, but the backtraces are bounded.
+        assert!(PANIC_COUNTER.get() - before >= times as u64);
+        assert_eq!(logs.matches("Panicked: crash loop").count(), times);
+        assert_eq!(logs.matches("location=\"src/mgmt/apm/panic.rs:").count(), times);
+        let backtraces = logs.matches("backtrace=").count();
+        assert!(backtraces <= (DEFAULT_PANIC_BACKTRACES_PER_MINUTE as usize), "{} backtraces", backtraces);
+    }
+}
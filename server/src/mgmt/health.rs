@@ -21,6 +21,9 @@
  */
 
 use std::collections::HashMap;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
 
 use axum::{ async_trait, extract::State, response::IntoResponse, routing::get, Router };
 use hyper::StatusCode;
@@ -160,6 +163,81 @@ impl HealthChecker for RedisClusterChecker {
     }
 }
 
+// The health of the writes to the primary db, which is tracked by the outcomes of the write requests, so
+// that the writes are rejected during the outage instead of piling up on the broken db, and a write is let
+// through to probe every retry-after, i.e. the circuit breaker.
+pub struct WriteHealth {
+    retry_after: Duration,
+    degraded_since: Mutex<Option<Instant>>,
+    probing: AtomicBool,
+}
+
+impl WriteHealth {
+    pub fn new(retry_after: Duration) -> Self {
+        Self {
+            retry_after,
+            degraded_since: Mutex::new(None),
+            probing: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.degraded_since.lock().unwrap().is_some()
+    }
+
+    // Permit the write if healthy or as the probe, otherwise returns the remaining time to retry after.
+    pub fn try_acquire(&self) -> Result<WritePermit<'_>, Duration> {
+        let degraded_since = match *self.degraded_since.lock().unwrap() {
+            Some(since) => since,
+            None => {
+                return Ok(WritePermit { health: self, probe: false });
+            }
+        };
+        let elapsed = degraded_since.elapsed();
+        if elapsed >= self.retry_after && !self.probing.swap(true, Ordering::SeqCst) {
+            tracing::info!("Probing the writes after degraded for {:?}", elapsed);
+            return Ok(WritePermit { health: self, probe: true });
+        }
+        Err(self.retry_after.saturating_sub(elapsed))
+    }
+
+    fn record(&self, succeeded: bool) {
+        let mut degraded_since = self.degraded_since.lock().unwrap();
+        if succeeded {
+            if degraded_since.take().is_some() {
+                tracing::info!("Recovered the writes from degraded.");
+            }
+        } else {
+            if degraded_since.is_none() {
+                tracing::warn!("Degraded the writes because of the storage failure, rejecting for {:?}", self.retry_after);
+            }
+            *degraded_since = Some(Instant::now());
+        }
+    }
+}
+
+// The permit of the write acquired from the WriteHealth, the probing is released on dropped whether the
+// outcome is recorded or not (e.g. the request is cancelled), so that the probing never gets stuck.
+pub struct WritePermit<'a> {
+    health: &'a WriteHealth,
+    probe: bool,
+}
+
+impl WritePermit<'_> {
+    // Record the outcome of the write which has reached the storage.
+    pub fn record(self, succeeded: bool) {
+        self.health.record(succeeded);
+    }
+}
+
+impl Drop for WritePermit<'_> {
+    fn drop(&mut self) {
+        if self.probe {
+            self.health.probing.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
 pub(crate) fn init() -> Router<AppState> {
     Router::new().route(HEALTHZ_URI, get(handle_healthz))
     // .route(STARTUP_HEALTHZ_URI, get(handle_healthz_startup))
@@ -185,6 +263,9 @@ async fn handle_healthz(State(state): State<AppState>) -> impl IntoResponse {
         result.status = "DOWN".to_string();
     }
 
+    let writes = if state.write_health.is_degraded() { "DEGRADED" } else { "UP" };
+    result.details.insert("writes".to_string(), writes.to_string());
+
     let redis_cluster_check = RedisClusterChecker::new().check(&state).await;
     result.details.extend(redis_cluster_check.details);
     if redis_cluster_check.status == "DOWN" {
@@ -193,3 +274,24 @@ async fn handle_healthz(State(state): State<AppState>) -> impl IntoResponse {
 
     (StatusCode::OK, serde_json::to_string(&result).unwrap())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_permit_releases_probing_on_drop() {
+        let health = WriteHealth::new(Duration::ZERO);
+        health.try_acquire().unwrap().record(false);
+        assert!(health.is_degraded());
+
+        // The probe dropped without the outcome (e.g. cancelled) is released for the next probe.
+        let probe = health.try_acquire().unwrap();
+        assert!(health.try_acquire().is_err());
+        drop(probe);
+        let probe = health.try_acquire().unwrap();
+        assert!(health.is_degraded());
+        probe.record(true);
+        assert!(!health.is_degraded());
+    }
+}
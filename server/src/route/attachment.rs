@@ -0,0 +1,134 @@
+/*
+ * SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+ *
+ * Copyleft (c) 2024 James Wong. This file is part of James Wong.
+ * is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * James Wong is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * IMPORTANT: Any software that fully or partially contains or uses materials
+ * covered by this license must also be released under the GNU GPL license.
+ * This includes modifications and derived works.
+ */
+
+use std::path::PathBuf;
+
+use axum::{
+    body::Bytes,
+    extract::{ Path, State },
+    http::{ header, StatusCode },
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+
+use crate::{ context::state::AppState, errors::AppError, utils::{ auths::AuthUserClaims, files } };
+
+pub fn init() -> Router<AppState> {
+    Router::new().route("/modules/attachment/*id", get(handle_download_attachment).put(handle_upload_attachment))
+}
+
+#[utoipa::path(
+    put,
+    path = "/modules/attachment/{id}",
+    params(("id" = String, Path, description = "The attachment id, i.e. the relative path, e.g. '2024/a1b2c3.png'.")),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 201, description = "Upload the attachment of the current user, overwritten if exists."),
+        (status = 400, description = "The attachment id escaping out of the storage root.")
+    ),
+    tag = "Attachment"
+)]
+pub async fn handle_upload_attachment(
+    State(state): State<AppState>,
+    claims: AuthUserClaims,
+    Path(id): Path<String>,
+    body: Bytes
+) -> Result<StatusCode, AppError> {
+    let path = resolve_attachment_path(&state, &claims, &id).await?;
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await.map_err(|e| AppError::Internal(e.into()))?;
+    }
+    tokio::fs::write(&path, body).await.map_err(|e| AppError::Internal(e.into()))?;
+    Ok(StatusCode::CREATED)
+}
+
+#[utoipa::path(
+    get,
+    path = "/modules/attachment/{id}",
+    params(("id" = String, Path, description = "The attachment id, i.e. the relative path, e.g. '2024/a1b2c3.png'.")),
+    responses(
+        (status = 200, description = "Download the attachment of the current user.", content_type = "application/octet-stream"),
+        (status = 400, description = "The attachment id escaping out of the storage root."),
+        (status = 404, description = "The attachment not found.")
+    ),
+    tag = "Attachment"
+)]
+pub async fn handle_download_attachment(
+    State(state): State<AppState>,
+    claims: AuthUserClaims,
+    Path(id): Path<String>
+) -> Result<impl IntoResponse, AppError> {
+    let path = resolve_attachment_path(&state, &claims, &id).await?;
+    match tokio::fs::read(&path).await {
+        Ok(content) => Ok(([(header::CONTENT_TYPE, "application/octet-stream")], content)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(AppError::NotFound(format!("attachment {}", id))),
+        Err(e) => Err(AppError::Internal(e.into())),
+    }
+}
+
+// Resolve the attachment path sandboxed under the directory of the user in the configured storage root.
+async fn resolve_attachment_path(state: &AppState, claims: &AuthUserClaims, id: &str) -> Result<PathBuf, AppError> {
+    let root = PathBuf::from(&state.config.webnote.attachment_dir).join(claims.uid.to_string());
+    tokio::fs::create_dir_all(&root).await.map_err(|e| AppError::Internal(e.into()))?;
+    files::resolve_sandboxed_path(&root, id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{ body::Body, http::{ Method, Request }, Extension };
+    use tower::ServiceExt;
+
+    async fn call(app: Router, method: Method, uri: &str, body: &'static [u8]) -> (StatusCode, Vec<u8>) {
+        let request = Request::builder().method(method).uri(uri).body(Body::from(body)).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        (status, axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_attachment_upload_and_download_sandboxed() {
+        let dir = std::env::temp_dir().join(format!("mywebnote-attachments-{}", uuid::Uuid::new_v4()));
+        let attachment_dir = dir.to_string_lossy().to_string();
+        let state = crate::context::state::tests::new_test_state(move |p| {
+            p.webnote.attachment_dir = attachment_dir;
+        }).await;
+        let app = |uid: i64| init().layer(Extension(AuthUserClaims::for_test(uid, "a@b.com"))).with_state(state.clone());
+
+        let (status, _) = call(app(1001), Method::PUT, "/modules/attachment/2024/a1b2c3.png", b"png").await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, content) = call(app(1001), Method::GET, "/modules/attachment/2024/a1b2c3.png", b"").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content, b"png");
+        assert!(dir.join("1001").join("2024").join("a1b2c3.png").exists());
+
+        // The attachments of the other users are never reached.
+        let (status, _) = call(app(1002), Method::GET, "/modules/attachment/2024/a1b2c3.png", b"").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(app(1002), Method::GET, "/modules/attachment/..%2F1001/2024/a1b2c3.png", b"").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = call(app(1002), Method::PUT, "/modules/attachment/2024/..%2F..%2F1001/x.png", b"x").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+/*
+ * SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+ *
+ * Copyleft (c) 2024 James Wong. This file is part of James Wong.
+ * is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * James Wong is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * IMPORTANT: Any software that fully or partially contains or uses materials
+ * covered by this license must also be released under the GNU GPL license.
+ * This includes modifications and derived works.
+ */
+
+use axum::{ extract::{ Query, State }, routing::get, Router };
+
+use crate::{
+    config::config_serve::AuthProperties,
+    context::state::AppState,
+    errors::AppError,
+    handler::audit::{ AuditHandler, IAuditHandler },
+    types::{ audit::{ QueryAuditLogsRequest, QueryAuditLogsResponse }, PageRequest },
+    utils::auths::AuthUserClaims,
+};
+
+use super::{ ContentFormat, Negotiated };
+
+pub fn init() -> Router<AppState> {
+    Router::new().route("/sys/audit/logs/query", get(handle_query_audit_logs))
+}
+
+#[utoipa::path(
+    get,
+    path = "/sys/audit/logs/query",
+    params(QueryAuditLogsRequest, PageRequest),
+    responses(
+        (status = 200, description = "Getting for the audit logs by filters, the page size is capped.", body = QueryAuditLogsResponse),
+        (status = 403, description = "Querying the audit logs of the other users by non-admin.")
+    ),
+    tag = "Audit"
+)]
+pub async fn handle_query_audit_logs(
+    State(state): State<AppState>,
+    format: ContentFormat,
+    claims: AuthUserClaims,
+    Query(mut param): Query<QueryAuditLogsRequest>,
+    Query(page): Query<PageRequest>
+) -> Result<Negotiated<QueryAuditLogsResponse>, AppError> {
+    param.uid = resolve_audit_uid(&state.config.auth, &claims, param.uid)?;
+    let (page, data) = get_audit_handler(&state).find(param, page).await?;
+    Ok(Negotiated(format, QueryAuditLogsResponse::new(page, data)))
+}
+
+// Resolve the uid filter of the audit logs, the admins query all (or any) users, and the others query
+// only their own.
+fn resolve_audit_uid(auth: &AuthProperties, principal: &AuthUserClaims, uid: Option<i64>) -> Result<Option<i64>, AppError> {
+    if auth.is_admin(principal.uid) {
+        return Ok(uid);
+    }
+    match uid {
+        Some(uid) if uid != principal.uid => {
+            Err(AppError::Forbidden(format!("querying the audit logs of user {} requires admin", uid)))
+        }
+        _ => Ok(Some(principal.uid)),
+    }
+}
+
+fn get_audit_handler(state: &AppState) -> Box<dyn IAuditHandler + '_> {
+    Box::new(AuditHandler::new(state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_audit_uid_own_and_admin_only() {
+        let auth = AuthProperties { admin_uids: Some(vec![1]), ..AuthProperties::default() };
+
+        // The non admin is forced to query its own.
+        let alice = AuthUserClaims::for_test(2, "a@b.com");
+        assert_eq!(resolve_audit_uid(&auth, &alice, None).unwrap(), Some(2));
+        assert_eq!(resolve_audit_uid(&auth, &alice, Some(2)).unwrap(), Some(2));
+        assert!(matches!(resolve_audit_uid(&auth, &alice, Some(3)), Err(AppError::Forbidden(_))));
+
+        let admin = AuthUserClaims::for_test(1, "a@b.com");
+        assert_eq!(resolve_audit_uid(&auth, &admin, None).unwrap(), None);
+        assert_eq!(resolve_audit_uid(&auth, &admin, Some(3)).unwrap(), Some(3));
+    }
+}
@@ -20,18 +20,22 @@
  * This includes modifications and derived works.
  */
 
+use std::net::SocketAddr;
 use std::result::Result;
 use std::result::Result::Ok;
 use axum::{
+    async_trait,
     body::Body,
-    extract::{ Query, Request, State },
-    http::{ header, StatusCode },
+    extract::{ ConnectInfo, FromRequestParts, Query, Request, State },
+    http::{ header, request::Parts, Response, StatusCode },
     middleware::Next,
     response::{ Html, IntoResponse },
     routing::{ get, post },
+    Json,
     Router,
 };
 
+use chrono::Utc;
 use hyper::HeaderMap;
 use oauth2::{ AuthorizationCode, CsrfToken, Scope, TokenResponse };
 
@@ -41,65 +45,99 @@ use openidconnect::{
     Nonce,
 };
 
-use tower_cookies::{ cookie::{ time::{ self, Duration }, CookieBuilder }, CookieManagerLayer };
+use tower_cookies::{ cookie::{ time::{ self, Duration }, SameSite }, Cookie, CookieManagerLayer };
 
 use crate::{
-    config::{ config_serve::DEFAULT_404_HTML, resources::handle_static },
+    config::{ config_serve::{ RunProfile, WebServeConfig, DEFAULT_404_HTML, DEFAULT_JWT_EXPIRING_WINDOW }, resources::handle_static },
     context::state::AppState,
-    handler::auth::{ AuthHandler, IAuthHandler, PrincipalType },
+    handler::auth::{ AuthHandler, IAuthHandler, PrincipalType, ProviderUserInfo, AUTH_STATE_EXPIRE_SECS },
     types::{
         auth::{
-            CallbackGithubRequest,
-            CallbackOidcRequest,
+            DebugQuery,
+            DebugWhoAmIResponse,
             EthersWalletLoginRequest,
+            ExtAuthzRequest,
+            ExtAuthzResponse,
             GithubUserInfo,
             LogoutRequest,
+            RefreshTokenRequest,
+            OAuthCallbackError,
+            OAuthCallbackParams,
             PasswordLoginRequest,
+            EmailLoginRequest,
             PasswordPubKeyRequest,
             PasswordPubKeyResponse,
+            TokenInvalidReason,
+            ValidateTokenResponse,
         },
+        build_envelope,
+        ApiVersion,
         RespBase,
     },
     utils::{ self, auths::{ self, AuthUserClaims, SecurityContext }, webs },
 };
 
+use super::config::CONFIG_FEATURES_URI;
 use super::ValidatedJson;
 
 pub const ROOT_URI: &str = "/";
 pub const AUTH_PASSWORD_PUBKEY_URI: &str = "/auth/password/pubkey";
 pub const AUTH_PASSWORD_VERIFY_URI: &str = "/auth/password/verify";
+pub const AUTH_PASSWORD_LOGIN_URI: &str = "/auth/password/login";
 pub const AUTH_CONNECT_OIDC_URI: &str = "/auth/connect/oidc";
 pub const AUTH_CONNECT_GITHUB_URI: &str = "/auth/connect/github";
 pub const AUTH_CALLBACK_OIDC_URI: &str = "/auth/callback/oidc";
 pub const AUTH_CALLBACK_GITHUB_URI: &str = "/auth/callback/github";
 pub const AUTH_WALLET_ETHERS_VERIFY_URI: &str = "/auth/wallet/ethers/verify";
 pub const AUTH_LOGOUT_URI: &str = "/auth/logout";
+pub const AUTH_LOGOUT_ALL_URI: &str = "/auth/logout/all";
+pub const AUTH_TOKEN_REFRESH_URI: &str = "/auth/token/refresh";
+pub const AUTH_DEBUG_WHOAMI_URI: &str = "/auth/debug/whoami";
+pub const AUTH_VALIDATE_URI: &str = "/auth/validate";
 pub const STATIC_RESOURCES_URI: &str = "/static/*file";
 
-pub const EXCLUDED_PATHS: [&str; 8] = [
+pub const EXCLUDED_PATHS: [&str; 13] = [
     AUTH_PASSWORD_PUBKEY_URI,
     AUTH_PASSWORD_VERIFY_URI,
+    AUTH_PASSWORD_LOGIN_URI,
     AUTH_CONNECT_OIDC_URI,
     AUTH_CONNECT_GITHUB_URI,
     AUTH_CALLBACK_OIDC_URI,
     AUTH_CALLBACK_GITHUB_URI,
     AUTH_WALLET_ETHERS_VERIFY_URI,
+    // It reports the invalid (e.g. expired) tokens too, and is only enabled in dev profile.
+    AUTH_DEBUG_WHOAMI_URI,
+    // It reports the validity of token itself without any auth side effects.
+    AUTH_VALIDATE_URI,
+    // The access token is usually expired when refreshing, the refresh token is validated instead.
+    AUTH_TOKEN_REFRESH_URI,
+    // The login page adapts to the enabled providers before authenticated.
+    CONFIG_FEATURES_URI,
     STATIC_RESOURCES_URI,
 ];
 
 pub const CSRF_TOKEN_NAME: &str = "csrf_token";
+// The response header of the remaining seconds of the access token which is about to expire.
+pub const TOKEN_EXPIRING_HEADER: &str = "X-Token-Expiring";
+// The cookie of oauth2 authorization session id, which the cached 'state' and 'nonce' belong to.
+pub const AUTH_SID_COOKIE_NAME: &str = "_auth_sid";
 
 pub fn init() -> Router<AppState> {
     Router::new()
         //.route(ROOT_URI, get(handle_page_root))
         .route(AUTH_PASSWORD_PUBKEY_URI, post(handle_password_pubkey))
         .route(AUTH_PASSWORD_VERIFY_URI, post(handle_password_verify))
+        .route(AUTH_PASSWORD_LOGIN_URI, post(handle_password_login))
         .route(AUTH_CONNECT_OIDC_URI, get(handle_connect_oidc))
         .route(AUTH_CONNECT_GITHUB_URI, get(handle_connect_github))
         .route(AUTH_CALLBACK_OIDC_URI, get(handle_callback_oidc))
         .route(AUTH_CALLBACK_GITHUB_URI, get(handle_callback_github))
         .route(AUTH_WALLET_ETHERS_VERIFY_URI, post(handle_wallet_ethers_verify))
         .route(AUTH_LOGOUT_URI, get(handle_logout))
+        .route(AUTH_LOGOUT_ALL_URI, post(handle_logout_all))
+        .route(AUTH_TOKEN_REFRESH_URI, post(handle_refresh_token))
+        .route(AUTH_DEBUG_WHOAMI_URI, get(handle_debug_whoami))
+        .route(AUTH_VALIDATE_URI, get(handle_validate_token))
         .route(STATIC_RESOURCES_URI, get(handle_static))
         .fallback(handle_page_404) // Global auto internal forwarding when not found.
         .layer(CookieManagerLayer::new())
@@ -109,10 +147,11 @@ pub fn init() -> Router<AppState> {
 
 pub async fn auth_middleware(
     State(state): State<AppState>,
-    req: Request<Body>,
+    mut req: Request<Body>,
     next: Next
 ) -> impl IntoResponse {
-    let path = auths::clean_context_path(&state.config.server.context_path, req.uri().path());
+    let uri = req.uri().clone();
+    let path = auths::clean_context_path(&state.config.server.context_path, uri.path());
 
     // 1. Exclude paths that don't require authentication.
     // 1.1 Paths that must be excluded according to the authentication mechanism's requirements.
@@ -133,39 +172,29 @@ pub async fn auth_middleware(
     }
 
     // 2. Verify for bearer token.
-    let (is_authenticated, claims) = if let Some(auth_header) = req.headers().get("Authorization") {
-        // 2.1 with Header
-        if let std::result::Result::Ok(auth_str) = auth_header.to_str() {
-            if auth_str.starts_with("Bearer ") {
-                let ak = &auth_str[7..];
-                validate_token(&state, ak).await
-            } else {
-                // for compatibility no 'Bearer' prefix.
-                validate_token(&state, auth_str).await
-            }
-        } else {
-            (false, None)
-        }
-    } else {
-        // 2.2 with Cookie
-        let ak = req
-            .headers()
-            .get("Cookie")
-            .map(|c| {
-                let cookie_str = String::from_utf8(c.as_bytes().to_vec()).unwrap();
-                webs::get_cookie_from_str(cookie_str.as_str(), &state.config.auth_jwt_ak_name)
-            })
-            .unwrap_or(None);
-        if ak.is_some() {
-            validate_token(&state, ak.unwrap().as_str()).await
-        } else {
-            (false, None)
-        }
+    let token = get_request_token(&state, req.headers());
+
+    // 2.1 Reject the oversized token early, avoid to decode it.
+    if let Some(ak) = token.as_deref().filter(|ak| auths::is_jwt_oversized(&state.config, ak)) {
+        tracing::warn!("Rejected the oversized token of {} bytes.", ak.len());
+        let status = StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE;
+        let errmsg = format!("Token too large, the max is {} bytes", state.config.auth.jwt_max_bytes.unwrap_or_default());
+        let body = build_envelope(ApiVersion::current(), status.as_u16() as i64, &errmsg);
+        return (status, Json(body)).into_response();
+    }
+
+    let (is_authenticated, claims) = match token {
+        Some(ak) => validate_token(&state, ak.as_str()).await,
+        None => (false, None),
     };
 
     if is_authenticated {
         // 3. Bind authenticated info to context.
         tracing::info!("Authenticated user: {:?}", claims);
+        let exp = claims.as_ref().map(|c| c.exp);
+        if let Some(claims) = &claims {
+            req.extensions_mut().insert(claims.clone());
+        }
         SecurityContext::get_instance().bind(claims).await;
 
         // If logged in, and redirect to home page
@@ -181,7 +210,11 @@ pub async fn auth_middleware(
         }
 
         // 4. Pass to call next routes.
-        return next.run(req).await;
+        let mut response = next.run(req).await;
+        if let Some(exp) = exp {
+            set_token_expiring_header(&state.config, exp, &mut response);
+        }
+        return response;
     }
 
     // 5. Unauthenticated Response.
@@ -195,32 +228,107 @@ pub async fn auth_middleware(
     )
 }
 
-async fn validate_token(state: &AppState, ak: &str) -> (bool, Option<AuthUserClaims>) {
-    // 1. Verify the token is valid.
-    match auths::validate_jwt(&state.config, ak) {
-        std::result::Result::Ok(claims) => {
-            let exp = time::OffsetDateTime::from_unix_timestamp(claims.exp as i64).unwrap();
-            let now = time::OffsetDateTime::now_utc();
-            if exp > now {
-                // 2. Verify whether the token is in the cancelled blacklist.
-                let cache = state.string_cache.get(&state.config);
-                match cache.get(get_auth_handler(state).build_logout_blacklist_key(ak)).await {
-                    std::result::Result::Ok(logout) => {
-                        tracing::warn!("Invalid the token because in blacklist for {}", ak);
-                        (logout.is_none(), Some(claims))
-                    }
-                    Err(_) => {
-                        tracing::debug!("Valid the token because not in blacklist for {}", ak);
-                        (true, Some(claims))
-                    }
+// ----- Global external authorization interceptors. -----
+
+pub async fn ext_authz_middleware(
+    State(state): State<AppState>,
+    req: Request<Body>,
+    next: Next
+) -> impl IntoResponse {
+    let ext_authz = &state.config.auth.ext_authz;
+    let url = match &ext_authz.url {
+        Some(url) if ext_authz.enabled.unwrap_or(false) => url,
+        _ => {
+            return next.run(req).await;
+        }
+    };
+    let path = auths::clean_context_path(&state.config.server.context_path, req.uri().path());
+    if
+        EXCLUDED_PATHS.contains(&path) ||
+        state.config.ext_authz_bypass_matcher
+            .as_ref()
+            .map(|glob| glob.is_match(path))
+            .unwrap_or(false)
+    {
+        return next.run(req).await;
+    }
+
+    let authz_req = ExtAuthzRequest {
+        method: req.method().to_string(),
+        path: path.to_string(),
+        // The user of this request bound by the auth_middleware, which runs before.
+        user: req.extensions().get::<AuthUserClaims>().cloned(),
+    };
+    let result = state.default_http_client
+        .post(url)
+        .timeout(std::time::Duration::from_millis(ext_authz.timeout.unwrap_or(3000)))
+        .json(&authz_req)
+        .send().await
+        .and_then(|resp| resp.error_for_status());
+    let allow = match result {
+        Ok(resp) =>
+            match resp.json::<ExtAuthzResponse>().await {
+                Ok(authz_resp) => authz_resp.allow,
+                Err(e) => {
+                    tracing::warn!("Invalid external authorization response. reason: {:?}", e);
+                    false
                 }
-            } else {
-                tracing::debug!("Valid the token for {}", ak);
-                (false, Some(claims))
             }
+        Err(e) => {
+            // Fail closed, the unavailable authorizer denies all.
+            tracing::warn!("Failed to call external authorization. reason: {:?}", e);
+            false
         }
-        Err(_) => {
-            tracing::warn!("Invalid the token because expired for {}", ak);
+    };
+    if allow {
+        return next.run(req).await;
+    }
+
+    tracing::info!("Denied by external authorization: {} {}", authz_req.method, authz_req.path);
+    utils::auths::auth_resp_redirect_or_json(
+        &state.config,
+        req.headers(),
+        state.config.auth.unauthz_url.to_owned().unwrap().as_str(),
+        StatusCode::FORBIDDEN,
+        "Forbidden",
+        None
+    )
+}
+
+// Get the access token of request from the header first, and then the cookie.
+fn get_request_token(state: &AppState, headers: &HeaderMap) -> Option<String> {
+    if let Some(auth_header) = headers.get("Authorization") {
+        // 1. with Header
+        let auth_str = auth_header.to_str().ok()?;
+        // for compatibility no 'Bearer' prefix.
+        Some(auth_str.strip_prefix("Bearer ").unwrap_or(auth_str).to_string())
+    } else {
+        // 2. with Cookie
+        headers
+            .get("Cookie")
+            .map(|c| {
+                let cookie_str = String::from_utf8(c.as_bytes().to_vec()).unwrap();
+                webs::get_cookie_from_str(cookie_str.as_str(), &state.config.auth_jwt_ak_name)
+            })
+            .unwrap_or(None)
+    }
+}
+
+// Hint the client to refresh the access token which expires within the window.
+fn set_token_expiring_header(config: &WebServeConfig, exp: usize, response: &mut Response<Body>) {
+    let window_secs = (config.auth.jwt_expiring_window.unwrap_or(DEFAULT_JWT_EXPIRING_WINDOW) / 1000) as i64;
+    let remaining = (exp as i64) - Utc::now().timestamp();
+    if window_secs > 0 && remaining <= window_secs {
+        response.headers_mut().insert(TOKEN_EXPIRING_HEADER, header::HeaderValue::from(remaining.max(0)));
+    }
+}
+
+async fn validate_token(state: &AppState, ak: &str) -> (bool, Option<AuthUserClaims>) {
+    // Verify the signature, expiry and whether the token is in the cancelled blacklist.
+    match auths::validate_jwt_with_blacklist(state, ak).await {
+        Ok(claims) => (true, Some(claims)),
+        Err(reason) => {
+            tracing::warn!("Invalid the token because {:?} for {}", reason, ak);
             (false, None)
         }
     }
@@ -293,6 +401,7 @@ pub async fn handle_password_verify(
     request: axum::extract::Request<Body>
 ) -> impl IntoResponse {
     let headers = &request.headers().clone();
+    let peer = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|c| c.0);
     let body = request.into_body();
 
     let param: PasswordLoginRequest = match
@@ -327,18 +436,42 @@ pub async fn handle_password_verify(
         }
     };
 
-    match get_auth_handler(&state).handle_password_verify(param).await {
+    let handler = get_auth_handler(&state);
+    let mut throttle_subjects = vec![format!("account:{}", param.username)];
+    if let Some(ip) = get_request_client_ip(&state, peer, headers) {
+        throttle_subjects.push(format!("ip:{}", ip));
+    }
+    match handler.handle_login_throttle_check(&throttle_subjects).await {
+        Ok(Some(retry_after)) => {
+            return login_locked_response(&state, headers, retry_after);
+        }
+        Ok(None) => {}
+        Err(e) => tracing::warn!("Unable to check login throttle. reason: {:?}", e),
+    }
+
+    match handler.handle_password_verify(param).await {
         Ok(user) => {
-            get_auth_handler(&state).handle_login_success(
+            if let Err(e) = handler.handle_login_throttle_reset(&throttle_subjects).await {
+                tracing::warn!("Unable to reset login throttle. reason: {:?}", e);
+            }
+            handler.handle_login_success(
                 &state.config,
                 PrincipalType::Password,
                 user.base.id.unwrap(),
                 &user.name.to_owned().unwrap_or_default().to_string(),
                 &user.email.to_owned().unwrap_or_default().to_string(),
+                get_request_client_ip(&state, peer, headers),
                 &headers
-            ).await
+            ).await.unwrap_or_else(|e| login_error_response(&state, headers, e))
         }
         Err(e) => {
+            match handler.handle_login_throttle_failure(&throttle_subjects).await {
+                Ok(Some(retry_after)) => {
+                    return login_locked_response(&state, headers, retry_after);
+                }
+                Ok(None) => {}
+                Err(e) => tracing::warn!("Unable to record login failure. reason: {:?}", e),
+            }
             let errmsg = format!("Failed to login. {:?}", e.to_string());
             tracing::warn!("{}", errmsg);
             let result = RespBase::errmsg(errmsg.as_str());
@@ -347,6 +480,101 @@ pub async fn handle_password_verify(
     }
 }
 
+#[utoipa::path(
+    post,
+    path = AUTH_PASSWORD_LOGIN_URI,
+    request_body = EmailLoginRequest,
+    responses(
+        (status = 200, description = "Email/password login."),
+        (status = 401, description = "Invalid email or password."),
+        (status = 429, description = "Too many login failures.")
+    ),
+    tag = "Authentication"
+)]
+async fn handle_password_login(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    ValidatedJson(param): ValidatedJson<EmailLoginRequest>
+) -> impl IntoResponse {
+    let handler = get_auth_handler(&state);
+    let client_ip = get_request_client_ip(&state, connect_info.map(|c| c.0), &headers);
+    let mut throttle_subjects = vec![format!("account:{}", param.email)];
+    if let Some(ip) = &client_ip {
+        throttle_subjects.push(format!("ip:{}", ip));
+    }
+    match handler.handle_login_throttle_check(&throttle_subjects).await {
+        Ok(Some(retry_after)) => {
+            return login_locked_response(&state, &headers, retry_after);
+        }
+        Ok(None) => {}
+        Err(e) => tracing::warn!("Unable to check login throttle. reason: {:?}", e),
+    }
+
+    match handler.handle_login_password(&param.email, &param.password, client_ip, &headers).await {
+        Ok(response) => {
+            if let Err(e) = handler.handle_login_throttle_reset(&throttle_subjects).await {
+                tracing::warn!("Unable to reset login throttle. reason: {:?}", e);
+            }
+            response
+        }
+        // The token issuing failure is of the server, which is not counted as the login failure.
+        Err(e) if e.is::<jsonwebtoken::errors::Error>() => login_error_response(&state, &headers, e),
+        Err(e) => {
+            match handler.handle_login_throttle_failure(&throttle_subjects).await {
+                Ok(Some(retry_after)) => {
+                    return login_locked_response(&state, &headers, retry_after);
+                }
+                Ok(None) => {}
+                Err(e) => tracing::warn!("Unable to record login failure. reason: {:?}", e),
+            }
+            auths::auth_resp_redirect_or_json(
+                &state.config,
+                &headers,
+                &state.config.auth.login_url.to_owned().unwrap(),
+                StatusCode::UNAUTHORIZED,
+                e.to_string().as_str(),
+                None
+            )
+        }
+    }
+}
+
+// The client ip of throttling and login auditing, which is the peer address, and the proxy headers are
+// trusted only if the peer is one of the configured trusted proxies.
+fn get_request_client_ip(state: &AppState, peer: Option<SocketAddr>, headers: &HeaderMap) -> Option<String> {
+    webs::get_trusted_client_ip(&state.config.server.trusted_proxies, peer.map(|p| p.ip()), headers)
+}
+
+fn login_error_response(state: &AppState, headers: &HeaderMap, e: anyhow::Error) -> axum::response::Response {
+    tracing::error!("Failed to issue the login tokens. reason: {:?}", e);
+    auths::auth_resp_redirect_or_json(
+        &state.config,
+        headers,
+        &state.config.auth.login_url.to_owned().unwrap(),
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to issue the login tokens",
+        None
+    )
+}
+
+fn login_locked_response(
+    state: &AppState,
+    headers: &HeaderMap,
+    retry_after: u64
+) -> axum::response::Response {
+    let mut response = auths::auth_resp_redirect_or_json(
+        &state.config,
+        headers,
+        &state.config.auth.login_url.to_owned().unwrap(),
+        StatusCode::TOO_MANY_REQUESTS,
+        "Too many login failures, please try again later",
+        None
+    );
+    response.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(retry_after));
+    response
+}
+
 // ----- OIDC/Github OAuth2 login. -----
 
 #[utoipa::path(
@@ -360,15 +588,52 @@ async fn handle_connect_oidc(
     headers: header::HeaderMap
 ) -> impl IntoResponse {
     match &state.oidc_client {
-        Some(client) => {
-            let (auth_url, csrf_token, nonce) = client
+        Some(discovery) => {
+            let client = match discovery.get().await {
+                Ok(client) => client,
+                Err(e) => {
+                    return auths::auth_resp_redirect_or_json(
+                        &state.config,
+                        &headers,
+                        &state.config.auth.login_url.to_owned().unwrap(),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("Failed to discover OIDC provider. {:?}", e).as_str(),
+                        None
+                    );
+                }
+            };
+            let sid = uuid::Uuid::new_v4().to_string();
+            let pkce_challenge = if state.config.auth.oidc.pkce.unwrap_or(true) {
+                match get_auth_handler(&state).handle_auth_create_pkce(&sid).await {
+                    Ok(challenge) => Some(challenge),
+                    Err(e) => {
+                        let errmsg = format!("Failed to create pkce. {:?}", e);
+                        tracing::error!(errmsg);
+                        return auths::auth_resp_redirect_or_json(
+                            &state.config,
+                            &headers,
+                            &state.config.auth.login_url.to_owned().unwrap(),
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            errmsg.as_str(),
+                            None
+                        );
+                    }
+                }
+            } else {
+                None
+            };
+
+            let mut request = client
                 .authorize_url(
                     CoreAuthenticationFlow::AuthorizationCode,
                     CsrfToken::new_random,
                     Nonce::new_random
                 )
-                .add_scope(Scope::new(state.config.auth.oidc.scope.clone().unwrap()))
-                .url();
+                .add_scope(Scope::new(state.config.auth.oidc.scope.clone().unwrap()));
+            if let Some(challenge) = pkce_challenge {
+                request = request.set_pkce_challenge(challenge);
+            }
+            let (auth_url, csrf_token, nonce) = request.url();
 
             tracing::debug!(
                 "Connecting to OIDC url: {}, csrf: {:?}, nonce: {:?}",
@@ -377,35 +642,25 @@ async fn handle_connect_oidc(
                 nonce
             );
 
-            match
-                get_auth_handler(&state).handle_auth_create_nonce(
-                    csrf_token.secret(),
-                    nonce.secret().to_string()
-                ).await
-            {
-                std::result::Result::Ok(_) => {
-                    // crsf 校验 nonce 的机制仅支持浏览器环境, 如 Android/iOS 等 CS 客户端可忽略.
-                    let csrf_cookie = CookieBuilder::new("_csrf_token", csrf_token.secret())
-                        .path("/")
-                        .http_only(true)
-                        //.secure(true) // true: indicates that only https requests will carry
-                        .max_age(
-                            Duration::milliseconds(
-                                state.config.auth.jwt_validity_ak.unwrap() as i64
-                            )
-                        )
-                        .build();
+            match create_auth_session(
+                &state,
+                &headers,
+                &sid,
+                &csrf_token,
+                Some(nonce.secret().to_string())
+            ).await {
+                std::result::Result::Ok(sid_cookie) => {
                     return auths::auth_resp_redirect_or_json(
                         &state.config,
                         &headers,
                         auth_url.as_str(),
                         StatusCode::OK,
                         "ok",
-                        Some((None, None, Some(csrf_cookie)))
+                        Some((None, None, Some(sid_cookie)))
                     );
                 }
                 Err(e) => {
-                    let errmsg = format!("Failed to create nonce. {:?}", e);
+                    let errmsg = format!("Failed to create auth session. {:?}", e);
                     tracing::error!(errmsg);
                     return auths::auth_resp_redirect_or_json(
                         &state.config,
@@ -443,18 +698,35 @@ async fn handle_connect_github(
 ) -> impl IntoResponse {
     match &state.github_client {
         Some(client) => {
-            let (auth_url, _) = client
+            let (auth_url, csrf_token) = client
                 .authorize_url(oauth2::CsrfToken::new_random)
                 .add_scope(Scope::new(state.config.auth.github.scope.clone().unwrap()))
                 .url();
-            return auths::auth_resp_redirect_or_json(
-                &state.config,
-                &headers,
-                auth_url.as_str(),
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "ok",
-                None
-            );
+            let sid = uuid::Uuid::new_v4().to_string();
+            match create_auth_session(&state, &headers, &sid, &csrf_token, None).await {
+                std::result::Result::Ok(sid_cookie) => {
+                    return auths::auth_resp_redirect_or_json(
+                        &state.config,
+                        &headers,
+                        auth_url.as_str(),
+                        StatusCode::OK,
+                        "ok",
+                        Some((None, None, Some(sid_cookie)))
+                    );
+                }
+                Err(e) => {
+                    let errmsg = format!("Failed to create auth session. {:?}", e);
+                    tracing::error!(errmsg);
+                    return auths::auth_resp_redirect_or_json(
+                        &state.config,
+                        &headers,
+                        &state.config.auth.login_url.to_owned().unwrap(),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        errmsg.as_str(),
+                        None
+                    );
+                }
+            }
         }
         None => {
             return auths::auth_resp_redirect_or_json(
@@ -469,36 +741,177 @@ async fn handle_connect_github(
     }
 }
 
+// Create the authorization session of the random id, which caches the 'state' (and 'nonce' of OIDC)
+// to be verified on the callback, and returns the session cookie. If the user has logged in, the
+// provider identity of the callback will be linked to the user.
+async fn create_auth_session(
+    state: &AppState,
+    headers: &header::HeaderMap,
+    sid: &str,
+    csrf_token: &CsrfToken,
+    nonce: Option<String>
+) -> Result<Cookie<'static>, anyhow::Error> {
+    let handler = get_auth_handler(state);
+    handler.handle_auth_create_state(sid, csrf_token.secret().to_owned()).await?;
+    if let Some(nonce) = nonce {
+        handler.handle_auth_create_nonce(sid, nonce).await?;
+    }
+    if let Some(ak) = get_request_token(state, headers) {
+        if let Ok(claims) = auths::validate_jwt_with_blacklist(state, &ak).await {
+            handler.handle_auth_create_link(sid, claims.uid).await?;
+        }
+    }
+    let mut sid_cookie = webs::build_cookie(
+        AUTH_SID_COOKIE_NAME,
+        sid,
+        Duration::seconds(AUTH_STATE_EXPIRE_SECS as i64)
+    );
+    // The IdP callback is a cross-site navigation, which the strict cookie is not sent.
+    sid_cookie.set_same_site(SameSite::Lax);
+    Ok(sid_cookie)
+}
+
+// Link the provider identity to the user who connected it after logged in, otherwise login by it.
+async fn link_or_login_provider_user(
+    state: &AppState,
+    headers: &header::HeaderMap,
+    userinfo: &dyn ProviderUserInfo
+) -> Result<i64, anyhow::Error> {
+    let handler = get_auth_handler(state);
+    let sid = webs::get_cookie_from_headers(AUTH_SID_COOKIE_NAME, headers).unwrap_or_default();
+    match handler.handle_auth_take_link(&sid).await? {
+        Some(uid) => handler.handle_link_identity(uid, userinfo).await,
+        None => handler.handle_provider_callback(userinfo).await,
+    }
+}
+
+// Verify the 'state' of the callback is the one created for the session, otherwise rejected as CSRF.
+async fn verify_callback_state(
+    state: &AppState,
+    headers: &header::HeaderMap,
+    callback: &ValidatedCallback
+) -> Result<(), Response<Body>> {
+    let sid = webs::get_cookie_from_headers(AUTH_SID_COOKIE_NAME, headers).unwrap_or_default();
+    match get_auth_handler(state).handle_auth_verify_state(&sid, callback.params.state.as_deref()).await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            tracing::warn!("Rejected the oauth2 callback of invalid state. {}", e);
+            Err(
+                auths::auth_resp_redirect_or_json(
+                    &state.config,
+                    headers,
+                    &state.config.auth.login_url.to_owned().unwrap(),
+                    StatusCode::FORBIDDEN,
+                    e.to_string().as_str(),
+                    None
+                )
+            )
+        }
+    }
+}
+
+// ----- OAuth2 callbacks. -----
+
+// The validated callback of the OIDC/Github providers, which has the authorization code, otherwise the
+// provider returned error (or missing code) is rejected as the failure redirect of login page.
+pub struct ValidatedCallback {
+    pub code: String,
+    pub params: OAuthCallbackParams,
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for ValidatedCallback {
+    type Rejection = Response<Body>;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let Query(params) = Query::<OAuthCallbackParams>
+            ::from_request_parts(parts, state).await
+            .map_err(|e| e.into_response())?;
+        match params.validate_code() {
+            Ok(code) => Ok(ValidatedCallback { code, params }),
+            Err(e) => {
+                let status = match e {
+                    OAuthCallbackError::AccessDenied => StatusCode::FORBIDDEN,
+                    OAuthCallbackError::Provider(_) | OAuthCallbackError::MissingCode => StatusCode::BAD_REQUEST,
+                };
+                tracing::warn!("Rejected the oauth2 callback of {}. {}", parts.uri.path(), e);
+                Err(
+                    auths::auth_resp_redirect_or_json(
+                        &state.config,
+                        &parts.headers,
+                        &state.config.auth.login_url.to_owned().unwrap(),
+                        status,
+                        e.to_string().as_str(),
+                        None
+                    )
+                )
+            }
+        }
+    }
+}
+
 #[utoipa::path(
     get,
     path = AUTH_CALLBACK_OIDC_URI,
+    params(OAuthCallbackParams),
     responses((status = 200, description = "Callback for OIDC.")),
     tag = "Authentication"
 )]
 async fn handle_callback_oidc(
     State(state): State<AppState>,
-    Query(param): Query<CallbackOidcRequest>,
+    callback: ValidatedCallback,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
     headers: header::HeaderMap
 ) -> impl IntoResponse {
+    if let Err(resp) = verify_callback_state(&state, &headers, &callback).await {
+        return resp;
+    }
     match &state.oidc_client {
-        Some(client) => {
-            let code = match param.code {
-                Some(code) => code,
-                None => {
+        Some(discovery) => {
+            let client = match discovery.get().await {
+                Ok(client) => client,
+                Err(e) => {
                     return auths::auth_resp_redirect_or_json(
                         &state.config,
                         &headers,
                         &state.config.auth.login_url.to_owned().unwrap(),
-                        StatusCode::BAD_REQUEST,
-                        format!("Missing authentication code").as_str(),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("Failed to discover OIDC provider. {:?}", e).as_str(),
                         None
                     );
                 }
             };
+            let code = callback.code;
 
-            let token_result: Result<CoreTokenResponse, _> = client
-                .exchange_code(AuthorizationCode::new(code))
-                .request_async(async_http_client).await;
+            let pkce_verifier = if state.config.auth.oidc.pkce.unwrap_or(true) {
+                let sid = webs::get_cookie_from_headers(AUTH_SID_COOKIE_NAME, &headers).unwrap_or_default();
+                match get_auth_handler(&state).handle_auth_take_pkce_verifier(&sid).await {
+                    Ok(Some(verifier)) => Some(verifier),
+                    result => {
+                        let errmsg = match result {
+                            Err(e) => format!("Failed to get pkce verifier. {:?}", e),
+                            _ => "Expired or used pkce verifier".to_string(),
+                        };
+                        tracing::warn!(errmsg);
+                        return auths::auth_resp_redirect_or_json(
+                            &state.config,
+                            &headers,
+                            &state.config.auth.login_url.to_owned().unwrap(),
+                            StatusCode::FORBIDDEN,
+                            errmsg.as_str(),
+                            None
+                        );
+                    }
+                }
+            } else {
+                None
+            };
+
+            let mut request = client.exchange_code(AuthorizationCode::new(code));
+            if let Some(verifier) = pkce_verifier {
+                request = request.set_pkce_verifier(verifier);
+            }
+            let token_result: Result<CoreTokenResponse, _> = request.request_async(async_http_client).await;
 
             match token_result {
                 Ok(token_response) => {
@@ -591,23 +1004,10 @@ async fn handle_callback_oidc(
                         }
                     };
 
-                    //let oidc_sub = claims.subject().to_string();
-                    let oidc_name = userinfo
-                        .preferred_username()
-                        .map(|c| c.to_string())
-                        .unwrap_or_default();
-                    let oidc_email = userinfo
-                        .email()
-                        .map(|c| c.to_string())
-                        .unwrap_or_default();
-
                     tracing::debug!("Received oidc user info: {:?}", userinfo);
-                    // tracing::debug!("User oidc subject: {:?}", oidc_name);
-                    // tracing::debug!("User oidc name: {:?}", oidc_name);
-                    // tracing::debug!("User oidc email: {:?}", oidc_email);
 
                     let result = match
-                        get_auth_handler(&state).handle_auth_callback_oidc(userinfo).await
+                        link_or_login_provider_user(&state, &headers, &userinfo).await
                     {
                         Ok(uid) => {
                             if uid > 0 {
@@ -615,10 +1015,11 @@ async fn handle_callback_oidc(
                                     &state.config,
                                     PrincipalType::OIDC,
                                     uid,
-                                    &oidc_name,
-                                    &oidc_email,
+                                    ProviderUserInfo::display_name(&userinfo).unwrap_or_default().as_str(),
+                                    ProviderUserInfo::email(&userinfo).unwrap_or_default().as_str(),
+                                    get_request_client_ip(&state, connect_info.map(|c| c.0), &headers),
                                     &headers
-                                ).await
+                                ).await.unwrap_or_else(|e| login_error_response(&state, &headers, e))
                             } else {
                                 return auths::auth_resp_redirect_or_json(
                                     &state.config,
@@ -671,27 +1072,40 @@ async fn handle_callback_oidc(
 #[utoipa::path(
     get,
     path = AUTH_CALLBACK_GITHUB_URI,
+    params(OAuthCallbackParams),
     responses((status = 200, description = "Callback for github.")),
     tag = "Authentication"
 )]
 async fn handle_callback_github(
     State(state): State<AppState>,
-    Query(param): Query<CallbackGithubRequest>,
+    callback: ValidatedCallback,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
     headers: HeaderMap
 ) -> impl IntoResponse {
+    if let Err(resp) = verify_callback_state(&state, &headers, &callback).await {
+        return resp;
+    }
     match &state.github_client {
         Some(client) => {
             let token_result = client
-                .exchange_code(
-                    AuthorizationCode::new(param.code.expect("Missing authorization code"))
-                )
+                .exchange_code(AuthorizationCode::new(callback.code))
                 .request_async(oauth2::reqwest::async_http_client).await;
 
             match token_result {
                 Ok(token) => {
-                    let url = state.config.auth.github.user_info_url
-                        .clone()
-                        .expect("Missing 'user_info_url' configure");
+                    let url = match state.config.auth.github.user_info_url.clone() {
+                        Some(url) => url,
+                        None => {
+                            return auths::auth_resp_redirect_or_json(
+                                &state.config,
+                                &headers,
+                                &state.config.auth.login_url.to_owned().unwrap(),
+                                StatusCode::INTERNAL_SERVER_ERROR,
+                                "Missing 'user_info_url' configured",
+                                None
+                            );
+                        }
+                    };
 
                     // see:https://docs.github.com/en/rest/users/users?apiVersion=2022-11-28#get-a-user
                     let resp = match
@@ -735,21 +1149,21 @@ async fn handle_callback_github(
                         }
                     };
                     tracing::info!("Received github user info {:?}", user_info);
-
-                    let github_sub = user_info.id;
-                    let github_uname = user_info.login;
-                    let github_email = user_info.email;
-                    let github_user = GithubUserInfo::default(
-                        github_sub,
-                        github_uname.to_owned(),
-                        github_email.to_owned()
-                    );
+                    // The malformed upstream payload is the bad gateway rather than the internal error.
+                    if user_info.id.is_none() || user_info.login.is_none() {
+                        return auths::auth_resp_redirect_or_json(
+                            &state.config,
+                            &headers,
+                            &state.config.auth.login_url.to_owned().unwrap(),
+                            StatusCode::BAD_GATEWAY,
+                            "Invalid github user info, missing the id or login",
+                            None
+                        );
+                    }
 
                     // TODO: using dependency injection to get the handler
                     let result = match
-                        get_auth_handler(&state).handle_auth_callback_github(
-                            github_user.clone()
-                        ).await
+                        link_or_login_provider_user(&state, &headers, &user_info).await
                     {
                         Ok(uid) => {
                             if uid > 0 {
@@ -757,10 +1171,11 @@ async fn handle_callback_github(
                                     &state.config,
                                     PrincipalType::Github,
                                     uid,
-                                    github_uname.unwrap_or_default().as_str(),
-                                    github_email.unwrap_or_default().as_str(),
+                                    user_info.login.unwrap_or_default().as_str(),
+                                    user_info.email.unwrap_or_default().as_str(),
+                                    get_request_client_ip(&state, connect_info.map(|c| c.0), &headers),
                                     &headers
-                                ).await
+                                ).await.unwrap_or_else(|e| login_error_response(&state, &headers, e))
                             } else {
                                 return auths::auth_resp_redirect_or_json(
                                     &state.config,
@@ -838,6 +1253,7 @@ pub async fn handle_wallet_ethers_verify(
     request: axum::extract::Request<Body>
 ) -> impl IntoResponse {
     let headers = &request.headers().clone();
+    let peer = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|c| c.0);
     let body = request.into_body();
 
     let param: EthersWalletLoginRequest = match
@@ -883,8 +1299,9 @@ pub async fn handle_wallet_ethers_verify(
                 uid,
                 "",
                 "",
+                get_request_client_ip(&state, peer, headers),
                 &headers
-            ).await
+            ).await.unwrap_or_else(|e| login_error_response(&state, headers, e))
         }
         Err(e) => {
             let errmsg = format!("Failed to login. {:?}", e.to_string());
@@ -895,6 +1312,91 @@ pub async fn handle_wallet_ethers_verify(
     }
 }
 
+// ----- Debug. -----
+
+async fn handle_debug_whoami(
+    State(state): State<AppState>,
+    Query(query): Query<DebugQuery>,
+    headers: HeaderMap
+) -> impl IntoResponse {
+    // Disabled entirely in production, as if the route doesn't exist.
+    if state.config.profile != RunProfile::Dev {
+        return handle_page_404().await.into_response();
+    }
+
+    let mut resp = DebugWhoAmIResponse { claims: None, expired: false, blacklisted: false, error: None };
+    let ak = match get_request_token(&state, &headers) {
+        Some(ak) => ak,
+        None => {
+            resp.error = Some("No token in the header or cookie".to_string());
+            return webs::debug_json_response(&state.config, query.pretty, &resp);
+        }
+    };
+    match auths::decode_jwt_allow_expired(&state.config, &ak) {
+        Ok(mut claims) => {
+            resp.expired = (claims.exp as i64) <= time::OffsetDateTime::now_utc().unix_timestamp();
+            let key = get_auth_handler(&state).build_logout_blacklist_key(&ak);
+            resp.blacklisted = matches!(state.string_cache.get(&state.config).get(key).await, Ok(Some(_)));
+            // Don't expose the sensitive extra values.
+            if let Some(ext) = claims.ext.as_mut() {
+                ext.values_mut().for_each(|v| *v = "******".to_string());
+            }
+            resp.claims = Some(claims);
+        }
+        Err(e) => resp.error = Some(e.to_string()),
+    }
+    webs::debug_json_response(&state.config, query.pretty, &resp)
+}
+
+// ----- Token validation. -----
+
+#[utoipa::path(
+    get,
+    path = AUTH_VALIDATE_URI,
+    responses(
+        (status = 200, description = "The token is valid.", body = ValidateTokenResponse),
+        (status = 401, description = "The token is invalid.", body = ValidateTokenResponse),
+        (status = 429, description = "Too many validating requests.")
+    ),
+    tag = "Authentication"
+)]
+async fn handle_validate_token(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap
+) -> impl IntoResponse {
+    let client_ip = get_request_client_ip(&state, connect_info.map(|c| c.0), &headers);
+    let subject = format!("ip:{}", client_ip.unwrap_or_default());
+    match get_auth_handler(&state).handle_validate_rate_limit(&subject).await {
+        Ok(Some(retry_after)) => {
+            let status = StatusCode::TOO_MANY_REQUESTS;
+            let body = build_envelope(ApiVersion::current(), status.as_u16() as i64, "Too many validating requests");
+            let mut response = (status, Json(body)).into_response();
+            response.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(retry_after));
+            return response;
+        }
+        Ok(None) => {}
+        Err(e) => tracing::warn!("Unable to check validate rate limit. reason: {:?}", e),
+    }
+
+    // Neither binding the security context nor issuing cookies.
+    let resp = match get_request_token(&state, &headers) {
+        Some(ak) => auths::validate_jwt_with_blacklist(&state, &ak).await,
+        None => Err(TokenInvalidReason::Missing),
+    };
+    match resp {
+        Ok(claims) => {
+            let expires_in = (claims.exp as i64) - time::OffsetDateTime::now_utc().unix_timestamp();
+            let resp = ValidateTokenResponse { valid: true, expires_in: Some(expires_in), reason: None };
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(reason) => {
+            let resp = ValidateTokenResponse { valid: false, expires_in: None, reason: Some(reason) };
+            (StatusCode::UNAUTHORIZED, Json(resp)).into_response()
+        }
+    }
+}
+
 // ----- Logout. -----
 
 #[utoipa::path(
@@ -919,12 +1421,8 @@ async fn handle_logout(
 
     match get_auth_handler(&state).handle_logout(logout).await {
         Ok(_) => {
-            let removal_ak = CookieBuilder::new(state.config.auth_jwt_ak_name.to_string(), "_")
-                .removal()
-                .build();
-            let removal_rk = CookieBuilder::new(state.config.auth_jwt_rk_name.to_string(), "_")
-                .removal()
-                .build();
+            let removal_ak = webs::build_auth_removal_cookie(&state.config, &state.config.auth_jwt_ak_name);
+            let removal_rk = webs::build_auth_removal_cookie(&state.config, &state.config.auth_jwt_rk_name);
 
             auths::auth_resp_redirect_or_json(
                 &state.config,
@@ -949,7 +1447,506 @@ async fn handle_logout(
     }
 }
 
+#[utoipa::path(
+    post,
+    path = AUTH_LOGOUT_ALL_URI,
+    responses((status = 200, description = "Logout everywhere, revoked all the tokens of current user.")),
+    tag = "Authentication"
+)]
+async fn handle_logout_all(
+    State(state): State<AppState>,
+    claims: Option<AuthUserClaims>,
+    headers: header::HeaderMap
+) -> impl IntoResponse {
+    let result = match claims {
+        Some(claims) => get_auth_handler(&state).handle_logout_all(&claims.uid.to_string()).await,
+        None => Err(anyhow::Error::msg("No current user")),
+    };
+    match result {
+        Ok(_) => {
+            let removal_ak = webs::build_auth_removal_cookie(&state.config, &state.config.auth_jwt_ak_name);
+            let removal_rk = webs::build_auth_removal_cookie(&state.config, &state.config.auth_jwt_rk_name);
+
+            auths::auth_resp_redirect_or_json(
+                &state.config,
+                &headers,
+                state.config.auth.login_url.to_owned().unwrap().as_str(),
+                StatusCode::OK,
+                "Logged out everywhere",
+                Some((Some(removal_ak), Some(removal_rk), None))
+            )
+        }
+        Err(e) => {
+            tracing::error!("Failed to logout all. {:?}", e);
+            auths::auth_resp_redirect_or_json(
+                &state.config,
+                &headers,
+                &state.config.auth.login_url.to_owned().unwrap(),
+                StatusCode::BAD_REQUEST,
+                e.to_string().as_str(),
+                None
+            )
+        }
+    }
+}
+
+// ----- Refresh token. -----
+
+#[utoipa::path(
+    post,
+    path = AUTH_TOKEN_REFRESH_URI,
+    request_body = RefreshTokenRequest,
+    responses(
+        (status = 200, description = "Refreshed the access token."),
+        (status = 401, description = "The refresh token is invalid, expired or revoked.")
+    ),
+    tag = "Authentication"
+)]
+async fn handle_refresh_token(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    param: Option<Json<RefreshTokenRequest>>
+) -> impl IntoResponse {
+    // The refresh token is never accepted from the query string, which would be leaked by the access logs,
+    // the browser history and the Referer header.
+    let refresh_token = param
+        .and_then(|Json(param)| param.refresh_token)
+        .or_else(|| webs::get_cookie_from_headers(&state.config.auth_jwt_rk_name, &headers));
+    let refresh_token = match refresh_token {
+        Some(rk) => rk,
+        None => {
+            return auths::auth_resp_redirect_or_json(
+                &state.config,
+                &headers,
+                &state.config.auth.login_url.to_owned().unwrap(),
+                StatusCode::UNAUTHORIZED,
+                "Missing refresh token",
+                None
+            );
+        }
+    };
+
+    match get_auth_handler(&state).handle_refresh_token(&refresh_token, &headers).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            tracing::warn!("Failed to refresh token. {}", e);
+            auths::auth_resp_redirect_or_json(
+                &state.config,
+                &headers,
+                &state.config.auth.login_url.to_owned().unwrap(),
+                StatusCode::UNAUTHORIZED,
+                e.to_string().as_str(),
+                None
+            )
+        }
+    }
+}
+
 fn get_auth_handler(state: &AppState) -> Box<dyn IAuthHandler + '_> {
     // TODO: using dependency injection to get the handler
     Box::new(AuthHandler::new(state))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower::ServiceExt;
+    use crate::context::state::tests::new_test_state;
+
+    // The mock authorizer denies the path '/denied', and the path '/owner' except the user 7.
+    async fn start_mock_authorizer() -> String {
+        let app = Router::new().route(
+            "/authz",
+            post(|Json(req): Json<ExtAuthzRequest>| async move {
+                let owner = req.path != "/owner" || req.user.is_some_and(|u| u.uid == 7);
+                Json(ExtAuthzResponse { allow: req.path != "/denied" && owner })
+            })
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}/authz", addr)
+    }
+
+    async fn get_status(app: Router, uri: &str) -> StatusCode {
+        let response = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap()).await
+            .unwrap();
+        response.status()
+    }
+
+    async fn new_app(customize: impl FnOnce(&mut crate::config::config_serve::WebServeProperties)) -> Router {
+        let state = new_test_state(customize).await;
+        Router::new()
+            .route("/allowed", get(|| async { "ok" }))
+            .route("/denied", get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn_with_state(state, ext_authz_middleware))
+    }
+
+    #[tokio::test]
+    async fn test_ext_authz_allow_and_deny() {
+        let url = start_mock_authorizer().await;
+        let app = new_app(|p| {
+            p.auth.ext_authz.enabled = Some(true);
+            p.auth.ext_authz.url = Some(url);
+        }).await;
+
+        assert_eq!(get_status(app.clone(), "/allowed").await, StatusCode::OK);
+        assert_eq!(get_status(app, "/denied").await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_ext_authz_sends_request_user() {
+        let url = start_mock_authorizer().await;
+        let state = new_test_state(|p| {
+            p.auth.ext_authz.enabled = Some(true);
+            p.auth.ext_authz.url = Some(url);
+        }).await;
+        let app = |uid: i64| {
+            let claims = auths::validate_jwt(
+                &state.config,
+                &auths::create_jwt(&state.config, &PrincipalType::Password, uid, "a", "a@b.com", false, None)
+            ).unwrap();
+            Router::new()
+                .route("/owner", get(|| async { "ok" }))
+                .layer(axum::middleware::from_fn_with_state(state.clone(), ext_authz_middleware))
+                .layer(axum::Extension(claims))
+        };
+
+        // The concurrent requests are authorized by their own users.
+        let (owner, other) = tokio::join!(get_status(app(7), "/owner"), get_status(app(8), "/owner"));
+        assert_eq!(owner, StatusCode::OK);
+        assert_eq!(other, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_ext_authz_bypass_paths_and_unavailable() {
+        let url = start_mock_authorizer().await;
+        let app = new_app(|p| {
+            p.auth.ext_authz.enabled = Some(true);
+            p.auth.ext_authz.url = Some(url);
+            p.auth.ext_authz.bypass_paths = Some(vec!["/denied".to_string()]);
+        }).await;
+        assert_eq!(get_status(app, "/denied").await, StatusCode::OK);
+
+        // The unavailable authorizer denies all.
+        let app = new_app(|p| {
+            p.auth.ext_authz.enabled = Some(true);
+            p.auth.ext_authz.url = Some("http://127.0.0.1:1/authz".to_string());
+        }).await;
+        assert_eq!(get_status(app, "/allowed").await, StatusCode::FORBIDDEN);
+    }
+
+    async fn call_whoami(profile: RunProfile, token: Option<&str>) -> (StatusCode, Option<serde_json::Value>) {
+        let state = new_test_state(|p| {
+            p.profile = profile;
+        }).await;
+        let mut request = Request::builder().uri(AUTH_DEBUG_WHOAMI_URI);
+        if let Some(token) = token {
+            request = request.header("Authorization", format!("Bearer {}", token));
+        }
+        let response = init()
+            .with_state(state)
+            .oneshot(request.body(Body::empty()).unwrap()).await
+            .unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).ok())
+    }
+
+    fn new_test_token() -> String {
+        let config = crate::config::config_serve::WebServeProperties::default().to_config();
+        let ext = std::collections::HashMap::from([("tenant".to_string(), "secret".to_string())]);
+        auths::create_jwt(&config, &PrincipalType::Password, 1001, "alice", "a@b.com", false, Some(ext))
+    }
+
+    #[tokio::test]
+    async fn test_debug_whoami_in_dev() {
+        let (status, body) = call_whoami(RunProfile::Dev, Some(&new_test_token())).await;
+        let body = body.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["claims"]["uid"], 1001);
+        assert_eq!(body["claims"]["ext"]["tenant"], "******");
+        assert_eq!(body["expired"], false);
+        assert_eq!(body["blacklisted"], false);
+
+        let (status, body) = call_whoami(RunProfile::Dev, Some("invalid")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.unwrap()["error"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_debug_whoami_pretty_in_dev() {
+        let state = new_test_state(|p| {
+            p.profile = RunProfile::Dev;
+        }).await;
+        let request = Request::builder()
+            .uri(format!("{}?pretty=true", AUTH_DEBUG_WHOAMI_URI))
+            .header("Authorization", format!("Bearer {}", new_test_token()))
+            .body(Body::empty())
+            .unwrap();
+        let response = init().with_state(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(body.starts_with("{\n  \""), "{}", body);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap()["claims"]["uid"], 1001);
+    }
+
+    async fn call_validate(
+        state: AppState,
+        token: &str,
+        peer: &str,
+        forwarded_for: &str
+    ) -> (StatusCode, serde_json::Value) {
+        let request = Request::builder()
+            .uri(AUTH_VALIDATE_URI)
+            .header("Authorization", format!("Bearer {}", token))
+            .header("X-Forwarded-For", forwarded_for)
+            .body(Body::empty())
+            .unwrap();
+        let peer = ConnectInfo(format!("{}:40000", peer).parse::<SocketAddr>().unwrap());
+        let response = init().layer(axum::Extension(peer)).with_state(state).oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_validate_token_valid_expired_and_revoked() {
+        let state = new_test_state(|_| {}).await;
+        let token = new_test_token();
+        let (status, body) = call_validate(state.clone(), &token, "10.0.0.1", "10.0.0.1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["valid"], true);
+        assert!(body["expires_in"].as_i64().unwrap() > 0);
+        assert!(body.get("reason").is_none());
+
+        let (status, body) = call_validate(state.clone(), "invalid", "10.0.0.1", "10.0.0.1").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["reason"], "malformed");
+
+        // Revoked by logout.
+        let key = get_auth_handler(&state).build_logout_blacklist_key(&token);
+        state.string_cache.get(&state.config).set(key, "1".to_string(), Some(60)).await.unwrap();
+        let (status, body) = call_validate(state.clone(), &token, "10.0.0.1", "10.0.0.1").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["valid"], false);
+        assert_eq!(body["reason"], "revoked");
+
+        let expired_state = new_test_state(|p| {
+            p.auth.jwt_validity_ak = Some(0);
+        }).await;
+        let expired = auths::create_jwt(&expired_state.config, &PrincipalType::Password, 1, "a", "a@b.com", false, None);
+        let (status, body) = call_validate(expired_state, &expired, "10.0.0.1", "10.0.0.1").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["reason"], "expired");
+    }
+
+    #[tokio::test]
+    async fn test_validate_token_rate_limited_per_ip() {
+        let state = new_test_state(|p| {
+            p.auth.validate_rate_limit.max_requests = Some(2);
+            p.server.trusted_proxies = vec!["172.16.0.1".to_string()];
+        }).await;
+        let token = new_test_token();
+        // Exactly at the limit, and the spoofed 'X-Forwarded-For' of the untrusted peer doesn't bypass it.
+        for spoofed in ["1.1.1.1", "2.2.2.2"] {
+            assert_eq!(call_validate(state.clone(), &token, "10.0.0.2", spoofed).await.0, StatusCode::OK);
+        }
+        let (status, _) = call_validate(state.clone(), &token, "10.0.0.2", "3.3.3.3").await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(call_validate(state.clone(), &token, "10.0.0.3", "10.0.0.2").await.0, StatusCode::OK);
+
+        // The clients behind the trusted proxy are limited by the forwarded ip.
+        for _ in 0..2 {
+            assert_eq!(call_validate(state.clone(), &token, "172.16.0.1", "10.0.0.4").await.0, StatusCode::OK);
+        }
+        let (status, _) = call_validate(state.clone(), &token, "172.16.0.1", "10.0.0.4").await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(call_validate(state, &token, "172.16.0.1", "10.0.0.5").await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_auth_middleware_rejects_oversized_token() {
+        let state = new_test_state(|p| {
+            p.auth.jwt_max_bytes = Some(64);
+        }).await;
+        let app = Router::new()
+            .route("/protected", get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn_with_state(state, auth_middleware));
+
+        let request = Request::builder()
+            .uri("/protected")
+            .header("Authorization", format!("Bearer {}", "x".repeat(65)))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["errcode"], 431);
+
+        // The token within the max bytes goes to the normal validation.
+        let request = Request::builder()
+            .uri("/protected")
+            .header("Authorization", "Bearer invalid")
+            .header("Accept", "application/json")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(app.oneshot(request).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    }
+
+    async fn call_protected_with_validity(validity_ak: u64) -> Response<Body> {
+        let state = new_test_state(|p| {
+            p.auth.jwt_validity_ak = Some(validity_ak);
+        }).await;
+        let token = auths::create_jwt(&state.config, &PrincipalType::Password, 1, "a", "a@b.com", false, None);
+        let app = Router::new()
+            .route("/protected", get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn_with_state(state, auth_middleware));
+        let request = Request::builder()
+            .uri("/protected")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap();
+        app.oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_auth_middleware_binds_claims_to_request() {
+        let state = new_test_state(|_| {}).await;
+        let app = Router::new()
+            .route("/whoami", get(|claims: AuthUserClaims| async move { claims.uid.to_string() }))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), auth_middleware));
+        let call = |uid: i64| {
+            let token = auths::create_jwt(&state.config, &PrincipalType::Password, uid, "a", "a@b.com", false, None);
+            let request = Request::builder()
+                .uri("/whoami")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        // The concurrent requests see their own claims.
+        let responses = futures::future::join_all((1..=20).map(call)).await;
+        for (uid, response) in (1..=20).zip(responses) {
+            let bytes = axum::body::to_bytes(response.unwrap().into_body(), usize::MAX).await.unwrap();
+            assert_eq!(String::from_utf8(bytes.to_vec()).unwrap(), uid.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth_middleware_hints_expiring_token() {
+        let response = call_protected_with_validity(30_000).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let remaining: i64 = response.headers()[TOKEN_EXPIRING_HEADER].to_str().unwrap().parse().unwrap();
+        assert!((28..=30).contains(&remaining), "{}", remaining);
+    }
+
+    #[tokio::test]
+    async fn test_auth_middleware_no_hint_for_fresh_token() {
+        let response = call_protected_with_validity(3_600_000).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(TOKEN_EXPIRING_HEADER).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_debug_whoami_not_found_in_prod() {
+        let (status, _) = call_whoami(RunProfile::Prod, Some(&new_test_token())).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    async fn call_callback(app: Router<AppState>, uri: &str, browser: bool) -> (StatusCode, HeaderMap, String) {
+        let state = new_test_state(|_| {}).await;
+        let mut request = Request::builder().uri(uri);
+        if browser {
+            request = request.header(header::USER_AGENT, "Mozilla/5.0");
+        }
+        let response = app.with_state(state).oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+        let (status, headers) = (response.status(), response.headers().clone());
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, headers, String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    fn new_callback_app() -> Router<AppState> {
+        Router::new().route(
+            "/callback",
+            get(|callback: ValidatedCallback| async move { format!("{},{:?}", callback.code, callback.params.state) })
+        )
+    }
+
+    #[tokio::test]
+    async fn test_callback_params_with_code() {
+        let (status, _, body) = call_callback(new_callback_app(), "/callback?code=abc&state=xyz", false).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"abc,Some("xyz")"#);
+    }
+
+    #[tokio::test]
+    async fn test_callback_params_provider_error() {
+        let uri = "/callback?error=invalid_scope&error_description=Unknown%20scope&state=xyz";
+        let (status, _, body) = call_callback(new_callback_app(), uri, false).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("invalid_scope: Unknown scope"), "{}", body);
+
+        // The provider declined is rejected distinctly, and before exchanging on the real callback route.
+        let uri = format!("{}?error=access_denied&code=abc", AUTH_CALLBACK_GITHUB_URI);
+        let (status, _, body) = call_callback(init(), &uri, false).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(body.contains("Authorization declined on the provider"), "{}", body);
+
+        // The browser is redirected to the login page with the troubleshooting.
+        let (status, headers, _) = call_callback(init(), &uri, true).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert!(headers[header::LOCATION].to_str().unwrap().contains("#help-troubleshooting-is-"));
+    }
+
+    #[tokio::test]
+    async fn test_callback_params_missing_code() {
+        for uri in ["/callback", "/callback?code=&state=xyz"] {
+            let (status, _, body) = call_callback(new_callback_app(), uri, false).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert!(body.contains("Missing authentication code"), "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_callback_rejects_invalid_state() {
+        let uri = format!("{}?code=abc&state=xyz", AUTH_CALLBACK_GITHUB_URI);
+        let (status, _, body) = call_callback(init(), &uri, false).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(body.contains("Expired or used state"), "{}", body);
+
+        let state = new_test_state(|_| {}).await;
+        get_auth_handler(&state).handle_auth_create_state("sid-1", "xyz".to_string()).await.unwrap();
+        let call = |uri: String| {
+            let request = Request::builder()
+                .uri(uri)
+                .header(header::COOKIE, format!("{}=sid-1", AUTH_SID_COOKIE_NAME))
+                .body(Body::empty())
+                .unwrap();
+            init().with_state(state.clone()).oneshot(request)
+        };
+        let response = call(format!("{}?code=abc&state=other", AUTH_CALLBACK_GITHUB_URI)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        // The state has been used by the mismatched callback.
+        let response = call(format!("{}?code=abc&state=xyz", AUTH_CALLBACK_GITHUB_URI)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_callback_accepts_valid_state() {
+        let state = new_test_state(|_| {}).await;
+        get_auth_handler(&state).handle_auth_create_state("sid-1", "xyz".to_string()).await.unwrap();
+        let request = Request::builder()
+            .uri(format!("{}?code=abc&state=xyz", AUTH_CALLBACK_GITHUB_URI))
+            .header(header::COOKIE, format!("{}=sid-1", AUTH_SID_COOKIE_NAME))
+            .body(Body::empty())
+            .unwrap();
+        let response = init().with_state(state).oneshot(request).await.unwrap();
+        // Passed the state verification, and then failed of the unconfigured github client.
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
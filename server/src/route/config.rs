@@ -0,0 +1,89 @@
+/*
+ * SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+ *
+ * Copyleft (c) 2024 James Wong. This file is part of James Wong.
+ * is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * James Wong is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * IMPORTANT: Any software that fully or partially contains or uses materials
+ * covered by this license must also be released under the GNU GPL license.
+ * This includes modifications and derived works.
+ */
+
+use axum::{ extract::State, routing::get, Json, Router };
+
+use crate::{ context::state::AppState, types::config::FeatureFlags };
+
+pub const CONFIG_FEATURES_URI: &str = "/config/features";
+
+pub fn init() -> Router<AppState> {
+    Router::new().route(CONFIG_FEATURES_URI, get(handle_get_features))
+}
+
+#[utoipa::path(
+    get,
+    path = "/config/features",
+    responses((
+        status = 200,
+        description = "Getting for the effective non-secret feature flags, e.g. the enabled login providers.",
+        body = FeatureFlags,
+    )),
+    tag = "Config"
+)]
+pub async fn handle_get_features(State(state): State<AppState>) -> Json<FeatureFlags> {
+    Json(FeatureFlags::from_config(&state.config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{ body::Body, extract::Request };
+    use tower::ServiceExt;
+    use crate::context::state::tests::new_test_state;
+
+    async fn get_features(state: AppState) -> serde_json::Value {
+        let response = init()
+            .with_state(state)
+            .oneshot(Request::builder().uri(CONFIG_FEATURES_URI).body(Body::empty()).unwrap()).await
+            .unwrap();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_features_report_disabled_providers() {
+        let state = new_test_state(|p| {
+            p.auth.oidc.enabled = Some(false);
+        }).await;
+        let features = get_features(state).await;
+        assert_eq!(features["login_providers"]["oidc"], serde_json::json!(false));
+        assert_eq!(features["login_providers"]["github"], serde_json::json!(false));
+        assert_eq!(features["login_providers"]["password"], serde_json::json!(true));
+        assert_eq!(features["dev_profile"], serde_json::json!(false));
+    }
+
+    #[tokio::test]
+    async fn test_features_not_leak_secrets() {
+        let state = new_test_state(|p| {
+            p.auth.jwt_secret = Some("jwt-secret-value".to_string());
+            p.auth.oidc.client_secret = Some("oidc-secret-value".to_string());
+        }).await;
+        let features = get_features(state).await;
+
+        let body = features.to_string();
+        assert!(!body.contains("secret-value"));
+        let mut keys: Vec<_> = features.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, ["api_version", "dev_profile", "login_providers", "otel", "swagger", "trash_auto_purge"]);
+    }
+}
@@ -30,12 +30,18 @@ use axum::{
 
 use crate::{
     context::state::AppState,
+    errors::AppError,
     handler::document::IDocumentHandler,
     types::{
-        document::{ DeleteDocumentResponse, QueryDocumentResponse, SaveDocumentResponse },
+        document::{
+            DeleteDocumentResponse,
+            QueryDeletedDocumentResponse,
+            QueryDocumentResponse,
+            SaveDocumentResponse,
+        },
         PageRequest,
     },
-    utils::auths::SecurityContext,
+    utils::auths::{ AuthUserClaims, SecurityContext },
 };
 use crate::handler::document::DocumentHandler;
 use crate::types::document::{ QueryDocumentRequest, SaveDocumentRequest, DeleteDocumentRequest };
@@ -45,8 +51,10 @@ use super::ValidatedJson;
 pub fn init() -> Router<AppState> {
     Router::new()
         .route("/modules/document/query", get(handle_query_documents))
+        .route("/modules/document/trash", get(handle_query_deleted_documents))
         .route("/modules/document/save", post(handle_save_document))
         .route("/modules/document/delete", post(handle_delete_document))
+        .route("/sys/document/purge", post(handle_purge_document))
 }
 
 #[utoipa::path(
@@ -74,6 +82,28 @@ pub async fn handle_query_documents(
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/modules/document/trash",
+    params(PageRequest),
+    responses((
+        status = 200,
+        description = "Getting for the soft-deleted documents (trash) of the current user.",
+        body = QueryDeletedDocumentResponse,
+    )),
+    tag = "Document"
+)]
+pub async fn handle_query_deleted_documents(
+    State(state): State<AppState>,
+    claims: AuthUserClaims,
+    Query(page): Query<PageRequest>
+) -> impl IntoResponse {
+    match get_document_handler(&state).find_deleted(&claims, page).await {
+        Ok((page, data)) => Ok(Json(QueryDeletedDocumentResponse::new(page, data))),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
 #[utoipa::path(
     post,
     path = "/modules/document/save",
@@ -108,6 +138,95 @@ async fn handle_delete_document(
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/sys/document/purge",
+    request_body = DeleteDocumentRequest,
+    responses(
+        (status = 200, description = "Purge (permanently delete) for the deleted document in trash by the admins.", body = DeleteDocumentResponse),
+        (status = 403, description = "Purging by non-admin."),
+        (status = 404, description = "The document is not found."),
+        (status = 409, description = "The document is active, which must be deleted first.")
+    ),
+    tag = "Document"
+)]
+async fn handle_purge_document(
+    State(state): State<AppState>,
+    claims: AuthUserClaims,
+    Json(param): Json<DeleteDocumentRequest>
+) -> Result<Json<DeleteDocumentResponse>, AppError> {
+    if !state.config.auth.is_admin(claims.uid) {
+        return Err(AppError::Forbidden("purging the documents requires admin".to_string()));
+    }
+    let count = get_document_handler(&state).purge(param).await.map_err(AppError::storage)?;
+    Ok(Json(DeleteDocumentResponse::new(count)))
+}
+
 fn get_document_handler(state: &AppState) -> Box<dyn IDocumentHandler + '_> {
     Box::new(DocumentHandler::new(state))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{ body::Body, http::{ header, Method, Request }, Extension };
+    use tower::ServiceExt;
+    use crate::context::state::tests::new_test_state;
+
+    const ADMIN_UID: i64 = 9001;
+
+    async fn call(app: &Router, method: Method, uri: &str, body: Option<serde_json::Value>) -> (StatusCode, serde_json::Value) {
+        let request = Request::builder().method(method).uri(uri).header(header::CONTENT_TYPE, "application/json");
+        let body = body.map(|b| Body::from(b.to_string())).unwrap_or_else(Body::empty);
+        let response = app.clone().oneshot(request.body(body).unwrap()).await.unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null))
+    }
+
+    #[tokio::test]
+    async fn test_document_delete_trash_and_purge() {
+        let state = new_test_state(|p| {
+            p.auth.admin_uids = Some(vec![ADMIN_UID]);
+        }).await;
+        let app = |claims: AuthUserClaims| init().layer(Extension(claims)).with_state(state.clone());
+
+        let save = serde_json::json!({ "key": "e2e-1", "name": "e2e-1", "type": "Note", "content": "hello" });
+        let (status, saved) = call(&app(AuthUserClaims::for_test(1001, "any@example.com")), Method::POST, "/modules/document/save", Some(save)).await;
+        assert_eq!(status, StatusCode::OK);
+        let id = saved["id"].as_i64().unwrap();
+        // The owner is the creator stamped on inserting.
+        let owner = {
+            let repo = state.document_repo.lock().await;
+            repo.get(&state.config).select_by_id(id).await.unwrap().unwrap().base.create_by.unwrap()
+        };
+        let owner_app = app(AuthUserClaims::for_test(1001, &owner));
+        let admin_app = app(AuthUserClaims::for_test(ADMIN_UID, "admin@example.com"));
+        let purge = || Some(serde_json::json!({ "id": id }));
+
+        // The active document could not be purged.
+        let (status, _) = call(&admin_app, Method::POST, "/sys/document/purge", purge()).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (status, deleted) = call(&owner_app, Method::POST, "/modules/document/delete", purge()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(deleted["count"], 1);
+
+        let (status, trash) = call(&owner_app, Method::GET, "/modules/document/trash", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let ids = trash["data"].as_array().unwrap().iter().map(|d| d["id"].as_i64().unwrap()).collect::<Vec<_>>();
+        assert!(ids.contains(&id), "{:?}", trash);
+
+        // The purge is of the admins only, even the owner is forbidden.
+        let (status, _) = call(&owner_app, Method::POST, "/sys/document/purge", purge()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, purged) = call(&admin_app, Method::POST, "/sys/document/purge", purge()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(purged["count"], 1);
+        let (_, trash) = call(&owner_app, Method::GET, "/modules/document/trash", None).await;
+        assert!(trash["data"].as_array().unwrap().iter().all(|d| d["id"].as_i64() != Some(id)));
+        let (status, _) = call(&admin_app, Method::POST, "/sys/document/purge", purge()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -20,16 +20,54 @@
  * This includes modifications and derived works.
  */
 
-use axum::{ async_trait, extract::Query, Json };
+use axum::{ async_trait, body::Bytes, extract::{ Query, State }, middleware::Next, Json };
 use axum::extract::rejection::{ JsonRejection, QueryRejection };
 use axum::response::{ IntoResponse, Response };
-use axum::extract::{ FromRequest, Request };
-use serde::de::DeserializeOwned;
+use axum::extract::{ FromRequest, FromRequestParts, Request };
+use axum::http::request::Parts;
+use axum::http::{ header, HeaderMap, HeaderName, HeaderValue, Method };
+use std::time::Duration;
+use tower_http::cors::{ AllowCredentials, AllowHeaders, AllowMethods, AllowOrigin, CorsLayer };
+use serde::{ de::DeserializeOwned, Serialize };
 use hyper::StatusCode;
 use validator::Validate;
 
+use crate::config::config_serve::{
+    self,
+    CorsProperties,
+    DEFAULT_CACHE_CONTROL,
+    DEFAULT_CORS_MAX_AGE,
+    DEFAULT_DEGRADED_RETRY_AFTER,
+    DEFAULT_MAX_JSON_ARRAY_LEN,
+    DEFAULT_MAX_JSON_DEPTH,
+};
+use crate::context::state::AppState;
+use crate::errors::{ AppError, StorageFault };
+use crate::mgmt::apm::logging::should_log_access;
+use crate::mgmt::apm::otel::{ extract_trace_context, record_slo_violation, TracePropagator };
+use crate::types::{
+    ApiVersion,
+    RequestCorrelation,
+    ACCEPT_VERSION_HEADER,
+    API_VERSION,
+    REQUEST_CORRELATION,
+    REQUEST_ID_HEADER,
+};
+use crate::utils::auths::clean_context_path;
+use crate::utils::times;
+use crate::route::auths::{
+    AUTH_PASSWORD_LOGIN_URI,
+    AUTH_PASSWORD_PUBKEY_URI,
+    AUTH_PASSWORD_VERIFY_URI,
+    AUTH_TOKEN_REFRESH_URI,
+    AUTH_WALLET_ETHERS_VERIFY_URI,
+};
+
 pub mod api_v1;
+pub mod attachment;
+pub mod audit;
 pub mod auths;
+pub mod config;
 pub mod document;
 pub mod folder;
 pub mod settings;
@@ -52,6 +90,20 @@ impl<T, S> FromRequest<S>
         req: Request<axum::body::Body>,
         state: &S
     ) -> Result<Self, Self::Rejection> {
+        // Pre-scan the raw body for the structural limits, before materializing it. The body is buffered by the
+        // Bytes extractor, which honors the DefaultBodyLimit, i.e. the oversized is rejected with 413.
+        let (parts, body) = req.into_parts();
+        let bytes = Bytes::from_request(Request::from_parts(parts.clone(), body), state).await.map_err(|e|
+            (e.status(), format!("Json parsing error: {}", e.body_text())).into_response()
+        )?;
+        let config = config_serve::get_config();
+        check_json_limits(
+            &bytes,
+            config.server.max_json_depth.unwrap_or(DEFAULT_MAX_JSON_DEPTH),
+            config.server.max_json_array_len.unwrap_or(DEFAULT_MAX_JSON_ARRAY_LEN)
+        ).map_err(|e| (StatusCode::BAD_REQUEST, format!("Json parsing error: {}", e)).into_response())?;
+        let req = Request::from_parts(parts, axum::body::Body::from(bytes));
+
         let Json(value) = Json::<T>
             ::from_request(req, state).await
             .map_err(|e|
@@ -68,6 +120,175 @@ impl<T, S> FromRequest<S>
     }
 }
 
+// ----- Content negotiation of serialization formats. -----
+
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
+// The serialization format of request/response bodies, sharing the same serde types.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ContentFormat {
+    #[default]
+    Json,
+    MsgPack,
+}
+
+impl ContentFormat {
+    // Parse the media type of Accept or Content-Type header, e.g: 'application/msgpack; q=0.9, */*'
+    pub fn from_media_type(value: &str) -> Option<Self> {
+        value
+            .split(',')
+            .map(|item| item.split(';').next().unwrap_or_default().trim().to_lowercase())
+            .find_map(|media| {
+                match media.as_str() {
+                    MSGPACK_CONTENT_TYPE | "application/x-msgpack" => Some(Self::MsgPack),
+                    "application/json" => Some(Self::Json),
+                    _ => None,
+                }
+            })
+    }
+
+    fn from_header(headers: &HeaderMap, name: HeaderName) -> Self {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Self::from_media_type)
+            .unwrap_or_default()
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::MsgPack => MSGPACK_CONTENT_TYPE,
+        }
+    }
+
+    pub fn to_vec<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            // Serialize the structs as maps with field names, to be as self-describing as json.
+            Self::MsgPack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn from_slice<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Self::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Self::MsgPack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+// Extract the response format by the Accept header, defaults to json.
+#[async_trait]
+impl<S> FromRequestParts<S> for ContentFormat where S: Send + Sync {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_header(&parts.headers, header::ACCEPT))
+    }
+}
+
+// The response body serialized in the negotiated format.
+pub struct Negotiated<T>(pub ContentFormat, pub T);
+
+impl<T: Serialize> IntoResponse for Negotiated<T> {
+    fn into_response(self) -> Response {
+        let Negotiated(format, value) = self;
+        if format == ContentFormat::Json {
+            return Json(value).into_response();
+        }
+        match format.to_vec(&value) {
+            Ok(bytes) => ([(header::CONTENT_TYPE, format.content_type())], bytes).into_response(),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Serialization error: {}", e)).into_response(),
+        }
+    }
+}
+
+// The validated request body of json or msgpack according to the Content-Type header.
+pub struct ValidatedBody<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S>
+    for ValidatedBody<T>
+    where
+        T: DeserializeOwned + Validate,
+        S: Send + Sync,
+        Json<T>: FromRequest<S, Rejection = JsonRejection>
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request<axum::body::Body>, state: &S) -> Result<Self, Self::Rejection> {
+        let format = ContentFormat::from_header(req.headers(), header::CONTENT_TYPE);
+        if format == ContentFormat::Json {
+            let ValidatedJson(value) = ValidatedJson::<T>::from_request(req, state).await?;
+            return Ok(ValidatedBody(value));
+        }
+
+        let bytes = Bytes::from_request(req, state).await.map_err(|e|
+            (e.status(), format!("MessagePack parsing error: {}", e.body_text())).into_response()
+        )?;
+        let value = format
+            .from_slice::<T>(&bytes)
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("MessagePack parsing error: {}", e)).into_response())?;
+        value
+            .validate()
+            .map_err(|e| {
+                (StatusCode::BAD_REQUEST, format!("Validation error: {:?}", e)).into_response()
+            })?;
+
+        Ok(ValidatedBody(value))
+    }
+}
+
+// Check the max nesting depth and the max elements of each array of the json text, without parsing it.
+fn check_json_limits(bytes: &[u8], max_depth: usize, max_array_len: usize) -> Result<(), String> {
+    // The element separators of each opened container, None for objects.
+    let mut stack: Vec<Option<usize>> = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    for &b in bytes {
+        if in_string {
+            match b {
+                _ if escaped => {
+                    escaped = false;
+                }
+                b'\\' => {
+                    escaped = true;
+                }
+                b'"' => {
+                    in_string = false;
+                }
+                _ => {}
+            }
+            continue;
+        }
+        match b {
+            b'"' => {
+                in_string = true;
+            }
+            b'{' | b'[' => {
+                if stack.len() >= max_depth {
+                    return Err(format!("exceeded the max depth {}", max_depth));
+                }
+                stack.push(if b == b'[' { Some(0) } else { None });
+            }
+            b'}' | b']' => {
+                stack.pop();
+            }
+            b',' => {
+                if let Some(Some(separators)) = stack.last_mut() {
+                    *separators += 1;
+                    if *separators + 1 > max_array_len {
+                        return Err(format!("exceeded the max array length {}", max_array_len));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
 pub struct ValidatedQuery<T>(pub T);
 
 #[async_trait]
@@ -96,3 +317,890 @@ impl<T, S> FromRequest<S>
         Ok(ValidatedQuery(value))
     }
 }
+
+// ----- Global CORS interceptors. -----
+
+pub fn build_cors_layer(cors: &CorsProperties) -> CorsLayer {
+    let is_any = |values: &[String]| values.iter().any(|v| v == "*");
+    let allow_credentials = cors.allow_credentials.unwrap_or(false) && !is_any(&cors.hosts);
+
+    let origins = cors.hosts
+        .iter()
+        .filter_map(|host| {
+            HeaderValue::from_str(host)
+                .map_err(|e| tracing::warn!("Ignored the invalid cors host: {}. {}", host, e))
+                .ok()
+        })
+        .collect::<Vec<_>>();
+    let allow_origin = if is_any(&cors.hosts) { AllowOrigin::any() } else { AllowOrigin::list(origins.clone()) };
+
+    // The wildcard of headers/methods isn't allowed with credentials, so mirror the request instead.
+    let allow_headers = match (is_any(&cors.headers), allow_credentials) {
+        (true, true) => AllowHeaders::mirror_request(),
+        (true, false) => AllowHeaders::any(),
+        _ => AllowHeaders::list(cors.headers.iter().filter_map(|h| h.parse::<HeaderName>().ok())),
+    };
+    let allow_methods = match (is_any(&cors.methods), allow_credentials) {
+        (true, true) => AllowMethods::mirror_request(),
+        (true, false) => AllowMethods::any(),
+        _ => AllowMethods::list(cors.methods.iter().filter_map(|m| m.parse::<Method>().ok())),
+    };
+
+    let mut layer = CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_headers(allow_headers)
+        .allow_methods(allow_methods)
+        .max_age(Duration::from_secs(cors.max_age.unwrap_or(DEFAULT_CORS_MAX_AGE)))
+        // Let the cross-origin client read the hint of refreshing the access token.
+        .expose_headers([HeaderName::from_static("x-token-expiring")]);
+    if allow_credentials {
+        layer = layer.allow_credentials(
+            AllowCredentials::predicate(move |origin, _| origins.contains(origin))
+        );
+    }
+    layer
+}
+
+// ----- Global Cache-Control interceptors. -----
+
+pub async fn cache_control_middleware(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next
+) -> Response {
+    let path = clean_context_path(&state.config.server.context_path, req.uri().path());
+    let value = state.config.cache_control_matchers
+        .iter()
+        .find(|(matcher, _)| matcher.is_match(path))
+        .map(|(_, value)| value.clone())
+        .unwrap_or(HeaderValue::from_static(DEFAULT_CACHE_CONTROL));
+
+    let mut response = next.run(req).await;
+    // Respect the Cache-Control that has been set by the handler.
+    if !response.headers().contains_key(header::CACHE_CONTROL) {
+        response.headers_mut().insert(header::CACHE_CONTROL, value);
+    }
+    response
+}
+
+// ----- Global access log (and latency budget) interceptors. -----
+
+pub async fn access_log_middleware(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next
+) -> Response {
+    let method = req.method().clone();
+    let uri = req.uri().clone();
+    let start = std::time::Instant::now();
+
+    let response = next.run(req).await;
+    let status = response.status();
+    let elapsed_ms = start.elapsed().as_millis() as u64;
+    if should_log_access(&state.config.logging.access_log, status) {
+        tracing::info!(
+            method = %method,
+            uri = %uri,
+            status = status.as_u16(),
+            elapsed_ms = elapsed_ms,
+            "access"
+        );
+    }
+
+    let path = clean_context_path(&state.config.server.context_path, uri.path());
+    let budget = state.config.latency_budget_matchers
+        .iter()
+        .find(|(matcher, _)| matcher.is_match(path))
+        .map(|(_, budget)| *budget);
+    if let Some(budget) = budget.filter(|budget| elapsed_ms > *budget) {
+        record_slo_violation(path, budget, elapsed_ms);
+    }
+    response
+}
+
+// ----- Global response envelope version interceptors. -----
+
+pub async fn envelope_version_middleware(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next
+) -> Response {
+    let version = req
+        .headers()
+        .get(ACCEPT_VERSION_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(ApiVersion::parse)
+        .unwrap_or(state.config.server.api_version);
+    API_VERSION.scope(version, next.run(req)).await
+}
+
+// ----- Global response timezone interceptors. -----
+
+// The larger (or unknown size) json responses are not localized, to avoid buffering the huge bodies.
+const MAX_LOCALIZE_BODY_BYTES: u64 = 4 * 1024 * 1024;
+
+// Render the timestamps of the json responses in the configured 'server.timezone', see: times::localize_timestamps
+pub async fn timezone_middleware(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let response = next.run(req).await;
+    let timezone = match &state.config.timezone {
+        Some(timezone) => *timezone,
+        None => {
+            return response;
+        }
+    };
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    let size = axum::body::HttpBody::size_hint(response.body()).exact();
+    if !is_json || size.map_or(true, |size| size > MAX_LOCALIZE_BODY_BYTES) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, MAX_LOCALIZE_BODY_BYTES as usize).await {
+        std::result::Result::Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::warn!("Failed to read the response body of localizing timestamps. {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read response body").into_response();
+        }
+    };
+    let body = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        std::result::Result::Ok(mut value) => {
+            times::localize_timestamps(&mut value, &timezone);
+            parts.headers.remove(header::CONTENT_LENGTH);
+            value.to_string().into_bytes()
+        }
+        Err(_) => bytes.to_vec(),
+    };
+    Response::from_parts(parts, axum::body::Body::from(body))
+}
+
+// ----- Global degraded mode interceptors. -----
+
+// The login writes are passed through during the outage, so that the users could still sign in, of which
+// the writes (e.g. the login audit) are best-effort.
+const DEGRADED_EXEMPT_PATHS: [&str; 5] = [
+    AUTH_PASSWORD_PUBKEY_URI,
+    AUTH_PASSWORD_VERIFY_URI,
+    AUTH_PASSWORD_LOGIN_URI,
+    AUTH_WALLET_ETHERS_VERIFY_URI,
+    AUTH_TOKEN_REFRESH_URI,
+];
+
+// The writes are rejected by 503 during the outage of the primary db, while the reads are passed through,
+// see: WriteHealth. The storage failure of the write is responded as 503 too, so that the client retries.
+pub async fn degraded_mode_middleware(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let path = clean_context_path(&state.config.server.context_path, req.uri().path());
+    if
+        !state.config.server.degraded_mode.unwrap_or(false) ||
+        is_read_method(req.method()) ||
+        DEGRADED_EXEMPT_PATHS.contains(&path)
+    {
+        return next.run(req).await;
+    }
+    let permit = match state.write_health.try_acquire() {
+        std::result::Result::Ok(permit) => permit,
+        Err(retry_after) => {
+            return unavailable_response(retry_after);
+        }
+    };
+
+    let response = next.run(req).await;
+    if response.extensions().get::<StorageFault>().is_some() {
+        permit.record(false);
+        let retry_after = state.config.server.degraded_retry_after.unwrap_or(DEFAULT_DEGRADED_RETRY_AFTER);
+        return unavailable_response(Duration::from_secs(retry_after));
+    }
+    // Only the succeeded write has reached the storage, the rejected (e.g. 400) tells nothing of the health.
+    if response.status().is_success() {
+        permit.record(true);
+    }
+    response
+}
+
+fn is_read_method(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+fn unavailable_response(retry_after: Duration) -> Response {
+    // Round up, so that the client does not retry before the probing is permitted.
+    let secs = retry_after.as_millis().div_ceil(1000).max(1);
+    let mut response = AppError::Unavailable("the writes are degraded, please retry later".to_string()).into_response();
+    response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(secs as u64));
+    response
+}
+
+// ----- Global request id interceptors. -----
+
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// Only the reasonable incoming request id is reused, to avoid the log injection.
+fn is_valid_request_id(value: &str) -> bool {
+    !value.is_empty() &&
+        value.len() <= MAX_REQUEST_ID_LEN &&
+        value.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+// The outermost span of the request, which continues the remote trace of the headers (e.g. the W3C 'traceparent')
+// if present, otherwise starts a new trace.
+pub fn make_request_span<B>(request: &axum::http::Request<B>, propagator: TracePropagator) -> tracing::Span {
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    let span = tracing::info_span!(
+        "http_request",
+        method = %request.method(),
+        uri = %request.uri(),
+        path = %request.uri().path()
+    );
+    if let Some(context) = extract_trace_context(request.headers(), propagator) {
+        span.set_parent(context);
+    }
+    span
+}
+
+// The request id is recorded in the span of the request, so that all the logs of the request carry it.
+pub async fn request_id_middleware(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    use tracing::Instrument;
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| is_valid_request_id(v))
+        .map(String::from)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let correlation = RequestCorrelation {
+        request_id: request_id.clone(),
+        expose: state.config.server.error_correlation_ids.unwrap_or(true),
+    };
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %req.method(),
+        path = %req.uri().path()
+    );
+    let mut response = REQUEST_CORRELATION.scope(correlation, next.run(req).instrument(span)).await;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{ body::Body, routing::get, Router };
+    use tower::ServiceExt;
+    use crate::context::state::tests::new_test_state;
+    use crate::errors::AppError;
+    use crate::types::RespBase;
+    use crate::utils::auths::AuthUserClaims;
+
+    async fn get_cache_control(app: Router, uri: &str) -> Option<String> {
+        let response = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap()).await
+            .unwrap();
+        response
+            .headers()
+            .get(header::CACHE_CONTROL)
+            .map(|v| v.to_str().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn test_cache_control_middleware() {
+        let state = new_test_state(|p| {
+            p.server.context_path = Some("/serve".to_string());
+        }).await;
+        let app = Router::new()
+            .route("/serve/auth/logout", get(|| async { "ok" }))
+            .route("/serve/sys/settings/query", get(|| async { "ok" }))
+            .route("/serve/sys/user/current", get(|| async { "ok" }))
+            .route(
+                "/serve/custom",
+                get(|| async { ([(header::CACHE_CONTROL, "max-age=5")], "ok") })
+            )
+            .layer(axum::middleware::from_fn_with_state(state, cache_control_middleware));
+
+        assert_eq!(
+            get_cache_control(app.clone(), "/serve/auth/logout").await,
+            Some("no-store".to_string())
+        );
+        assert_eq!(
+            get_cache_control(app.clone(), "/serve/sys/settings/query").await,
+            Some("private, max-age=60".to_string())
+        );
+        assert_eq!(
+            get_cache_control(app.clone(), "/serve/sys/user/current").await,
+            Some("no-store".to_string())
+        );
+        assert_eq!(get_cache_control(app, "/serve/custom").await, Some("max-age=5".to_string()));
+    }
+
+    #[derive(serde::Deserialize, Validate)]
+    struct TestPayload {
+        #[allow(unused)]
+        value: serde_json::Value,
+    }
+
+    async fn post_json(body: String) -> StatusCode {
+        let app = Router::new().route(
+            "/echo",
+            axum::routing::post(|ValidatedJson(_): ValidatedJson<TestPayload>| async { "ok" })
+        );
+        let request = Request::builder()
+            .method("POST")
+            .uri("/echo")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap();
+        app.oneshot(request).await.unwrap().status()
+    }
+
+    #[test]
+    fn test_check_json_limits() {
+        assert!(check_json_limits(br#"{"a":[1,2,{"b":"[[[,,,"}]}"#, 3, 3).is_ok());
+        assert!(check_json_limits(br#"[[[[1]]]]"#, 3, 10).is_err());
+        assert!(check_json_limits(br#"{"a":[1,2,3,4]}"#, 3, 3).is_err());
+        // The escaped quote should not end the string.
+        assert!(check_json_limits(br#"{"a":"\"[[[[[["}"#, 2, 10).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_validated_json_rejects_deeply_nested() {
+        let depth = DEFAULT_MAX_JSON_DEPTH + 1;
+        let nested = format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert_eq!(post_json(format!(r#"{{"value":{}}}"#, nested)).await, StatusCode::BAD_REQUEST);
+
+        let huge = vec!["0"; DEFAULT_MAX_JSON_ARRAY_LEN + 1].join(",");
+        assert_eq!(post_json(format!(r#"{{"value":[{}]}}"#, huge)).await, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_validated_body_rejects_oversized() {
+        #[derive(serde::Deserialize, Validate)]
+        struct Payload {
+            #[allow(unused)]
+            name: String,
+        }
+        let app = Router::new()
+            .route("/echo", axum::routing::post(|ValidatedBody(_): ValidatedBody<Payload>| async { "ok" }))
+            .layer(axum::extract::DefaultBodyLimit::max(64));
+        let call = |content_type: &'static str, body: Vec<u8>| {
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri("/echo")
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::from(body))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let name = "x".repeat(100);
+        let json = serde_json::to_vec(&serde_json::json!({ "name": name })).unwrap();
+        assert_eq!(call("application/json", json).await.unwrap().status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let msgpack = rmp_serde::to_vec_named(&serde_json::json!({ "name": name })).unwrap();
+        assert_eq!(call(MSGPACK_CONTENT_TYPE, msgpack).await.unwrap().status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let json = br#"{"name":"note"}"#.to_vec();
+        assert_eq!(call("application/json", json).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_validated_json_accepts_normal_payload() {
+        let body = r#"{"value":{"name":"note","tags":["a","b"],"nested":{"items":[1,2,3]}}}"#;
+        assert_eq!(post_json(body.to_string()).await, StatusCode::OK);
+    }
+
+    async fn call_with_budget(handler_sleep_ms: u64) -> Vec<opentelemetry_sdk::export::trace::SpanData> {
+        use tracing::Instrument;
+        let state = new_test_state(|p| {
+            p.server.latency_budgets = vec![config_serve::LatencyBudget {
+                path: "/slow".to_string(),
+                budget: 50,
+            }];
+        }).await;
+        let app = Router::new()
+            .route(
+                "/slow",
+                get(move || async move {
+                    tokio::time::sleep(std::time::Duration::from_millis(handler_sleep_ms)).await;
+                    "ok"
+                })
+            )
+            .layer(axum::middleware::from_fn_with_state(state, access_log_middleware));
+
+        let (subscriber, provider, exporter) = crate::mgmt::apm::otel::tests::new_in_memory_subscriber();
+        let guard = tracing::subscriber::set_default(subscriber);
+        let request = Request::builder().uri("/slow").body(Body::empty()).unwrap();
+        app.oneshot(request).instrument(tracing::info_span!("http_request")).await.unwrap();
+        drop(guard);
+        provider.force_flush();
+        exporter.get_finished_spans().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_latency_budget_exceeded_warns() {
+        let spans = call_with_budget(80).await;
+        let span = spans.iter().find(|s| s.name == "http_request").unwrap();
+        assert!(span.attributes.iter().any(|kv| kv.key.as_str() == "slo.violated"));
+        assert!(span.events.iter().any(|e| e.name == "latency budget exceeded"));
+    }
+
+    #[tokio::test]
+    async fn test_latency_budget_not_exceeded() {
+        let spans = call_with_budget(0).await;
+        let span = spans.iter().find(|s| s.name == "http_request").unwrap();
+        assert!(!span.attributes.iter().any(|kv| kv.key.as_str() == "slo.violated"));
+        assert!(!span.events.iter().any(|e| e.name == "latency budget exceeded"));
+    }
+
+    async fn call_with_accept_version(version: Option<&str>, fail: bool) -> serde_json::Value {
+        let state = new_test_state(|_| {}).await;
+        let app = Router::new()
+            .route(
+                "/envelope",
+                get(move || async move {
+                    if fail {
+                        AppError::NotFound("user 1".to_string()).into_response()
+                    } else {
+                        RespBase::success().to_json().into_response()
+                    }
+                })
+            )
+            .layer(axum::middleware::from_fn_with_state(state, envelope_version_middleware));
+
+        let mut request = Request::builder().uri("/envelope");
+        if let Some(version) = version {
+            request = request.header(ACCEPT_VERSION_HEADER, version);
+        }
+        let response = app.oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_envelope_version_by_accept_version() {
+        let v1_error = serde_json::json!({ "errcode": 404, "errmsg": "Not found: user 1" });
+        assert_eq!(call_with_accept_version(None, true).await, v1_error);
+        assert_eq!(call_with_accept_version(Some("v1"), true).await, v1_error);
+        assert_eq!(call_with_accept_version(Some("unknown"), true).await, v1_error);
+        assert_eq!(
+            call_with_accept_version(None, false).await,
+            serde_json::json!({ "errcode": 0, "errmsg": "ok" })
+        );
+
+        assert_eq!(
+            call_with_accept_version(Some("v2"), true).await,
+            serde_json::json!({ "error": { "code": 404, "message": "Not found: user 1" } })
+        );
+        assert_eq!(call_with_accept_version(Some("2"), false).await, serde_json::json!({ "error": null }));
+    }
+
+    async fn call_with_request_id(
+        expose: bool,
+        headers: Vec<(&str, &str)>,
+        traced: bool
+    ) -> (Option<String>, serde_json::Value) {
+        use tracing::Instrument;
+        let state = new_test_state(|p| {
+            p.server.error_correlation_ids = Some(expose);
+        }).await;
+        let app = Router::new()
+            .route(
+                "/failed",
+                get(|| async { AppError::Internal(anyhow::anyhow!("connection refused")).into_response() })
+            )
+            .layer(axum::middleware::from_fn_with_state(state.clone(), envelope_version_middleware))
+            .layer(axum::middleware::from_fn_with_state(state, request_id_middleware));
+
+        let mut request = Request::builder().uri("/failed");
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        let request = request.body(Body::empty()).unwrap();
+        let response = if traced {
+            let (subscriber, _provider, _exporter) = crate::mgmt::apm::otel::tests::new_in_memory_subscriber();
+            let _guard = tracing::subscriber::set_default(subscriber);
+            app.oneshot(request).instrument(tracing::info_span!("http_request")).await.unwrap()
+        } else {
+            app.oneshot(request).await.unwrap()
+        };
+        let request_id = response
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .map(|v| v.to_str().unwrap().to_string());
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (request_id, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_logs_of_request_carry_request_id() {
+        use tracing_subscriber::layer::SubscriberExt;
+        let state = new_test_state(|_| {}).await;
+        let app = Router::new()
+            .route(
+                "/hello",
+                get(|| async {
+                    tracing::info!("handling hello");
+                    "hello"
+                })
+            )
+            .layer(axum::middleware::from_fn_with_state(state, request_id_middleware));
+
+        let logs = crate::mgmt::apm::panic::tests::CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber
+            ::registry()
+            .with(tracing_subscriber::fmt::layer().with_writer(move || writer.clone()).with_ansi(false));
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let mut request_ids = Vec::new();
+        for _ in 0..2 {
+            let request = Request::builder().uri("/hello?name=x").body(Body::empty()).unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            request_ids.push(response.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_string());
+        }
+
+        assert_ne!(request_ids[0], request_ids[1]);
+        let lines = logs
+            .contents()
+            .lines()
+            .filter(|line| line.contains("handling hello"))
+            .map(String::from)
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        for (line, request_id) in lines.iter().zip(request_ids.iter()) {
+            assert!(line.contains(&format!("request_id={}", request_id)), "{}", line);
+            assert!(line.contains("method=GET") && line.contains("path=/hello"), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_request_span_continues_remote_trace() {
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+        use opentelemetry::trace::TraceContextExt;
+        let (subscriber, _provider, _exporter) = crate::mgmt::apm::otel::tests::new_in_memory_subscriber();
+        tracing::subscriber::with_default(subscriber, || {
+            let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+            let request = Request::builder()
+                .header("traceparent", format!("00-{}-00f067aa0ba902b7-01", trace_id))
+                .body(())
+                .unwrap();
+            let span = make_request_span(&request, TracePropagator::W3c);
+            assert_eq!(span.context().span().span_context().trace_id().to_string(), trace_id);
+            let request = Request::builder().header("b3", format!("{}-00f067aa0ba902b7-1", trace_id)).body(()).unwrap();
+            let span = make_request_span(&request, TracePropagator::B3);
+            assert_eq!(span.context().span().span_context().trace_id().to_string(), trace_id);
+
+            // A new trace is started without (or with the invalid) traceparent.
+            let request = Request::builder().header("traceparent", "invalid").body(()).unwrap();
+            let span = make_request_span(&request, TracePropagator::W3c);
+            let new_trace_id = span.context().span().span_context().trace_id().to_string();
+            assert_ne!(new_trace_id, trace_id);
+            assert!(span.context().span().span_context().is_valid());
+        });
+    }
+
+    #[tokio::test]
+    async fn test_error_body_carries_request_id_of_header() {
+        let (request_id, body) = call_with_request_id(true, vec![], false).await;
+        let request_id = request_id.unwrap();
+        assert!(!request_id.is_empty());
+        assert_eq!(body["request_id"], serde_json::json!(request_id));
+        assert_eq!(body["errcode"], serde_json::json!(500));
+        assert!(body.get("trace_id").is_none());
+
+        // The reasonable incoming request id is reused, the others are replaced.
+        let (request_id, body) = call_with_request_id(true, vec![(REQUEST_ID_HEADER, "abc-123")], false).await;
+        assert_eq!(request_id.as_deref(), Some("abc-123"));
+        assert_eq!(body["request_id"], serde_json::json!("abc-123"));
+        let (request_id, body) = call_with_request_id(true, vec![(REQUEST_ID_HEADER, "a b\"c")], false).await;
+        assert_ne!(request_id.as_deref(), Some("a b\"c"));
+        assert_eq!(body["request_id"], serde_json::json!(request_id.unwrap()));
+
+        // The v2 envelope carries them in the 'error'.
+        let (request_id, body) = call_with_request_id(true, vec![(ACCEPT_VERSION_HEADER, "v2")], false).await;
+        assert_eq!(body["error"]["request_id"], serde_json::json!(request_id.unwrap()));
+        assert_eq!(body["error"]["code"], serde_json::json!(500));
+    }
+
+    #[tokio::test]
+    async fn test_error_body_carries_trace_id_when_traced() {
+        let (request_id, body) = call_with_request_id(true, vec![], true).await;
+        assert_eq!(body["request_id"], serde_json::json!(request_id.unwrap()));
+        let trace_id = body["trace_id"].as_str().unwrap();
+        assert_eq!(trace_id.len(), 32);
+    }
+
+    #[tokio::test]
+    async fn test_error_body_without_correlation_ids_if_disabled() {
+        let (request_id, body) = call_with_request_id(false, vec![], true).await;
+        assert!(request_id.is_some());
+        assert_eq!(body, serde_json::json!({ "errcode": 500, "errmsg": "Internal error" }));
+    }
+
+    async fn call_with_timezone(timezone: Option<&str>) -> serde_json::Value {
+        let state = new_test_state(|p| {
+            p.server.timezone = timezone.map(String::from);
+        }).await;
+        let app = Router::new()
+            .route(
+                "/rows",
+                get(|| async { axum::Json(serde_json::json!({ "data": [crate::types::BaseBean::new(Some(1), None, None)] })) })
+            )
+            .layer(axum::middleware::from_fn_with_state(state, timezone_middleware));
+        let response = app.oneshot(Request::builder().uri("/rows").body(Body::empty()).unwrap()).await.unwrap();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_timestamps_stored_in_utc_and_rendered_in_zone() {
+        let before = chrono::Utc::now().timestamp_millis();
+        let body = call_with_timezone(Some("+08:00")).await;
+        let row = &body["data"][0];
+
+        // The stamped value is the UTC millis regardless of the configured zone.
+        let create_time = row["create_time"].as_i64().unwrap();
+        assert!(create_time >= before && create_time - before < 5000);
+        let expected = chrono::DateTime
+            ::from_timestamp_millis(create_time)
+            .unwrap()
+            .with_timezone(&chrono::FixedOffset::east_opt(8 * 3600).unwrap())
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, false);
+        assert_eq!(row["create_time_local"], serde_json::json!(expected));
+        assert!(expected.ends_with("+08:00"));
+        assert!(row["update_time_local"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_timestamps_not_rendered_without_timezone() {
+        let body = call_with_timezone(None).await;
+        assert!(body["data"][0]["create_time"].is_i64());
+        assert!(body["data"][0].get("create_time_local").is_none());
+    }
+
+    // Fail (or recover) the writes of settings on the primary db, while the reads are still served.
+    async fn simulate_write_outage(state: &AppState, outage: bool) {
+        let dir = state.config.db.sqlite.dir.clone().unwrap();
+        let url = format!("sqlite://{}/sqlite.db", dir);
+        let pool = sqlx::SqlitePool::connect(&url).await.unwrap();
+        let statement = if outage {
+            "CREATE TRIGGER outage BEFORE INSERT ON settings BEGIN SELECT RAISE(FAIL, 'disk I/O error'); END"
+        } else {
+            "DROP TRIGGER outage"
+        };
+        sqlx::query(statement).execute(&pool).await.unwrap();
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_degraded_mode_rejects_writes_and_serves_reads() {
+        let state = new_test_state(|p| {
+            p.server.degraded_mode = Some(true);
+            p.server.degraded_retry_after = Some(1);
+            p.auth.admin_uids = Some(vec![1]);
+        }).await;
+        let admin = AuthUserClaims::for_test(1, "a@b.com");
+        let app = crate::route::settings
+            ::init()
+            .layer(axum::Extension(admin))
+            .with_state(state.clone())
+            .layer(axum::middleware::from_fn_with_state(state.clone(), degraded_mode_middleware));
+        let read = || {
+            let request = Request::builder().uri("/sys/settings/query").body(Body::empty()).unwrap();
+            app.clone().oneshot(request)
+        };
+        let write = |name: &str| {
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri("/sys/settings/save")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(format!(r#"{{"name":"{}","scope":"global","value":"{{}}"}}"#, name)))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+        let invalid_write = || {
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri("/sys/settings/save")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"name":"","scope":"global"}"#))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+        assert_eq!(write("editor").await.unwrap().status(), StatusCode::OK);
+
+        // The failed write degrades, and the later writes are rejected without reaching the db.
+        simulate_write_outage(&state, true).await;
+        let response = write("theme").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "1");
+        assert!(state.write_health.is_degraded());
+        assert_eq!(write("theme").await.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+        let response = read().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&bytes).contains("editor"));
+
+        // Recovered once the probing write succeeds after the retry-after.
+        simulate_write_outage(&state, false).await;
+        assert_eq!(write("theme").await.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        // The rejected probe has not reached the db, which neither recovers nor blocks the next probe.
+        assert_eq!(invalid_write().await.unwrap().status(), StatusCode::BAD_REQUEST);
+        assert!(state.write_health.is_degraded());
+        assert_eq!(write("theme").await.unwrap().status(), StatusCode::OK);
+        assert!(!state.write_health.is_degraded());
+        assert_eq!(write("font").await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_degraded_mode_passes_login_through() {
+        let state = new_test_state(|p| {
+            p.server.degraded_mode = Some(true);
+            p.server.degraded_retry_after = Some(60);
+        }).await;
+        let app = Router::new()
+            .route(AUTH_PASSWORD_LOGIN_URI, axum::routing::post(|| async { "ok" }))
+            .route("/write", axum::routing::post(|| async { "ok" }))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), degraded_mode_middleware));
+        state.write_health.try_acquire().unwrap().record(false);
+
+        let post = |uri: &str| Request::builder().method(Method::POST).uri(uri).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(post(AUTH_PASSWORD_LOGIN_URI)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.oneshot(post("/write")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_degraded_mode_disabled_responds_storage_error() {
+        let state = new_test_state(|_| {}).await;
+        let app = Router::new()
+            .route(
+                "/write",
+                axum::routing::post(|| async { Err::<(), _>(AppError::Storage(anyhow::anyhow!("disk I/O error"))) })
+            )
+            .layer(axum::middleware::from_fn_with_state(state.clone(), degraded_mode_middleware));
+        let request = Request::builder().method(Method::POST).uri("/write").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!state.write_health.is_degraded());
+    }
+
+    async fn preflight(cors: CorsProperties, origin: &str) -> Response {
+        let app = Router::new().route("/sys/user/current", get(|| async { "ok" })).layer(build_cors_layer(&cors));
+        let request = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/sys/user/current")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .body(Body::empty())
+            .unwrap();
+        app.oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cors_preflight_max_age() {
+        let response = preflight(CorsProperties::default(), "https://a.example.com").await;
+        let headers = response.headers();
+        assert_eq!(headers.get(header::ACCESS_CONTROL_MAX_AGE).unwrap(), "600");
+        assert_eq!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "*");
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cors_credentials_only_for_allowlisted_hosts() {
+        let cors = CorsProperties {
+            hosts: vec!["https://app.example.com".to_string()],
+            max_age: Some(3600),
+            allow_credentials: Some(true),
+            ..CorsProperties::default()
+        };
+        let response = preflight(cors.clone(), "https://app.example.com").await;
+        let headers = response.headers();
+        assert_eq!(headers.get(header::ACCESS_CONTROL_MAX_AGE).unwrap(), "3600");
+        assert_eq!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://app.example.com");
+        assert_eq!(headers.get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).unwrap(), "true");
+
+        let response = preflight(cors, "https://evil.example.com").await;
+        assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+        assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).is_none());
+
+        // Never allow the credentials for the wildcard hosts.
+        let cors = CorsProperties { allow_credentials: Some(true), ..CorsProperties::default() };
+        let response = preflight(cors, "https://evil.example.com").await;
+        assert_eq!(response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "*");
+        assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).is_none());
+    }
+
+    fn new_settings_response() -> crate::types::settings::QuerySettingsResponse {
+        let mut settings = crate::types::settings::Settings::new(Some("editor".to_string()), None, None);
+        settings.value = Some(r#"{"theme":"dark"}"#.to_string());
+        // The del_flag is never serialized.
+        settings.base.del_flag = None;
+        crate::types::settings::QuerySettingsResponse::new(
+            crate::types::PageResponse::new(Some(1), Some(0), Some(10)),
+            vec![settings]
+        )
+    }
+
+    #[test]
+    fn test_content_format_round_trip() {
+        let response = new_settings_response();
+        for format in [ContentFormat::Json, ContentFormat::MsgPack] {
+            let bytes = format.to_vec(&response).unwrap();
+            let decoded: crate::types::settings::QuerySettingsResponse = format.from_slice(&bytes).unwrap();
+            assert_eq!(decoded, response);
+        }
+        // The msgpack is more compact than json.
+        assert!(
+            ContentFormat::MsgPack.to_vec(&response).unwrap().len() <
+                ContentFormat::Json.to_vec(&response).unwrap().len()
+        );
+
+        assert_eq!(ContentFormat::from_media_type("application/msgpack; q=0.9, */*"), Some(ContentFormat::MsgPack));
+        assert_eq!(ContentFormat::from_media_type("text/html, application/json"), Some(ContentFormat::Json));
+        assert_eq!(ContentFormat::from_media_type("*/*"), None);
+    }
+
+    #[tokio::test]
+    async fn test_negotiated_response_and_msgpack_body() {
+        #[derive(serde::Deserialize, Validate)]
+        struct Payload {
+            #[validate(length(min = 1, max = 8))]
+            name: String,
+        }
+        let app = Router::new().route(
+            "/echo",
+            axum::routing::post(|format: ContentFormat, ValidatedBody(payload): ValidatedBody<Payload>| async move {
+                Negotiated(format, serde_json::json!({ "name": payload.name }))
+            })
+        );
+        let call = |content_type: &'static str, accept: &'static str, body: Vec<u8>| {
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri("/echo")
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::ACCEPT, accept)
+                .body(Body::from(body))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let body = rmp_serde::to_vec_named(&serde_json::json!({ "name": "note" })).unwrap();
+        let response = call(MSGPACK_CONTENT_TYPE, MSGPACK_CONTENT_TYPE, body).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), MSGPACK_CONTENT_TYPE);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(value["name"], "note");
+
+        // Defaults to json, and the msgpack body is validated too.
+        let response = call("application/json", "*/*", br#"{"name":"note"}"#.to_vec()).await.unwrap();
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "application/json");
+        let body = rmp_serde::to_vec_named(&serde_json::json!({ "name": "overlong-name" })).unwrap();
+        let response = call(MSGPACK_CONTENT_TYPE, "*/*", body).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -21,32 +21,41 @@
  */
 
 use axum::{
-    extract::{ Json, Query, State },
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{ Query, State },
     routing::{ get, post },
     Router,
 };
 
 use crate::{
     context::state::AppState,
+    errors::AppError,
     handler::settings::ISettingsHandler,
     types::{
         settings::{ DeleteSettingsResponse, QuerySettingsResponse, SaveSettingsResponse },
         PageRequest,
     },
-    utils::auths::SecurityContext,
+    utils::auths::AuthUserClaims,
 };
 use crate::handler::settings::SettingsHandler;
-use crate::types::settings::{ QuerySettingsRequest, SaveSettingsRequest, DeleteSettingsRequest };
+use crate::types::settings::{
+    QuerySettingsRequest,
+    SaveSettingsRequest,
+    DeleteSettingsRequest,
+    DiffSettingsRequest,
+    DiffSettingsResponse,
+    ImportSettingsRequest,
+    ImportSettingsResponse,
+};
 
-use super::ValidatedJson;
+use super::{ ContentFormat, Negotiated, ValidatedBody };
 
 pub fn init() -> Router<AppState> {
     Router::new()
         .route("/sys/settings/query", get(handle_query_settings))
         .route("/sys/settings/save", post(handle_save_settings))
         .route("/sys/settings/delete", post(handle_delete_settings))
+        .route("/sys/settings/import", post(handle_import_settings))
+        .route("/sys/settings/diff", post(handle_diff_settings))
 }
 
 #[utoipa::path(
@@ -62,16 +71,13 @@ pub fn init() -> Router<AppState> {
 )]
 pub async fn handle_query_settings(
     State(state): State<AppState>,
+    format: ContentFormat,
+    claims: Option<AuthUserClaims>,
     Query(param): Query<QuerySettingsRequest>,
     Query(page): Query<PageRequest>
-) -> impl IntoResponse {
-    let cur_settings = SecurityContext::get_instance().get().await;
-    tracing::info!("current settings: {:?}", cur_settings);
-
-    match get_settings_handler(&state).find(param, page).await {
-        Ok((page, data)) => Ok(Json(QuerySettingsResponse::new(page, data))),
-        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
-    }
+) -> Result<Negotiated<QuerySettingsResponse>, AppError> {
+    let (page, data) = get_settings_handler(&state).find(param, page, claims).await?;
+    Ok(Negotiated(format, QuerySettingsResponse::new(page, data)))
 }
 
 #[utoipa::path(
@@ -83,12 +89,12 @@ pub async fn handle_query_settings(
 )]
 async fn handle_save_settings(
     State(state): State<AppState>,
-    ValidatedJson(param): ValidatedJson<SaveSettingsRequest>
-) -> impl IntoResponse {
-    match get_settings_handler(&state).save(param).await {
-        Ok(result) => Ok(Json(SaveSettingsResponse::new(result))),
-        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
-    }
+    format: ContentFormat,
+    claims: AuthUserClaims,
+    ValidatedBody(param): ValidatedBody<SaveSettingsRequest>
+) -> Result<Negotiated<SaveSettingsResponse>, AppError> {
+    let outcome = get_settings_handler(&state).save(&claims, param).await?;
+    Ok(Negotiated(format, SaveSettingsResponse::new(outcome)))
 }
 
 #[utoipa::path(
@@ -100,12 +106,46 @@ async fn handle_save_settings(
 )]
 async fn handle_delete_settings(
     State(state): State<AppState>,
-    Json(param): Json<DeleteSettingsRequest>
-) -> impl IntoResponse {
-    match get_settings_handler(&state).delete(param).await {
-        Ok(result) => Ok(Json(DeleteSettingsResponse::new(result))),
-        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
-    }
+    format: ContentFormat,
+    claims: AuthUserClaims,
+    ValidatedBody(param): ValidatedBody<DeleteSettingsRequest>
+) -> Result<Negotiated<DeleteSettingsResponse>, AppError> {
+    let outcome = get_settings_handler(&state).delete(&claims, param).await?;
+    Ok(Negotiated(format, DeleteSettingsResponse::new(outcome)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/sys/settings/import",
+    request_body = ImportSettingsRequest,
+    responses((status = 200, description = "Import for settings all or nothing.", body = ImportSettingsResponse)),
+    tag = "Settings"
+)]
+async fn handle_import_settings(
+    State(state): State<AppState>,
+    format: ContentFormat,
+    claims: AuthUserClaims,
+    ValidatedBody(param): ValidatedBody<ImportSettingsRequest>
+) -> Result<Negotiated<ImportSettingsResponse>, AppError> {
+    let resp = get_settings_handler(&state).import(&claims, param).await?;
+    Ok(Negotiated(format, resp))
+}
+
+#[utoipa::path(
+    post,
+    path = "/sys/settings/diff",
+    request_body = DiffSettingsRequest,
+    responses((status = 200, description = "Update only the changed items of the settings layer.", body = DiffSettingsResponse)),
+    tag = "Settings"
+)]
+async fn handle_diff_settings(
+    State(state): State<AppState>,
+    format: ContentFormat,
+    claims: AuthUserClaims,
+    ValidatedBody(param): ValidatedBody<DiffSettingsRequest>
+) -> Result<Negotiated<DiffSettingsResponse>, AppError> {
+    let resp = get_settings_handler(&state).diff(&claims, param).await?;
+    Ok(Negotiated(format, resp))
 }
 
 fn get_settings_handler(state: &AppState) -> Box<dyn ISettingsHandler + '_> {
@@ -22,21 +22,31 @@
 
 use axum::{
     extract::{ Json, Query, State },
-    http::StatusCode,
+    http::{ header, StatusCode },
     response::IntoResponse,
     routing::{ get, post },
     Router,
 };
 
 use crate::{
+    config::config_serve::WebServeProperties,
     context::state::AppState,
-    handler::user::IUserHandler,
+    errors::AppError,
+    handler::{ auth::{ AuthHandler, IAuthHandler }, user::IUserHandler },
     types::{
-        user::{ DeleteUserResponse, QueryUserResponse, SaveUserRequestWith, SaveUserResponse },
+        user::{
+            DeleteUserResponse,
+            EraseUserRequest,
+            EraseUserResponse,
+            ExportUserDataRequest,
+            QueryUserResponse,
+            SaveUserRequestWith,
+            SaveUserResponse,
+        },
         PageRequest,
         RespBase,
     },
-    utils::auths::SecurityContext,
+    utils::auths::{ AuthUserClaims, SecurityContext },
 };
 use crate::handler::user::UserHandler;
 use crate::types::user::{ QueryUserRequest, SaveUserRequest, DeleteUserRequest };
@@ -50,6 +60,8 @@ pub fn init() -> Router<AppState> {
         .route("/sys/user/query", get(handle_query_users))
         .route("/sys/user/save", post(handle_save_user))
         .route("/sys/user/delete", post(handle_delete_user))
+        .route("/sys/user/export", get(handle_export_user_data))
+        .route("/sys/user/erase", post(handle_erase_user))
 }
 
 #[utoipa::path(
@@ -68,7 +80,7 @@ async fn handle_get_current_user(State(state): State<AppState>) -> impl IntoResp
     {
         Ok(result) => {
             match result {
-                Some(user) => Ok(Json(user)),
+                Some(user) => Ok(Json(user.as_ref().clone().redacted())),
                 None => Err(StatusCode::NO_CONTENT),
             }
         }
@@ -105,7 +117,7 @@ async fn handle_post_current_user(
         ).await
     {
         Ok(_) => (StatusCode::OK, RespBase::success().to_json()).into_response(),
-        Err(e) => (StatusCode::OK, RespBase::error(e).to_json()).into_response(),
+        Err(e) => (StatusCode::OK, RespBase::error(StatusCode::INTERNAL_SERVER_ERROR, e).to_json()).into_response(),
     }
 }
 
@@ -161,6 +173,118 @@ async fn handle_delete_user(
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/sys/user/export",
+    params(ExportUserDataRequest),
+    responses(
+        (status = 200, description = "Export all the data of user as the downloadable JSON file.", body = UserDataExport),
+        (status = 403, description = "Exporting the other user by non-admin."),
+        (status = 404, description = "The user not found.")
+    ),
+    tag = "User"
+)]
+async fn handle_export_user_data(
+    State(state): State<AppState>,
+    claims: Option<AuthUserClaims>,
+    Query(param): Query<ExportUserDataRequest>
+) -> Result<impl IntoResponse, AppError> {
+    let uid = resolve_target_uid(&state.config, claims.as_ref(), param.uid)?;
+    tracing::info!("Exporting the data of user: {}, by: {:?}", uid, claims.map(|p| p.uid));
+
+    let export = get_user_handler(&state)
+        .export(uid).await
+        .map_err(AppError::storage)?
+        .ok_or_else(|| AppError::NotFound(format!("user {}", uid)))?;
+    let disposition = format!("attachment; filename=\"user-{}-export.json\"", uid);
+    Ok(([(header::CONTENT_DISPOSITION, disposition)], Json(export)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/sys/user/erase",
+    request_body = EraseUserRequest,
+    responses(
+        (status = 200, description = "Erase all the personal data of user, and revoke the tokens.", body = EraseUserResponse),
+        (status = 403, description = "Erasing the other user by non-admin."),
+        (status = 404, description = "The user not found.")
+    ),
+    tag = "User"
+)]
+async fn handle_erase_user(
+    State(state): State<AppState>,
+    claims: Option<AuthUserClaims>,
+    ValidatedJson(param): ValidatedJson<EraseUserRequest>
+) -> Result<Json<EraseUserResponse>, AppError> {
+    let uid = resolve_target_uid(&state.config, claims.as_ref(), param.uid)?;
+    tracing::info!("Erasing the user: {}, by: {:?}", uid, claims.map(|p| p.uid));
+
+    let erased = get_user_handler(&state).erase(uid).await.map_err(AppError::storage)?;
+    // The erased user could not login anymore, and the outstanding tokens are revoked as well, but the
+    // erasure is committed already, so that failing to revoke must not fail the request.
+    if let Err(e) = AuthHandler::new(&state).handle_logout_all(&uid.to_string()).await {
+        tracing::error!("Failed to revoke the tokens of the erased user: {}, reason: {}", uid, e);
+    }
+    Ok(Json(erased))
+}
+
+// Resolve the user to act on (e.g. export or erase), the current user by default, and the other users by
+// the admins only.
+fn resolve_target_uid(
+    config: &WebServeProperties,
+    principal: Option<&AuthUserClaims>,
+    target: Option<i64>
+) -> Result<i64, AppError> {
+    let principal = principal.ok_or_else(|| AppError::Auth("No current user".to_string()))?;
+    match target {
+        None => Ok(principal.uid),
+        Some(uid) if uid == principal.uid => Ok(uid),
+        Some(uid) => {
+            if config.auth.is_admin(principal.uid) {
+                Ok(uid)
+            } else {
+                Err(AppError::Forbidden(format!("acting on the user {} requires admin", uid)))
+            }
+        }
+    }
+}
+
 fn get_user_handler(state: &AppState) -> Box<dyn IUserHandler + '_> {
     Box::new(UserHandler::new(state))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_target_uid_self_and_admin_only() {
+        let mut config = WebServeProperties::default();
+        config.auth.admin_uids = Some(vec![1]);
+
+        let alice = AuthUserClaims::for_test(2, "a@b.com");
+        let admin = AuthUserClaims::for_test(1, "a@b.com");
+
+        assert!(matches!(resolve_target_uid(&config, None, None), Err(AppError::Auth(_))));
+        assert_eq!(resolve_target_uid(&config, Some(&alice), None).unwrap(), 2);
+        assert_eq!(resolve_target_uid(&config, Some(&alice), Some(2)).unwrap(), 2);
+        assert!(matches!(resolve_target_uid(&config, Some(&alice), Some(3)), Err(AppError::Forbidden(_))));
+        assert_eq!(resolve_target_uid(&config, Some(&admin), Some(3)).unwrap(), 3);
+
+        config.auth.admin_uids = None;
+        assert!(matches!(resolve_target_uid(&config, Some(&admin), Some(3)), Err(AppError::Forbidden(_))));
+    }
+
+    #[test]
+    fn test_query_user_response_redacts_password() {
+        let user = crate::types::user::User {
+            email: Some("a@b.com".to_string()),
+            password: Some("$scrypt$ln=15,r=8,p=1$salt$hash".to_string()),
+            ..Default::default()
+        };
+        let response = QueryUserResponse::new(crate::types::PageResponse::new(Some(1), Some(1), Some(10)), vec![user]);
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["data"][0]["email"], "a@b.com");
+        assert!(json["data"][0]["password"].is_null(), "{}", json);
+    }
+}
@@ -0,0 +1,144 @@
+/*
+ * SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+ *
+ * Copyleft (c) 2024 James Wong. This file is part of James Wong.
+ * is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * James Wong is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * IMPORTANT: Any software that fully or partially contains or uses materials
+ * covered by this license must also be released under the GNU GPL license.
+ * This includes modifications and derived works.
+ */
+
+use std::sync::Arc;
+
+use anyhow::Error;
+use axum::async_trait;
+
+use futures::stream::TryStreamExt;
+use mongodb::Collection;
+use mongodb::bson::{ doc, Document };
+
+use crate::config::config_serve::DbProperties;
+use crate::types::audit::AuditLog;
+use crate::types::{ PageRequest, PageResponse };
+use super::AsyncRepository;
+use super::mongo::MongoRepository;
+use crate::{
+    dynamic_mongo_select_by_ids,
+    dynamic_mongo_select_deleted,
+    dynamic_mongo_count,
+    dynamic_mongo_insert,
+};
+
+pub struct AuditLogMongoRepository {
+    #[allow(unused)]
+    inner: Arc<MongoRepository<AuditLog>>,
+    collection: Collection<AuditLog>,
+}
+
+impl AuditLogMongoRepository {
+    pub async fn new(config: &DbProperties) -> Result<Self, Error> {
+        let inner = Arc::new(MongoRepository::new(config).await?);
+        let collection = inner.get_database().collection("audit_logs");
+        Ok(AuditLogMongoRepository { inner, collection })
+    }
+}
+
+// Build the filter by the uid, event type and range of create_time.
+fn build_audit_log_filter(audit_log: &AuditLog) -> Document {
+    let mut filter = Document::new();
+    if let Some(uid) = audit_log.uid {
+        filter.insert("uid", uid);
+    }
+    if let Some(event_type) = audit_log.event_type.as_ref().filter(|t| !t.is_empty()) {
+        filter.insert("event_type", event_type);
+    }
+    let mut range = Document::new();
+    if let Some(start_time) = audit_log.start_time {
+        range.insert("$gte", start_time);
+    }
+    if let Some(end_time) = audit_log.end_time {
+        range.insert("$lte", end_time);
+    }
+    if !range.is_empty() {
+        filter.insert("create_time", range);
+    }
+    filter
+}
+
+#[async_trait]
+impl AsyncRepository<AuditLog> for AuditLogMongoRepository {
+    async fn select(
+        &self,
+        audit_log: AuditLog,
+        page: PageRequest
+    ) -> Result<(PageResponse, Vec<AuditLog>), Error> {
+        let filter = build_audit_log_filter(&audit_log);
+        let total_count = self.collection.count_documents(filter.clone()).await?;
+        let result = self.collection
+            .find(filter)
+            .skip(page.get_offset() as u64)
+            .limit(page.get_limit() as i64)
+            .sort(doc! { "create_time": -1 }).await?
+            .try_collect().await?;
+
+        let page = PageResponse::new(Some(total_count as i64), Some(page.get_offset()), Some(page.get_limit()));
+        Ok((page, result))
+    }
+
+    async fn select_by_id(&self, id: i64) -> Result<Option<AuditLog>, Error> {
+        let filter = doc! { "id": id, "del_flag": { "$ne": 1 } };
+        let audit_log = self.collection.find_one(filter).await?;
+        Ok(audit_log)
+    }
+
+    async fn select_by_ids(&self, ids: Vec<i64>) -> Result<Vec<AuditLog>, Error> {
+        dynamic_mongo_select_by_ids!(ids, self.collection)
+    }
+
+    async fn select_deleted(&self, audit_log: AuditLog, page: PageRequest) -> Result<(PageResponse, Vec<AuditLog>), Error> {
+        dynamic_mongo_select_deleted!(audit_log, self.collection, page)
+    }
+
+    async fn count_by(&self, audit_log: AuditLog, not_null_fields: &[&str]) -> Result<i64, Error> {
+        dynamic_mongo_count!(audit_log, self.collection, not_null_fields)
+    }
+
+    async fn insert(&self, mut audit_log: AuditLog) -> Result<i64, Error> {
+        dynamic_mongo_insert!(audit_log, self.collection)
+    }
+
+    async fn update(&self, _audit_log: AuditLog) -> Result<i64, Error> {
+        Err(Error::msg("Unsupported to update the append only audit logs"))
+    }
+
+    async fn delete_all(&self) -> Result<u64, Error> {
+        let result = self.collection.delete_many(doc! {}).await?;
+        Ok(result.deleted_count)
+    }
+
+    async fn delete_by_id(&self, id: i64) -> Result<u64, Error> {
+        let filter = doc! { "id": id };
+        let result = self.collection.delete_one(filter).await?;
+        Ok(result.deleted_count)
+    }
+
+    async fn purge_by_id(&self, id: i64) -> Result<u64, Error> {
+        super::mongo::purge_mongo_by_id(&self.collection, "audit_log", id).await
+    }
+
+    async fn purge_deleted_before(&self, update_time: i64) -> Result<u64, Error> {
+        super::mongo::purge_mongo_deleted_before(&self.collection, update_time).await
+    }
+}
@@ -0,0 +1,169 @@
+/*
+ * SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+ *
+ * Copyleft (c) 2024 James Wong. This file is part of James Wong.
+ * is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * James Wong is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * IMPORTANT: Any software that fully or partially contains or uses materials
+ * covered by this license must also be released under the GNU GPL license.
+ * This includes modifications and derived works.
+ */
+
+use anyhow::{ Context, Error, Ok };
+use axum::async_trait;
+use sqlx::Row;
+
+use crate::config::config_serve::DbProperties;
+use crate::types::audit::AuditLog;
+use crate::types::PageRequest;
+use crate::types::PageResponse;
+use crate::utils::types::GenericValue;
+use super::AsyncRepository;
+use super::sqlite::{ audit_sqlite_schema, SQLiteRepository };
+
+pub struct AuditLogSQLiteRepository {
+    inner: SQLiteRepository<AuditLog>,
+}
+
+impl AuditLogSQLiteRepository {
+    pub async fn new(config: &DbProperties) -> Result<Self, Error> {
+        let inner = SQLiteRepository::new(config).await?;
+        if config.schema_audit.unwrap_or(true) {
+            audit_sqlite_schema(inner.get_pool(), "audit_logs", &AuditLog::new(None, None, None, None)).await?;
+        }
+        Ok(AuditLogSQLiteRepository { inner })
+    }
+}
+
+// Build the where clause by the filters of uid, event type and range of create_time, which are covered
+// by the indexes ended with create_time, see: migrations/20241020000000_audit_logs.sql
+fn build_audit_log_where(audit_log: &AuditLog) -> (String, Vec<GenericValue>) {
+    let mut fields = vec!["del_flag = 0".to_string()];
+    let mut params = Vec::new();
+    if let Some(uid) = audit_log.uid {
+        fields.push("uid = ?".to_string());
+        params.push(GenericValue::Int64(uid));
+    }
+    if let Some(event_type) = audit_log.event_type.as_ref().filter(|t| !t.is_empty()) {
+        fields.push("event_type = ?".to_string());
+        params.push(GenericValue::String(event_type.to_owned()));
+    }
+    if let Some(start_time) = audit_log.start_time {
+        fields.push("create_time >= ?".to_string());
+        params.push(GenericValue::Int64(start_time));
+    }
+    if let Some(end_time) = audit_log.end_time {
+        fields.push("create_time <= ?".to_string());
+        params.push(GenericValue::Int64(end_time));
+    }
+    (fields.join(" AND "), params)
+}
+
+#[async_trait]
+impl AsyncRepository<AuditLog> for AuditLogSQLiteRepository {
+    async fn select(
+        &self,
+        audit_log: AuditLog,
+        page: PageRequest
+    ) -> Result<(PageResponse, Vec<AuditLog>), Error> {
+        let (where_clause, params) = build_audit_log_where(&audit_log);
+
+        let total_query = format!("SELECT COUNT(1) FROM audit_logs WHERE {}", where_clause);
+        let total_count = super::sqlite
+            ::bind_sqlite_params(sqlx::query(&total_query), &params)
+            .fetch_one(self.inner.get_read_pool()).await?
+            .get::<i64, _>(0);
+
+        let query = format!(
+            "SELECT * FROM audit_logs WHERE {} ORDER BY create_time DESC LIMIT {} OFFSET {}",
+            where_clause,
+            page.get_limit(),
+            page.get_offset()
+        );
+        let mut operator = sqlx::query_as::<_, AuditLog>(&query);
+        for param in params.iter() {
+            operator = match param {
+                GenericValue::Int64(v) => operator.bind(v),
+                GenericValue::String(v) => operator.bind(v),
+                GenericValue::Int32(v) => operator.bind(v),
+                GenericValue::Bool(v) => operator.bind(v),
+            };
+        }
+        let result = operator.fetch_all(self.inner.get_read_pool()).await?;
+
+        let page = PageResponse::new(Some(total_count), Some(page.get_offset()), Some(page.get_limit()));
+        Ok((page, result))
+    }
+
+    async fn select_by_id(&self, id: i64) -> Result<Option<AuditLog>, Error> {
+        let audit_log = sqlx
+            ::query_as::<_, AuditLog>("SELECT * FROM audit_logs WHERE id = $1 AND del_flag = 0")
+            .bind(id)
+            .fetch_optional(self.inner.get_read_pool()).await?;
+        Ok(audit_log)
+    }
+
+    async fn select_by_ids(&self, ids: Vec<i64>) -> Result<Vec<AuditLog>, Error> {
+        dynamic_sqlite_select_by_ids!(ids, "audit_logs", self.inner.get_read_pool(), AuditLog)
+    }
+
+    async fn select_deleted(&self, audit_log: AuditLog, page: PageRequest) -> Result<(PageResponse, Vec<AuditLog>), Error> {
+        dynamic_sqlite_select_deleted!(audit_log, "audit_logs", self.inner.get_read_pool(), page, AuditLog)
+    }
+
+    async fn count_by(&self, audit_log: AuditLog, not_null_fields: &[&str]) -> Result<i64, Error> {
+        dynamic_sqlite_count!(audit_log, "audit_logs", self.inner.get_read_pool(), not_null_fields)
+    }
+
+    async fn insert(&self, mut audit_log: AuditLog) -> Result<i64, Error> {
+        let inserted_id = dynamic_sqlite_insert!(
+            audit_log,
+            "audit_logs",
+            self.inner.get_pool()
+        ).context("Failed to insert audit_logs")?;
+        tracing::debug!("Inserted audit_logs.id: {:?}", inserted_id);
+        Ok(inserted_id)
+    }
+
+    async fn update(&self, _audit_log: AuditLog) -> Result<i64, Error> {
+        Err(Error::msg("Unsupported to update the append only audit logs"))
+    }
+
+    async fn save_all(&self, audit_logs: Vec<AuditLog>) -> Result<Vec<i64>, Error> {
+        dynamic_sqlite_save_all!(audit_logs, "audit_logs", &self.inner)
+    }
+
+    async fn delete_all(&self) -> Result<u64, Error> {
+        let delete_result = sqlx::query("DELETE FROM audit_logs").execute(self.inner.get_pool()).await?;
+        tracing::info!("Deleted result: {:?}", delete_result);
+        Ok(delete_result.rows_affected())
+    }
+
+    async fn delete_by_id(&self, id: i64) -> Result<u64, Error> {
+        let delete_result = sqlx
+            ::query("DELETE FROM audit_logs WHERE id = $1")
+            .bind(id)
+            .execute(self.inner.get_pool()).await?;
+        tracing::info!("Deleted result: {:?}", delete_result);
+        Ok(delete_result.rows_affected())
+    }
+
+    async fn purge_by_id(&self, id: i64) -> Result<u64, Error> {
+        super::sqlite::purge_sqlite_by_id(self.inner.get_pool(), "audit_logs", "audit_log", id).await
+    }
+
+    async fn purge_deleted_before(&self, update_time: i64) -> Result<u64, Error> {
+        super::sqlite::purge_sqlite_deleted_before(self.inner.get_pool(), "audit_logs", update_time).await
+    }
+}
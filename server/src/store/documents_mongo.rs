@@ -33,7 +33,14 @@ use crate::types::document::Document;
 use crate::types::{ PageRequest, PageResponse };
 use super::AsyncRepository;
 use super::mongo::MongoRepository;
-use crate::{ dynamic_mongo_query, dynamic_mongo_insert, dynamic_mongo_update };
+use crate::{
+    dynamic_mongo_query,
+    dynamic_mongo_select_by_ids,
+    dynamic_mongo_select_deleted,
+    dynamic_mongo_count,
+    dynamic_mongo_insert,
+    dynamic_mongo_update,
+};
 
 pub struct DocumentMongoRepository {
     #[allow(unused)]
@@ -65,14 +72,24 @@ impl AsyncRepository<Document> for DocumentMongoRepository {
         }
     }
 
-    async fn select_by_id(&self, id: i64) -> Result<Document, Error> {
-        let filter = doc! { "id": id };
-        let document = self.collection
-            .find_one(filter).await?
-            .ok_or_else(|| Error::msg("Document not found"))?;
+    async fn select_by_id(&self, id: i64) -> Result<Option<Document>, Error> {
+        let filter = doc! { "id": id, "del_flag": { "$ne": 1 } };
+        let document = self.collection.find_one(filter).await?;
         Ok(document)
     }
 
+    async fn select_by_ids(&self, ids: Vec<i64>) -> Result<Vec<Document>, Error> {
+        dynamic_mongo_select_by_ids!(ids, self.collection)
+    }
+
+    async fn select_deleted(&self, document: Document, page: PageRequest) -> Result<(PageResponse, Vec<Document>), Error> {
+        dynamic_mongo_select_deleted!(document, self.collection, page)
+    }
+
+    async fn count_by(&self, document: Document, not_null_fields: &[&str]) -> Result<i64, Error> {
+        dynamic_mongo_count!(document, self.collection, not_null_fields)
+    }
+
     async fn insert(&self, mut document: Document) -> Result<i64, Error> {
         dynamic_mongo_insert!(document, self.collection)
     }
@@ -91,4 +108,16 @@ impl AsyncRepository<Document> for DocumentMongoRepository {
         let result = self.collection.delete_one(filter).await?;
         Ok(result.deleted_count)
     }
+
+    async fn soft_delete_by_id(&self, id: i64) -> Result<u64, Error> {
+        super::mongo::soft_delete_mongo_by_id(&self.collection, id).await
+    }
+
+    async fn purge_by_id(&self, id: i64) -> Result<u64, Error> {
+        super::mongo::purge_mongo_by_id(&self.collection, "document", id).await
+    }
+
+    async fn purge_deleted_before(&self, update_time: i64) -> Result<u64, Error> {
+        super::mongo::purge_mongo_deleted_before(&self.collection, update_time).await
+    }
 }
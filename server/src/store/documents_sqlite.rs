@@ -20,7 +20,7 @@
  * This includes modifications and derived works.
  */
 
-use anyhow::{ Error, Ok };
+use anyhow::{ Context, Error, Ok };
 use axum::async_trait;
 
 use crate::config::config_serve::DbProperties;
@@ -52,33 +52,44 @@ impl AsyncRepository<Document> for DocumentSQLiteRepository {
         let result = dynamic_sqlite_query!(
             document,
             "documents",
-            self.inner.get_pool(),
+            self.inner.get_read_pool(),
             "update_time",
             page,
             Document
-        ).unwrap();
+        ).context("Failed to select documents")?;
 
         tracing::info!("query documents: {:?}", result);
         Ok((result.0, result.1))
     }
 
-    async fn select_by_id(&self, id: i64) -> Result<Document, Error> {
+    async fn select_by_id(&self, id: i64) -> Result<Option<Document>, Error> {
         let document = sqlx
-            ::query_as::<_, Document>("SELECT * FROM documents WHERE id = $1")
+            ::query_as::<_, Document>("SELECT * FROM documents WHERE id = $1 AND del_flag = 0")
             .bind(id)
-            .fetch_one(self.inner.get_pool()).await
-            .unwrap();
+            .fetch_optional(self.inner.get_read_pool()).await?;
 
         tracing::info!("query document: {:?}", document);
         Ok(document)
     }
 
+    async fn select_by_ids(&self, ids: Vec<i64>) -> Result<Vec<Document>, Error> {
+        dynamic_sqlite_select_by_ids!(ids, "documents", self.inner.get_read_pool(), Document)
+    }
+
+    async fn select_deleted(&self, document: Document, page: PageRequest) -> Result<(PageResponse, Vec<Document>), Error> {
+        dynamic_sqlite_select_deleted!(document, "documents", self.inner.get_read_pool(), page, Document)
+    }
+
+    async fn count_by(&self, document: Document, not_null_fields: &[&str]) -> Result<i64, Error> {
+        dynamic_sqlite_count!(document, "documents", self.inner.get_read_pool(), not_null_fields)
+    }
+
     async fn insert(&self, mut document: Document) -> Result<i64, Error> {
         let inserted_id = dynamic_sqlite_insert!(
             document,
             "documents",
             self.inner.get_pool()
-        ).unwrap();
+        ).context("Failed to insert documents")?;
         tracing::info!("Inserted document.id: {:?}", inserted_id);
         Ok(inserted_id)
     }
@@ -88,16 +99,20 @@ impl AsyncRepository<Document> for DocumentSQLiteRepository {
             document,
             "documents",
             self.inner.get_pool()
-        ).unwrap();
+        ).context("Failed to update documents")?;
         tracing::info!("Updated document.id: {:?}", updated_id);
         Ok(updated_id)
     }
 
+    async fn save_all(&self, documents: Vec<Document>) -> Result<Vec<i64>, Error> {
+        dynamic_sqlite_save_all!(documents, "documents", &self.inner)
+    }
+
     async fn delete_all(&self) -> Result<u64, Error> {
         let delete_result = sqlx
             ::query("DELETE FROM documents")
             .execute(self.inner.get_pool()).await
-            .unwrap();
+            .context("Failed to delete all documents")?;
 
         tracing::info!("Deleted result: {:?}", delete_result);
         Ok(delete_result.rows_affected())
@@ -108,9 +123,22 @@ impl AsyncRepository<Document> for DocumentSQLiteRepository {
             ::query("DELETE FROM documents WHERE id = $1")
             .bind(id)
             .execute(self.inner.get_pool()).await
-            .unwrap();
+            .with_context(|| format!("Failed to delete documents by id: {}", id))?;
 
         tracing::info!("Deleted result: {:?}", delete_result);
         Ok(delete_result.rows_affected())
     }
+
+    async fn soft_delete_by_id(&self, id: i64) -> Result<u64, Error> {
+        super::sqlite::soft_delete_sqlite_by_id(self.inner.get_pool(), "documents", id).await
+            .with_context(|| format!("Failed to soft delete documents by id: {}", id))
+    }
+
+    async fn purge_by_id(&self, id: i64) -> Result<u64, Error> {
+        super::sqlite::purge_sqlite_by_id(self.inner.get_pool(), "documents", "document", id).await
+    }
+
+    async fn purge_deleted_before(&self, update_time: i64) -> Result<u64, Error> {
+        super::sqlite::purge_sqlite_deleted_before(self.inner.get_pool(), "documents", update_time).await
+    }
 }
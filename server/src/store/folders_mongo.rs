@@ -33,7 +33,14 @@ use crate::types::folder::Folder;
 use crate::types::{ PageRequest, PageResponse };
 use super::AsyncRepository;
 use super::mongo::MongoRepository;
-use crate::{ dynamic_mongo_query, dynamic_mongo_insert, dynamic_mongo_update };
+use crate::{
+    dynamic_mongo_query,
+    dynamic_mongo_select_by_ids,
+    dynamic_mongo_select_deleted,
+    dynamic_mongo_count,
+    dynamic_mongo_insert,
+    dynamic_mongo_update,
+};
 
 pub struct FolderMongoRepository {
     #[allow(unused)]
@@ -65,14 +72,24 @@ impl AsyncRepository<Folder> for FolderMongoRepository {
         }
     }
 
-    async fn select_by_id(&self, id: i64) -> Result<Folder, Error> {
-        let filter = doc! { "id": id };
-        let folder = self.collection
-            .find_one(filter).await?
-            .ok_or_else(|| Error::msg("Folder not found"))?;
+    async fn select_by_id(&self, id: i64) -> Result<Option<Folder>, Error> {
+        let filter = doc! { "id": id, "del_flag": { "$ne": 1 } };
+        let folder = self.collection.find_one(filter).await?;
         Ok(folder)
     }
 
+    async fn select_by_ids(&self, ids: Vec<i64>) -> Result<Vec<Folder>, Error> {
+        dynamic_mongo_select_by_ids!(ids, self.collection)
+    }
+
+    async fn select_deleted(&self, folder: Folder, page: PageRequest) -> Result<(PageResponse, Vec<Folder>), Error> {
+        dynamic_mongo_select_deleted!(folder, self.collection, page)
+    }
+
+    async fn count_by(&self, folder: Folder, not_null_fields: &[&str]) -> Result<i64, Error> {
+        dynamic_mongo_count!(folder, self.collection, not_null_fields)
+    }
+
     async fn insert(&self, mut folder: Folder) -> Result<i64, Error> {
         dynamic_mongo_insert!(folder, self.collection)
     }
@@ -91,4 +108,12 @@ impl AsyncRepository<Folder> for FolderMongoRepository {
         let result = self.collection.delete_one(filter).await?;
         Ok(result.deleted_count)
     }
+
+    async fn purge_by_id(&self, id: i64) -> Result<u64, Error> {
+        super::mongo::purge_mongo_by_id(&self.collection, "folder", id).await
+    }
+
+    async fn purge_deleted_before(&self, update_time: i64) -> Result<u64, Error> {
+        super::mongo::purge_mongo_deleted_before(&self.collection, update_time).await
+    }
 }
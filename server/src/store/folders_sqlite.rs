@@ -20,7 +20,7 @@
  * This includes modifications and derived works.
  */
 
-use anyhow::{ Error, Ok };
+use anyhow::{ Context, Error, Ok };
 use axum::async_trait;
 
 use crate::config::config_serve::DbProperties;
@@ -52,44 +52,59 @@ impl AsyncRepository<Folder> for FolderSQLiteRepository {
         let result = dynamic_sqlite_query!(
             folder,
             "folders",
-            self.inner.get_pool(),
+            self.inner.get_read_pool(),
             "update_time",
             page,
             Folder
-        ).unwrap();
+        ).context("Failed to select folders")?;
 
         tracing::info!("query folders: {:?}", result);
         Ok((result.0, result.1))
     }
 
-    async fn select_by_id(&self, id: i64) -> Result<Folder, Error> {
+    async fn select_by_id(&self, id: i64) -> Result<Option<Folder>, Error> {
         let folder = sqlx
-            ::query_as::<_, Folder>("SELECT * FROM folders WHERE id = $1")
+            ::query_as::<_, Folder>("SELECT * FROM folders WHERE id = $1 AND del_flag = 0")
             .bind(id)
-            .fetch_one(self.inner.get_pool()).await
-            .unwrap();
+            .fetch_optional(self.inner.get_read_pool()).await?;
 
         tracing::info!("query folder: {:?}", folder);
         Ok(folder)
     }
 
+    async fn select_by_ids(&self, ids: Vec<i64>) -> Result<Vec<Folder>, Error> {
+        dynamic_sqlite_select_by_ids!(ids, "folders", self.inner.get_read_pool(), Folder)
+    }
+
+    async fn select_deleted(&self, folder: Folder, page: PageRequest) -> Result<(PageResponse, Vec<Folder>), Error> {
+        dynamic_sqlite_select_deleted!(folder, "folders", self.inner.get_read_pool(), page, Folder)
+    }
+
+    async fn count_by(&self, folder: Folder, not_null_fields: &[&str]) -> Result<i64, Error> {
+        dynamic_sqlite_count!(folder, "folders", self.inner.get_read_pool(), not_null_fields)
+    }
+
     async fn insert(&self, mut folder: Folder) -> Result<i64, Error> {
-        let inserted_id = dynamic_sqlite_insert!(folder, "folders", self.inner.get_pool()).unwrap();
+        let inserted_id = dynamic_sqlite_insert!(folder, "folders", self.inner.get_pool()).context("Failed to insert folders")?;
         tracing::info!("Inserted folder.id: {:?}", inserted_id);
         Ok(inserted_id)
     }
 
     async fn update(&self, mut folder: Folder) -> Result<i64, Error> {
-        let updated_id = dynamic_sqlite_update!(folder, "folders", self.inner.get_pool()).unwrap();
+        let updated_id = dynamic_sqlite_update!(folder, "folders", self.inner.get_pool()).context("Failed to update folders")?;
         tracing::info!("Updated folder.id: {:?}", updated_id);
         Ok(updated_id)
     }
 
+    async fn save_all(&self, folders: Vec<Folder>) -> Result<Vec<i64>, Error> {
+        dynamic_sqlite_save_all!(folders, "folders", &self.inner)
+    }
+
     async fn delete_all(&self) -> Result<u64, Error> {
         let delete_result = sqlx
             ::query("DELETE FROM folders")
             .execute(self.inner.get_pool()).await
-            .unwrap();
+            .context("Failed to delete all folders")?;
 
         tracing::info!("Deleted result: {:?}", delete_result);
         Ok(delete_result.rows_affected())
@@ -100,9 +115,17 @@ impl AsyncRepository<Folder> for FolderSQLiteRepository {
             ::query("DELETE FROM folders WHERE id = $1")
             .bind(id)
             .execute(self.inner.get_pool()).await
-            .unwrap();
+            .with_context(|| format!("Failed to delete folders by id: {}", id))?;
 
         tracing::info!("Deleted result: {:?}", delete_result);
         Ok(delete_result.rows_affected())
     }
+
+    async fn purge_by_id(&self, id: i64) -> Result<u64, Error> {
+        super::sqlite::purge_sqlite_by_id(self.inner.get_pool(), "folders", "folder", id).await
+    }
+
+    async fn purge_deleted_before(&self, update_time: i64) -> Result<u64, Error> {
+        super::sqlite::purge_sqlite_deleted_before(self.inner.get_pool(), "folders", update_time).await
+    }
 }
@@ -23,6 +23,8 @@
 pub mod mongo;
 #[macro_use]
 pub mod sqlite;
+pub mod audit_logs_mongo;
+pub mod audit_logs_sqlite;
 pub mod documents_mongo;
 pub mod documents_sqlite;
 pub mod folders_mongo;
@@ -31,31 +33,116 @@ pub mod settings_sqlite;
 pub mod settings_mongo;
 pub mod users_sqlite;
 pub mod users_mongo;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "postgres")]
+pub mod users_postgres;
 
-use anyhow::Error;
+use anyhow::{ anyhow, Error };
 use axum::async_trait;
+use tokio::sync::Mutex;
 
 use crate::{
-    config::config_serve::{ WebServeProperties, DbType },
-    types::{ PageResponse, PageRequest },
+    config::config_serve::{ WebServeProperties, DbType, ErasureStrategy },
+    types::{ user::ErasureOutcome, PageResponse, PageRequest },
 };
 
 #[async_trait] // solution2: async fn + dyn polymorphism problem.
-pub trait AsyncRepository<T>: Send {
+pub trait AsyncRepository<T>: Send + Sync {
     // solution1: async fn + dyn polymorphism problem.
     // fn select(&self) -> Box<dyn Future<Output = Result<Page<T>, Error>> + Send>;
     async fn select(&self, mut param: T, page: PageRequest) -> Result<(PageResponse, Vec<T>), Error>
         where T: 'static + Send + Sync;
-    async fn select_by_id(&self, id: i64) -> Result<T, Error> where T: 'static + Send + Sync;
+    // Select the row by id, the not found id is Ok(None) rather than an error.
+    async fn select_by_id(&self, id: i64) -> Result<Option<T>, Error> where T: 'static + Send + Sync;
+    // Select the rows by ids in batch, the not found (or soft-deleted) ids are simply absent in result.
+    async fn select_by_ids(&self, ids: Vec<i64>) -> Result<Vec<T>, Error> where T: 'static + Send + Sync;
+    // Select the soft-deleted (del_flag = 1) rows matched the param fields (same as select), i.e. the trash,
+    // which are ordered by the deletion (update) time desc.
+    async fn select_deleted(&self, mut param: T, page: PageRequest) -> Result<(PageResponse, Vec<T>), Error>
+        where T: 'static + Send + Sync;
+    // Count the rows matched the param fields (same as select) and with all the not null fields present,
+    // the soft-deleted rows are excluded.
+    async fn count_by(&self, mut param: T, not_null_fields: &[&str]) -> Result<i64, Error>
+        where T: 'static + Send + Sync;
     async fn insert(&self, mut param: T) -> Result<i64, Error> where T: 'static + Send + Sync;
     async fn update(&self, mut param: T) -> Result<i64, Error> where T: 'static + Send + Sync;
+    // Update the row only if its update_time is still the expected (i.e. the last seen by the caller, none if
+    // never updated), otherwise fails with the StoreError::Conflict, i.e. the optimistic locking.
+    async fn update_if_unchanged(&self, _param: T, expected_update_time: Option<i64>) -> Result<i64, Error>
+        where T: 'static + Send + Sync
+    {
+        Err(anyhow!("Update if unchanged since {:?} is not supported by the repository", expected_update_time))
+    }
+    // Save (insert without id or update with id) all the rows atomically in a transaction, i.e. all or
+    // nothing, returns the ids in order, and -1 for the unchanged (or not found) updates.
+    async fn save_all(&self, params: Vec<T>) -> Result<Vec<i64>, Error> where T: 'static + Send + Sync {
+        Err(anyhow!("Save all of {} rows atomically is not supported by the repository", params.len()))
+    }
+    // Insert all the rows with the multi-rows statements in a transaction, returns the ids in the input order.
+    async fn insert_batch(&self, params: Vec<T>) -> Result<Vec<i64>, Error> where T: 'static + Send + Sync {
+        Err(anyhow!("Batch insert of {} rows is not supported by the repository", params.len()))
+    }
     async fn delete_all(&self) -> Result<u64, Error>;
+    // Delete the row by the default behavior, i.e. soft-deleted if the 'db.soft-delete' enabled, or hard
+    // deleted otherwise (for the users only, the others are always hard deleted for now).
     async fn delete_by_id(&self, id: i64) -> Result<u64, Error>;
+    // Soft-delete the active row, i.e. set the del_flag = 1 and stamp the update time, so that it's excluded
+    // from the queries but remains in the table until purged.
+    async fn soft_delete_by_id(&self, id: i64) -> Result<u64, Error> {
+        Err(anyhow!("Soft delete by id: {} is not supported by the repository", id))
+    }
+    // Permanently delete the row whether soft-deleted or not.
+    async fn hard_delete_by_id(&self, id: i64) -> Result<u64, Error> {
+        Err(anyhow!("Hard delete by id: {} is not supported by the repository", id))
+    }
+    // Permanently (hard) delete the row which must have been soft-deleted, the active row is rejected, so
+    // that it cannot be hard deleted accidentally.
+    async fn purge_by_id(&self, id: i64) -> Result<u64, Error>;
+    // Permanently delete all the soft-deleted rows of which deleted (updated) before the time (millis).
+    async fn purge_deleted_before(&self, update_time: i64) -> Result<u64, Error>;
+    // Erase the row with all the personal data owned by it (i.e. the right to erasure) in a transaction,
+    // which is supported by the repositories of users only.
+    async fn erase(&self, id: i64, strategy: ErasureStrategy) -> Result<ErasureOutcome, Error> {
+        Err(anyhow!("Erasure by id: {} ({:?}) is not supported by the repository", id, strategy))
+    }
+}
+
+/// The typed errors of repositories, which are carried by the anyhow::Error and could be downcasted.
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("Not found {0} by id: {1}")]
+    NotFound(&'static str, i64),
+    // The active row cannot be purged (hard deleted) before soft-deleted.
+    #[error("Cannot purge the active {0} by id: {1}")]
+    NotDeleted(&'static str, i64),
+    // The row has been updated by others since the caller last seen, which could be retried after reloaded.
+    #[error("Conflict update of {0} by id: {1}, it has been changed since last seen")]
+    Conflict(&'static str, i64),
+    #[error("{0}")]
+    InvalidCursor(String),
+    #[error("Storage unavailable: {0}")]
+    StorageUnavailable(#[source] anyhow::Error),
+}
+
+/// Whether the error is caused by the unique constraint (index) violation of the insert/update.
+pub fn is_unique_violation(e: &Error) -> bool {
+    if let Some(sqlx::Error::Database(db)) = e.downcast_ref::<sqlx::Error>() {
+        return db.is_unique_violation();
+    }
+    if let Some(e) = e.downcast_ref::<mongodb::error::Error>() {
+        // see:https://www.mongodb.com/docs/manual/reference/error-codes/#mongodb-error-11000
+        if let mongodb::error::ErrorKind::Write(mongodb::error::WriteFailure::WriteError(we)) = &*e.kind {
+            return we.code == 11000;
+        }
+    }
+    false
 }
 
 pub struct RepositoryContainer<T> where T: 'static + Send + Sync {
     sqlite_repo: Box<dyn AsyncRepository<T>>,
     mongo_repo: Box<dyn AsyncRepository<T>>,
+    postgres_repo: Option<Box<dyn AsyncRepository<T>>>,
 }
 
 impl<T> RepositoryContainer<T> where T: 'static + Send + Sync {
@@ -66,9 +153,17 @@ impl<T> RepositoryContainer<T> where T: 'static + Send + Sync {
         RepositoryContainer {
             sqlite_repo,
             mongo_repo,
+            postgres_repo: None,
         }
     }
 
+    // Only the modules with the postgres repository are backed by the postgres, the others are
+    // still backed by the sqlite.
+    pub fn with_postgres(mut self, postgres_repo: Box<dyn AsyncRepository<T>>) -> Self {
+        self.postgres_repo = Some(postgres_repo);
+        self
+    }
+
     fn sqlite_repo(&self) -> &dyn AsyncRepository<T> {
         &*self.sqlite_repo
     }
@@ -81,6 +176,32 @@ impl<T> RepositoryContainer<T> where T: 'static + Send + Sync {
         match config.db.db_type {
             DbType::Sqlite => self.sqlite_repo(),
             DbType::Mongo => self.mongo_repo(),
+            DbType::Postgres => self.postgres_repo.as_deref().unwrap_or_else(|| self.sqlite_repo()),
+        }
+    }
+}
+
+// The page size of selecting all the rows, e.g. the rows tied to the user on exporting.
+const SELECT_ALL_PAGE_SIZE: u32 = 500;
+
+// Select all the rows matched the param page by page.
+pub async fn select_all<T>(
+    repo: &Mutex<RepositoryContainer<T>>,
+    config: &WebServeProperties,
+    param: T
+) -> Result<Vec<T>, Error>
+    where T: Clone + 'static + Send + Sync
+{
+    let mut rows = Vec::new();
+    let mut num = 1;
+    loop {
+        let page = PageRequest { num: Some(num), limit: Some(SELECT_ALL_PAGE_SIZE), ..PageRequest::default() };
+        let (_, data) = repo.lock().await.get(config).select(param.clone(), page).await?;
+        let last = data.len() < (SELECT_ALL_PAGE_SIZE as usize);
+        rows.extend(data);
+        if last {
+            return Ok(rows);
         }
+        num += 1;
     }
 }
@@ -28,11 +28,13 @@ use anyhow::Error;
 use axum::async_trait;
 
 use mongodb::options::{ ReadConcern, WriteConcern };
-use mongodb::{ Client, Database, options::ClientOptions };
+use mongodb::bson::doc;
+use mongodb::{ Client, Collection, Database, options::ClientOptions };
 
-use super::AsyncRepository;
+use super::{ AsyncRepository, StoreError };
 use crate::config::config_serve::DbProperties;
 use crate::types::{ PageResponse, PageRequest };
+use crate::utils::times;
 
 pub struct MongoRepository<T: Any + Send + Sync> {
     phantom: PhantomData<T>,
@@ -84,10 +86,22 @@ impl<T: Any + Send + Sync> AsyncRepository<T> for MongoRepository<T> {
         unimplemented!("select not implemented for MongoRepository")
     }
 
-    async fn select_by_id(&self, id: i64) -> Result<T, Error> {
+    async fn select_by_id(&self, id: i64) -> Result<Option<T>, Error> {
         unimplemented!("select_by_id not implemented for MongoRepository")
     }
 
+    async fn select_by_ids(&self, ids: Vec<i64>) -> Result<Vec<T>, Error> {
+        unimplemented!("select_by_ids not implemented for MongoRepository")
+    }
+
+    async fn select_deleted(&self, param: T, page: PageRequest) -> Result<(PageResponse, Vec<T>), Error> {
+        unimplemented!("select_deleted not implemented for MongoRepository")
+    }
+
+    async fn count_by(&self, param: T, not_null_fields: &[&str]) -> Result<i64, Error> {
+        unimplemented!("count_by not implemented for MongoRepository")
+    }
+
     async fn insert(&self, param: T) -> Result<i64, Error> {
         unimplemented!("insert not implemented for MongoRepository")
     }
@@ -103,6 +117,46 @@ impl<T: Any + Send + Sync> AsyncRepository<T> for MongoRepository<T> {
     async fn delete_by_id(&self, id: i64) -> Result<u64, Error> {
         unimplemented!("delete_by_id not implemented for MongoRepository")
     }
+
+    async fn purge_by_id(&self, id: i64) -> Result<u64, Error> {
+        unimplemented!("purge_by_id not implemented for MongoRepository")
+    }
+
+    async fn purge_deleted_before(&self, update_time: i64) -> Result<u64, Error> {
+        unimplemented!("purge_deleted_before not implemented for MongoRepository")
+    }
+}
+
+// Soft-delete the active document of the collection, the absent (or already soft-deleted) is not affected.
+pub async fn soft_delete_mongo_by_id<T: Send + Sync>(collection: &Collection<T>, id: i64) -> Result<u64, Error> {
+    let filter = doc! { "id": id, "del_flag": { "$ne": 1 } };
+    let update = doc! { "$set": { "del_flag": 1, "update_time": times::now_millis() } };
+    Ok(collection.update_one(filter, update).await?.modified_count)
+}
+
+// Hard delete the soft-deleted document of the collection, the active is rejected and the absent is not found.
+pub async fn purge_mongo_by_id<T: Send + Sync>(
+    collection: &Collection<T>,
+    name: &'static str,
+    id: i64
+) -> Result<u64, Error> {
+    let purged = collection.delete_one(doc! { "id": id, "del_flag": 1 }).await?.deleted_count;
+    if purged == 0 {
+        if collection.count_documents(doc! { "id": id }).await? > 0 {
+            return Err(StoreError::NotDeleted(name, id).into());
+        }
+        return Err(StoreError::NotFound(name, id).into());
+    }
+    tracing::info!("Purged the row of {} by id: {}", name, id);
+    Ok(purged)
+}
+
+pub async fn purge_mongo_deleted_before<T: Send + Sync>(
+    collection: &Collection<T>,
+    update_time: i64
+) -> Result<u64, Error> {
+    let filter = doc! { "del_flag": 1, "update_time": { "$lt": update_time } };
+    Ok(collection.delete_many(filter).await?.deleted_count)
 }
 
 #[macro_export]
@@ -127,6 +181,8 @@ macro_rules! dynamic_mongo_query {
             if let Some(id) = $bean.base.id {
                 filter.insert("id", id);
             }
+            // The soft-deleted documents are excluded, see: select_deleted.
+            filter.insert("del_flag", doc! { "$ne": 1 });
 
             let options = mongodb::options::FindOptions::builder()
                 .skip($page.get_offset() as u64)
@@ -160,6 +216,96 @@ macro_rules! dynamic_mongo_query {
     };
 }
 
+#[macro_export]
+macro_rules! dynamic_mongo_count {
+    ($bean:expr, $collection:expr, $not_null_fields:expr) => {
+        {
+            use mongodb::bson::{doc, Bson, Document};
+
+            let serialized = serde_json::to_value(&$bean).unwrap();
+            let obj = serialized.as_object().unwrap();
+
+            let mut filter = Document::new();
+            for (key, value) in obj {
+                if !value.is_null() {
+                    let v = value.as_str().unwrap_or("");
+                    if !v.is_empty() {
+                        filter.insert(key, v);
+                    }
+                }
+            }
+            if let Some(id) = $bean.base.id {
+                filter.insert("id", id);
+            }
+            for field in $not_null_fields.iter() {
+                filter.insert(*field, doc! { "$nin": [Bson::Null, ""] });
+            }
+            filter.insert("del_flag", doc! { "$ne": 1 });
+
+            $collection
+                .count_documents(filter).await
+                .map(|count| count as i64)
+                .map_err(|e| anyhow::Error::from(e))
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! dynamic_mongo_select_by_ids {
+    ($ids:expr, $collection:expr) => {
+        {
+            use futures::stream::TryStreamExt;
+            use mongodb::bson::doc;
+
+            let filter = doc! { "id": { "$in": $ids }, "del_flag": { "$ne": 1 } };
+            match $collection.find(filter).await {
+                std::result::Result::Ok(cursor) => {
+                    cursor.try_collect::<Vec<_>>().await.map_err(|e| anyhow::Error::from(e))
+                }
+                Err(e) => Err(anyhow::Error::from(e)),
+            }
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! dynamic_mongo_select_deleted {
+    ($bean:expr, $collection:expr, $page:expr) => {
+        {
+            use futures::stream::TryStreamExt;
+            use mongodb::bson::{doc, Document};
+
+            let serialized = serde_json::to_value(&$bean).unwrap();
+            let obj = serialized.as_object().unwrap();
+
+            let mut filter = Document::new();
+            for (key, value) in obj {
+                if !value.is_null() {
+                    let v = value.as_str().unwrap_or("");
+                    if !v.is_empty() {
+                        filter.insert(key, v);
+                    }
+                }
+            }
+            if let Some(id) = $bean.base.id {
+                filter.insert("id", id);
+            }
+            filter.insert("del_flag", 1);
+
+            let total_count = $collection.count_documents(filter.clone()).await?;
+            let result = $collection
+                .find(filter)
+                .skip($page.get_offset() as u64)
+                .limit($page.get_limit() as i64)
+                .sort(doc! { "update_time": -1 }).await?
+                .try_collect().await?;
+
+            let page = PageResponse::new(Some(total_count as i64), Some($page.get_offset()), Some($page.get_limit()));
+            std::result::Result::<_, anyhow::Error>::Ok((page, result))
+        }
+    };
+}
+
 #[macro_export]
 macro_rules! dynamic_mongo_insert {
     ($bean:expr, $collection:expr) => {
@@ -220,7 +366,17 @@ macro_rules! dynamic_mongo_update {
                 }
             }
 
-            let filter = doc! { "id": id };
+            // Only update when any of the biz fields changed, so that nothing is updated when unchanged.
+            let changes: Vec<Bson> = update_doc
+                .iter()
+                .filter(|(key, _)| !$crate::types::BASE_BEAN_FIELDS.contains(&key.as_str()))
+                .map(|(key, value)| Bson::Document(doc! { key: { "$ne": value.clone() } }))
+                .collect();
+            let filter = if changes.is_empty() {
+                doc! { "id": id }
+            } else {
+                doc! { "id": id, "$or": changes }
+            };
             let update = doc! { "$set": update_doc };
             let result = $collection.update_one(filter, update).await?;
 
@@ -0,0 +1,363 @@
+/*
+ * SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+ *
+ * Copyleft (c) 2024 James Wong. This file is part of James Wong.
+ * is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * James Wong is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * IMPORTANT: Any software that fully or partially contains or uses materials
+ * covered by this license must also be released under the GNU GPL license.
+ * This includes modifications and derived works.
+ */
+
+use std::any::Any;
+use std::marker::PhantomData;
+
+use anyhow::Error;
+use serde::Serialize;
+use sqlx::{ postgres::{ PgArguments, PgPoolOptions, PgRow }, Arguments, FromRow, PgPool };
+use tracing::{ debug, info };
+
+use crate::{
+    config::config_serve::{ DbProperties, DEFAULT_DB_POSTGRES_MAX_CONNECTIONS },
+    types::{ PageRequest, PageResponse },
+    utils::{ times, types::GenericValue },
+};
+use super::StoreError;
+
+pub struct PostgresRepository<T: Any + Send + Sync> {
+    phantom: PhantomData<T>,
+    pool: PgPool,
+}
+
+impl<T: Any + Send + Sync> PostgresRepository<T> {
+    pub async fn new(config: &DbProperties) -> Result<Self, Error> {
+        let url = config.postgres.url
+            .to_owned()
+            .filter(|url| !url.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("Postgres url missing configured, see: 'db.postgres.url'"))?;
+        let pool = PgPoolOptions::new()
+            .max_connections(config.postgres.max_connections.unwrap_or(DEFAULT_DB_POSTGRES_MAX_CONNECTIONS))
+            .connect(&url).await
+            .map_err(|e| {
+                tracing::info!("Database postgres connection error: {:?}", e);
+                e
+            })?;
+        tracing::info!("Successfully connected to the postgres database");
+
+        let results = sqlx::migrate!("./migrations_postgres").run(&pool).await;
+        debug!("Migration result: {:?}", results);
+        results?;
+        Ok(PostgresRepository { phantom: PhantomData, pool })
+    }
+
+    pub fn get_pool(&self) -> &PgPool {
+        &self.pool
+    }
+}
+
+// Convert the params into the postgres arguments, which are bound to the placeholders '$1', '$2', ...
+pub fn to_pg_arguments(params: &[GenericValue]) -> PgArguments {
+    let mut arguments = PgArguments::default();
+    for param in params {
+        match param {
+            GenericValue::Bool(v) => arguments.add(*v),
+            GenericValue::Int32(v) => arguments.add(*v),
+            GenericValue::Int64(v) => arguments.add(*v),
+            GenericValue::String(v) => arguments.add(v.to_owned()),
+        }
+    }
+    arguments
+}
+
+// Build the conditions of the non-empty string fields and the id of serialized bean, same as the sqlite,
+// the placeholders are numbered after the existing params.
+pub fn build_pg_conditions(serialized: &serde_json::Value, params: &mut Vec<GenericValue>) -> Vec<String> {
+    let mut conditions = Vec::new();
+    for (key, value) in serialized.as_object().into_iter().flatten() {
+        if key == "id" {
+            if let Some(id) = value.as_i64() {
+                params.push(GenericValue::Int64(id));
+                conditions.push(format!("id = ${}", params.len()));
+            }
+        } else if let Some(v) = value.as_str().filter(|v| !v.is_empty()) {
+            params.push(GenericValue::String(v.to_string()));
+            conditions.push(format!("{} = ${}", key, params.len()));
+        }
+    }
+    conditions
+}
+
+// Select the page of rows matched the bean, with the extra conditions (e.g. 'del_flag = 1').
+pub async fn select_pg_page<T>(
+    pool: &PgPool,
+    table: &str,
+    bean: &impl Serialize,
+    extra_conditions: &[&str],
+    order_by: &str,
+    page: &PageRequest
+) -> Result<(PageResponse, Vec<T>), Error>
+    where T: for<'r> FromRow<'r, PgRow> + Send + Unpin
+{
+    let mut params = Vec::new();
+    let mut conditions = build_pg_conditions(&serde_json::to_value(bean)?, &mut params);
+    conditions.extend(extra_conditions.iter().map(|c| c.to_string()));
+    let where_clause = if conditions.is_empty() { "1=1".to_string() } else { conditions.join(" AND ") };
+
+    let total_count = if page.is_count() {
+        let total_query = format!("SELECT COUNT(1) FROM {} WHERE {}", table, where_clause);
+        Some(sqlx::query_scalar_with::<_, i64, _>(&total_query, to_pg_arguments(&params)).fetch_one(pool).await?)
+    } else {
+        None
+    };
+
+    let query = format!(
+        "SELECT * FROM {} WHERE {} ORDER BY {} LIMIT {} OFFSET {}",
+        table,
+        where_clause,
+        order_by,
+        page.get_limit(),
+        page.get_offset()
+    );
+    let result = sqlx::query_as_with::<_, T, _>(&query, to_pg_arguments(&params)).fetch_all(pool).await?;
+
+    let page = PageResponse::new(total_count, Some(page.get_offset()), Some(page.get_limit()));
+    Ok((page, result))
+}
+
+pub async fn count_pg(
+    pool: &PgPool,
+    table: &str,
+    bean: &impl Serialize,
+    not_null_fields: &[&str]
+) -> Result<i64, Error> {
+    let mut params = Vec::new();
+    let mut conditions = build_pg_conditions(&serde_json::to_value(bean)?, &mut params);
+    for field in not_null_fields.iter() {
+        // Notice: The field name is concatenated into SQL, so only the identifier is allowed.
+        if field.is_empty() || !field.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(anyhow::anyhow!("Invalid not null field name: {}", field));
+        }
+        conditions.push(format!("({} IS NOT NULL AND {} != '')", field, field));
+    }
+    conditions.push("del_flag = 0".to_string());
+
+    let query = format!("SELECT COUNT(1) FROM {} WHERE {}", table, conditions.join(" AND "));
+    Ok(sqlx::query_scalar_with::<_, i64, _>(&query, to_pg_arguments(&params)).fetch_one(pool).await?)
+}
+
+pub async fn select_pg_by_ids<T>(pool: &PgPool, table: &str, ids: Vec<i64>) -> Result<Vec<T>, Error>
+    where T: for<'r> FromRow<'r, PgRow> + Send + Unpin
+{
+    // No chunking is needed, since the ids are bound as an array.
+    let query = format!("SELECT * FROM {} WHERE id = ANY($1) AND del_flag = 0", table);
+    Ok(sqlx::query_as::<_, T>(&query).bind(ids).fetch_all(pool).await?)
+}
+
+// Build the insert statement of the non-empty fields of serialized bean, none if no fields.
+pub fn build_pg_insert(table: &str, serialized: &serde_json::Value) -> Option<(String, Vec<GenericValue>)> {
+    let mut fields = Vec::new();
+    let mut params = Vec::new();
+    for (key, value) in serialized.as_object()? {
+        let param = match value {
+            serde_json::Value::Bool(v) => GenericValue::Bool(*v),
+            serde_json::Value::Number(v) => GenericValue::Int64(v.as_i64()?),
+            serde_json::Value::String(v) if !v.is_empty() => GenericValue::String(v.to_string()),
+            _ => {
+                continue;
+            }
+        };
+        fields.push(key.as_str());
+        params.push(param);
+    }
+    if fields.is_empty() {
+        return None;
+    }
+    let placeholders: Vec<String> = (1..=params.len()).map(|i| format!("${}", i)).collect();
+    let query = format!(
+        "INSERT INTO {} ({}) VALUES ({}) RETURNING id",
+        table,
+        fields.join(","),
+        placeholders.join(",")
+    );
+    Some((query, params))
+}
+
+// Build the update statement by id of the non-empty fields of serialized bean, which updates nothing
+// when the biz fields are unchanged (same as the sqlite), none if no fields.
+pub fn build_pg_update(table: &str, id: i64, serialized: &serde_json::Value) -> Option<(String, Vec<GenericValue>)> {
+    let mut fields = Vec::new();
+    let mut params = Vec::new();
+    let mut changed_keys = Vec::new();
+    for (key, value) in serialized.as_object()? {
+        let param = match value {
+            serde_json::Value::Bool(v) => GenericValue::Bool(*v),
+            serde_json::Value::Number(v) => GenericValue::Int64(v.as_i64()?),
+            serde_json::Value::String(v) if !v.is_empty() => GenericValue::String(v.to_string()),
+            _ => {
+                continue;
+            }
+        };
+        params.push(param);
+        fields.push(format!("{} = ${}", key, params.len()));
+        if !crate::types::BASE_BEAN_FIELDS.contains(&key.as_str()) {
+            changed_keys.push((key.as_str(), params.len()));
+        }
+    }
+    if fields.is_empty() {
+        return None;
+    }
+
+    params.push(GenericValue::Int64(id));
+    let mut query = format!("UPDATE {} SET {} WHERE id = ${}", table, fields.join(", "), params.len());
+    if !changed_keys.is_empty() {
+        // Reuse the placeholders of the set values, so that the params are not duplicated.
+        let changes: Vec<String> = changed_keys
+            .iter()
+            .map(|(key, i)| format!("{} IS DISTINCT FROM ${}", key, i))
+            .collect();
+        query = format!("{} AND ({})", query, changes.join(" OR "));
+    }
+    Some((query, params))
+}
+
+pub async fn insert_pg(pool: &PgPool, table: &str, serialized: &serde_json::Value) -> Result<i64, Error> {
+    match build_pg_insert(table, serialized) {
+        Some((query, params)) => {
+            let id = sqlx::query_scalar_with::<_, i64, _>(&query, to_pg_arguments(&params)).fetch_optional(pool).await?;
+            Ok(id.unwrap_or(-1))
+        }
+        None => Ok(-1),
+    }
+}
+
+// Update the row by id, returns the id if updated, -1 if unchanged (or not found) and 0 if no fields.
+pub async fn update_pg(pool: &PgPool, table: &str, id: i64, serialized: &serde_json::Value) -> Result<i64, Error> {
+    match build_pg_update(table, id, serialized) {
+        Some((query, params)) => {
+            let result = sqlx::query_with(&query, to_pg_arguments(&params)).execute(pool).await?;
+            Ok(if result.rows_affected() > 0 { id } else { -1 })
+        }
+        None => Ok(0),
+    }
+}
+
+// Execute the inserts (without id) or updates (with id) of the serialized beans in a transaction, it's
+// rolled back on any error. Returns the ids in order, and -1 for the unchanged (or not found) updates.
+pub async fn save_all_pg(
+    pool: &PgPool,
+    table: &str,
+    beans: Vec<(Option<i64>, serde_json::Value)>
+) -> Result<Vec<i64>, Error> {
+    let total = beans.len();
+    let mut ids = Vec::with_capacity(total);
+    let mut tx = pool.begin().await?;
+    for (id, serialized) in beans.iter() {
+        let saved_id = match id {
+            Some(id) =>
+                match build_pg_update(table, *id, serialized) {
+                    Some((query, params)) => {
+                        let result = sqlx::query_with(&query, to_pg_arguments(&params)).execute(&mut *tx).await?;
+                        if result.rows_affected() > 0 { *id } else { -1 }
+                    }
+                    None => -1,
+                }
+            None =>
+                match build_pg_insert(table, serialized) {
+                    Some((query, params)) =>
+                        sqlx
+                            ::query_scalar_with::<_, i64, _>(&query, to_pg_arguments(&params))
+                            .fetch_optional(&mut *tx).await?
+                            .unwrap_or(-1),
+                    None => -1,
+                }
+        };
+        ids.push(saved_id);
+    }
+    tx.commit().await?;
+    info!("Committed {} rows of {}", total, table);
+    Ok(ids)
+}
+
+// Soft-delete the active row of the table, the absent (or already soft-deleted) is not affected.
+pub async fn soft_delete_pg_by_id(pool: &PgPool, table: &str, id: i64) -> Result<u64, Error> {
+    let query = format!("UPDATE {} SET del_flag = 1, update_time = $1 WHERE id = $2 AND del_flag = 0", table);
+    let deleted = sqlx::query(&query).bind(times::now_millis()).bind(id).execute(pool).await?.rows_affected();
+    info!("Soft-deleted {} row of {} by id: {}", deleted, table, id);
+    Ok(deleted)
+}
+
+// Hard delete the soft-deleted row of the table, the active row is rejected and the absent is not found.
+pub async fn purge_pg_by_id(pool: &PgPool, table: &str, entity: &'static str, id: i64) -> Result<u64, Error> {
+    let query = format!("DELETE FROM {} WHERE id = $1 AND del_flag = 1", table);
+    let purged = sqlx::query(&query).bind(id).execute(pool).await?.rows_affected();
+    if purged == 0 {
+        let query = format!("SELECT COUNT(1) FROM {} WHERE id = $1", table);
+        let exists = sqlx::query_scalar::<_, i64>(&query).bind(id).fetch_one(pool).await? > 0;
+        if exists {
+            return Err(StoreError::NotDeleted(entity, id).into());
+        }
+        return Err(StoreError::NotFound(entity, id).into());
+    }
+    info!("Purged the row of {} by id: {}", table, id);
+    Ok(purged)
+}
+
+pub async fn purge_pg_deleted_before(pool: &PgPool, table: &str, update_time: i64) -> Result<u64, Error> {
+    let query = format!("DELETE FROM {} WHERE del_flag = 1 AND update_time < $1", table);
+    let purged = sqlx::query(&query).bind(update_time).execute(pool).await?.rows_affected();
+    info!("Purged {} deleted rows of {} before {}", purged, table, update_time);
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_pg_insert_numbered_placeholders() {
+        let serialized = serde_json::json!({ "id": 1, "name": "alice", "email": "", "phone": null });
+        let (query, params) = build_pg_insert("users", &serialized).unwrap();
+        assert_eq!(query, "INSERT INTO users (id,name) VALUES ($1,$2) RETURNING id");
+        assert_eq!(params, vec![GenericValue::Int64(1), GenericValue::String("alice".to_string())]);
+        assert!(build_pg_insert("users", &serde_json::json!({ "name": "" })).is_none());
+    }
+
+    #[test]
+    fn test_build_pg_update_only_if_changed() {
+        let serialized = serde_json::json!({ "name": "alice", "update_time": 100 });
+        let (query, params) = build_pg_update("users", 7, &serialized).unwrap();
+        assert_eq!(query, "UPDATE users SET name = $1, update_time = $2 WHERE id = $3 AND (name IS DISTINCT FROM $1)");
+        assert_eq!(params.len(), 3);
+        assert_eq!(params[2], GenericValue::Int64(7));
+    }
+
+    #[tokio::test]
+    async fn test_new_without_url() {
+        let mut config = DbProperties::default();
+        config.postgres.url = None;
+        let err = PostgresRepository::<serde_json::Value>::new(&config).await.err().unwrap();
+        assert!(err.to_string().contains("db.postgres.url"), "{}", err);
+    }
+
+    #[test]
+    fn test_validate_postgres_url() {
+        let mut properties = crate::config::config_serve::WebServeProperties::default();
+        properties.db.db_type = crate::config::config_serve::DbType::Postgres;
+        properties.db.postgres.url = None;
+        assert!(properties.clone().validate().is_err());
+
+        properties.db.postgres.url = Some("postgres://postgres@localhost:5432/mywebnote".to_string());
+        assert!(properties.validate().is_ok());
+    }
+}
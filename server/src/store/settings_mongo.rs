@@ -33,7 +33,14 @@ use crate::types::settings::Settings;
 use crate::types::{ PageRequest, PageResponse };
 use super::AsyncRepository;
 use super::mongo::MongoRepository;
-use crate::{ dynamic_mongo_query, dynamic_mongo_insert, dynamic_mongo_update };
+use crate::{
+    dynamic_mongo_query,
+    dynamic_mongo_select_by_ids,
+    dynamic_mongo_select_deleted,
+    dynamic_mongo_count,
+    dynamic_mongo_insert,
+    dynamic_mongo_update,
+};
 
 pub struct SettingsMongoRepository {
     #[allow(unused)]
@@ -65,14 +72,24 @@ impl AsyncRepository<Settings> for SettingsMongoRepository {
         }
     }
 
-    async fn select_by_id(&self, id: i64) -> Result<Settings, Error> {
-        let filter = doc! { "id": id };
-        let settings = self.collection
-            .find_one(filter).await?
-            .ok_or_else(|| Error::msg("Settings not found"))?;
+    async fn select_by_id(&self, id: i64) -> Result<Option<Settings>, Error> {
+        let filter = doc! { "id": id, "del_flag": { "$ne": 1 } };
+        let settings = self.collection.find_one(filter).await?;
         Ok(settings)
     }
 
+    async fn select_by_ids(&self, ids: Vec<i64>) -> Result<Vec<Settings>, Error> {
+        dynamic_mongo_select_by_ids!(ids, self.collection)
+    }
+
+    async fn select_deleted(&self, settings: Settings, page: PageRequest) -> Result<(PageResponse, Vec<Settings>), Error> {
+        dynamic_mongo_select_deleted!(settings, self.collection, page)
+    }
+
+    async fn count_by(&self, settings: Settings, not_null_fields: &[&str]) -> Result<i64, Error> {
+        dynamic_mongo_count!(settings, self.collection, not_null_fields)
+    }
+
     async fn insert(&self, mut settings: Settings) -> Result<i64, Error> {
         dynamic_mongo_insert!(settings, self.collection)
     }
@@ -91,4 +108,12 @@ impl AsyncRepository<Settings> for SettingsMongoRepository {
         let result = self.collection.delete_one(filter).await?;
         Ok(result.deleted_count)
     }
+
+    async fn purge_by_id(&self, id: i64) -> Result<u64, Error> {
+        super::mongo::purge_mongo_by_id(&self.collection, "settings", id).await
+    }
+
+    async fn purge_deleted_before(&self, update_time: i64) -> Result<u64, Error> {
+        super::mongo::purge_mongo_deleted_before(&self.collection, update_time).await
+    }
 }
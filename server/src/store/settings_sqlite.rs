@@ -20,7 +20,7 @@
  * This includes modifications and derived works.
  */
 
-use anyhow::{ Error, Ok };
+use anyhow::{ Context, Error, Ok };
 use axum::async_trait;
 
 use crate::config::config_serve::DbProperties;
@@ -28,7 +28,7 @@ use crate::types::settings::Settings;
 use crate::types::PageRequest;
 use crate::types::PageResponse;
 use super::AsyncRepository;
-use super::sqlite::SQLiteRepository;
+use super::sqlite::{ audit_sqlite_schema, SQLiteRepository };
 
 pub struct SettingsSQLiteRepository {
     inner: SQLiteRepository<Settings>,
@@ -36,9 +36,11 @@ pub struct SettingsSQLiteRepository {
 
 impl SettingsSQLiteRepository {
     pub async fn new(config: &DbProperties) -> Result<Self, Error> {
-        Ok(SettingsSQLiteRepository {
-            inner: SQLiteRepository::new(config).await?,
-        })
+        let inner = SQLiteRepository::new(config).await?;
+        if config.schema_audit.unwrap_or(true) {
+            audit_sqlite_schema(inner.get_pool(), "settings", &Settings::new(None, None, None)).await?;
+        }
+        Ok(SettingsSQLiteRepository { inner })
     }
 }
 
@@ -52,33 +54,44 @@ impl AsyncRepository<Settings> for SettingsSQLiteRepository {
         let result = dynamic_sqlite_query!(
             settings,
             "settings",
-            self.inner.get_pool(),
+            self.inner.get_read_pool(),
             "update_time",
             page,
             Settings
-        ).unwrap();
+        ).context("Failed to select settings")?;
 
         tracing::info!("query settings: {:?}", result);
         Ok((result.0, result.1))
     }
 
-    async fn select_by_id(&self, id: i64) -> Result<Settings, Error> {
+    async fn select_by_id(&self, id: i64) -> Result<Option<Settings>, Error> {
         let settings = sqlx
-            ::query_as::<_, Settings>("SELECT * FROM settings WHERE id = $1")
+            ::query_as::<_, Settings>("SELECT * FROM settings WHERE id = $1 AND del_flag = 0")
             .bind(id)
-            .fetch_one(self.inner.get_pool()).await
-            .unwrap();
+            .fetch_optional(self.inner.get_read_pool()).await?;
 
         tracing::info!("query settings: {:?}", settings);
         Ok(settings)
     }
 
+    async fn select_by_ids(&self, ids: Vec<i64>) -> Result<Vec<Settings>, Error> {
+        dynamic_sqlite_select_by_ids!(ids, "settings", self.inner.get_read_pool(), Settings)
+    }
+
+    async fn select_deleted(&self, settings: Settings, page: PageRequest) -> Result<(PageResponse, Vec<Settings>), Error> {
+        dynamic_sqlite_select_deleted!(settings, "settings", self.inner.get_read_pool(), page, Settings)
+    }
+
+    async fn count_by(&self, settings: Settings, not_null_fields: &[&str]) -> Result<i64, Error> {
+        dynamic_sqlite_count!(settings, "settings", self.inner.get_read_pool(), not_null_fields)
+    }
+
     async fn insert(&self, mut settings: Settings) -> Result<i64, Error> {
         let inserted_id = dynamic_sqlite_insert!(
             settings,
             "settings",
             self.inner.get_pool()
-        ).unwrap();
+        ).context("Failed to insert settings")?;
         tracing::info!("Inserted settings.id: {:?}", inserted_id);
         Ok(inserted_id)
     }
@@ -88,16 +101,20 @@ impl AsyncRepository<Settings> for SettingsSQLiteRepository {
             settings,
             "settings",
             self.inner.get_pool()
-        ).unwrap();
+        ).context("Failed to update settings")?;
         tracing::info!("Updated settings.id: {:?}", updated_id);
         Ok(updated_id)
     }
 
+    async fn save_all(&self, settings: Vec<Settings>) -> Result<Vec<i64>, Error> {
+        dynamic_sqlite_save_all!(settings, "settings", &self.inner)
+    }
+
     async fn delete_all(&self) -> Result<u64, Error> {
         let delete_result = sqlx
             ::query("DELETE FROM settings")
             .execute(self.inner.get_pool()).await
-            .unwrap();
+            .context("Failed to delete all settings")?;
 
         tracing::info!("Deleted result: {:?}", delete_result);
         Ok(delete_result.rows_affected())
@@ -108,9 +125,17 @@ impl AsyncRepository<Settings> for SettingsSQLiteRepository {
             ::query("DELETE FROM settings WHERE id = $1")
             .bind(id)
             .execute(self.inner.get_pool()).await
-            .unwrap();
+            .with_context(|| format!("Failed to delete settings by id: {}", id))?;
 
         tracing::info!("Deleted result: {:?}", delete_result);
         Ok(delete_result.rows_affected())
     }
+
+    async fn purge_by_id(&self, id: i64) -> Result<u64, Error> {
+        super::sqlite::purge_sqlite_by_id(self.inner.get_pool(), "settings", "settings", id).await
+    }
+
+    async fn purge_deleted_before(&self, update_time: i64) -> Result<u64, Error> {
+        super::sqlite::purge_sqlite_deleted_before(self.inner.get_pool(), "settings", update_time).await
+    }
 }
@@ -23,16 +23,63 @@
 use std::any::Any;
 use std::marker::PhantomData;
 use std::fs;
-use std::path::Path;
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicI64, AtomicU32, Ordering };
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
 
-use anyhow::Error;
+use anyhow::{ anyhow, Error };
 use axum::async_trait;
+use serde::Serialize;
 
 use tracing::{ info, debug };
-use sqlx::{ migrate::MigrateDatabase, Pool, Sqlite, SqlitePool };
+use sqlx::{
+    migrate::MigrateDatabase,
+    pool::PoolConnectionMetadata,
+    query::Query,
+    sqlite::{ SqliteArguments, SqlitePoolOptions },
+    Connection,
+    Pool,
+    Row,
+    Sqlite,
+    SqlitePool,
+    Transaction,
+};
+use tokio::sync::{ OwnedSemaphorePermit, Semaphore };
 
-use crate::{ config::config_serve::DbProperties, types::{ PageResponse, PageRequest } };
-use super::AsyncRepository;
+use crate::{
+    config::config_serve::{
+        DbProperties,
+        DEFAULT_DB_MAX_TRANSACTIONS,
+        DEFAULT_DB_READ_REPLICA_MAX_LAG,
+        DEFAULT_DB_RECONNECT_ERROR_THRESHOLD,
+        DEFAULT_DB_TRANSACTION_ACQUIRE_TIMEOUT,
+    },
+    types::{ PageResponse, PageRequest },
+    utils::{ times, types::GenericValue },
+};
+use super::{ AsyncRepository, StoreError };
+
+// The max number of bind parameters of a statement, which is the SQLITE_MAX_VARIABLE_NUMBER
+// default of the SQLite versions prior to 3.32.0.
+pub const SQLITE_MAX_BIND_PARAMS: usize = 999;
+
+// The pragmas allowed to be configured, since the names and values are concatenated into the statement.
+const SQLITE_PRAGMA_ALLOWLIST: [&str; 10] = [
+    "auto_vacuum",
+    "busy_timeout",
+    "cache_size",
+    "foreign_keys",
+    "journal_mode",
+    "mmap_size",
+    "secure_delete",
+    "synchronous",
+    "temp_store",
+    "wal_autocheckpoint",
+];
+
+// The rows interval of logging the progress of saving all in transaction.
+const SAVE_ALL_PROGRESS_INTERVAL: usize = 1000;
 
 //
 // const MIGRATION_INIT_SQL: &str = include_str!("../../migrations/20240710083754_init.sql");
@@ -40,6 +87,11 @@ use super::AsyncRepository;
 pub struct SQLiteRepository<T: Any + Send + Sync> {
     phantom: PhantomData<T>,
     pool: SqlitePool,
+    read_pool: Option<SqlitePool>,
+    // The reads within the max lag (ms) of the replica after the last write (ms) are directed to the primary.
+    read_max_lag: i64,
+    last_write_at: AtomicI64,
+    tx_limiter: TransactionLimiter,
 }
 
 impl<T: Any + Send + Sync> SQLiteRepository<T> {
@@ -67,14 +119,28 @@ impl<T: Any + Send + Sync> SQLiteRepository<T> {
         // SQLite in-memory database.
         // let db_url = format!("sqlite::memory:");
 
-        match SqlitePool::connect(&db_url).await {
+        let pragmas = Arc::new(parse_sqlite_pragmas(config)?);
+        match connect_pool(&db_url, Path::new(&dir).join("sqlite.db"), config, pragmas.clone()).await {
             Ok(pool) => {
                 tracing::info!("Successfully connected to the database");
                 let pool = Self::init_migration(pool).await;
+                let read_pool = Self::connect_read_replica(config, pragmas).await;
 
                 Ok(SQLiteRepository {
                     phantom: PhantomData,
                     pool,
+                    read_pool,
+                    read_max_lag: config.read_replica
+                        .as_ref()
+                        .and_then(|replica| replica.max_lag)
+                        .unwrap_or(DEFAULT_DB_READ_REPLICA_MAX_LAG) as i64,
+                    last_write_at: AtomicI64::new(0),
+                    tx_limiter: TransactionLimiter::new(
+                        config.max_transactions.unwrap_or(DEFAULT_DB_MAX_TRANSACTIONS),
+                        Duration::from_millis(
+                            config.transaction_acquire_timeout.unwrap_or(DEFAULT_DB_TRANSACTION_ACQUIRE_TIMEOUT)
+                        )
+                    ),
                 })
             }
             Err(e) => {
@@ -85,6 +151,27 @@ impl<T: Any + Send + Sync> SQLiteRepository<T> {
         }
     }
 
+    // Connect to the read replica in read-only mode, the replica is synchronized from the primary
+    // externally (e.g. litestream), so it's not migrated at here.
+    async fn connect_read_replica(config: &DbProperties, pragmas: Arc<Vec<(String, String)>>) -> Option<SqlitePool> {
+        let dir = config.read_replica
+            .as_ref()
+            .and_then(|replica| replica.sqlite.as_ref())
+            .and_then(|sqlite| sqlite.dir.to_owned())?;
+
+        let db_url = format!("sqlite://{}/sqlite.db?mode=ro", &dir);
+        match connect_pool(&db_url, Path::new(&dir).join("sqlite.db"), config, pragmas).await {
+            Ok(pool) => {
+                tracing::info!("Successfully connected to the read replica database {}", db_url);
+                Some(pool)
+            }
+            Err(e) => {
+                tracing::warn!("Failed to connect read replica {}, fallback to primary. {}", db_url, e);
+                None
+            }
+        }
+    }
+
     async fn init_migration(pool: Pool<Sqlite>) -> Pool<Sqlite> {
         // let default_dir = std::env
         //   ::current_dir()
@@ -114,9 +201,255 @@ impl<T: Any + Send + Sync> SQLiteRepository<T> {
         pool
     }
 
+    // Get the primary pool for the writes, the reads following are directed to the primary within the max lag.
     pub fn get_pool(&self) -> &SqlitePool {
+        self.last_write_at.store(times::now_millis(), Ordering::Relaxed);
         &self.pool
     }
+
+    // Get the pool for read only queries, which is the replica if configured, except the reads that follow
+    // a write within the max lag, which may not be replicated yet.
+    pub fn get_read_pool(&self) -> &SqlitePool {
+        match &self.read_pool {
+            Some(read_pool) if times::now_millis() - self.last_write_at.load(Ordering::Relaxed) >= self.read_max_lag => {
+                read_pool
+            }
+            _ => &self.pool,
+        }
+    }
+
+    // Begin the transaction of the primary pool within the max concurrent transactions.
+    pub async fn begin(&self) -> Result<LimitedTransaction, Error> {
+        self.last_write_at.store(times::now_millis(), Ordering::Relaxed);
+        self.tx_limiter.begin(&self.pool).await
+    }
+}
+
+// The guard of the max concurrent transactions, since the SQLite serializes the writes, the excess long
+// transactions would otherwise hold all the pooled connections and starve (or deadlock) the others.
+pub struct TransactionLimiter {
+    semaphore: Arc<Semaphore>,
+    max_transactions: usize,
+    timeout: Duration,
+}
+
+impl TransactionLimiter {
+    pub fn new(max_transactions: usize, timeout: Duration) -> Self {
+        let max_transactions = max_transactions.max(1);
+        Self {
+            semaphore: Arc::new(Semaphore::new(max_transactions)),
+            max_transactions,
+            timeout,
+        }
+    }
+
+    // Begin the transaction once permitted, fails as the storage unavailable if not permitted in timeout.
+    pub async fn begin(&self, pool: &SqlitePool) -> Result<LimitedTransaction, Error> {
+        let permit = match tokio::time::timeout(self.timeout, self.semaphore.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => permit,
+            Ok(Err(e)) => {
+                return Err(StoreError::StorageUnavailable(e.into()).into());
+            }
+            Err(_) => {
+                tracing::warn!(
+                    "Timed out of beginning transaction in {:?}, the max concurrent transactions is {}",
+                    self.timeout,
+                    self.max_transactions
+                );
+                return Err(
+                    StoreError::StorageUnavailable(
+                        anyhow!("Too many concurrent transactions, the max is {}", self.max_transactions)
+                    ).into()
+                );
+            }
+        };
+        let tx = pool.begin().await?;
+        Ok(LimitedTransaction { tx, _permit: permit })
+    }
+}
+
+// The transaction holds the permit until it's committed, or rolled back on dropped.
+pub struct LimitedTransaction {
+    pub tx: Transaction<'static, Sqlite>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl LimitedTransaction {
+    pub async fn commit(self) -> Result<(), Error> {
+        self.tx.commit().await?;
+        Ok(())
+    }
+}
+
+// Connect the pool with the health checked connections, which are recreated after the database file
+// is replaced (e.g. restored from backup) or a burst of connection errors.
+async fn connect_pool(
+    db_url: &str,
+    db_file: PathBuf,
+    config: &DbProperties,
+    pragmas: Arc<Vec<(String, String)>>
+) -> Result<SqlitePool, sqlx::Error> {
+    let health = Arc::new(
+        PoolHealth::new(
+            db_url,
+            db_file,
+            config.test_on_acquire.unwrap_or(true),
+            config.reconnect_error_threshold.unwrap_or(DEFAULT_DB_RECONNECT_ERROR_THRESHOLD)
+        )
+    );
+    SqlitePoolOptions::new()
+        .after_connect(move |conn, _meta| {
+            let pragmas = pragmas.clone();
+            Box::pin(async move {
+                for (name, value) in pragmas.iter() {
+                    sqlx::query(&format!("PRAGMA {} = {}", name, value)).execute(&mut *conn).await?;
+                }
+                Ok(())
+            })
+        })
+        // Ping by the health checking, so that the errors could be counted.
+        .test_before_acquire(false)
+        .before_acquire(move |conn, meta| {
+            let health = health.clone();
+            Box::pin(async move { Ok(health.check(conn, meta).await) })
+        })
+        .connect(db_url).await
+}
+
+// Parse the configured pragmas, the name must be allowlisted and the value must be an identifier or number.
+pub fn parse_sqlite_pragmas(config: &DbProperties) -> Result<Vec<(String, String)>, Error> {
+    let pragmas = config.sqlite_pragmas.clone().unwrap_or_default();
+    pragmas
+        .into_iter()
+        .map(|(name, value)| {
+            let name = name.to_ascii_lowercase();
+            if !SQLITE_PRAGMA_ALLOWLIST.contains(&name.as_str()) {
+                return Err(anyhow!("Unsupported sqlite pragma: {}, allowed: {:?}", name, SQLITE_PRAGMA_ALLOWLIST));
+            }
+            let value = value.trim().to_string();
+            if value.is_empty() || !value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                return Err(anyhow!("Invalid value of sqlite pragma {}: '{}'", name, value));
+            }
+            Ok((name, value))
+        })
+        .collect()
+}
+
+// The health of the pooled connections, all the connections created before the last reconnect are
+// discarded on acquiring, so that the pool is recreated lazily without replacing the pool itself.
+struct PoolHealth {
+    db_url: String,
+    db_file: PathBuf,
+    test_on_acquire: bool,
+    error_threshold: u32,
+    errors: AtomicU32,
+    reconnects: AtomicU32,
+    reconnected_at: Mutex<Option<Instant>>,
+    file_identity: Mutex<Option<(u64, u64)>>,
+}
+
+impl PoolHealth {
+    fn new(db_url: &str, db_file: PathBuf, test_on_acquire: bool, error_threshold: u32) -> Self {
+        let file_identity = Mutex::new(Self::read_file_identity(&db_file));
+        Self {
+            db_url: db_url.to_string(),
+            db_file,
+            test_on_acquire,
+            error_threshold: error_threshold.max(1),
+            errors: AtomicU32::new(0),
+            reconnects: AtomicU32::new(0),
+            reconnected_at: Mutex::new(None),
+            file_identity,
+        }
+    }
+
+    // Whether the connection could be acquired, the rejected one is closed and another is opened.
+    async fn check(&self, conn: &mut sqlx::SqliteConnection, meta: PoolConnectionMetadata) -> bool {
+        if self.is_stale(meta.age) {
+            return false;
+        }
+        let identity = Self::read_file_identity(&self.db_file);
+        let replaced = {
+            let mut file_identity = self.file_identity.lock().unwrap();
+            let replaced = *file_identity != identity;
+            *file_identity = identity;
+            replaced
+        };
+        if replaced {
+            self.reconnect("the database file is replaced or removed");
+            return false;
+        }
+        if self.test_on_acquire {
+            if let Err(e) = conn.ping().await {
+                tracing::warn!("Failed to ping the pooled connection of {}. {}", self.db_url, e);
+                if self.errors.fetch_add(1, Ordering::SeqCst) + 1 >= self.error_threshold {
+                    self.reconnect("a burst of connection errors");
+                }
+                return false;
+            }
+        }
+        self.errors.store(0, Ordering::SeqCst);
+        true
+    }
+
+    // Whether the connection of age was created before the last reconnect.
+    fn is_stale(&self, age: std::time::Duration) -> bool {
+        match *self.reconnected_at.lock().unwrap() {
+            Some(reconnected_at) => Instant::now().checked_sub(age).map_or(true, |created| created <= reconnected_at),
+            None => false,
+        }
+    }
+
+    fn reconnect(&self, reason: &str) {
+        *self.reconnected_at.lock().unwrap() = Some(Instant::now());
+        self.errors.store(0, Ordering::SeqCst);
+        let attempt = self.reconnects.fetch_add(1, Ordering::SeqCst) + 1;
+        tracing::warn!("Reconnecting the database {} because of {}, attempt {}", self.db_url, reason, attempt);
+    }
+
+    #[cfg(unix)]
+    fn read_file_identity(db_file: &Path) -> Option<(u64, u64)> {
+        use std::os::unix::fs::MetadataExt;
+        fs::metadata(db_file).ok().map(|meta| (meta.dev(), meta.ino()))
+    }
+
+    #[cfg(not(unix))]
+    fn read_file_identity(_db_file: &Path) -> Option<(u64, u64)> {
+        None
+    }
+}
+
+// Check the columns of table (by 'PRAGMA table_info') against the fields of entity, which are the
+// columns of the dynamic sql macros, so that the drifted schema fails fast at startup instead of
+// the cryptic query errors at runtime. The extra columns (e.g. added by a newer migration) are allowed.
+pub async fn audit_sqlite_schema<S: Serialize>(pool: &SqlitePool, table: &str, sample: &S) -> Result<(), Error> {
+    let mut expected: Vec<String> = match serde_json::to_value(sample)? {
+        serde_json::Value::Object(fields) => fields.into_iter().map(|(name, _)| name).collect(),
+        _ => return Err(anyhow!("The entity of table '{}' is not a struct", table)),
+    };
+    // The del_flag is skipped by serde, but it's the column of all tables.
+    expected.push("del_flag".to_string());
+
+    let actual: Vec<String> = sqlx
+        ::query("SELECT name FROM pragma_table_info(?)")
+        .bind(table)
+        .fetch_all(pool).await?
+        .iter()
+        .map(|row| row.get::<String, _>("name"))
+        .collect();
+
+    let mut missing: Vec<&String> = expected.iter().filter(|c| !actual.contains(c)).collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    missing.sort();
+    Err(
+        anyhow!(
+            "Schema drift of table '{}', missing columns: {:?}. Please check the migrations, or disable by 'db.schema-audit: false'",
+            table,
+            missing
+        )
+    )
 }
 
 #[allow(unused)]
@@ -130,10 +463,26 @@ impl<T: Any + Send + Sync> AsyncRepository<T> for SQLiteRepository<T> {
         unimplemented!("select not implemented for SQLiteRepository")
     }
 
-    async fn select_by_id(&self, id: i64) -> Result<T, Error> {
+    async fn select_by_id(&self, id: i64) -> Result<Option<T>, Error> {
         unimplemented!("select_by_id not implemented for SQLiteRepository")
     }
 
+    async fn select_by_ids(&self, ids: Vec<i64>) -> Result<Vec<T>, Error> {
+        unimplemented!("select_by_ids not implemented for SQLiteRepository")
+    }
+
+    async fn select_deleted(&self, param: T, page: PageRequest) -> Result<(PageResponse, Vec<T>), Error> {
+        unimplemented!("select_deleted not implemented for SQLiteRepository")
+    }
+
+    async fn count_by(&self, param: T, not_null_fields: &[&str]) -> Result<i64, Error> {
+        unimplemented!("count_by not implemented for SQLiteRepository")
+    }
+
+    async fn save_all(&self, params: Vec<T>) -> Result<Vec<i64>, Error> {
+        unimplemented!("save_all not implemented for SQLiteRepository")
+    }
+
     async fn insert(&self, param: T) -> Result<i64, Error> {
         unimplemented!("insert not implemented for SQLiteRepository");
         let pool = self.get_pool();
@@ -150,6 +499,14 @@ impl<T: Any + Send + Sync> AsyncRepository<T> for SQLiteRepository<T> {
     async fn delete_by_id(&self, id: i64) -> Result<u64, Error> {
         unimplemented!("delete_by_id not implemented for SQLiteRepository")
     }
+
+    async fn purge_by_id(&self, id: i64) -> Result<u64, Error> {
+        unimplemented!("purge_by_id not implemented for SQLiteRepository")
+    }
+
+    async fn purge_deleted_before(&self, update_time: i64) -> Result<u64, Error> {
+        unimplemented!("purge_deleted_before not implemented for SQLiteRepository")
+    }
 }
 
 macro_rules! dynamic_sqlite_query {
@@ -178,36 +535,64 @@ macro_rules! dynamic_sqlite_query {
                   fields.push("id = ?".to_string());
                   params.push(id.to_string());
               }
-              let where_clause = if fields.is_empty() {
-                  "1=1".to_string()
+              // The soft-deleted rows are excluded, see: select_deleted.
+              fields.push("del_flag = 0".to_string());
+              let where_clause = fields.join(" AND ");
+
+              // Queries the total count and data in a (deferred, i.e. snapshot) read transaction, so that the
+              // total is consistent with the data.
+              let mut tx = $pool.begin().await.map_err(|e| anyhow::Error::from(e))?;
+              let total_count = if $page.is_count() {
+                  let total_query = format!("SELECT COUNT(1) FROM {} WHERE {}", $table, where_clause);
+                  let mut operator = sqlx::query_scalar::<_, i64>(&total_query);
+                  for param in params.iter() {
+                      operator = operator.bind(param);
+                  }
+                  Some(operator.fetch_one(&mut *tx).await.map_err(|e| anyhow::Error::from(e))?)
               } else {
-                  fields.join(" AND ")
+                  None
               };
 
-              // Queries to get total count.
-              let total_query = format!("SELECT COUNT(1) FROM {} WHERE {}", $table, where_clause);
-              use sqlx::Row;
-              let total_count = sqlx::query(&total_query)
-                .fetch_one($pool)
-                .await
-                .map(|row| row.get::<i64, _>(0) as i64)
-                .unwrap();
-
-              // Queries to get data.
-              let query = format!("SELECT * FROM {} WHERE {} ORDER BY {} LIMIT {} OFFSET {}", 
-                    $table, where_clause, $order_by, $page.get_limit(), $page.get_offset());
+              // Queries to get data, by the keyset of (update_time, id) descending in the cursor mode, the never
+              // updated rows are ordered as the update_time 0, and one more row is fetched to tell the last page.
+              let cursor = match $page.cursor {
+                  Some(_) => Some($page.decode_cursor().map_err(|e| crate::store::StoreError::InvalidCursor(e.to_string()))?),
+                  None => None,
+              };
+              let query = match cursor {
+                  Some(keys) => format!(
+                      "SELECT * FROM {} WHERE {}{} ORDER BY IFNULL(update_time, 0) DESC, id DESC LIMIT {}",
+                      $table,
+                      where_clause,
+                      if keys.is_some() { " AND (IFNULL(update_time, 0), id) < (?, ?)" } else { "" },
+                      $page.get_limit() + 1
+                  ),
+                  None => format!("SELECT * FROM {} WHERE {} ORDER BY {} LIMIT {} OFFSET {}",
+                      $table, where_clause, $order_by, $page.get_limit(), $page.get_offset()),
+              };
 
               let mut operator = sqlx::query_as::<_, $($t),+>(&query);
               for param in params.iter() {
                   operator = operator.bind(param);
               }
+              if let Some(Some((update_time, id))) = cursor {
+                  operator = operator.bind(update_time).bind(id);
+              }
 
-              match operator.fetch_all($pool).await {
-                  std::result::Result::Ok(result) => {
-                    let page = PageResponse::new(
-                        Some(total_count),
+              let fetched = operator.fetch_all(&mut *tx).await;
+              tx.commit().await.map_err(|e| anyhow::Error::from(e))?;
+              match fetched {
+                  std::result::Result::Ok(mut result) => {
+                    let mut page = PageResponse::new(
+                        total_count,
                         Some($page.get_offset()),
                         Some($page.get_limit()));
+                    if cursor.is_some() && result.len() > ($page.get_limit() as usize) {
+                        result.truncate($page.get_limit() as usize);
+                        page.next_cursor = result.last().map(|last| {
+                            PageResponse::encode_cursor(last.base.update_time.unwrap_or(0), last.base.id.unwrap_or(0))
+                        });
+                    }
                       Ok((page, result))
                   },
                   Err(error) => {
@@ -218,67 +603,135 @@ macro_rules! dynamic_sqlite_query {
     };
 }
 
-macro_rules! dynamic_sqlite_insert {
-    ($bean:expr, $table:expr, $pool:expr) => {
+macro_rules! dynamic_sqlite_count {
+    ($bean:expr, $table:expr, $pool:expr, $not_null_fields:expr) => {
         {
-            use crate::utils::types::GenericValue;
+            let serialized = serde_json::to_value(&$bean).unwrap();
+            let obj = serialized.as_object().unwrap();
 
-            // Notice:
-            // 1. (SQLite) Because the ORM library is not used for the time being, the fields are dynamically
-            // parsed based on serde_json, so the #[serde(rename="xx")] annotation is effective.
-            // 2. (MongoDB) The underlying BSON serialization is also based on serde, so using #[serde(rename="xx")] is also valid
-            // TODO: It is recommended to use an ORM framework, see: https://github.com/diesel-rs/diesel
-            $bean.base.pre_insert(None).await;
-            let serialized = serde_json::to_value($bean).unwrap();
+            let mut fields = Vec::new();
+            let mut params = Vec::new();
+            for (key, value) in obj {
+                if !value.is_null() {
+                    let v = value.as_str().unwrap_or("");
+                    if !v.is_empty() {
+                        fields.push(format!("{} = ?", key));
+                        params.push(v.to_string());
+                    }
+                }
+            }
+            if let Some(id) = $bean.base.id {
+                fields.push("id = ?".to_string());
+                params.push(id.to_string());
+            }
+            for field in $not_null_fields.iter() {
+                // Notice: The field name is concatenated into SQL, so only the identifier is allowed.
+                if field.is_empty() || !field.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                    return Err(anyhow::anyhow!("Invalid not null field name: {}", field));
+                }
+                fields.push(format!("({} IS NOT NULL AND {} != '')", field, field));
+            }
+            fields.push("del_flag = 0".to_string());
+
+            let query = format!("SELECT COUNT(1) FROM {} WHERE {}", $table, fields.join(" AND "));
+            let mut operator = sqlx::query_scalar::<_, i64>(&query);
+            for param in params.iter() {
+                operator = operator.bind(param);
+            }
+            operator.fetch_one($pool).await.map_err(|e| anyhow::Error::from(e))
+        }
+    };
+}
+
+macro_rules! dynamic_sqlite_select_by_ids {
+    ($ids:expr, $table:expr, $pool:expr, $t:ty) => {
+        {
+            let mut result = Vec::with_capacity($ids.len());
+            // Chunking the ids, because of the limit of bind parameters of the SQLite statement.
+            for chunk in $ids.chunks($crate::store::sqlite::SQLITE_MAX_BIND_PARAMS) {
+                let placeholders = vec!["?"; chunk.len()].join(", ");
+                let query = format!(
+                    "SELECT * FROM {} WHERE id IN ({}) AND del_flag = 0",
+                    $table,
+                    placeholders
+                );
+                let mut operator = sqlx::query_as::<_, $t>(&query);
+                for id in chunk.iter() {
+                    operator = operator.bind(id);
+                }
+                result.extend(operator.fetch_all($pool).await.map_err(|e| anyhow::Error::from(e))?);
+            }
+            std::result::Result::<Vec<$t>, anyhow::Error>::Ok(result)
+        }
+    };
+}
+
+macro_rules! dynamic_sqlite_select_deleted {
+    ($bean:expr, $table:expr, $pool:expr, $page:expr, $t:ty) => {
+        {
+            let serialized = serde_json::to_value(&$bean).unwrap();
             let obj = serialized.as_object().unwrap();
 
             let mut fields = Vec::new();
-            let mut values = Vec::new();
             let mut params = Vec::new();
             for (key, value) in obj {
                 if !value.is_null() {
-                    if value.is_boolean() {
-                        let v = value.as_bool().unwrap();
-                        fields.push(key.as_str());
-                        values.push("?");
-                        params.push(GenericValue::Bool(v));
-                    } else if value.is_number() {
-                        let v = value.as_i64().unwrap();
-                        fields.push(key.as_str());
-                        values.push("?");
-                        params.push(GenericValue::Int64(v));
-                    } else if value.is_string() {
-                        let v = value.as_str().unwrap_or("");
-                        if !v.is_empty() {
-                            fields.push(key.as_str());
-                            values.push("?");
-                            params.push(GenericValue::String(v.to_string()));
-                        }
+                    let v = value.as_str().unwrap_or("");
+                    if !v.is_empty() {
+                        fields.push(format!("{} = ?", key));
+                        params.push(v.to_string());
                     }
                 }
             }
-            if fields.is_empty() {
-                return Ok(-1);
+            if let Some(id) = $bean.base.id {
+                fields.push("id = ?".to_string());
+                params.push(id.to_string());
             }
+            fields.push("del_flag = 1".to_string());
+            let where_clause = fields.join(" AND ");
 
-            // let fields_str = fields
-            //  .iter()
-            //  .map(|s| s.as_str())
-            //  .collect::<Vec<&str>>()
-            //  .join(",");
-            let query = format!("INSERT INTO {} ({}) VALUES ({})", $table, fields.join(","), values.join(","));
+            let total_query = format!("SELECT COUNT(1) FROM {} WHERE {}", $table, where_clause);
+            let mut total_operator = sqlx::query_scalar::<_, i64>(&total_query);
+            for param in params.iter() {
+                total_operator = total_operator.bind(param);
+            }
+            let total_count = total_operator.fetch_one($pool).await.map_err(|e| anyhow::Error::from(e))?;
 
-            let mut operator = sqlx::query(&query);
+            let query = format!(
+                "SELECT * FROM {} WHERE {} ORDER BY update_time DESC LIMIT {} OFFSET {}",
+                $table,
+                where_clause,
+                $page.get_limit(),
+                $page.get_offset()
+            );
+            let mut operator = sqlx::query_as::<_, $t>(&query);
             for param in params.iter() {
-                if let GenericValue::Bool(v) = param {
-                    operator = operator.bind(v);
-                } else if let GenericValue::Int64(v) = param {
-                    operator = operator.bind(v);
-                } else if let GenericValue::String(v) = param {
-                    operator = operator.bind(v);
-                }
+                operator = operator.bind(param);
             }
+            let result = operator.fetch_all($pool).await.map_err(|e| anyhow::Error::from(e))?;
+
+            let page = PageResponse::new(Some(total_count), Some($page.get_offset()), Some($page.get_limit()));
+            std::result::Result::<(PageResponse, Vec<$t>), anyhow::Error>::Ok((page, result))
+        }
+    };
+}
+
+macro_rules! dynamic_sqlite_insert {
+    ($bean:expr, $table:expr, $pool:expr) => {
+        {
+            // Notice:
+            // 1. (SQLite) Because the ORM library is not used for the time being, the fields are dynamically
+            // parsed based on serde_json, so the #[serde(rename="xx")] annotation is effective.
+            // 2. (MongoDB) The underlying BSON serialization is also based on serde, so using #[serde(rename="xx")] is also valid
+            // TODO: It is recommended to use an ORM framework, see: https://github.com/diesel-rs/diesel
+            $bean.base.pre_insert(None).await;
+            let serialized = serde_json::to_value($bean)?;
+            let (query, params) = match crate::store::sqlite::build_sqlite_insert($table, &serialized) {
+                Some(statement) => statement,
+                None => return Ok(-1),
+            };
 
+            let operator = crate::store::sqlite::bind_sqlite_params(sqlx::query(&query), &params);
             match operator.execute($pool).await {
                 std::result::Result::Ok(result) => {
                     if result.rows_affected() > 0 {
@@ -296,8 +749,6 @@ macro_rules! dynamic_sqlite_insert {
 macro_rules! dynamic_sqlite_update {
     ($bean:expr, $table:expr, $pool:expr) => {
         {
-            use crate::utils::types::GenericValue;
-
             $bean.base.pre_update(None).await;
 
             // Notice:
@@ -305,48 +756,14 @@ macro_rules! dynamic_sqlite_update {
             // parsed based on serde_json, so the #[serde(rename="xx")] annotation is effective.
             // 2. (MongoDB) The underlying BSON serialization is also based on serde, so using #[serde(rename="xx")] is also valid
             // TODO: It is recommended to use an ORM framework, see: https://github.com/diesel-rs/diesel
-            let id = $bean.base.id.unwrap();
-            let serialized = serde_json::to_value($bean).unwrap();
-            let obj = serialized.as_object().unwrap();
-
-            let mut fields = Vec::new();
-            let mut params = Vec::new();
-            for (key, value) in obj {
-                if !value.is_null() {
-                    if value.is_boolean() {
-                        let v = value.as_bool().unwrap();
-                        fields.push(format!("{} = ?", key));
-                        params.push(GenericValue::Bool(v));
-                    } else if value.is_number() {
-                        let v = value.as_i64().unwrap();
-                        fields.push(format!("{} = ?", key));
-                        params.push(GenericValue::Int64(v));
-                    } else if value.is_string() {
-                        let v = value.as_str().unwrap_or("");
-                        if !v.is_empty() {
-                            fields.push(format!("{} = ?", key));
-                            params.push(GenericValue::String(v.to_string()));
-                        }
-                    }
-                }
-            }
-            if fields.is_empty() {
-                return Ok(0);
-            }
-
-            let query = format!("UPDATE {} SET {} WHERE id = ?", $table, fields.join(", "));
-            let mut operator = sqlx::query(&query);
-            for param in params.iter() {
-                if let GenericValue::Bool(v) = param {
-                    operator = operator.bind(v);
-                } else if let GenericValue::Int64(v) = param {
-                    operator = operator.bind(v);
-                } else if let GenericValue::String(v) = param {
-                    operator = operator.bind(v);
-                }
-            }
-            operator = operator.bind(id);
+            let id = $bean.base.id.ok_or_else(|| anyhow::anyhow!("The id is required to update {}", $table))?;
+            let serialized = serde_json::to_value($bean)?;
+            let (query, params) = match crate::store::sqlite::build_sqlite_update($table, id, &serialized) {
+                Some(statement) => statement,
+                None => return Ok(0),
+            };
 
+            let operator = crate::store::sqlite::bind_sqlite_params(sqlx::query(&query), &params);
             match operator.execute($pool).await {
                 std::result::Result::Ok(result) => {
                     if result.rows_affected() > 0 {
@@ -360,3 +777,302 @@ macro_rules! dynamic_sqlite_update {
         }
     };
 }
+
+macro_rules! dynamic_sqlite_save_all {
+    ($beans:expr, $table:expr, $repo:expr) => {
+        {
+            let mut serialized_beans = Vec::with_capacity($beans.len());
+            for mut bean in $beans {
+                // Keep the original id, since the pre_insert will assign a new one.
+                let id = bean.base.id;
+                match id {
+                    Some(_) => bean.base.pre_update(None).await,
+                    None => {
+                        bean.base.pre_insert(None).await;
+                    }
+                }
+                serialized_beans.push((id, serde_json::to_value(&bean)?));
+            }
+            crate::store::sqlite::save_all_in_transaction($repo, $table, serialized_beans).await
+        }
+    };
+}
+
+macro_rules! dynamic_sqlite_insert_batch {
+    ($beans:expr, $table:expr, $repo:expr) => {
+        {
+            let mut serialized_beans = Vec::with_capacity($beans.len());
+            for mut bean in $beans {
+                let id = bean.base.pre_insert(None).await;
+                serialized_beans.push((id, serde_json::to_value(&bean)?));
+            }
+            crate::store::sqlite::insert_batch_in_transaction($repo, $table, serialized_beans).await
+        }
+    };
+}
+
+// Build the insert statement of the non-empty fields of serialized bean, none if no fields.
+pub fn build_sqlite_insert(table: &str, serialized: &serde_json::Value) -> Option<(String, Vec<GenericValue>)> {
+    let (fields, params) = sqlite_insert_fields(serialized)?;
+    if fields.is_empty() {
+        return None;
+    }
+    let values = vec!["?"; fields.len()];
+    let query = format!("INSERT INTO {} ({}) VALUES ({})", table, fields.join(","), values.join(","));
+    Some((query, params))
+}
+
+// Build the multi-rows insert statements of the serialized beans in order, the consecutive beans of
+// the same non-empty fields are inserted by one statement limited by the max bind params.
+pub fn build_sqlite_insert_batch(table: &str, serialized: &[serde_json::Value]) -> Vec<(String, Vec<GenericValue>)> {
+    let mut statements = Vec::new();
+    let mut pending: Option<(Vec<&str>, usize, Vec<GenericValue>)> = None;
+    let flush = |pending: Option<(Vec<&str>, usize, Vec<GenericValue>)>, statements: &mut Vec<_>| {
+        if let Some((fields, rows, params)) = pending {
+            let row = format!("({})", vec!["?"; fields.len()].join(","));
+            let query = format!("INSERT INTO {} ({}) VALUES {}", table, fields.join(","), vec![row; rows].join(","));
+            statements.push((query, params));
+        }
+    };
+    for bean in serialized {
+        let (fields, params) = match sqlite_insert_fields(bean) {
+            Some((fields, params)) if !fields.is_empty() => (fields, params),
+            _ => continue,
+        };
+        pending = match pending {
+            Some((pending_fields, rows, mut pending_params))
+                if
+                    pending_fields == fields &&
+                    pending_params.len() + params.len() <= SQLITE_MAX_BIND_PARAMS
+            => {
+                pending_params.extend(params);
+                Some((pending_fields, rows + 1, pending_params))
+            }
+            other => {
+                flush(other, &mut statements);
+                Some((fields, 1, params))
+            }
+        };
+    }
+    flush(pending, &mut statements);
+    statements
+}
+
+// Resolve the non-empty fields with the bind params of the serialized bean.
+fn sqlite_insert_fields(serialized: &serde_json::Value) -> Option<(Vec<&str>, Vec<GenericValue>)> {
+    let mut fields = Vec::new();
+    let mut params = Vec::new();
+    for (key, value) in serialized.as_object()? {
+        if !value.is_null() {
+            if value.is_boolean() {
+                fields.push(key.as_str());
+                params.push(GenericValue::Bool(value.as_bool().unwrap()));
+            } else if value.is_number() {
+                fields.push(key.as_str());
+                params.push(GenericValue::Int64(value.as_i64().unwrap()));
+            } else if value.is_string() {
+                let v = value.as_str().unwrap_or("");
+                if !v.is_empty() {
+                    fields.push(key.as_str());
+                    params.push(GenericValue::String(v.to_string()));
+                }
+            }
+        }
+    }
+    Some((fields, params))
+}
+
+// Build the update statement by id of the non-empty fields of serialized bean, which updates nothing
+// when the biz fields are unchanged, none if no fields.
+pub fn build_sqlite_update(
+    table: &str,
+    id: i64,
+    serialized: &serde_json::Value
+) -> Option<(String, Vec<GenericValue>)> {
+    let mut fields = Vec::new();
+    let mut params = Vec::new();
+    // The changed conditions of the biz fields, so that nothing is updated when unchanged.
+    let mut changes = Vec::new();
+    let mut change_params = Vec::new();
+    for (key, value) in serialized.as_object()? {
+        if !value.is_null() {
+            let param = if value.is_boolean() {
+                GenericValue::Bool(value.as_bool().unwrap())
+            } else if value.is_number() {
+                GenericValue::Int64(value.as_i64().unwrap())
+            } else if value.is_string() && !value.as_str().unwrap_or("").is_empty() {
+                GenericValue::String(value.as_str().unwrap().to_string())
+            } else {
+                continue;
+            };
+            fields.push(format!("{} = ?", key));
+            if !crate::types::BASE_BEAN_FIELDS.contains(&key.as_str()) {
+                changes.push(format!("{} IS NOT ?", key));
+                change_params.push(param.clone());
+            }
+            params.push(param);
+        }
+    }
+    if fields.is_empty() {
+        return None;
+    }
+
+    let query = if changes.is_empty() {
+        format!("UPDATE {} SET {} WHERE id = ?", table, fields.join(", "))
+    } else {
+        format!("UPDATE {} SET {} WHERE id = ? AND ({})", table, fields.join(", "), changes.join(" OR "))
+    };
+    params.push(GenericValue::Int64(id));
+    params.extend(change_params);
+    Some((query, params))
+}
+
+// Update the active row in a transaction only if its update_time is still the expected, and select it to tell
+// the conflicted from the absent when nothing updated, returns -1 if the biz fields are unchanged.
+pub async fn update_sqlite_if_unchanged<T: Any + Send + Sync>(
+    repo: &SQLiteRepository<T>,
+    table: &'static str,
+    id: i64,
+    serialized: &serde_json::Value,
+    expected_update_time: Option<i64>
+) -> Result<i64, Error> {
+    let (query, mut params) = match build_sqlite_update(table, id, serialized) {
+        Some(statement) => statement,
+        None => {
+            return Ok(0);
+        }
+    };
+    let query = format!("{} AND del_flag = 0 AND IFNULL(update_time, 0) = ?", query);
+    params.push(GenericValue::Int64(expected_update_time.unwrap_or(0)));
+
+    let mut tx = repo.begin().await?;
+    let result = bind_sqlite_params(sqlx::query(&query), &params).execute(&mut *tx.tx).await?;
+    if result.rows_affected() > 0 {
+        tx.commit().await?;
+        return Ok(id);
+    }
+    let current = sqlx
+        ::query_scalar::<_, Option<i64>>(&format!("SELECT update_time FROM {} WHERE id = ? AND del_flag = 0", table))
+        .bind(id)
+        .fetch_optional(&mut *tx.tx).await?;
+    tx.commit().await?;
+    match current {
+        None => Err(StoreError::NotFound(table, id).into()),
+        Some(current) if current.unwrap_or(0) != expected_update_time.unwrap_or(0) => {
+            Err(StoreError::Conflict(table, id).into())
+        }
+        Some(_) => Ok(-1),
+    }
+}
+
+// Soft-delete the active row of the table, the absent (or already soft-deleted) is not affected.
+pub async fn soft_delete_sqlite_by_id(pool: &SqlitePool, table: &str, id: i64) -> Result<u64, Error> {
+    let query = format!("UPDATE {} SET del_flag = 1, update_time = ? WHERE id = ? AND del_flag = 0", table);
+    let deleted = sqlx::query(&query).bind(times::now_millis()).bind(id).execute(pool).await?.rows_affected();
+    info!("Soft-deleted {} row of {} by id: {}", deleted, table, id);
+    Ok(deleted)
+}
+
+// Hard delete the soft-deleted row of the table, the active row is rejected and the absent is not found.
+pub async fn purge_sqlite_by_id(
+    pool: &SqlitePool,
+    table: &str,
+    entity: &'static str,
+    id: i64
+) -> Result<u64, Error> {
+    let query = format!("DELETE FROM {} WHERE id = ? AND del_flag = 1", table);
+    let purged = sqlx::query(&query).bind(id).execute(pool).await?.rows_affected();
+    if purged == 0 {
+        let query = format!("SELECT COUNT(1) FROM {} WHERE id = ?", table);
+        let exists = sqlx::query_scalar::<_, i64>(&query).bind(id).fetch_one(pool).await? > 0;
+        if exists {
+            return Err(StoreError::NotDeleted(entity, id).into());
+        }
+        return Err(StoreError::NotFound(entity, id).into());
+    }
+    info!("Purged the row of {} by id: {}", table, id);
+    Ok(purged)
+}
+
+pub async fn purge_sqlite_deleted_before(pool: &SqlitePool, table: &str, update_time: i64) -> Result<u64, Error> {
+    let query = format!("DELETE FROM {} WHERE del_flag = 1 AND update_time < ?", table);
+    let purged = sqlx::query(&query).bind(update_time).execute(pool).await?.rows_affected();
+    info!("Purged {} deleted rows of {} before {}", purged, table, update_time);
+    Ok(purged)
+}
+
+pub fn bind_sqlite_params<'q>(
+    mut query: Query<'q, Sqlite, SqliteArguments<'q>>,
+    params: &'q [GenericValue]
+) -> Query<'q, Sqlite, SqliteArguments<'q>> {
+    for param in params {
+        query = match param {
+            GenericValue::Bool(v) => query.bind(v),
+            GenericValue::Int32(v) => query.bind(v),
+            GenericValue::Int64(v) => query.bind(v),
+            GenericValue::String(v) => query.bind(v),
+        };
+    }
+    query
+}
+
+// Insert all the beans (with the ids assigned) with the multi-rows statements in a transaction.
+pub async fn insert_batch_in_transaction<T: Any + Send + Sync>(
+    repo: &SQLiteRepository<T>,
+    table: &str,
+    beans: Vec<(i64, serde_json::Value)>
+) -> Result<Vec<i64>, Error> {
+    if beans.is_empty() {
+        return Ok(vec![]);
+    }
+    let (ids, serialized): (Vec<i64>, Vec<serde_json::Value>) = beans.into_iter().unzip();
+    let statements = build_sqlite_insert_batch(table, &serialized);
+    let mut tx = repo.begin().await?;
+    let mut inserted = 0;
+    for (query, params) in statements.iter() {
+        let result = bind_sqlite_params(sqlx::query(query), params).execute(&mut *tx.tx).await?;
+        inserted += result.rows_affected();
+    }
+    if inserted != (ids.len() as u64) {
+        return Err(anyhow!("Batch inserted {}/{} rows of {}, rolled back", inserted, ids.len(), table));
+    }
+    tx.commit().await?;
+    info!("Committed batch insert {} rows of {} by {} statements", ids.len(), table, statements.len());
+    Ok(ids)
+}
+
+// Execute the inserts (without id) or updates (with id) of the serialized beans in a transaction, it's
+// rolled back on any error. Returns the ids in order, and -1 for the unchanged (or not found) updates.
+pub async fn save_all_in_transaction<T: Any + Send + Sync>(
+    repo: &SQLiteRepository<T>,
+    table: &str,
+    beans: Vec<(Option<i64>, serde_json::Value)>
+) -> Result<Vec<i64>, Error> {
+    let total = beans.len();
+    let mut ids = Vec::with_capacity(total);
+    let mut tx = repo.begin().await?;
+    for (i, (id, serialized)) in beans.iter().enumerate() {
+        let statement = match id {
+            Some(id) => build_sqlite_update(table, *id, serialized),
+            None => build_sqlite_insert(table, serialized),
+        };
+        let saved_id = match statement {
+            Some((query, params)) => {
+                let result = bind_sqlite_params(sqlx::query(&query), &params).execute(&mut *tx.tx).await?;
+                match id {
+                    _ if result.rows_affected() == 0 => -1,
+                    Some(id) => *id,
+                    None => result.last_insert_rowid(),
+                }
+            }
+            None => -1,
+        };
+        ids.push(saved_id);
+        if (i + 1) % SAVE_ALL_PROGRESS_INTERVAL == 0 {
+            info!("Saved {}/{} rows of {} in transaction", i + 1, total, table);
+        }
+    }
+    tx.commit().await?;
+    info!("Committed {} rows of {}", total, table);
+    Ok(ids)
+}
@@ -25,28 +25,84 @@ use std::sync::Arc;
 use anyhow::Error;
 use axum::async_trait;
 
-use mongodb::Collection;
-use mongodb::bson::doc;
+use futures::stream::TryStreamExt;
+use mongodb::{ Collection, IndexModel };
+use mongodb::bson::{ doc, Document };
+use mongodb::options::IndexOptions;
 
-use crate::config::config_serve::DbProperties;
+use crate::config::config_serve::{ DbProperties, DbType };
 use crate::types::user::User;
 use crate::types::{ PageRequest, PageResponse };
-use super::AsyncRepository;
+use super::{ AsyncRepository, StoreError };
 use super::mongo::MongoRepository;
-use crate::{ dynamic_mongo_query, dynamic_mongo_insert, dynamic_mongo_update };
+use crate::{
+    dynamic_mongo_query,
+    dynamic_mongo_select_by_ids,
+    dynamic_mongo_select_deleted,
+    dynamic_mongo_count,
+    dynamic_mongo_insert,
+    dynamic_mongo_update,
+};
 
 pub struct UserMongoRepository {
     #[allow(unused)]
     inner: Arc<MongoRepository<User>>,
     collection: Collection<User>,
+    soft_delete: bool,
 }
 
 impl UserMongoRepository {
     pub async fn new(config: &DbProperties) -> Result<Self, Error> {
         let inner = Arc::new(MongoRepository::new(config).await?);
         let collection = inner.get_database().collection("users");
-        Ok(UserMongoRepository { inner, collection })
+        // The mongo repository is built (with the lazy connection) even if not used, so only the active one
+        // requires the server at startup.
+        if config.db_type == DbType::Mongo {
+            ensure_unique_sub_indexes(&collection).await?;
+        }
+        Ok(UserMongoRepository { inner, collection, soft_delete: config.soft_delete.unwrap_or(true) })
+    }
+}
+
+// The provider subjects identify the unique user, as the unique indexes of the migration (see:
+// migrations/20241015000000_users_unique_subs.sql), so that the concurrent first logins of the same subject
+// cannot create the duplicated users.
+const UNIQUE_SUB_FIELDS: [&str; 4] = ["oidc_claims_sub", "github_claims_sub", "google_claims_sub", "ethers_address"];
+
+// Create the unique indexes of the active users with the subject, the duplicated users created before are
+// soft-deleted except the earliest, otherwise the indexes could not be created.
+async fn ensure_unique_sub_indexes(collection: &Collection<User>) -> Result<(), Error> {
+    for field in UNIQUE_SUB_FIELDS {
+        let mut active = doc! { "del_flag": 0 };
+        active.insert(field, doc! { "$type": "string" });
+
+        let pipeline = vec![
+            doc! { "$match": active.clone() },
+            doc! { "$group": { "_id": format!("${}", field), "ids": { "$push": "$id" }, "count": { "$sum": 1 } } },
+            doc! { "$match": { "count": { "$gt": 1 } } }
+        ];
+        let mut duplicates = collection.aggregate(pipeline).await?;
+        while let Some(group) = duplicates.try_next().await? {
+            let mut ids = group
+                .get_array("ids")?
+                .iter()
+                .filter_map(|id| id.as_i64())
+                .collect::<Vec<_>>();
+            ids.sort();
+            tracing::warn!("Soft-deleting the duplicated users {:?} of the same {}, kept: {}", &ids[1..], field, ids[0]);
+            collection.update_many(doc! { "id": { "$in": &ids[1..] } }, doc! { "$set": { "del_flag": 1 } }).await?;
+        }
+
+        let mut keys = Document::new();
+        keys.insert(field, 1);
+        let options = IndexOptions::builder()
+            .name(format!("uk_users_{}", field))
+            .unique(true)
+            .partial_filter_expression(active)
+            .build();
+        collection.create_index(IndexModel::builder().keys(keys).options(options).build()).await?;
     }
+    Ok(())
 }
 
 #[async_trait]
@@ -66,14 +122,26 @@ impl AsyncRepository<User> for UserMongoRepository {
         }
     }
 
-    async fn select_by_id(&self, id: i64) -> Result<User, Error> {
-        let filter = doc! { "id": id };
+    async fn select_by_id(&self, id: i64) -> Result<Option<User>, Error> {
+        let filter = doc! { "id": id, "del_flag": { "$ne": 1 } };
         let user = self.collection
-            .find_one(filter).await?
-            .ok_or_else(|| Error::msg("User not found"))?;
+            .find_one(filter).await
+            .map_err(|e| StoreError::StorageUnavailable(e.into()))?;
         Ok(user)
     }
 
+    async fn select_by_ids(&self, ids: Vec<i64>) -> Result<Vec<User>, Error> {
+        dynamic_mongo_select_by_ids!(ids, self.collection)
+    }
+
+    async fn select_deleted(&self, user: User, page: PageRequest) -> Result<(PageResponse, Vec<User>), Error> {
+        dynamic_mongo_select_deleted!(user, self.collection, page)
+    }
+
+    async fn count_by(&self, user: User, not_null_fields: &[&str]) -> Result<i64, Error> {
+        dynamic_mongo_count!(user, self.collection, not_null_fields)
+    }
+
     async fn insert(&self, mut user: User) -> Result<i64, Error> {
         dynamic_mongo_insert!(user, self.collection)
     }
@@ -88,8 +156,24 @@ impl AsyncRepository<User> for UserMongoRepository {
     }
 
     async fn delete_by_id(&self, id: i64) -> Result<u64, Error> {
+        if self.soft_delete { self.soft_delete_by_id(id).await } else { self.hard_delete_by_id(id).await }
+    }
+
+    async fn soft_delete_by_id(&self, id: i64) -> Result<u64, Error> {
+        super::mongo::soft_delete_mongo_by_id(&self.collection, id).await
+    }
+
+    async fn hard_delete_by_id(&self, id: i64) -> Result<u64, Error> {
         let filter = doc! { "id": id };
         let result = self.collection.delete_one(filter).await?;
         Ok(result.deleted_count)
     }
+
+    async fn purge_by_id(&self, id: i64) -> Result<u64, Error> {
+        super::mongo::purge_mongo_by_id(&self.collection, "user", id).await
+    }
+
+    async fn purge_deleted_before(&self, update_time: i64) -> Result<u64, Error> {
+        super::mongo::purge_mongo_deleted_before(&self.collection, update_time).await
+    }
 }
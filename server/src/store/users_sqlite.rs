@@ -20,25 +20,33 @@
  * This includes modifications and derived works.
  */
 
-use anyhow::{ Error, Ok };
+use anyhow::{ Context, Error, Ok };
 use axum::async_trait;
 
-use crate::config::config_serve::DbProperties;
-use crate::types::user::User;
+use crate::config::config_serve::{ DbProperties, ErasureStrategy };
+use crate::types::settings::SETTINGS_SCOPE_USER;
+use crate::types::user::{ ErasureOutcome, User, ERASED_TOMBSTONE };
 use crate::types::PageRequest;
 use crate::types::PageResponse;
-use super::AsyncRepository;
-use super::sqlite::SQLiteRepository;
+use crate::utils::times;
+use super::{ AsyncRepository, StoreError };
+use super::sqlite::{ audit_sqlite_schema, soft_delete_sqlite_by_id, update_sqlite_if_unchanged, SQLiteRepository };
+
+// The columns of users allowed to sort by, see: PageRequest.sort_by
+pub const USER_SORT_COLUMNS: [&str; 5] = ["id", "name", "email", "create_time", "update_time"];
 
 pub struct UserSQLiteRepository {
     inner: SQLiteRepository<User>,
+    soft_delete: bool,
 }
 
 impl UserSQLiteRepository {
     pub async fn new(config: &DbProperties) -> Result<Self, Error> {
-        Ok(UserSQLiteRepository {
-            inner: SQLiteRepository::new(config).await?,
-        })
+        let inner = SQLiteRepository::new(config).await?;
+        if config.schema_audit.unwrap_or(true) {
+            audit_sqlite_schema(inner.get_pool(), "users", &User::default()).await?;
+        }
+        Ok(UserSQLiteRepository { inner, soft_delete: config.soft_delete.unwrap_or(true) })
     }
 }
 
@@ -49,14 +57,15 @@ impl AsyncRepository<User> for UserSQLiteRepository {
         user: User,
         page: PageRequest
     ) -> Result<(PageResponse, Vec<User>), Error> {
+        let order_by = page.get_order_by(&USER_SORT_COLUMNS, "update_time");
         let result = dynamic_sqlite_query!(
             user,
             "users",
-            self.inner.get_pool(),
-            "update_time",
+            self.inner.get_read_pool(),
+            order_by,
             page,
             User
-        ).unwrap();
+        ).context("Failed to select users")?;
 
         tracing::info!("query users: {:?}", result);
         Ok((result.0, result.1))
@@ -72,19 +81,31 @@ impl AsyncRepository<User> for UserSQLiteRepository {
         //   })
     }
 
-    async fn select_by_id(&self, id: i64) -> Result<User, Error> {
+    async fn select_by_id(&self, id: i64) -> Result<Option<User>, Error> {
         let user = sqlx
-            ::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+            ::query_as::<_, User>("SELECT * FROM users WHERE id = $1 AND del_flag = 0")
             .bind(id)
-            .fetch_one(self.inner.get_pool()).await
-            .unwrap();
+            .fetch_optional(self.inner.get_read_pool()).await
+            .map_err(|e| StoreError::StorageUnavailable(e.into()))?;
 
         tracing::info!("query user: {:?}", user);
         Ok(user)
     }
 
+    async fn select_by_ids(&self, ids: Vec<i64>) -> Result<Vec<User>, Error> {
+        dynamic_sqlite_select_by_ids!(ids, "users", self.inner.get_read_pool(), User)
+    }
+
+    async fn select_deleted(&self, user: User, page: PageRequest) -> Result<(PageResponse, Vec<User>), Error> {
+        dynamic_sqlite_select_deleted!(user, "users", self.inner.get_read_pool(), page, User)
+    }
+
+    async fn count_by(&self, user: User, not_null_fields: &[&str]) -> Result<i64, Error> {
+        dynamic_sqlite_count!(user, "users", self.inner.get_read_pool(), not_null_fields)
+    }
+
     async fn insert(&self, mut user: User) -> Result<i64, Error> {
-        let inserted_id = dynamic_sqlite_insert!(user, "users", self.inner.get_pool()).unwrap();
+        let inserted_id = dynamic_sqlite_insert!(user, "users", self.inner.get_pool())?;
         tracing::info!("Inserted user.id: {:?}", inserted_id);
         Ok(inserted_id)
 
@@ -113,7 +134,7 @@ impl AsyncRepository<User> for UserSQLiteRepository {
     }
 
     async fn update(&self, mut user: User) -> Result<i64, Error> {
-        let updated_id = dynamic_sqlite_update!(user, "users", self.inner.get_pool()).unwrap();
+        let updated_id = dynamic_sqlite_update!(user, "users", self.inner.get_pool()).context("Failed to update users")?;
         tracing::info!("Updated user.id: {:?}", updated_id);
         Ok(updated_id)
 
@@ -129,24 +150,730 @@ impl AsyncRepository<User> for UserSQLiteRepository {
         // Ok(update_result.rows_affected() as i64)
     }
 
+    async fn update_if_unchanged(&self, mut user: User, expected_update_time: Option<i64>) -> Result<i64, Error> {
+        let id = user.base.id.ok_or_else(|| anyhow::anyhow!("The id is required to update users"))?;
+        user.base.pre_update(None).await;
+        // The version must be advanced even if updated within the same millis, otherwise the stale is accepted.
+        user.base.update_time = user.base.update_time.max(expected_update_time.map(|t| t + 1));
+        let serialized = serde_json::to_value(&user)?;
+        let updated_id = update_sqlite_if_unchanged(&self.inner, "users", id, &serialized, expected_update_time).await?;
+        tracing::info!("Updated if unchanged user.id: {:?}", updated_id);
+        Ok(updated_id)
+    }
+
+    async fn save_all(&self, users: Vec<User>) -> Result<Vec<i64>, Error> {
+        dynamic_sqlite_save_all!(users, "users", &self.inner)
+    }
+
+    async fn insert_batch(&self, users: Vec<User>) -> Result<Vec<i64>, Error> {
+        dynamic_sqlite_insert_batch!(users, "users", &self.inner)
+    }
+
     async fn delete_all(&self) -> Result<u64, Error> {
         let delete_result = sqlx
             ::query("DELETE FROM users")
             .execute(self.inner.get_pool()).await
-            .unwrap();
+            .context("Failed to delete all users")?;
 
         tracing::info!("Deleted result: {:?}", delete_result);
         Ok(delete_result.rows_affected())
     }
 
     async fn delete_by_id(&self, id: i64) -> Result<u64, Error> {
+        if self.soft_delete { self.soft_delete_by_id(id).await } else { self.hard_delete_by_id(id).await }
+    }
+
+    async fn soft_delete_by_id(&self, id: i64) -> Result<u64, Error> {
+        soft_delete_sqlite_by_id(self.inner.get_pool(), "users", id).await
+            .with_context(|| format!("Failed to soft delete users by id: {}", id))
+    }
+
+    async fn hard_delete_by_id(&self, id: i64) -> Result<u64, Error> {
         let delete_result = sqlx
             ::query("DELETE FROM users WHERE id = $1")
             .bind(id)
             .execute(self.inner.get_pool()).await
-            .unwrap();
+            .with_context(|| format!("Failed to delete users by id: {}", id))?;
 
         tracing::info!("Deleted result: {:?}", delete_result);
         Ok(delete_result.rows_affected())
     }
+
+    async fn purge_by_id(&self, id: i64) -> Result<u64, Error> {
+        super::sqlite::purge_sqlite_by_id(self.inner.get_pool(), "users", "user", id).await
+    }
+
+    async fn purge_deleted_before(&self, update_time: i64) -> Result<u64, Error> {
+        super::sqlite::purge_sqlite_deleted_before(self.inner.get_pool(), "users", update_time).await
+    }
+
+    async fn erase(&self, id: i64, strategy: ErasureStrategy) -> Result<ErasureOutcome, Error> {
+        let now = times::now_millis();
+        // The settings and audit logs are in the same database, so that all are erased atomically.
+        let mut tx = self.inner.begin().await?;
+
+        // Scrub the personal data and provider identities to the tombstones, and soft-delete the user.
+        let users = sqlx
+            ::query(
+                "UPDATE users SET name = ?, email = NULL, phone = NULL, password = NULL, \
+                 oidc_claims_sub = NULL, oidc_claims_name = NULL, oidc_claims_email = NULL, \
+                 github_claims_sub = NULL, github_claims_name = NULL, github_claims_email = NULL, \
+                 google_claims_sub = NULL, google_claims_name = NULL, google_claims_email = NULL, verified_email = NULL, \
+                 ethers_address = NULL, del_flag = 1, update_time = ? WHERE id = ?"
+            )
+            .bind(ERASED_TOMBSTONE)
+            .bind(now)
+            .bind(id)
+            .execute(&mut *tx.tx).await
+            .with_context(|| format!("Failed to scrub users by id: {}", id))?
+            .rows_affected();
+        if users == 0 {
+            return Err(StoreError::NotFound("user", id).into());
+        }
+
+        let (settings, audit_logs) = match strategy {
+            ErasureStrategy::Delete => {
+                let settings = sqlx
+                    ::query("DELETE FROM settings WHERE scope = ? AND owner = ?")
+                    .bind(SETTINGS_SCOPE_USER)
+                    .bind(id.to_string())
+                    .execute(&mut *tx.tx).await
+                    .context("Failed to delete settings of erased user")?;
+                let audit_logs = sqlx
+                    ::query("DELETE FROM audit_logs WHERE uid = ?")
+                    .bind(id)
+                    .execute(&mut *tx.tx).await
+                    .context("Failed to delete audit_logs of erased user")?;
+                (settings.rows_affected(), audit_logs.rows_affected())
+            }
+            ErasureStrategy::Anonymize => {
+                // Detach the settings from the user, which are soft-deleted so that never resolved again.
+                let settings = sqlx
+                    ::query("UPDATE settings SET owner = ?, del_flag = 1, update_time = ? WHERE scope = ? AND owner = ?")
+                    .bind(ERASED_TOMBSTONE)
+                    .bind(now)
+                    .bind(SETTINGS_SCOPE_USER)
+                    .bind(id.to_string())
+                    .execute(&mut *tx.tx).await
+                    .context("Failed to anonymize settings of erased user")?;
+                // Keep the audit logs referenced to the tombstoned user, but scrub the details and client IPs.
+                let audit_logs = sqlx
+                    ::query("UPDATE audit_logs SET detail = NULL, client_ip = NULL, update_time = ? WHERE uid = ?")
+                    .bind(now)
+                    .bind(id)
+                    .execute(&mut *tx.tx).await
+                    .context("Failed to anonymize audit_logs of erased user")?;
+                (settings.rows_affected(), audit_logs.rows_affected())
+            }
+        };
+        tx.commit().await?;
+
+        tracing::info!("Erased user.id: {}, strategy: {:?}, settings: {}, audit_logs: {}", id, strategy, settings, audit_logs);
+        Ok(ErasureOutcome { users, settings, audit_logs })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::config_serve::{ ReadReplicaProperties, SqliteProperties };
+    use crate::store::sqlite::SQLITE_MAX_BIND_PARAMS;
+    use crate::types::SortOrder;
+    use sqlx::Row;
+
+    fn new_test_config() -> DbProperties {
+        let dir = std::env::temp_dir().join(format!("mywebnote_test_{}", uuid::Uuid::new_v4()));
+        let mut config = DbProperties::default();
+        config.sqlite.dir = Some(dir.to_string_lossy().to_string());
+        config
+    }
+
+    async fn new_test_repo() -> UserSQLiteRepository {
+        UserSQLiteRepository::new(&new_test_config()).await.unwrap()
+    }
+
+    fn new_user(name: &str, github_claims_sub: Option<&str>) -> User {
+        User {
+            name: Some(name.to_string()),
+            github_claims_sub: github_claims_sub.map(|s| s.to_string()),
+            ..User::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_count_by_all_and_provider_present() {
+        let repo = new_test_repo().await;
+        repo.insert(new_user("alice", Some("1001"))).await.unwrap();
+        repo.insert(new_user("bob", None)).await.unwrap();
+        let deleted = repo.insert(new_user("carol", Some("1003"))).await.unwrap();
+        sqlx::query("UPDATE users SET del_flag = 1 WHERE id = ?")
+            .bind(deleted)
+            .execute(repo.inner.get_pool()).await
+            .unwrap();
+
+        assert_eq!(repo.count_by(User::default(), &[]).await.unwrap(), 2);
+        assert_eq!(repo.count_by(User::default(), &["github_claims_sub"]).await.unwrap(), 1);
+        assert_eq!(repo.count_by(new_user("bob", None), &[]).await.unwrap(), 1);
+    }
+
+    async fn count_rows(repo: &UserSQLiteRepository, id: i64) -> i64 {
+        sqlx::query_scalar::<_, i64>("SELECT COUNT(1) FROM users WHERE id = ?")
+            .bind(id)
+            .fetch_one(repo.inner.get_pool()).await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_unique_subs_migration_dedupes_users() {
+        let repo = new_test_repo().await;
+        let pool = repo.inner.get_pool();
+        // As the databases with the duplicated users created before the unique indexes.
+        sqlx::query("drop index uk_users_github_claims_sub").execute(pool).await.unwrap();
+        let first = repo.insert(new_user("alice", Some("1001"))).await.unwrap();
+        let duplicated = repo.insert(new_user("alice2", Some("1001"))).await.unwrap();
+        let other = repo.insert(new_user("bob", Some("1002"))).await.unwrap();
+        let absent = repo.insert(new_user("carol", None)).await.unwrap();
+        let absent2 = repo.insert(new_user("dave", None)).await.unwrap();
+
+        let migration = include_str!("../../migrations/20241015000000_users_unique_subs.sql");
+        sqlx::Executor::execute(pool, migration).await.unwrap();
+        for id in [first, other, absent, absent2] {
+            assert!(repo.select_by_id(id).await.unwrap().is_some());
+        }
+        assert!(repo.select_by_id(duplicated).await.unwrap().is_none());
+        assert_eq!(count_rows(&repo, duplicated).await, 1);
+        assert!(repo.insert(new_user("alice3", Some("1001"))).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_soft_deleted_rows_excluded_from_queries_but_kept() {
+        let repo = new_test_repo().await;
+        let alice = repo.insert(new_user("alice", None)).await.unwrap();
+        let bob = repo.insert(new_user("bob", None)).await.unwrap();
+
+        // The soft delete is the default.
+        assert_eq!(repo.delete_by_id(bob).await.unwrap(), 1);
+        assert_eq!(repo.delete_by_id(bob).await.unwrap(), 0);
+        assert!(repo.select_by_id(bob).await.unwrap().is_none());
+        let (page, users) = repo.select(User::default(), PageRequest::default()).await.unwrap();
+        assert_eq!(page.total, Some(1));
+        assert_eq!(users.iter().map(|u| u.base.id.unwrap()).collect::<Vec<_>>(), vec![alice]);
+        assert!(repo.select(new_user("bob", None), PageRequest::default()).await.unwrap().1.is_empty());
+
+        // But remains in the table, with the deletion time stamped.
+        assert_eq!(count_rows(&repo, bob).await, 1);
+        let (_, deleted) = repo.select_deleted(User::default(), PageRequest::default()).await.unwrap();
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(deleted[0].base.del_flag, Some(1));
+        assert!(deleted[0].base.update_time >= deleted[0].base.create_time);
+
+        assert_eq!(repo.hard_delete_by_id(bob).await.unwrap(), 1);
+        assert_eq!(count_rows(&repo, bob).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_delete_by_id_hard_deletes_if_soft_delete_disabled() {
+        let config = DbProperties { soft_delete: Some(false), ..new_test_config() };
+        let repo = UserSQLiteRepository::new(&config).await.unwrap();
+        let id = repo.insert(new_user("alice", None)).await.unwrap();
+
+        assert_eq!(repo.delete_by_id(id).await.unwrap(), 1);
+        assert_eq!(count_rows(&repo, id).await, 0);
+        assert!(repo.select_deleted(User::default(), PageRequest::default()).await.unwrap().1.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_select_deleted_only_soft_deleted_rows() {
+        let repo = new_test_repo().await;
+        repo.insert(new_user("alice", None)).await.unwrap();
+        let bob = repo.insert(new_user("bob", None)).await.unwrap();
+        let carol = repo.insert(new_user("carol", None)).await.unwrap();
+        soft_delete(&repo, bob, 1000).await;
+        soft_delete(&repo, carol, 2000).await;
+
+        let (page, users) = repo.select_deleted(User::default(), PageRequest::default()).await.unwrap();
+        assert_eq!(page.total, Some(2));
+        let names = users.iter().map(|u| u.name.clone().unwrap()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["carol", "bob"]);
+        assert_eq!(users[0].base.update_time, Some(2000));
+
+        let (_, users) = repo.select_deleted(new_user("bob", None), PageRequest::default()).await.unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].base.id, Some(bob));
+        let (_, users) = repo.select_deleted(new_user("alice", None), PageRequest::default()).await.unwrap();
+        assert!(users.is_empty());
+    }
+
+    async fn soft_delete(repo: &UserSQLiteRepository, id: i64, delete_time: i64) {
+        sqlx::query("UPDATE users SET del_flag = 1, update_time = ? WHERE id = ?")
+            .bind(delete_time)
+            .bind(id)
+            .execute(repo.inner.get_pool()).await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_purge_by_id_only_soft_deleted_row() {
+        let repo = new_test_repo().await;
+        let active = repo.insert(new_user("alice", None)).await.unwrap();
+        let deleted = repo.insert(new_user("bob", None)).await.unwrap();
+        soft_delete(&repo, deleted, 1000).await;
+
+        assert_eq!(repo.purge_by_id(deleted).await.unwrap(), 1);
+        let (_, users) = repo.select_deleted(User::default(), PageRequest::default()).await.unwrap();
+        assert!(users.is_empty());
+
+        let err = repo.purge_by_id(active).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::NotDeleted("user", _))));
+        assert_eq!(repo.count_by(new_user("alice", None), &[]).await.unwrap(), 1);
+
+        let err = repo.purge_by_id(deleted).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::NotFound("user", _))));
+    }
+
+    #[tokio::test]
+    async fn test_purge_deleted_before() {
+        let repo = new_test_repo().await;
+        repo.insert(new_user("alice", None)).await.unwrap();
+        let old = repo.insert(new_user("bob", None)).await.unwrap();
+        let recent = repo.insert(new_user("carol", None)).await.unwrap();
+        soft_delete(&repo, old, 1000).await;
+        soft_delete(&repo, recent, 3000).await;
+
+        assert_eq!(repo.purge_deleted_before(2000).await.unwrap(), 1);
+        let (_, users) = repo.select_deleted(User::default(), PageRequest::default()).await.unwrap();
+        assert_eq!(users.iter().map(|u| u.base.id.unwrap()).collect::<Vec<_>>(), vec![recent]);
+        assert_eq!(repo.count_by(User::default(), &[]).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_count_by_rejects_invalid_field() {
+        let repo = new_test_repo().await;
+        assert!(repo.count_by(User::default(), &["1=1 OR name"]).await.is_err());
+    }
+
+    async fn query_pragma(repo: &UserSQLiteRepository, name: &str) -> String {
+        let mut conn = repo.inner.get_pool().acquire().await.unwrap();
+        let row = sqlx::query(&format!("PRAGMA {}", name)).fetch_one(&mut *conn).await.unwrap();
+        row.try_get::<String, _>(0).unwrap_or_else(|_| row.get::<i64, _>(0).to_string())
+    }
+
+    #[tokio::test]
+    async fn test_default_pragmas_set_on_acquired_connections() {
+        let repo = new_test_repo().await;
+        assert_eq!(query_pragma(&repo, "journal_mode").await, "wal");
+        assert_eq!(query_pragma(&repo, "busy_timeout").await, "5000");
+    }
+
+    #[tokio::test]
+    async fn test_configured_pragmas_set_on_acquired_connections() {
+        let mut config = new_test_config();
+        let pragmas = config.sqlite_pragmas.as_mut().unwrap();
+        pragmas.insert("synchronous".to_string(), "NORMAL".to_string());
+        pragmas.insert("Foreign_Keys".to_string(), "ON".to_string());
+        pragmas.insert("busy_timeout".to_string(), "1234".to_string());
+        let repo = UserSQLiteRepository::new(&config).await.unwrap();
+
+        assert_eq!(query_pragma(&repo, "synchronous").await, "1");
+        assert_eq!(query_pragma(&repo, "foreign_keys").await, "1");
+        assert_eq!(query_pragma(&repo, "busy_timeout").await, "1234");
+    }
+
+    #[tokio::test]
+    async fn test_rejects_pragmas_not_allowlisted_or_unsafe() {
+        let mut config = new_test_config();
+        config.sqlite_pragmas.as_mut().unwrap().insert("writable_schema".to_string(), "ON".to_string());
+        assert!(UserSQLiteRepository::new(&config).await.is_err());
+
+        let mut config = new_test_config();
+        config.sqlite_pragmas
+            .as_mut()
+            .unwrap()
+            .insert("synchronous".to_string(), "OFF; DROP TABLE users".to_string());
+        assert!(UserSQLiteRepository::new(&config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reads_from_replica_and_writes_to_primary() {
+        // Prepare the replica db that has diverged from the primary.
+        let replica_config = new_test_config();
+        let replica = UserSQLiteRepository::new(&replica_config).await.unwrap();
+        replica.insert(new_user("replica", None)).await.unwrap();
+
+        let mut config = new_test_config();
+        config.read_replica = Some(ReadReplicaProperties {
+            sqlite: Some(replica_config.sqlite.clone()),
+            max_lag: Some(0),
+        });
+        let repo = UserSQLiteRepository::new(&config).await.unwrap();
+        let id = repo.insert(new_user("primary", None)).await.unwrap();
+
+        let (_, users) = repo.select(User::default(), PageRequest::default()).await.unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].name, Some("replica".to_string()));
+        assert_eq!(repo.count_by(new_user("primary", None), &[]).await.unwrap(), 0);
+
+        let primary_count = sqlx
+            ::query_scalar::<_, i64>("SELECT COUNT(1) FROM users WHERE id = ?")
+            .bind(id)
+            .fetch_one(repo.inner.get_pool()).await
+            .unwrap();
+        assert_eq!(primary_count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_reads_after_write_from_primary_within_max_lag() {
+        let replica_config = new_test_config();
+        let _replica = UserSQLiteRepository::new(&replica_config).await.unwrap();
+
+        let mut config = new_test_config();
+        config.read_replica = Some(ReadReplicaProperties {
+            sqlite: Some(replica_config.sqlite.clone()),
+            max_lag: Some(200),
+        });
+        let repo = UserSQLiteRepository::new(&config).await.unwrap();
+        let id = repo.insert(new_user("primary", None)).await.unwrap();
+
+        // The replica has not caught up yet, but the read after the write is directed to the primary.
+        let user = repo.select_by_id(id).await.unwrap();
+        assert_eq!(user.and_then(|u| u.name), Some("primary".to_string()));
+
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        assert!(repo.select_by_id(id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reads_fallback_to_primary_without_replica() {
+        let mut config = new_test_config();
+        config.read_replica = Some(ReadReplicaProperties {
+            sqlite: Some(SqliteProperties {
+                dir: Some("/nonexistent/mywebnote_replica".to_string()),
+            }),
+            max_lag: None,
+        });
+        let repo = UserSQLiteRepository::new(&config).await.unwrap();
+        repo.insert(new_user("primary", None)).await.unwrap();
+
+        assert_eq!(repo.count_by(User::default(), &[]).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_select_by_ids_present_absent_and_deleted() {
+        let repo = new_test_repo().await;
+        let alice = repo.insert(new_user("alice", None)).await.unwrap();
+        let bob = repo.insert(new_user("bob", None)).await.unwrap();
+        let deleted = repo.insert(new_user("carol", None)).await.unwrap();
+        sqlx::query("UPDATE users SET del_flag = 1 WHERE id = ?")
+            .bind(deleted)
+            .execute(repo.inner.get_pool()).await
+            .unwrap();
+
+        let users = repo.select_by_ids(vec![alice, 999_999, bob, deleted]).await.unwrap();
+        let mut ids = users.iter().map(|u| u.base.id.unwrap()).collect::<Vec<_>>();
+        ids.sort();
+        let mut expected = vec![alice, bob];
+        expected.sort();
+        assert_eq!(ids, expected);
+
+        assert!(repo.select_by_ids(vec![]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_select_by_ids_chunk_boundary() {
+        let repo = new_test_repo().await;
+        let first = repo.insert(new_user("first", None)).await.unwrap();
+        let last = repo.insert(new_user("last", None)).await.unwrap();
+
+        // The ids of a full chunk, and the last one falls into the next chunk.
+        let mut ids = (1..SQLITE_MAX_BIND_PARAMS as i64).map(|i| -i).collect::<Vec<_>>();
+        ids.insert(0, first);
+        ids.push(last);
+        assert_eq!(ids.len(), SQLITE_MAX_BIND_PARAMS + 1);
+
+        let users = repo.select_by_ids(ids).await.unwrap();
+        assert_eq!(users.len(), 2);
+        assert!(users.iter().any(|u| u.base.id == Some(last)));
+    }
+
+    #[tokio::test]
+    async fn test_insert_batch_returns_ids_in_input_order() {
+        let repo = new_test_repo().await;
+        assert!(repo.insert_batch(vec![]).await.unwrap().is_empty());
+
+        // The mixed fields are split into multiple statements, and the rows over the max bind params too.
+        let users = (0..500)
+            .map(|i| {
+                let sub = format!("{}", 1000 + i);
+                new_user(&format!("user-{}", i), if i % 100 == 0 { Some(sub.as_str()) } else { None })
+            })
+            .collect::<Vec<_>>();
+        let ids = repo.insert_batch(users).await.unwrap();
+        assert_eq!(ids.len(), 500);
+
+        for (i, id) in ids.iter().enumerate() {
+            let user = repo.select_by_id(*id).await.unwrap().unwrap();
+            assert_eq!(user.name, Some(format!("user-{}", i)));
+            assert_eq!(user.github_claims_sub.is_some(), i % 100 == 0);
+        }
+        assert_eq!(repo.count_by(User::default(), &[]).await.unwrap(), 500);
+    }
+
+    #[tokio::test]
+    async fn test_insert_batch_rolls_back_all_on_error() {
+        let repo = new_test_repo().await;
+        repo.insert(new_user("alice", Some("1001"))).await.unwrap();
+
+        // The last row conflicts with the provider subject of the existing user.
+        let users = vec![new_user("bob", None), new_user("carol", None), new_user("dave", Some("1001"))];
+        assert!(repo.insert_batch(users).await.is_err());
+        assert_eq!(repo.count_by(User::default(), &[]).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_update_if_unchanged_rejects_stale_update() {
+        let repo = new_test_repo().await;
+        let id = repo.insert(new_user("alice", None)).await.unwrap();
+
+        // Both the editors have seen the same version.
+        let seen = repo.select_by_id(id).await.unwrap().unwrap();
+        let mut first = seen.clone();
+        first.name = Some("alice-1".to_string());
+        let mut second = seen.clone();
+        second.name = Some("alice-2".to_string());
+
+        assert_eq!(repo.update_if_unchanged(first, seen.base.update_time).await.unwrap(), id);
+        let err = repo.update_if_unchanged(second.clone(), seen.base.update_time).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::Conflict("users", i)) if *i == id), "{:?}", err);
+        let current = repo.select_by_id(id).await.unwrap().unwrap();
+        assert_eq!(current.name.as_deref(), Some("alice-1"));
+        assert!(current.base.update_time > seen.base.update_time);
+
+        // Retried with the reloaded version.
+        assert_eq!(repo.update_if_unchanged(second, current.base.update_time).await.unwrap(), id);
+        assert_eq!(repo.select_by_id(id).await.unwrap().unwrap().name.as_deref(), Some("alice-2"));
+
+        let mut absent = seen.clone();
+        absent.base.id = Some(999_999);
+        let err = repo.update_if_unchanged(absent, None).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::NotFound(..))), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn test_select_total_matches_across_page_sizes() {
+        let repo = new_test_repo().await;
+        let users = (0..23).map(|i| new_user(&format!("user-{}", i % 4), None)).collect::<Vec<_>>();
+        repo.insert_batch(users).await.unwrap();
+
+        for (limit, total_pages) in [(1, 23), (5, 5), (10, 3), (23, 1), (50, 1)] {
+            let (page, data) = repo
+                .select(User::default(), PageRequest { num: Some(1), limit: Some(limit), ..PageRequest::default() }).await
+                .unwrap();
+            assert_eq!(page.total, Some(23), "limit: {}", limit);
+            assert_eq!(page.total_pages, Some(total_pages), "limit: {}", limit);
+            assert_eq!(data.len(), (limit as usize).min(23));
+        }
+
+        // The total is counted by the same conditions of the data.
+        let page = PageRequest { num: Some(1), limit: Some(2), ..PageRequest::default() };
+        let (page, data) = repo.select(new_user("user-1", None), page).await.unwrap();
+        assert_eq!((page.total, page.total_pages, data.len()), (Some(6), Some(3), 2));
+
+        let page = PageRequest { num: Some(1), limit: Some(5), count: Some(false), ..PageRequest::default() };
+        let (page, data) = repo.select(User::default(), page).await.unwrap();
+        assert_eq!((page.total, page.total_pages, data.len()), (None, None, 5));
+    }
+
+    async fn select_names(repo: &UserSQLiteRepository, sort_by: &str, order: Option<SortOrder>) -> Vec<String> {
+        let page = PageRequest { sort_by: Some(sort_by.to_string()), order, ..PageRequest::default() };
+        let (_, users) = repo.select(User::default(), page).await.unwrap();
+        users.into_iter().map(|u| u.name.unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_select_sorted_by_name_and_update_time() {
+        let repo = new_test_repo().await;
+        for (name, update_time) in [("bob", 3000), ("carol", 1000), ("alice", 2000)] {
+            let id = repo.insert(new_user(name, None)).await.unwrap();
+            sqlx::query("UPDATE users SET update_time = ? WHERE id = ?")
+                .bind(update_time)
+                .bind(id)
+                .execute(repo.inner.get_pool()).await
+                .unwrap();
+        }
+
+        assert_eq!(select_names(&repo, "name", Some(SortOrder::Asc)).await, vec!["alice", "bob", "carol"]);
+        assert_eq!(select_names(&repo, "name", Some(SortOrder::Desc)).await, vec!["carol", "bob", "alice"]);
+        assert_eq!(select_names(&repo, "update_time", Some(SortOrder::Asc)).await, vec!["carol", "alice", "bob"]);
+        assert_eq!(select_names(&repo, "update_time", None).await, vec!["bob", "alice", "carol"]);
+        let (_, users) = repo.select(User::default(), PageRequest::default()).await.unwrap();
+        let names = users.into_iter().map(|u| u.name.unwrap()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["bob", "alice", "carol"]);
+    }
+
+    #[tokio::test]
+    async fn test_select_rejects_unknown_sort_column() {
+        let repo = new_test_repo().await;
+        for (name, update_time) in [("alice", 1000), ("bob", 2000)] {
+            let id = repo.insert(new_user(name, None)).await.unwrap();
+            sqlx::query("UPDATE users SET update_time = ? WHERE id = ?")
+                .bind(update_time)
+                .bind(id)
+                .execute(repo.inner.get_pool()).await
+                .unwrap();
+        }
+
+        // The unknown column is never interpolated, but falls back to the default update_time desc.
+        let injected = "name; DROP TABLE users; --";
+        assert_eq!(select_names(&repo, injected, Some(SortOrder::Asc)).await, vec!["bob", "alice"]);
+        assert_eq!(select_names(&repo, "password", Some(SortOrder::Asc)).await, vec!["bob", "alice"]);
+        assert_eq!(repo.count_by(User::default(), &[]).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_select_by_cursor_without_gaps_or_duplicates() {
+        let repo = new_test_repo().await;
+        let users = (0..37).map(|i| new_user(&format!("user-{}", i), None)).collect::<Vec<_>>();
+        let ids = repo.insert_batch(users).await.unwrap();
+        // The ties of update_time are ordered by id, and the none update_time are the last.
+        for (i, id) in ids.iter().enumerate() {
+            sqlx::query("UPDATE users SET update_time = ? WHERE id = ?")
+                .bind(if i % 5 == 0 { None } else { Some(1_000 + ((i as i64) % 7)) })
+                .bind(id)
+                .execute(repo.inner.get_pool()).await
+                .unwrap();
+        }
+
+        let mut paged = Vec::new();
+        let mut cursor = Some(String::new());
+        while let Some(c) = cursor {
+            let page = PageRequest { num: None, limit: Some(5), cursor: Some(c), ..PageRequest::default() };
+            let (resp, data) = repo.select(User::default(), page).await.unwrap();
+            assert!(data.len() <= 5);
+            paged.extend(data.iter().map(|u| (u.base.update_time.unwrap_or(0), u.base.id.unwrap())));
+            // The concurrent inserts (of the latest update_time) while paging neither shift nor repeat the rows.
+            repo.insert(new_user("concurrent", None)).await.unwrap();
+            cursor = resp.next_cursor;
+        }
+        assert_eq!(paged.len(), 37);
+        assert!(paged.windows(2).all(|w| w[0] > w[1]), "not strictly descending: {:?}", paged);
+        let mut paged_ids = paged.iter().map(|(_, id)| *id).collect::<Vec<_>>();
+        paged_ids.sort();
+        let mut expected = ids.clone();
+        expected.sort();
+        assert_eq!(paged_ids, expected);
+
+        let cursor = Some("not-a-cursor".to_string());
+        let page = PageRequest { num: None, limit: Some(5), cursor, ..PageRequest::default() };
+        let err = repo.select(User::default(), page).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::InvalidCursor(_))), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn test_select_by_id_found_not_found_and_unavailable() {
+        let repo = new_test_repo().await;
+        let id = repo.insert(new_user("alice", None)).await.unwrap();
+        assert_eq!(repo.select_by_id(id).await.unwrap().unwrap().name, Some("alice".to_string()));
+
+        assert!(repo.select_by_id(999_999).await.unwrap().is_none());
+
+        repo.inner.get_pool().close().await;
+        let err = repo.select_by_id(id).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::StorageUnavailable(_))));
+    }
+
+    #[tokio::test]
+    async fn test_returns_err_instead_of_panicking_on_closed_pool() {
+        let repo = new_test_repo().await;
+        let id = repo.insert(new_user("alice", None)).await.unwrap();
+        repo.inner.get_pool().close().await;
+
+        assert!(repo.select(User::default(), PageRequest::default()).await.is_err());
+        assert!(repo.insert(new_user("bob", None)).await.is_err());
+        let mut user = new_user("carol", None);
+        user.base.id = Some(id);
+        assert!(repo.update(user).await.is_err());
+        assert!(repo.delete_by_id(id).await.is_err());
+        let err = repo.delete_all().await.unwrap_err();
+        assert!(err.to_string().contains("Failed to delete all users"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_after_database_file_restored() {
+        let config = new_test_config();
+        let repo = UserSQLiteRepository::new(&config).await.unwrap();
+        repo.insert(new_user("alice", None)).await.unwrap();
+
+        let dir = std::path::PathBuf::from(config.sqlite.dir.unwrap());
+        let db_file = dir.join("sqlite.db");
+        let backup = dir.join("sqlite.db.bak");
+        sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)").execute(repo.inner.get_pool()).await.unwrap();
+        std::fs::copy(&db_file, &backup).unwrap();
+
+        // The database is gone, the pooled connections still hold the removed file.
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(dir.join(format!("sqlite.db{}", suffix)));
+        }
+        assert!(repo.insert(new_user("bob", None)).await.is_err());
+
+        // The database comes back, and the repository recovers with the new connections.
+        std::fs::copy(&backup, &db_file).unwrap();
+        repo.insert(new_user("carol", None)).await.unwrap();
+        assert_eq!(repo.count_by(User::default(), &[]).await.unwrap(), 2);
+        assert_eq!(repo.count_by(new_user("bob", None), &[]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_schema_audit_fails_fast_on_drift() {
+        let config = new_test_config();
+        let repo = UserSQLiteRepository::new(&config).await.unwrap();
+        sqlx::query("ALTER TABLE users RENAME COLUMN lang TO language")
+            .execute(repo.inner.get_pool()).await
+            .unwrap();
+
+        let err = UserSQLiteRepository::new(&config).await.err().unwrap().to_string();
+        assert!(err.contains("Schema drift of table 'users'"), "{}", err);
+        assert!(err.contains(r#"missing columns: ["lang"]"#), "{}", err);
+
+        // Skippable by config.
+        let mut config = config;
+        config.schema_audit = Some(false);
+        assert!(UserSQLiteRepository::new(&config).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_schema_audit_allows_extra_columns() {
+        let config = new_test_config();
+        let repo = UserSQLiteRepository::new(&config).await.unwrap();
+        sqlx::query("ALTER TABLE users ADD COLUMN nickname TEXT")
+            .execute(repo.inner.get_pool()).await
+            .unwrap();
+
+        assert!(UserSQLiteRepository::new(&config).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_transactions_over_limit_time_out() {
+        let mut config = new_test_config();
+        config.max_transactions = Some(2);
+        config.transaction_acquire_timeout = Some(200);
+        let repo = UserSQLiteRepository::new(&config).await.unwrap();
+
+        let mut tx1 = repo.inner.begin().await.unwrap();
+        let mut tx2 = repo.inner.begin().await.unwrap();
+        let err = repo.inner.begin().await.err().unwrap();
+        assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::StorageUnavailable(_))), "{}", err);
+        // The bulk save is in transaction too.
+        assert!(repo.save_all(vec![new_user("dave", None)]).await.is_err());
+
+        // The earlier transactions are not affected.
+        sqlx::query("INSERT INTO users (name) VALUES ('alice')").execute(&mut *tx1.tx).await.unwrap();
+        tx1.commit().await.unwrap();
+        sqlx::query("INSERT INTO users (name) VALUES ('bob')").execute(&mut *tx2.tx).await.unwrap();
+        tx2.commit().await.unwrap();
+        assert_eq!(repo.count_by(User::default(), &[]).await.unwrap(), 2);
+
+        // The permits are released after committed.
+        repo.save_all(vec![new_user("carol", None), new_user("dave", None)]).await.unwrap();
+        assert_eq!(repo.count_by(User::default(), &[]).await.unwrap(), 4);
+    }
 }
@@ -52,6 +52,7 @@ impl QueryUserApiV1Request {
             google_claims_sub: None,
             google_claims_name: None,
             google_claims_email: None,
+            verified_email: None,
             ethers_address: None,
             lang: None,
         }
@@ -66,7 +67,7 @@ pub struct QueryUserApiV1Response {
 
 impl QueryUserApiV1Response {
     pub fn new(page: PageResponse, data: Vec<User>) -> Self {
-        QueryUserApiV1Response { page: Some(page), data: Some(data) }
+        QueryUserApiV1Response { page: Some(page), data: Some(data.into_iter().map(User::redacted).collect()) }
     }
 }
 
@@ -123,6 +124,7 @@ impl SaveUserApiV1Request {
             google_claims_sub: self.google_claims_sub.clone(),
             google_claims_name: self.google_claims_name.clone(),
             google_claims_email: self.google_claims_email.clone(),
+            verified_email: None,
             ethers_address: self.ethers_address.clone(),
             lang: self.lang.clone(),
         }
@@ -0,0 +1,104 @@
+/*
+ * SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+ *
+ * Copyleft (c) 2024 James Wong. This file is part of James Wong.
+ * is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * James Wong is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * IMPORTANT: Any software that fully or partially contains or uses materials
+ * covered by this license must also be released under the GNU GPL license.
+ * This includes modifications and derived works.
+ */
+
+use sqlx::{ FromRow, sqlite::SqliteRow, Row };
+use serde::{ Deserialize, Serialize };
+use validator::Validate;
+
+use super::{ BaseBean, PageResponse };
+
+pub const AUDIT_EVENT_LOGIN: &str = "login";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
+pub struct AuditLog {
+    #[serde(flatten)]
+    pub base: BaseBean,
+    pub uid: Option<i64>,
+    pub event_type: Option<String>,
+    pub detail: Option<String>,
+    pub client_ip: Option<String>,
+    // The range of create_time (millis) for the query only, both inclusive and not persisted.
+    #[serde(skip)]
+    pub start_time: Option<i64>,
+    #[serde(skip)]
+    pub end_time: Option<i64>,
+}
+
+impl AuditLog {
+    pub fn new(uid: Option<i64>, event_type: Option<String>, detail: Option<String>, client_ip: Option<String>) -> Self {
+        AuditLog {
+            base: BaseBean::new(None, None, None),
+            uid,
+            event_type,
+            detail,
+            client_ip,
+            start_time: None,
+            end_time: None,
+        }
+    }
+}
+
+impl<'r> FromRow<'r, SqliteRow> for AuditLog {
+    fn from_row(row: &'r SqliteRow) -> Result<Self, sqlx::Error> {
+        Ok(AuditLog {
+            base: BaseBean::from_row(row).unwrap(),
+            uid: row.try_get("uid")?,
+            event_type: row.try_get("event_type")?,
+            detail: row.try_get("detail")?,
+            client_ip: row.try_get("client_ip")?,
+            start_time: None,
+            end_time: None,
+        })
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Validate, utoipa::ToSchema, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct QueryAuditLogsRequest {
+    pub uid: Option<i64>,
+    #[validate(length(min = 1, max = 32))]
+    pub event_type: Option<String>,
+    // The range of create time in millis, both inclusive.
+    pub start_time: Option<i64>,
+    pub end_time: Option<i64>,
+}
+
+impl QueryAuditLogsRequest {
+    pub fn to_audit_log(&self) -> AuditLog {
+        let mut audit_log = AuditLog::new(self.uid, self.event_type.clone(), None, None);
+        audit_log.start_time = self.start_time;
+        audit_log.end_time = self.end_time;
+        audit_log
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
+pub struct QueryAuditLogsResponse {
+    pub page: Option<PageResponse>,
+    pub data: Option<Vec<AuditLog>>,
+}
+
+impl QueryAuditLogsResponse {
+    pub fn new(page: PageResponse, data: Vec<AuditLog>) -> Self {
+        QueryAuditLogsResponse { page: Some(page), data: Some(data) }
+    }
+}
@@ -23,6 +23,8 @@
 use serde::{ Deserialize, Serialize };
 use validator::Validate;
 
+use crate::utils::auths::AuthUserClaims;
+
 // ----- Password login types. -----
 
 #[derive(Deserialize, Clone, Debug, Validate, utoipa::ToSchema, utoipa::IntoParams)]
@@ -46,20 +48,66 @@ pub struct PasswordLoginRequest {
     //pub seccode: Option<String>, // TODO: SMS/Email security code.
 }
 
-// ----- OIDC login types. ------
+// Notice: Not derived the Debug, so that the plaintext password would never be logged.
+#[derive(Deserialize, Clone, Validate, utoipa::ToSchema)]
+pub struct EmailLoginRequest {
+    #[validate(email, length(max = 256))]
+    pub email: String,
+    #[validate(length(min = 1, max = 1024))]
+    pub password: String,
+}
+
+// ----- OAuth2 callback types. ------
 
-#[derive(Deserialize, Clone, Debug, utoipa::ToSchema)]
-pub struct CallbackOidcRequest {
+// The query parameters of the OIDC/Github callback, either the 'code' or the 'error' is returned by
+// the provider, see: https://datatracker.ietf.org/doc/html/rfc6749#section-4.1.2
+#[derive(Deserialize, Clone, Debug, Default, utoipa::ToSchema, utoipa::IntoParams)]
+pub struct OAuthCallbackParams {
     pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
 }
 
-// ----- Github OAuth2 login types. -----
+#[derive(Clone, Debug, PartialEq)]
+pub enum OAuthCallbackError {
+    // The user declined the authorization on the provider.
+    AccessDenied,
+    // The other errors returned by the provider, e.g. 'invalid_scope'.
+    Provider(String),
+    MissingCode,
+}
 
-#[derive(Deserialize, Clone, Debug, utoipa::ToSchema)]
-pub struct CallbackGithubRequest {
-    pub code: Option<String>,
+impl OAuthCallbackParams {
+    // Returns the authorization code, the provider returned error takes precedence over the code.
+    pub fn validate_code(&self) -> Result<String, OAuthCallbackError> {
+        match (&self.error, &self.code) {
+            (Some(error), _) if error == "access_denied" => Err(OAuthCallbackError::AccessDenied),
+            (Some(error), _) =>
+                Err(
+                    OAuthCallbackError::Provider(match &self.error_description {
+                        Some(description) => format!("{}: {}", error, description),
+                        None => error.to_owned(),
+                    })
+                ),
+            (None, Some(code)) if !code.trim().is_empty() => Ok(code.to_owned()),
+            (None, _) => Err(OAuthCallbackError::MissingCode),
+        }
+    }
 }
 
+impl std::fmt::Display for OAuthCallbackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OAuthCallbackError::AccessDenied => write!(f, "Authorization declined on the provider"),
+            OAuthCallbackError::Provider(error) => write!(f, "Authorization failed on the provider ({})", error),
+            OAuthCallbackError::MissingCode => write!(f, "Missing authentication code"),
+        }
+    }
+}
+
+// ----- Github OAuth2 login types. -----
+
 /*
 curl -L \
 -H "Accept: application/vnd.github+json" \
@@ -181,6 +229,21 @@ impl GithubUserInfo {
     }
 }
 
+// ----- Google OAuth2 login types. -----
+
+// see:https://developers.google.com/identity/openid-connect/openid-connect#obtainuserinfo
+#[derive(Deserialize, Clone, Debug, Default, utoipa::ToSchema)]
+pub struct GoogleUserInfo {
+    pub sub: Option<String>,
+    pub name: Option<String>,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    pub picture: Option<String>,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub locale: Option<String>,
+}
+
 // ----- Wallet login types. -----
 
 #[derive(Deserialize, Clone, Debug, utoipa::ToSchema)]
@@ -220,3 +283,63 @@ pub struct LogoutRequest {
     pub access_token: Option<String>,
     pub refresh_token: Option<String>,
 }
+
+#[derive(Deserialize, Clone, Debug, utoipa::ToSchema, utoipa::IntoParams)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: Option<String>,
+}
+
+// ----- Debug types. -----
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct DebugQuery {
+    // Whether to pretty print the response, only honored in the dev profile.
+    pub pretty: Option<bool>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DebugWhoAmIResponse {
+    // The decoded claims of the request token, and the values of 'ext' are redacted.
+    pub claims: Option<AuthUserClaims>,
+    pub expired: bool,
+    pub blacklisted: bool,
+    pub error: Option<String>,
+}
+
+// ----- Token validation types. -----
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenInvalidReason {
+    Missing,
+    Oversized,
+    Malformed,
+    Expired,
+    Revoked,
+    // The refresh token is presented as the access token, which is only exchanged by the refresh endpoint.
+    RefreshToken,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
+pub struct ValidateTokenResponse {
+    pub valid: bool,
+    // The remaining seconds before the token expired, only for the valid token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_in: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<TokenInvalidReason>,
+}
+
+// ----- External authorization types. -----
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExtAuthzRequest {
+    pub method: String,
+    pub path: String,
+    pub user: Option<AuthUserClaims>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExtAuthzResponse {
+    pub allow: bool,
+}
@@ -0,0 +1,67 @@
+/*
+ * SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+ *
+ * Copyleft (c) 2024 James Wong. This file is part of James Wong.
+ * is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * James Wong is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * IMPORTANT: Any software that fully or partially contains or uses materials
+ * covered by this license must also be released under the GNU GPL license.
+ * This includes modifications and derived works.
+ */
+
+use serde::Serialize;
+
+use crate::config::config_serve::{ RunProfile, WebServeConfig, DEFAULT_DB_PURGE_DELETED_AFTER_DAYS };
+use super::ApiVersion;
+
+// The effective feature flags exposed to the clients (e.g. to hide the disabled login buttons).
+// Notice: Only the explicitly allowlisted non-secret flags are added here, never the config itself.
+#[derive(Serialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
+pub struct FeatureFlags {
+    pub dev_profile: bool,
+    pub login_providers: LoginProviderFlags,
+    #[schema(value_type = String, example = "v1")]
+    pub api_version: ApiVersion,
+    pub swagger: bool,
+    pub otel: bool,
+    // Whether the soft-deleted documents (in trash) are purged automatically.
+    pub trash_auto_purge: bool,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
+pub struct LoginProviderFlags {
+    pub password: bool,
+    pub oidc: bool,
+    pub github: bool,
+    pub ethers: bool,
+}
+
+impl FeatureFlags {
+    pub fn from_config(config: &WebServeConfig) -> Self {
+        FeatureFlags {
+            dev_profile: config.profile == RunProfile::Dev,
+            login_providers: LoginProviderFlags {
+                // The password and wallet logins are always enabled for now.
+                password: true,
+                oidc: config.auth.oidc.enabled.unwrap_or(false),
+                github: config.auth.github.enabled.unwrap_or(false),
+                ethers: true,
+            },
+            api_version: config.server.api_version,
+            swagger: config.swagger.enabled,
+            otel: config.mgmt.enabled && config.mgmt.otel.enabled,
+            trash_auto_purge: config.db.purge_deleted_after_days.unwrap_or(DEFAULT_DB_PURGE_DELETED_AFTER_DAYS) > 0,
+        }
+    }
+}
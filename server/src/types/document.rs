@@ -113,6 +113,36 @@ impl QueryDocumentResponse {
     }
 }
 
+// The soft-deleted document in the trash, the deletion time is the last update time.
+#[derive(Serialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
+pub struct DeletedDocument {
+    #[serde(flatten)]
+    pub document: Document,
+    pub delete_time: Option<i64>,
+}
+
+impl From<Document> for DeletedDocument {
+    fn from(document: Document) -> Self {
+        let delete_time = document.base.update_time;
+        DeletedDocument { document, delete_time }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
+pub struct QueryDeletedDocumentResponse {
+    pub page: Option<PageResponse>,
+    pub data: Option<Vec<DeletedDocument>>,
+}
+
+impl QueryDeletedDocumentResponse {
+    pub fn new(page: PageResponse, data: Vec<Document>) -> Self {
+        QueryDeletedDocumentResponse {
+            page: Some(page),
+            data: Some(data.into_iter().map(DeletedDocument::from).collect()),
+        }
+    }
+}
+
 #[derive(Deserialize, Clone, Debug, PartialEq, Validate, utoipa::ToSchema)]
 pub struct SaveDocumentRequest {
     pub id: Option<i64>,
@@ -26,20 +26,34 @@ pub mod user;
 pub mod document;
 pub mod folder;
 pub mod settings;
+pub mod audit;
+pub mod config;
 pub mod browser_indexeddb;
 
 use anyhow::Error;
+use base64::Engine;
 use hyper::StatusCode;
 use serde::{ Deserialize, Serialize };
-use chrono::Utc;
 use sqlx::prelude::FromRow;
 use validator::Validate;
 
-use crate::utils::{ auths::SecurityContext, snowflake::SnowflakeIdGenerator };
+use crate::mgmt::apm::otel::record_error_chain;
+use crate::utils::{ auths::SecurityContext, snowflake::SnowflakeIdGenerator, times };
 // use sqlx::{ Decode, FromRow };
 
 pub static DEFAULT_BY: &'static str = "0";
 
+// The fields of BaseBean, which are maintained automatically and not the biz changes.
+pub const BASE_BEAN_FIELDS: [&str; 7] = [
+    "id",
+    "status",
+    "create_by",
+    "create_time",
+    "update_by",
+    "update_time",
+    "del_flag",
+];
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, FromRow, utoipa::ToSchema)]
 pub struct BaseBean {
     #[schema(rename = "id")]
@@ -70,7 +84,7 @@ impl BaseBean {
     }
 
     pub fn new(id: Option<i64>, create_by: Option<String>, update_by: Option<String>) -> Self {
-        let now = Utc::now().timestamp_millis();
+        let now = times::now_millis();
         Self {
             id,
             status: Some(0),
@@ -82,17 +96,34 @@ impl BaseBean {
         }
     }
 
+    // Decode the base fields of the postgres row, of which the integer columns are all bigint except
+    // the del_flag, see: migrations_postgres/
+    #[cfg(feature = "postgres")]
+    pub fn from_pg_row(row: &sqlx::postgres::PgRow) -> Result<Self, sqlx::Error> {
+        use sqlx::Row;
+        Ok(Self {
+            id: row.try_get("id")?,
+            status: row.try_get::<Option<i64>, _>("status")?.map(|v| v as i8),
+            create_by: row.try_get("create_by")?,
+            create_time: row.try_get("create_time")?,
+            update_by: row.try_get("update_by")?,
+            update_time: row.try_get("update_time")?,
+            del_flag: row.try_get("del_flag")?,
+        })
+    }
+
     pub async fn pre_insert(&mut self, create_by: Option<String>) -> i64 {
         let by = create_by
             .or(SecurityContext::get_instance().get_current_email().await)
             .or(SecurityContext::get_instance().get_current_uname().await)
             .or(Some(DEFAULT_BY.to_string()));
 
-        self.id = Some(SnowflakeIdGenerator::default_next_jssafe());
+        let id = SnowflakeIdGenerator::default_next_jssafe();
+        self.id = Some(id);
         self.create_by = by;
-        self.create_time = Some(Utc::now().timestamp_millis());
+        self.create_time = Some(times::now_millis());
         self.del_flag = Some(0);
-        self.id.unwrap()
+        id
     }
 
     pub async fn pre_update(&mut self, update_by: Option<String>) {
@@ -102,7 +133,7 @@ impl BaseBean {
             .or(Some(DEFAULT_BY.to_string()));
 
         self.update_by = by;
-        self.update_time = Some(Utc::now().timestamp_millis());
+        self.update_time = Some(times::now_millis());
         self.del_flag = Some(0);
     }
 }
@@ -115,6 +146,18 @@ pub struct PageRequest {
     #[schema(example = "10")]
     #[validate(range(min = 1, max = 1000))]
     pub limit: Option<u32>, // The per page records count.
+    // The opaque cursor of the keyset pagination by (update_time, id) descending instead of the offset, the
+    // empty is the first page and the next is the 'next_cursor' of the previous page. (SQLite only)
+    pub cursor: Option<String>,
+    // Whether to count the total rows matched, default true, disable it for the cheaper query if the total
+    // is not needed, e.g. the infinite scrolling. (SQLite and Postgres)
+    pub count: Option<bool>,
+    // The column to sort by, which must be one of the sortable columns of the repository, otherwise falls back
+    // to the default order, i.e. never interpolated into the query. (SQLite users only, ignored in cursor mode)
+    #[schema(example = "update_time")]
+    pub sort_by: Option<String>,
+    // The sort direction of the 'sort_by' column, default desc.
+    pub order: Option<SortOrder>,
     // For large data of fast-queries cached condition acceleration.
     // pub cached_forward_last_min_id: Option<i64>,
     // pub cached_backend_last_max_id: Option<i64>,
@@ -125,10 +168,57 @@ impl PageRequest {
         PageRequest {
             num: Some(1),
             limit: Some(10),
+            cursor: None,
+            count: None,
+            sort_by: None,
+            order: None,
             // cached_forward_last_min_id: None,
             // cached_backend_last_max_id: None,
         }
     }
+
+    pub fn is_count(&self) -> bool {
+        self.count.unwrap_or(true)
+    }
+
+    // Resolve the 'ORDER BY' clause of the sort column and direction, the unset or not allowed column falls
+    // back to the default column desc, and the ties are ordered by id in the same direction for stable paging.
+    pub fn get_order_by(&self, allowed_columns: &[&str], default_column: &str) -> String {
+        let (column, order) = match self.sort_by.as_deref() {
+            Some(column) if allowed_columns.contains(&column) => (column, self.order.unwrap_or(SortOrder::Desc)),
+            // The order of the not allowed column is ignored as well, i.e. the default order as a whole.
+            Some(column) => {
+                tracing::warn!("Ignored the not allowed sort column: {:?}, fallback to: {}", column, default_column);
+                (default_column, SortOrder::Desc)
+            }
+            None => (default_column, self.order.unwrap_or(SortOrder::Desc)),
+        };
+        let order = match order {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        };
+        format!("{} {}, id {}", column, order, order)
+    }
+
+    // Decode the cursor to the (update_time, id) of the last row of the previous page, none if the first page.
+    pub fn decode_cursor(&self) -> Result<Option<(i64, i64)>, Error> {
+        let cursor = match self.cursor.as_deref() {
+            Some(cursor) if !cursor.is_empty() => cursor,
+            _ => {
+                return Ok(None);
+            }
+        };
+        let decoded = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok());
+        let keys = decoded.as_deref().and_then(|d| d.split_once(':'));
+        match keys.map(|(update_time, id)| (update_time.parse::<i64>(), id.parse::<i64>())) {
+            Some((Ok(update_time), Ok(id))) => Ok(Some((update_time, id))),
+            _ => Err(anyhow::anyhow!("Invalid page cursor: {}", cursor)),
+        }
+    }
+
     pub fn get_offset(&self) -> u32 {
         let n = self.num.unwrap_or(1);
         if n < 1 {
@@ -148,11 +238,23 @@ impl PageRequest {
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
 pub struct PageResponse {
     pub total: Option<i64>, // The current conditions snapshot data of total records count.
     pub num: Option<u32>, // page number.
     pub limit: Option<u32>, // The per page records count.
+    // The count of pages derived from the total and limit, none if the total is not counted.
+    pub total_pages: Option<i64>,
+    // The cursor of the next page of the keyset pagination, none if the last page.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
     // For large data of fast-queries cached condition acceleration.
     // pub cached_forward_last_min_id: Option<i64>,
     // pub cached_backend_last_max_id: Option<i64>,
@@ -160,12 +262,56 @@ pub struct PageResponse {
 
 impl PageResponse {
     pub fn new(total: Option<i64>, num: Option<u32>, limit: Option<u32>) -> Self {
+        let total_pages = total.zip(limit).map(|(total, limit)| {
+            let limit = (limit as i64).max(1);
+            (total.max(0) + limit - 1) / limit
+        });
         Self {
             total: total,
             num: num,
             limit,
+            total_pages,
+            next_cursor: None,
         }
     }
+
+    pub fn encode_cursor(update_time: i64, id: i64) -> String {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(format!("{}:{}", update_time, id))
+    }
+}
+
+// The outcome of the mutation, so that the clients can tell "created" from "updated" or "nothing changed".
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
+pub struct OperationOutcome {
+    pub action: OperationAction,
+    pub id: Option<i64>,
+    pub affected: u64, // The affected records count.
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, utoipa::ToSchema)]
+pub enum OperationAction {
+    Created,
+    Updated,
+    Deleted,
+    NoOp,
+}
+
+impl OperationOutcome {
+    pub fn created(id: i64) -> Self {
+        Self { action: OperationAction::Created, id: Some(id), affected: 1 }
+    }
+
+    pub fn updated(id: i64) -> Self {
+        Self { action: OperationAction::Updated, id: Some(id), affected: 1 }
+    }
+
+    pub fn deleted(id: i64, affected: u64) -> Self {
+        Self { action: OperationAction::Deleted, id: Some(id), affected }
+    }
+
+    pub fn noop(id: Option<i64>) -> Self {
+        Self { action: OperationAction::NoOp, id, affected: 0 }
+    }
 }
 
 #[derive(Serialize, Clone, Debug, PartialEq, Validate, utoipa::ToSchema, utoipa::IntoParams)]
@@ -182,9 +328,10 @@ impl RespBase {
         }
     }
 
-    pub(crate) fn error(e: Error) -> Self {
+    pub(crate) fn error(status: StatusCode, e: Error) -> Self {
+        record_error_chain(status, &e);
         Self {
-            errcode: Some(StatusCode::INTERNAL_SERVER_ERROR.as_u16() as i8),
+            errcode: Some(status.as_u16() as i8),
             errmsg: Some(e.to_string()),
         }
     }
@@ -198,6 +345,123 @@ impl RespBase {
     }
 
     pub(crate) fn to_json(&self) -> String {
-        serde_json::to_string(&self).unwrap()
+        to_enveloped_json(&self)
+    }
+}
+
+// ----- Response envelope versioning. -----
+
+pub const ACCEPT_VERSION_HEADER: &str = "Accept-Version";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum ApiVersion {
+    // The original shape: {"errcode": 0, "errmsg": "ok"}
+    #[default]
+    #[serde(rename = "v1")]
+    V1,
+    // The nested shape: {"error": null} or {"error": {"code": 500, "message": "..."}}
+    #[serde(rename = "v2")]
+    V2,
+}
+
+tokio::task_local! {
+    // The envelope version of the current request, scoped by the envelope version middleware.
+    pub static API_VERSION: ApiVersion;
+}
+
+impl ApiVersion {
+    // Parse the Accept-Version header value, e.g: 'v2', 'V2', '2'
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim().to_lowercase();
+        match value.strip_prefix('v').unwrap_or(&value) {
+            "1" => Some(ApiVersion::V1),
+            "2" => Some(ApiVersion::V2),
+            _ => None,
+        }
+    }
+
+    // The version of the current request, or the default v1 when outside of the request scope.
+    pub fn current() -> Self {
+        API_VERSION.try_with(|v| *v).unwrap_or_default()
+    }
+}
+
+// ----- Request correlation. -----
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RequestCorrelation {
+    pub request_id: String,
+    // Whether the ids are exposed in the error responses, the logs always carry them.
+    pub expose: bool,
+}
+
+tokio::task_local! {
+    // The correlation of the current request, scoped by the request id middleware.
+    pub static REQUEST_CORRELATION: RequestCorrelation;
+}
+
+impl RequestCorrelation {
+    // The correlation of the current request, or none when outside of the request scope.
+    pub fn current() -> Option<Self> {
+        REQUEST_CORRELATION.try_with(|c| c.clone()).ok()
+    }
+}
+
+// Build the error fields of the response envelope, all envelopes should be constructed here so that
+// the versions stay consistent.
+pub fn build_envelope(version: ApiVersion, errcode: i64, errmsg: &str) -> serde_json::Map<String, serde_json::Value> {
+    let mut envelope = serde_json::Map::new();
+    match version {
+        ApiVersion::V1 => {
+            envelope.insert("errcode".to_string(), errcode.into());
+            envelope.insert("errmsg".to_string(), errmsg.into());
+        }
+        ApiVersion::V2 => {
+            let error = if errcode == 0 {
+                serde_json::Value::Null
+            } else {
+                serde_json::json!({ "code": errcode, "message": errmsg })
+            };
+            envelope.insert("error".to_string(), error);
+        }
+    }
+    envelope
+}
+
+// Attach the correlation ids to the error envelope, at the top level of v1 or into the 'error' of v2.
+pub fn attach_correlation_ids(
+    envelope: &mut serde_json::Map<String, serde_json::Value>,
+    version: ApiVersion,
+    request_id: &str,
+    trace_id: Option<&str>
+) {
+    let target = match version {
+        ApiVersion::V1 => Some(envelope),
+        ApiVersion::V2 => envelope.get_mut("error").and_then(|e| e.as_object_mut()),
+    };
+    if let Some(target) = target {
+        target.insert("request_id".to_string(), request_id.into());
+        if let Some(trace_id) = trace_id {
+            target.insert("trace_id".to_string(), trace_id.into());
+        }
+    }
+}
+
+// Serialize the response that has the 'errcode' and 'errmsg' fields into the envelope of the current
+// request version, the other fields are kept as is.
+pub fn to_enveloped_json<T: Serialize>(body: &T) -> String {
+    let version = ApiVersion::current();
+    if version == ApiVersion::V1 {
+        return serde_json::to_string(body).unwrap();
+    }
+    let mut value = serde_json::to_value(body).unwrap();
+    if let Some(obj) = value.as_object_mut() {
+        let errcode = obj.remove("errcode").and_then(|v| v.as_i64()).unwrap_or_default();
+        let errmsg = obj.remove("errmsg");
+        let errmsg = errmsg.as_ref().and_then(|v| v.as_str()).unwrap_or_default();
+        obj.extend(build_envelope(version, errcode, errmsg));
     }
+    value.to_string()
 }
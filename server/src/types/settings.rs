@@ -21,16 +21,39 @@
  */
 
 use sqlx::{ FromRow, sqlite::SqliteRow, Row };
+use std::collections::BTreeMap;
 use serde::{ Deserialize, Serialize };
 use validator::Validate;
 
-use super::{ BaseBean, PageResponse };
+use super::{ BaseBean, OperationOutcome, PageResponse };
+
+pub const SETTINGS_SCOPE_GLOBAL: &str = "global";
+pub const SETTINGS_SCOPE_GROUP: &str = "group";
+pub const SETTINGS_SCOPE_USER: &str = "user";
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
 pub struct Settings {
     #[serde(flatten)]
     pub base: BaseBean,
     pub name: Option<String>,
+    // The layer of settings, e.g. 'global', 'group' or 'user'.
+    pub scope: Option<String>,
+    // The group id or user id of the layer, the global layer is none.
+    pub owner: Option<String>,
+    // The JSON object of the setting items.
+    pub value: Option<String>,
+}
+
+impl Settings {
+    pub fn new(name: Option<String>, scope: Option<String>, owner: Option<String>) -> Self {
+        Settings {
+            base: BaseBean::new(None, None, None),
+            name,
+            scope,
+            owner,
+            value: None,
+        }
+    }
 }
 
 impl<'r> FromRow<'r, SqliteRow> for Settings {
@@ -38,6 +61,9 @@ impl<'r> FromRow<'r, SqliteRow> for Settings {
         Ok(Settings {
             base: BaseBean::from_row(row).unwrap(),
             name: row.try_get("name")?,
+            scope: row.try_get("scope")?,
+            owner: row.try_get("owner")?,
+            value: row.try_get("value")?,
         })
     }
 }
@@ -47,18 +73,17 @@ impl<'r> FromRow<'r, SqliteRow> for Settings {
 pub struct QuerySettingsRequest {
     #[validate(length(min = 1, max = 64))]
     pub name: Option<String>,
+    // Whether to return only the user layer instead of the effective settings resolved by name.
+    pub user_only: Option<bool>,
 }
 
 impl QuerySettingsRequest {
     pub fn to_settings(&self) -> Settings {
-        Settings {
-            base: BaseBean::new(None, None, None),
-            name: Some(self.name.clone().unwrap_or_default()),
-        }
+        Settings::new(Some(self.name.clone().unwrap_or_default()), None, None)
     }
 }
 
-#[derive(Serialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
 pub struct QuerySettingsResponse {
     pub page: Option<PageResponse>,
     pub data: Option<Vec<Settings>>,
@@ -70,11 +95,17 @@ impl QuerySettingsResponse {
     }
 }
 
-#[derive(Deserialize, Clone, Debug, PartialEq, Validate, utoipa::ToSchema)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Validate, utoipa::ToSchema)]
 pub struct SaveSettingsRequest {
     pub id: Option<i64>,
     #[validate(length(min = 1, max = 64))]
     pub name: Option<String>,
+    #[validate(length(min = 1, max = 16))]
+    pub scope: Option<String>,
+    #[validate(length(min = 1, max = 64))]
+    pub owner: Option<String>,
+    #[validate(length(min = 1, max = 65535))]
+    pub value: Option<String>,
 }
 
 impl SaveSettingsRequest {
@@ -82,21 +113,61 @@ impl SaveSettingsRequest {
         Settings {
             base: BaseBean::new_default(self.id),
             name: self.name.clone(),
+            scope: self.scope.clone(),
+            owner: self.owner.clone(),
+            value: self.value.clone(),
         }
     }
 }
 
 #[derive(Serialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
 pub struct SaveSettingsResponse {
-    pub id: i64,
+    #[serde(flatten)]
+    pub outcome: OperationOutcome,
 }
 
 impl SaveSettingsResponse {
-    pub fn new(id: i64) -> Self {
-        SaveSettingsResponse { id }
+    pub fn new(outcome: OperationOutcome) -> Self {
+        SaveSettingsResponse { outcome }
     }
 }
 
+#[derive(Deserialize, Clone, Debug, PartialEq, Validate, utoipa::ToSchema)]
+pub struct ImportSettingsRequest {
+    // The items are validated concurrently by the handler, and imported all or nothing.
+    #[validate(length(min = 1))]
+    pub items: Vec<SaveSettingsRequest>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
+pub struct ImportSettingsResponse {
+    pub total: u64,
+    pub created: u64,
+    pub updated: u64,
+    // The items are unchanged, or the ids are not found.
+    pub unchanged: u64,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Validate, utoipa::ToSchema)]
+pub struct DiffSettingsRequest {
+    #[validate(length(min = 1, max = 16))]
+    pub scope: Option<String>,
+    #[validate(length(min = 1, max = 64))]
+    pub owner: Option<String>,
+    // The values by the names of the layer, either the changed only or the full set, and only the
+    // changed against the stored are written.
+    #[validate(length(min = 1))]
+    pub items: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
+pub struct DiffSettingsResponse {
+    pub total: u64,
+    pub created: u64,
+    pub updated: u64,
+    pub unchanged: u64,
+}
+
 #[derive(Deserialize, Clone, Debug, PartialEq, Validate, utoipa::ToSchema)]
 pub struct DeleteSettingsRequest {
     pub id: i64,
@@ -105,10 +176,12 @@ pub struct DeleteSettingsRequest {
 #[derive(Serialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
 pub struct DeleteSettingsResponse {
     pub count: u64,
+    #[serde(flatten)]
+    pub outcome: OperationOutcome,
 }
 
 impl DeleteSettingsResponse {
-    pub fn new(count: u64) -> Self {
-        DeleteSettingsResponse { count }
+    pub fn new(outcome: OperationOutcome) -> Self {
+        DeleteSettingsResponse { count: outcome.affected, outcome }
     }
 }
@@ -26,7 +26,12 @@ use sqlx::{ FromRow, sqlite::SqliteRow, Row };
 use serde::{ Deserialize, Serialize };
 use validator::Validate;
 
-use super::{ BaseBean, PageResponse };
+use crate::config::config_serve::ErasureStrategy;
+
+use super::{ audit::AuditLog, settings::Settings, BaseBean, OperationOutcome, PageResponse };
+
+// The tombstone of the scrubbed personal data of the erased user.
+pub const ERASED_TOMBSTONE: &str = "[erased]";
 
 // Manual impl for decode.
 // #[derive(Serialize, Deserialize, Clone, Debug, sqlx::sqlite::FromRow, sqlx::sqlite::Decode)]
@@ -47,6 +52,8 @@ pub struct User {
     pub google_claims_sub: Option<String>,
     pub google_claims_name: Option<String>,
     pub google_claims_email: Option<String>,
+    // The email verified by the login provider, only which is trusted to link the other providers by email.
+    pub verified_email: Option<String>,
     pub ethers_address: Option<String>,
     pub lang: Option<String>,
 }
@@ -68,12 +75,20 @@ impl Default for User {
             google_claims_sub: None,
             google_claims_name: None,
             google_claims_email: None,
+            verified_email: None,
             ethers_address: None,
             lang: None,
         }
     }
 }
 
+impl User {
+    // Redact the internal-only fields that should never leave the server, e.g. the password hash.
+    pub fn redacted(self) -> Self {
+        User { password: None, ..self }
+    }
+}
+
 impl<'r> FromRow<'r, SqliteRow> for User {
     fn from_row(row: &'r SqliteRow) -> Result<Self, sqlx::Error> {
         Ok(User {
@@ -91,6 +106,32 @@ impl<'r> FromRow<'r, SqliteRow> for User {
             google_claims_sub: row.try_get("google_claims_sub")?,
             google_claims_name: row.try_get("google_claims_name")?,
             google_claims_email: row.try_get("google_claims_email")?,
+            verified_email: row.try_get("verified_email")?,
+            ethers_address: row.try_get("ethers_address")?,
+            lang: row.try_get("lang")?,
+        })
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl<'r> FromRow<'r, sqlx::postgres::PgRow> for User {
+    fn from_row(row: &'r sqlx::postgres::PgRow) -> Result<Self, sqlx::Error> {
+        Ok(User {
+            base: BaseBean::from_pg_row(row)?,
+            name: row.try_get("name")?,
+            email: row.try_get("email")?,
+            phone: row.try_get("phone")?,
+            password: row.try_get("password")?,
+            oidc_claims_sub: row.try_get("oidc_claims_sub")?,
+            oidc_claims_name: row.try_get("oidc_claims_name")?,
+            oidc_claims_email: row.try_get("oidc_claims_email")?,
+            github_claims_sub: row.try_get("github_claims_sub")?,
+            github_claims_name: row.try_get("github_claims_name")?,
+            github_claims_email: row.try_get("github_claims_email")?,
+            google_claims_sub: row.try_get("google_claims_sub")?,
+            google_claims_name: row.try_get("google_claims_name")?,
+            google_claims_email: row.try_get("google_claims_email")?,
+            verified_email: row.try_get("verified_email")?,
             ethers_address: row.try_get("ethers_address")?,
             lang: row.try_get("lang")?,
         })
@@ -161,6 +202,7 @@ impl QueryUserRequest {
             google_claims_sub: None,
             google_claims_name: None,
             google_claims_email: None,
+            verified_email: None,
             ethers_address: None,
             lang: None,
         }
@@ -175,7 +217,7 @@ pub struct QueryUserResponse {
 
 impl QueryUserResponse {
     pub fn new(page: PageResponse, data: Vec<User>) -> Self {
-        QueryUserResponse { page: Some(page), data: Some(data) }
+        QueryUserResponse { page: Some(page), data: Some(data.into_iter().map(User::redacted).collect()) }
     }
 }
 
@@ -183,6 +225,7 @@ impl QueryUserResponse {
     Deserialize,
     Clone,
     Debug,
+    Default,
     PartialEq,
     Validate,
     utoipa::ToSchema,
@@ -220,6 +263,10 @@ pub struct SaveUserRequest {
     pub google_claims_name: Option<String>,
     #[validate(length(min = 1, max = 64))]
     pub google_claims_email: Option<String>,
+    // Only set by the verified provider logins, never by the clients.
+    #[serde(skip_deserializing)]
+    #[schema(read_only)]
+    pub verified_email: Option<String>,
     #[validate(length(min = 1, max = 64))]
     pub ethers_address: Option<String>,
     #[validate(length(min = 1, max = 64))]
@@ -243,6 +290,7 @@ impl SaveUserRequest {
             google_claims_sub: self.google_claims_sub.clone(),
             google_claims_name: self.google_claims_name.clone(),
             google_claims_email: self.google_claims_email.clone(),
+            verified_email: self.verified_email.clone(),
             ethers_address: self.ethers_address.clone(),
             lang: self.lang.clone(),
         }
@@ -251,12 +299,13 @@ impl SaveUserRequest {
 
 #[derive(Serialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
 pub struct SaveUserResponse {
-    pub id: i64,
+    #[serde(flatten)]
+    pub outcome: OperationOutcome,
 }
 
 impl SaveUserResponse {
-    pub fn new(id: i64) -> Self {
-        SaveUserResponse { id }
+    pub fn new(outcome: OperationOutcome) -> Self {
+        SaveUserResponse { outcome }
     }
 }
 
@@ -268,10 +317,53 @@ pub struct DeleteUserRequest {
 #[derive(Serialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
 pub struct DeleteUserResponse {
     pub count: u64,
+    #[serde(flatten)]
+    pub outcome: OperationOutcome,
 }
 
 impl DeleteUserResponse {
-    pub fn new(count: u64) -> Self {
-        DeleteUserResponse { count }
+    pub fn new(outcome: OperationOutcome) -> Self {
+        DeleteUserResponse { count: outcome.affected, outcome }
     }
 }
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Validate, utoipa::ToSchema, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportUserDataRequest {
+    // The user to export, default the current user, the other users could be exported by the admins only.
+    pub uid: Option<i64>,
+}
+
+// The bulk export of all the data tied to the user, e.g. for the GDPR data portability.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
+pub struct UserDataExport {
+    // The user record, with the internal-only fields (e.g. password hash) redacted.
+    pub user: User,
+    pub settings: Vec<Settings>,
+    pub audit_logs: Vec<AuditLog>,
+    // The time (millis) of exported.
+    pub export_time: i64,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Validate, utoipa::ToSchema)]
+pub struct EraseUserRequest {
+    // The user to erase, default the current user, the other users could be erased by the admins only.
+    pub uid: Option<i64>,
+}
+
+// The affected rows of erasing the user, i.e. scrubbed (or deleted) by the strategy.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, utoipa::ToSchema)]
+pub struct ErasureOutcome {
+    pub users: u64,
+    pub settings: u64,
+    pub audit_logs: u64,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, utoipa::ToSchema)]
+pub struct EraseUserResponse {
+    pub uid: i64,
+    #[schema(value_type = String, example = "anonymize")]
+    pub strategy: ErasureStrategy,
+    #[serde(flatten)]
+    pub outcome: ErasureOutcome,
+}
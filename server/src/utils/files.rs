@@ -0,0 +1,112 @@
+/*
+ * SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+ *
+ * Copyleft (c) 2024 James Wong. This file is part of James Wong.
+ * is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * James Wong is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * IMPORTANT: Any software that fully or partially contains or uses materials
+ * covered by this license must also be released under the GNU GPL license.
+ * This includes modifications and derived works.
+ */
+
+use std::path::{ Component, Path, PathBuf };
+
+use crate::errors::AppError;
+
+/// Resolves the storage path of the attachment id under the storage root, which must stay within the root
+/// to prevent the path traversal (e.g. '../' or absolute ids), otherwise rejected as the invalid parameter.
+///
+/// The deepest existing ancestor of the target (i.e. the target itself if exists) is canonicalized, and the
+/// rest components to be created (e.g. on uploading) are appended, so that the symlinks escaping out of the
+/// root are also rejected.
+pub fn resolve_sandboxed_path(root: &Path, id: &str) -> Result<PathBuf, AppError> {
+    let invalid = || AppError::Validation(format!("Invalid attachment id: {}", id));
+
+    let relative = Path::new(id);
+    if id.is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return Err(invalid());
+    }
+    let root = root
+        .canonicalize()
+        .map_err(|e| AppError::Internal(anyhow::anyhow!("Invalid storage root {:?}. {}", root, e)))?;
+    let target = root.join(relative);
+
+    let mut existing = target.as_path();
+    let mut rest = Vec::new();
+    let resolved = loop {
+        match existing.canonicalize() {
+            Ok(path) => {
+                break rest.iter().rev().fold(path, |path, name| path.join(name));
+            }
+            // The dangling symlink, which would be followed out of the root on creating.
+            Err(_) if existing.symlink_metadata().is_ok() => {
+                return Err(invalid());
+            }
+            Err(_) => {
+                rest.push(existing.file_name().ok_or_else(invalid)?);
+                existing = existing.parent().ok_or_else(invalid)?;
+            }
+        }
+    };
+    if !resolved.starts_with(&root) || resolved == root {
+        return Err(invalid());
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ErrorExt;
+    use axum::http::StatusCode;
+
+    fn new_root() -> PathBuf {
+        let root = std::env::temp_dir().join(format!("mywebnote-files-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("2024")).unwrap();
+        root
+    }
+
+    #[test]
+    fn test_resolve_sandboxed_path_inside_root() {
+        let root = new_root();
+        let path = resolve_sandboxed_path(&root, "2024/a1b2c3.png").unwrap();
+        assert_eq!(path, root.canonicalize().unwrap().join("2024").join("a1b2c3.png"));
+        assert!(resolve_sandboxed_path(&root, "new/a1b2c3.png").is_ok());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_sandboxed_path_rejects_traversal() {
+        let root = new_root();
+        for id in ["../etc/passwd", "2024/../../etc/passwd", "/etc/passwd", "..", "", "."] {
+            let err = resolve_sandboxed_path(&root, id).unwrap_err();
+            assert_eq!(err.status_code(), StatusCode::BAD_REQUEST, "id: {}", id);
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_sandboxed_path_rejects_escaping_symlink() {
+        let root = new_root();
+        std::os::unix::fs::symlink(std::env::temp_dir(), root.join("link")).unwrap();
+        std::os::unix::fs::symlink(root.join("absent"), root.join("dangling")).unwrap();
+        // Including the symlinked ancestor of the directories not existing yet.
+        for id in ["link/a1b2c3.png", "link/new/a1b2c3.png", "link/new/more/a1b2c3.png", "dangling", "dangling/a.png"] {
+            assert!(resolve_sandboxed_path(&root, id).is_err(), "id: {}", id);
+        }
+        assert!(resolve_sandboxed_path(&root, "2024/new/more/a1b2c3.png").is_ok());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -26,11 +26,15 @@ pub mod httpclients;
 pub mod mems;
 pub mod inets;
 pub mod ethers;
+pub mod files;
 pub mod rsa_ciphers;
 pub mod serde_beans;
+pub mod singleflight;
 pub mod oauth2;
 pub mod oidcs;
 pub mod snowflake;
+pub mod swr;
+pub mod times;
 pub mod types;
 pub mod webs;
 pub mod browser_indexeddb;
@@ -20,6 +20,9 @@
  * This includes modifications and derived works.
  */
 
+use std::time::Duration;
+
+use futures::FutureExt;
 use openidconnect::{
     core::{ CoreClient, CoreProviderMetadata },
     reqwest::async_http_client,
@@ -29,7 +32,12 @@ use openidconnect::{
     RedirectUrl,
 };
 
-use crate::config::config_serve::OidcProperties;
+use crate::config::config_serve::{
+    OidcProperties,
+    DEFAULT_OIDC_DISCOVERY_HARD_TTL,
+    DEFAULT_OIDC_DISCOVERY_TTL,
+};
+use crate::utils::swr::SwrCache;
 
 /*
 curl 'https://keycloak.example.com/realms/master/.well-known/openid-configuration'
@@ -324,37 +332,47 @@ curl 'https://keycloak.example.com/realms/master/.well-known/openid-configuratio
   }
 }
 */
-pub async fn create_oidc_client(oidc_config: &OidcProperties) -> Option<CoreClient> {
+/// Creates the OIDC client of the cached discovery, which is discovered at startup and then refreshed with
+/// the stale-while-revalidate semantics, so that the login isn't blocked by the refreshing discovery.
+pub async fn create_oidc_client(oidc_config: &OidcProperties) -> Option<SwrCache<CoreClient>> {
     if oidc_config.enabled.unwrap_or(false) {
-        let issuer_url = IssuerUrl::new(
-            oidc_config.issue_url.to_owned().expect("Missing 'issue_url' configured")
-        ).expect("Invalid 'issue_url' configured");
-
-        let client_id = ClientId::new(
-            oidc_config.client_id.to_owned().expect("Missing 'client_id' configured")
+        let config = oidc_config.to_owned();
+        let cache = SwrCache::new(
+            Box::new(move || discover_oidc_client(config.to_owned()).boxed()),
+            Duration::from_millis(oidc_config.discovery_ttl.unwrap_or(DEFAULT_OIDC_DISCOVERY_TTL)),
+            Duration::from_millis(oidc_config.discovery_hard_ttl.unwrap_or(DEFAULT_OIDC_DISCOVERY_HARD_TTL))
         );
+        cache.refresh().await.expect("Failed to discover provider metadata");
+        Some(cache)
+    } else {
+        None
+    }
+}
 
-        let client_secret = ClientSecret::new(
-            oidc_config.client_secret.to_owned().expect("Missing 'client_id' configured")
-        );
+async fn discover_oidc_client(oidc_config: OidcProperties) -> Result<CoreClient, anyhow::Error> {
+    let issuer_url = IssuerUrl::new(
+        oidc_config.issue_url.to_owned().expect("Missing 'issue_url' configured")
+    ).expect("Invalid 'issue_url' configured");
 
-        let redirect_url = RedirectUrl::new(
-            oidc_config.redirect_url.to_owned().expect("Missing 'redirect_url' configured")
-        ).expect("Invalid 'redirect_url' configured");
+    let client_id = ClientId::new(
+        oidc_config.client_id.to_owned().expect("Missing 'client_id' configured")
+    );
 
-        let provider_metadata = CoreProviderMetadata::discover_async(
-            issuer_url,
-            async_http_client
-        ).await.expect("Failed to discover provider metadata");
+    let client_secret = ClientSecret::new(
+        oidc_config.client_secret.to_owned().expect("Missing 'client_id' configured")
+    );
 
-        let client = CoreClient::from_provider_metadata(
-            provider_metadata,
-            client_id,
-            Some(client_secret)
-        ).set_redirect_uri(redirect_url);
+    let redirect_url = RedirectUrl::new(
+        oidc_config.redirect_url.to_owned().expect("Missing 'redirect_url' configured")
+    ).expect("Invalid 'redirect_url' configured");
 
-        Some(client)
-    } else {
-        None
-    }
+    let provider_metadata = CoreProviderMetadata::discover_async(issuer_url, async_http_client).await?;
+
+    let client = CoreClient::from_provider_metadata(
+        provider_metadata,
+        client_id,
+        Some(client_secret)
+    ).set_redirect_uri(redirect_url);
+
+    Ok(client)
 }
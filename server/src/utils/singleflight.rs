@@ -0,0 +1,160 @@
+/*
+ * SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+ *
+ * Copyleft (c) 2024 James Wong. This file is part of James Wong.
+ * is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * James Wong is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * IMPORTANT: Any software that fully or partially contains or uses materials
+ * covered by this license must also be released under the GNU GPL license.
+ * This includes modifications and derived works.
+ */
+
+use std::{ collections::HashMap, future::Future, sync::Mutex };
+
+use tokio::sync::watch;
+
+// The result of the in-flight call, none is failed that the waiters should call by themselves.
+type FlightResult<V> = Option<Option<V>>;
+
+/// Coalesces the concurrent identical calls (of the same key) into one, the waiters share the result
+/// of the in-flight call. The result isn't cached after the call completed, and the errors aren't
+/// shared, i.e. the waiters call by themselves if the in-flight call is failed (or cancelled).
+pub struct SingleFlight<V> {
+    calls: Mutex<HashMap<String, watch::Receiver<FlightResult<V>>>>,
+}
+
+enum Flight<V> {
+    Leader(watch::Sender<FlightResult<V>>),
+    Waiter(watch::Receiver<FlightResult<V>>),
+}
+
+// Remove the in-flight call when completed or cancelled.
+struct FlightGuard<'a, V> {
+    calls: &'a Mutex<HashMap<String, watch::Receiver<FlightResult<V>>>>,
+    key: &'a str,
+}
+
+impl<'a, V> Drop for FlightGuard<'a, V> {
+    fn drop(&mut self) {
+        self.calls.lock().unwrap().remove(self.key);
+    }
+}
+
+impl<V> Default for SingleFlight<V> {
+    fn default() -> Self {
+        Self { calls: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl<V> SingleFlight<V> where V: Clone + Send + Sync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn execute<F, Fut, E>(&self, key: &str, call: F) -> Result<V, E>
+        where F: FnOnce() -> Fut, Fut: Future<Output = Result<V, E>>
+    {
+        let flight = {
+            let mut calls = self.calls.lock().unwrap();
+            match calls.get(key) {
+                Some(rx) => Flight::Waiter(rx.clone()),
+                None => {
+                    let (tx, rx) = watch::channel(None);
+                    calls.insert(key.to_string(), rx);
+                    Flight::Leader(tx)
+                }
+            }
+        };
+
+        match flight {
+            Flight::Leader(tx) => {
+                let guard = FlightGuard { calls: &self.calls, key };
+                let result = call().await;
+                drop(guard);
+                let _ = tx.send(Some(result.as_ref().ok().cloned()));
+                result
+            }
+            Flight::Waiter(mut rx) => {
+                let shared = rx
+                    .wait_for(|r| r.is_some()).await
+                    .ok()
+                    .and_then(|r| r.clone().flatten());
+                match shared {
+                    Some(value) => Ok(value),
+                    None => call().await,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{ atomic::{ AtomicUsize, Ordering }, Arc };
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_singleflight_coalesces_concurrent_calls() {
+        let flight = Arc::new(SingleFlight::<Vec<String>>::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let tasks = (0..50).map(|_| {
+            let (flight, calls) = (flight.clone(), calls.clone());
+            tokio::spawn(async move {
+                flight.execute("1001:editor", || async {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    Ok::<_, String>(vec!["editor".to_string()])
+                }).await
+            })
+        });
+        let results = futures::future::join_all(tasks).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        for result in results {
+            assert_eq!(result.unwrap().unwrap(), vec!["editor".to_string()]);
+        }
+
+        // Not cached after completed.
+        flight.execute("1001:editor", || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Ok::<_, String>(vec![])
+        }).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_singleflight_errors_are_not_shared() {
+        let flight = Arc::new(SingleFlight::<i64>::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let tasks = (0..5).map(|_| {
+            let (flight, calls) = (flight.clone(), calls.clone());
+            tokio::spawn(async move {
+                flight.execute("k", || async {
+                    // The first (in-flight) call fails and the waiters call by themselves.
+                    let n = calls.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    if n == 0 { Err("unavailable".to_string()) } else { Ok(n as i64) }
+                }).await
+            })
+        });
+        let results = futures::future::join_all(tasks).await
+            .into_iter()
+            .map(|r| r.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(results.iter().filter(|r| r.is_err()).count(), 1);
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 4);
+    }
+}
@@ -22,7 +22,7 @@
 
 use std::sync::atomic::{ AtomicI64, Ordering };
 use std::sync::Mutex;
-use std::time::{ SystemTime, UNIX_EPOCH };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
 use std::env;
 use mac_address::get_mac_address;
 use lazy_static::lazy_static;
@@ -32,6 +32,7 @@ pub struct SnowflakeIdGenerator {
     datacenter_id: i64,
     worker_id: i64,
     sequence: AtomicI64,
+    last_timestamp: AtomicI64,
 }
 
 lazy_static! {
@@ -62,13 +63,13 @@ impl SnowflakeIdGenerator {
             datacenter_id,
             worker_id,
             sequence: AtomicI64::new(0),
+            last_timestamp: AtomicI64::new(0),
         })
     }
 
     pub fn next(&self) -> i64 {
-        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
-
         let sequence = self.sequence.fetch_add(1, Ordering::SeqCst) & 4095;
+        let timestamp = self.next_timestamp(sequence);
 
         // Notice: This generated id is not safe because the length will overflow the int memory in JavaScript.
         ((timestamp - self.epoch) << 22) |
@@ -78,9 +79,8 @@ impl SnowflakeIdGenerator {
     }
 
     pub fn next_jssafe(&self) -> i64 {
-        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
-
         let sequence = self.sequence.fetch_add(1, Ordering::SeqCst) & 0x3f;
+        let timestamp = self.next_timestamp(sequence);
 
         // Notice: This generated id is safe because the length is within the range of JavaScript int.
         ((timestamp - self.epoch) << 14) |
@@ -89,6 +89,23 @@ impl SnowflakeIdGenerator {
             sequence
     }
 
+    // When the sequence wraps around, wait for the next millisecond so that the bulk generated ids never collide,
+    // the waiting thread sleeps for the rest of current millisecond rather than spinning the CPU.
+    fn next_timestamp(&self, sequence: i64) -> i64 {
+        let mut now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        if sequence == 0 {
+            let last_timestamp = self.last_timestamp.load(Ordering::SeqCst);
+            while (now.as_millis() as i64) <= last_timestamp {
+                let next_millis = Duration::from_millis((last_timestamp + 1) as u64);
+                std::thread::sleep(next_millis.saturating_sub(now).max(Duration::from_micros(1)));
+                now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            }
+        }
+        let timestamp = now.as_millis() as i64;
+        self.last_timestamp.fetch_max(timestamp, Ordering::SeqCst);
+        timestamp
+    }
+
     fn get_datacenter_id() -> Result<i64, Box<dyn std::error::Error>> {
         // Try to get datacenter ID from environment variable
         if let Ok(dc_id) = env::var("DATACENTER_ID") {
@@ -143,4 +160,10 @@ mod tests {
         assert!(id1 < id2);
         assert!(id1 >= 0 && id2 <= JS_SAFE_INT_MAX);
     }
+
+    #[test]
+    fn test_snowflake_id_generator_jssafe_unique_in_bulk() {
+        let ids = (0..5000).map(|_| SnowflakeIdGenerator::default_next_jssafe()).collect::<std::collections::HashSet<_>>();
+        assert_eq!(ids.len(), 5000);
+    }
 }
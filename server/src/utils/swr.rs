@@ -0,0 +1,140 @@
+/*
+ * SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+ *
+ * Copyleft (c) 2024 James Wong. This file is part of James Wong.
+ * is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * James Wong is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * IMPORTANT: Any software that fully or partially contains or uses materials
+ * covered by this license must also be released under the GNU GPL license.
+ * This includes modifications and derived works.
+ */
+
+use std::sync::{ atomic::{ AtomicBool, Ordering }, Arc };
+use std::time::{ Duration, Instant };
+
+use anyhow::Error;
+use futures::future::BoxFuture;
+use tokio::sync::RwLock;
+
+pub type SwrLoader<V> = Box<dyn (Fn() -> BoxFuture<'static, Result<V, Error>>) + Send + Sync>;
+
+/// Caches the value loaded by the loader with the stale-while-revalidate semantics, i.e. the value is
+/// served immediately within the fresh TTL, the stale value (older than the fresh TTL and within the hard
+/// TTL) is also served immediately while refreshing in the background, and the value beyond the hard TTL
+/// (or absent) is refreshed blocking.
+pub struct SwrCache<V> {
+    loader: SwrLoader<V>,
+    fresh_ttl: Duration,
+    hard_ttl: Duration,
+    entry: RwLock<Option<(Arc<V>, Instant)>>,
+    refreshing: AtomicBool,
+}
+
+impl<V> SwrCache<V> where V: Send + Sync + 'static {
+    pub fn new(loader: SwrLoader<V>, fresh_ttl: Duration, hard_ttl: Duration) -> Self {
+        Self {
+            loader,
+            fresh_ttl,
+            hard_ttl: hard_ttl.max(fresh_ttl),
+            entry: RwLock::new(None),
+            refreshing: AtomicBool::new(false),
+        }
+    }
+
+    pub async fn get(self: &Arc<Self>) -> Result<Arc<V>, Error> {
+        let entry = self.entry.read().await.clone();
+        if let Some((value, loaded_at)) = entry {
+            let age = loaded_at.elapsed();
+            if age < self.fresh_ttl {
+                return Ok(value);
+            }
+            if age < self.hard_ttl {
+                // Only one background refreshing at the same time.
+                if !self.refreshing.swap(true, Ordering::SeqCst) {
+                    let this = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = this.refresh().await {
+                            tracing::warn!("Failed to refresh the stale cached value. {:?}", e);
+                        }
+                        this.refreshing.store(false, Ordering::SeqCst);
+                    });
+                }
+                return Ok(value);
+            }
+        }
+        self.refresh().await
+    }
+
+    pub async fn refresh(&self) -> Result<Arc<V>, Error> {
+        let value = Arc::new((self.loader)().await?);
+        *self.entry.write().await = Some((value.clone(), Instant::now()));
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use std::sync::atomic::AtomicUsize;
+
+    // The mock provider returns the number of loaded times as the document version.
+    fn new_cache(fresh_ms: u64, hard_ms: u64, delay_ms: u64) -> (Arc<SwrCache<usize>>, Arc<AtomicUsize>) {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let counter = loads.clone();
+        let loader: SwrLoader<usize> = Box::new(move || {
+            let counter = counter.clone();
+            (async move {
+                tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+                Ok(counter.fetch_add(1, Ordering::SeqCst) + 1)
+            }).boxed()
+        });
+        let cache = SwrCache::new(loader, Duration::from_millis(fresh_ms), Duration::from_millis(hard_ms));
+        (Arc::new(cache), loads)
+    }
+
+    #[tokio::test]
+    async fn test_swr_serves_fresh_value_without_loading() {
+        let (cache, loads) = new_cache(60_000, 120_000, 0);
+        assert_eq!(*cache.get().await.unwrap(), 1);
+        assert_eq!(*cache.get().await.unwrap(), 1);
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_swr_serves_stale_value_and_refreshes_in_background() {
+        let (cache, loads) = new_cache(50, 60_000, 200);
+        assert_eq!(*cache.get().await.unwrap(), 1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The stale value is served immediately (without waiting the slow provider).
+        let started = Instant::now();
+        assert_eq!(*cache.get().await.unwrap(), 1);
+        assert_eq!(*cache.get().await.unwrap(), 1);
+        assert!(started.elapsed() < Duration::from_millis(100));
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        assert_eq!(*cache.get().await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_swr_refreshes_blocking_beyond_hard_ttl() {
+        let (cache, loads) = new_cache(20, 50, 0);
+        assert_eq!(*cache.get().await.unwrap(), 1);
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert_eq!(*cache.get().await.unwrap(), 2);
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+    }
+}
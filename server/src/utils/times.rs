@@ -0,0 +1,121 @@
+/*
+ * SPDX-License-Identifier: GNU GENERAL PUBLIC LICENSE Version 3
+ *
+ * Copyleft (c) 2024 James Wong. This file is part of James Wong.
+ * is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * James Wong is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with James Wong.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * IMPORTANT: Any software that fully or partially contains or uses materials
+ * covered by this license must also be released under the GNU GPL license.
+ * This includes modifications and derived works.
+ */
+
+use anyhow::{ anyhow, Error };
+use chrono::{ DateTime, FixedOffset, SecondsFormat, Utc };
+
+// The suffix of the timestamp (millis) fields, e.g: 'create_time', 'update_time', 'delete_time'.
+const TIME_FIELD_SUFFIX: &str = "_time";
+// The suffix of the rendered timestamp fields in the configured zone, e.g: 'create_time_local'.
+const LOCAL_FIELD_SUFFIX: &str = "_local";
+
+// Parse the timezone of 'UTC' (or 'Z') or the fixed offset e.g: '+08:00', '-05:30'.
+pub fn parse_timezone(timezone: &str) -> Result<FixedOffset, Error> {
+    let timezone = timezone.trim();
+    if timezone.eq_ignore_ascii_case("utc") || timezone.eq_ignore_ascii_case("z") {
+        return Ok(FixedOffset::east_opt(0).unwrap());
+    }
+    timezone.parse::<FixedOffset>().map_err(|e| anyhow!("Invalid timezone '{}', expected 'UTC' or e.g. '+08:00'. {}", timezone, e))
+}
+
+// The current timestamp (millis), all the stored timestamps are of UTC regardless of the configured zone,
+// so that they are comparable and ordered consistently.
+pub fn now_millis() -> i64 {
+    Utc::now().timestamp_millis()
+}
+
+// Format the timestamp (millis) as RFC3339 in the timezone, e.g: '2024-01-01T08:00:00.000+08:00'.
+pub fn format_millis(millis: i64, timezone: &FixedOffset) -> Option<String> {
+    DateTime::<Utc>::from_timestamp_millis(millis).map(|t| {
+        t.with_timezone(timezone).to_rfc3339_opts(SecondsFormat::Millis, false)
+    })
+}
+
+// Add the rendered value in the timezone beside each timestamp field (recursively), the stored value is kept
+// as is, e.g: '"create_time": 1704067200000' is added with '"create_time_local": "2024-01-01T08:00:00.000+08:00"'.
+pub fn localize_timestamps(value: &mut serde_json::Value, timezone: &FixedOffset) {
+    match value {
+        serde_json::Value::Object(obj) => {
+            let rendered: Vec<(String, String)> = obj
+                .iter()
+                .filter(|(key, _)| key.ends_with(TIME_FIELD_SUFFIX))
+                .filter_map(|(key, v)| {
+                    let text = format_millis(v.as_i64()?, timezone)?;
+                    Some((format!("{}{}", key, LOCAL_FIELD_SUFFIX), text))
+                })
+                .collect();
+            for v in obj.values_mut() {
+                localize_timestamps(v, timezone);
+            }
+            for (key, text) in rendered {
+                obj.insert(key, text.into());
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for v in items.iter_mut() {
+                localize_timestamps(v, timezone);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_timezone() {
+        assert_eq!(parse_timezone("UTC").unwrap().local_minus_utc(), 0);
+        assert_eq!(parse_timezone("z").unwrap().local_minus_utc(), 0);
+        assert_eq!(parse_timezone("+08:00").unwrap().local_minus_utc(), 8 * 3600);
+        assert_eq!(parse_timezone("-05:30").unwrap().local_minus_utc(), -(5 * 3600 + 1800));
+        assert!(parse_timezone("Asia/Shanghai").is_err());
+    }
+
+    #[test]
+    fn test_localize_timestamps_in_zone() {
+        let timezone = parse_timezone("+08:00").unwrap();
+        let mut value = json!({
+            "page": { "total": 1 },
+            "data": [{ "name": "note", "create_time": 1704067200000i64, "update_time": null }]
+        });
+        localize_timestamps(&mut value, &timezone);
+
+        let row = &value["data"][0];
+        // The stored (UTC) value is kept as is.
+        assert_eq!(row["create_time"], json!(1704067200000i64));
+        assert_eq!(row["create_time_local"], json!("2024-01-01T08:00:00.000+08:00"));
+        assert!(row.get("update_time_local").is_none());
+        assert!(value["page"].get("total_local").is_none());
+    }
+
+    #[test]
+    fn test_now_millis_is_utc() {
+        let before = Utc::now().timestamp_millis();
+        let now = now_millis();
+        assert!(now >= before && now - before < 1000);
+        let utc = parse_timezone("UTC").unwrap();
+        assert!(format_millis(now, &utc).unwrap().ends_with("+00:00"));
+    }
+}
@@ -20,6 +20,7 @@
  * This includes modifications and derived works.
  */
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum GenericValue {
     Int32(i32),
     Int64(i64),
@@ -27,29 +27,83 @@ use axum::{
     response::{ IntoResponse, Redirect },
 };
 use hyper::StatusCode;
+use std::{ collections::HashMap, net::IpAddr };
+use serde::Serialize;
 use tower_cookies::{ cookie::{ time::Duration, CookieBuilder, SameSite }, Cookie };
 
+use crate::config::config_serve::{ CookieSameSite, RunProfile, WebServeConfig };
+
 pub const APPLICATION_JSON_HEADER_VALUE: HeaderValue = HeaderValue::from_static("application/json");
 
 pub fn create_cookie_headers(key: &str, value: &str) -> header::HeaderMap {
-    let cookie = CookieBuilder::new(key, value)
+    let mut response = Response::new(Body::empty());
+    add_cookies(&mut response, vec![build_cookie(key, value, Duration::seconds(60))]);
+    std::mem::take(response.headers_mut())
+}
+
+// Build the cookie with the uniform attributes, so that all the cookies set by server are consistent.
+pub fn build_cookie(name: &str, value: &str, max_age: Duration) -> Cookie<'static> {
+    CookieBuilder::new(name.to_owned(), value.to_owned())
         .path("/")
-        .max_age(Duration::seconds(60))
+        .max_age(max_age)
         //.secure(true) // true: indicates that only https requests will carry
         .http_only(true)
         .same_site(SameSite::Strict)
-        .build();
-    let header_value = cookie.to_string().parse::<HeaderValue>().expect("Failed to parse cookie");
-    let mut headers = header::HeaderMap::new();
-    headers.append(header::SET_COOKIE, header_value); // Will cover!
-    headers
+        .build()
+}
+
+// Build the cookie to remove the one built by build_cookie(), the path must be the same.
+pub fn build_removal_cookie(name: &str) -> Cookie<'static> {
+    let mut cookie = build_cookie(name, "", Duration::ZERO);
+    cookie.make_removal();
+    cookie
+}
+
+/// Build the auth (access/refresh token) cookie with the attributes configured by 'auth.cookie-*'.
+pub fn build_auth_cookie(config: &WebServeConfig, name: &str, value: &str, max_age: Duration) -> Cookie<'static> {
+    let auth = &config.auth;
+    let mut builder = CookieBuilder::new(name.to_owned(), value.to_owned())
+        .path(auth.cookie_path.to_owned().unwrap_or_else(|| String::from("/")))
+        .max_age(max_age)
+        .secure(auth.cookie_secure.unwrap_or(false))
+        .http_only(true)
+        .same_site(match auth.cookie_same_site {
+            CookieSameSite::Strict => SameSite::Strict,
+            CookieSameSite::Lax => SameSite::Lax,
+            CookieSameSite::None => SameSite::None,
+        });
+    if let Some(domain) = &auth.cookie_domain {
+        builder = builder.domain(domain.to_owned());
+    }
+    builder.build()
 }
 
+// Build the cookie to remove the one built by build_auth_cookie(), the path and domain must be the same.
+pub fn build_auth_removal_cookie(config: &WebServeConfig, name: &str) -> Cookie<'static> {
+    let mut cookie = build_auth_cookie(config, name, "", Duration::ZERO);
+    cookie.make_removal();
+    cookie
+}
+
+/// Appends the cookies as the multiple 'Set-Cookie' headers, and the later one wins if duplicated
+/// names, because the browser would only keep the last of them anyway.
 pub fn add_cookies(response: &mut Response<Body>, cookies: Vec<Cookie>) {
-    cookies.iter().for_each(|c| {
-        response
-            .headers_mut()
-            .append(header::SET_COOKIE, HeaderValue::from_str(&c.to_string()).unwrap());
+    let last_indexes: HashMap<&str, usize> = cookies
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (c.name(), i))
+        .collect();
+    cookies.iter().enumerate().for_each(|(i, c)| {
+        if last_indexes.get(c.name()) != Some(&i) {
+            tracing::debug!("Ignore the duplicated cookie '{}'.", c.name());
+            return;
+        }
+        match HeaderValue::from_str(&c.to_string()) {
+            Ok(value) => {
+                response.headers_mut().append(header::SET_COOKIE, value);
+            }
+            Err(e) => tracing::warn!("Ignore the invalid cookie '{}'. reason: {}", c.name(), e),
+        }
     });
 }
 
@@ -79,6 +133,55 @@ pub fn get_cookie_from_str(cookie_str: &str, key: &str) -> Option<String> {
         .map(|(_, value)| value)
 }
 
+// Gets the client ip from the proxy headers, the first one of 'X-Forwarded-For' is the original client.
+pub fn get_client_ip(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("X-Forwarded-For")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .or_else(|| headers.get("X-Real-IP").and_then(|value| value.to_str().ok()))
+        .map(|ip| ip.trim().to_string())
+        .filter(|ip| !ip.is_empty())
+}
+
+// Gets the client ip which can't be spoofed, i.e. the peer address, and the proxy headers are trusted only if
+// the peer is one of the trusted proxies, then the nearest hop not trusted of 'X-Forwarded-For' is the client,
+// because the farther hops could be appended by the client itself.
+pub fn get_trusted_client_ip(
+    trusted_proxies: &[String],
+    peer: Option<IpAddr>,
+    headers: &HeaderMap
+) -> Option<String> {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|p| p.trim().parse::<IpAddr>().is_ok_and(|p| &p == ip));
+    let peer = peer?;
+    if !is_trusted(&peer) {
+        return Some(peer.to_string());
+    }
+    let forwarded = headers
+        .get("X-Forwarded-For")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(',')
+                .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let client = forwarded
+        .iter()
+        .rev()
+        .find(|ip| !is_trusted(ip))
+        .or(forwarded.first())
+        .copied()
+        .or_else(|| {
+            headers
+                .get("X-Real-IP")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+        });
+    Some(client.unwrap_or(peer).to_string())
+}
+
 pub fn is_browser(headers: &HeaderMap) -> bool {
     let user_agent = headers
         .get("X-Accpet-Type")
@@ -92,6 +195,21 @@ pub fn is_browser(headers: &HeaderMap) -> bool {
     user_agent.contains("Mozilla")
 }
 
+/// The json response of debugging, which is pretty printed if requested by the '?pretty=' query (only
+/// honored in the dev profile) or the 'server.debug-pretty-json' by default.
+pub fn debug_json_response<T: Serialize>(config: &WebServeConfig, pretty: Option<bool>, value: &T) -> Response<Body> {
+    let default_pretty = config.server.debug_pretty_json.unwrap_or(false);
+    let pretty = match config.profile {
+        RunProfile::Dev => pretty.unwrap_or(default_pretty),
+        RunProfile::Prod => default_pretty,
+    };
+    let body = if pretty { serde_json::to_string_pretty(value) } else { serde_json::to_string(value) };
+    match body {
+        Ok(body) => ([(header::CONTENT_TYPE, APPLICATION_JSON_HEADER_VALUE)], body).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
 pub fn response_redirect_or_json(
     status: StatusCode,
     headers: &HeaderMap,
@@ -124,22 +242,14 @@ pub fn response_redirect_or_json(
         response = (status, json.to_string()).into_response();
         response.headers_mut().insert(header::CONTENT_TYPE, APPLICATION_JSON_HEADER_VALUE);
     }
-    if let Some(tuple3) = cookies {
-        let mut cs = Vec::new();
-        if tuple3.0.is_some() {
-            cs.push(tuple3.0.unwrap());
-        }
-        if tuple3.1.is_some() {
-            cs.push(tuple3.1.unwrap());
-        }
-        if tuple3.2.is_some() {
-            cs.push(tuple3.2.unwrap());
-        }
-        add_cookies(&mut response, cs);
+    // Both the redirect and json responses carry the same present cookies, and the absent are not emitted.
+    if let Some((ak, rk, csrf)) = cookies {
+        add_cookies(&mut response, [ak, rk, csrf].into_iter().flatten().collect());
     }
     response
 }
 
+#[cfg(test)]
 mod tests {
     #[allow(unused)]
     use super::*;
@@ -164,4 +274,180 @@ mod tests {
         let cookie = get_cookie_from_headers("test", headers);
         assert_eq!(cookie, Some("test".to_string()));
     }
+
+    fn get_set_cookies(response: &Response<Body>) -> Vec<String> {
+        response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .map(|v| v.to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_add_cookies_with_multiple_set_cookie_headers() {
+        let mut response = Response::new(Body::empty());
+        add_cookies(
+            &mut response,
+            vec![
+                build_cookie("_ak", "a", Duration::seconds(60)),
+                build_cookie("_rk", "r", Duration::seconds(120)),
+                build_removal_cookie("_csrf_token")
+            ]
+        );
+
+        let cookies = get_set_cookies(&response);
+        assert_eq!(cookies.len(), 3);
+        assert!(cookies[0].starts_with("_ak=a;"));
+        assert!(cookies[1].starts_with("_rk=r;"));
+        assert!(cookies[2].starts_with("_csrf_token=;"));
+        for c in cookies.iter() {
+            assert!(c.contains("Path=/"));
+            assert!(c.contains("HttpOnly"));
+            assert!(c.contains("SameSite=Strict"));
+        }
+        assert!(cookies[2].contains("Max-Age=0"));
+    }
+
+    #[test]
+    fn test_response_redirect_or_json_with_cookies() {
+        let cookies = || {
+            Some((
+                Some(build_cookie("_ak", "a", Duration::seconds(60))),
+                Some(build_cookie("_rk", "r", Duration::seconds(60))),
+                None,
+            ))
+        };
+
+        let mut headers = header::HeaderMap::new();
+        headers.insert("User-Agent", "Mozilla/5.0".parse().unwrap());
+        let response = response_redirect_or_json(StatusCode::OK, &headers, cookies(), "/", "ok", "{}");
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(get_set_cookies(&response).len(), 2);
+
+        let headers = header::HeaderMap::new();
+        let response = response_redirect_or_json(StatusCode::OK, &headers, cookies(), "/", "ok", "{}");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(get_set_cookies(&response).len(), 2);
+    }
+
+    #[test]
+    fn test_add_cookies_ignores_duplicated_names() {
+        let mut response = Response::new(Body::empty());
+        add_cookies(
+            &mut response,
+            vec![
+                build_cookie("_ak", "old", Duration::seconds(60)),
+                build_cookie("_rk", "r", Duration::seconds(60)),
+                build_cookie("_ak", "new", Duration::seconds(60))
+            ]
+        );
+
+        let cookies = get_set_cookies(&response);
+        assert_eq!(cookies.len(), 2);
+        assert!(cookies[0].starts_with("_rk=r;"));
+        assert!(cookies[1].starts_with("_ak=new;"));
+    }
+
+    fn auth_cookie_config(same_site: CookieSameSite, secure: bool, domain: Option<&str>) -> std::sync::Arc<WebServeConfig> {
+        let mut properties = crate::config::config_serve::WebServeProperties::default();
+        properties.auth.cookie_same_site = same_site;
+        properties.auth.cookie_secure = Some(secure);
+        properties.auth.cookie_domain = domain.map(|d| d.to_string());
+        properties.auth.cookie_path = Some("/serve".to_string());
+        properties.to_config()
+    }
+
+    #[test]
+    fn test_build_auth_cookie_with_strict_config() {
+        let config = auth_cookie_config(CookieSameSite::Strict, true, None);
+        let cookie = build_auth_cookie(&config, "_ak", "a", Duration::seconds(60)).to_string();
+        assert!(cookie.starts_with("_ak=a;"));
+        assert!(cookie.contains("SameSite=Strict"));
+        assert!(cookie.contains("Secure"));
+        assert!(cookie.contains("HttpOnly"));
+        assert!(cookie.contains("Path=/serve"));
+        assert!(!cookie.contains("Domain="));
+    }
+
+    #[test]
+    fn test_build_auth_cookie_with_lax_config() {
+        let config = auth_cookie_config(CookieSameSite::Lax, false, Some("wl4g.com"));
+        let cookie = build_auth_cookie(&config, "_ak", "a", Duration::seconds(60)).to_string();
+        assert!(cookie.contains("SameSite=Lax"));
+        assert!(!cookie.contains("Secure"));
+        assert!(cookie.contains("Domain=wl4g.com"));
+
+        // The removal cookie must match the path and domain to take effect.
+        let removal = build_auth_removal_cookie(&config, "_ak").to_string();
+        assert!(removal.contains("Max-Age=0"));
+        assert!(removal.contains("Path=/serve"));
+        assert!(removal.contains("Domain=wl4g.com"));
+    }
+
+    #[test]
+    fn test_build_auth_cookie_defaults_to_strict() {
+        let config = crate::config::config_serve::WebServeProperties::default().to_config();
+        let cookie = build_auth_cookie(&config, "_ak", "a", Duration::seconds(60)).to_string();
+        assert!(cookie.contains("SameSite=Strict"));
+        assert!(cookie.contains("HttpOnly"));
+        assert!(cookie.contains("Path=/"));
+    }
+
+    #[test]
+    fn test_get_client_ip() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(get_client_ip(&headers), None);
+
+        headers.insert("X-Real-IP", HeaderValue::from_static("10.0.0.2"));
+        assert_eq!(get_client_ip(&headers), Some("10.0.0.2".to_string()));
+
+        headers.insert("X-Forwarded-For", HeaderValue::from_static(" 10.0.0.1, 172.16.0.1"));
+        assert_eq!(get_client_ip(&headers), Some("10.0.0.1".to_string()));
+    }
+
+    #[test]
+    fn test_get_trusted_client_ip() {
+        let trusted = vec!["172.16.0.1".to_string(), "172.16.0.2".to_string()];
+        let client = "10.0.0.9".parse::<IpAddr>().ok();
+        let proxy = "172.16.0.1".parse::<IpAddr>().ok();
+        let mut headers = HeaderMap::new();
+        assert_eq!(get_trusted_client_ip(&trusted, None, &headers), None);
+        assert_eq!(get_trusted_client_ip(&trusted, proxy, &headers), Some("172.16.0.1".to_string()));
+
+        // The headers of the untrusted peer are ignored.
+        headers.insert("X-Forwarded-For", HeaderValue::from_static("10.0.0.1, 172.16.0.2"));
+        assert_eq!(get_trusted_client_ip(&trusted, client, &headers), Some("10.0.0.9".to_string()));
+        assert_eq!(get_trusted_client_ip(&[], proxy, &headers), Some("172.16.0.1".to_string()));
+
+        // The nearest untrusted hop, rather than the first one spoofed by the client.
+        assert_eq!(get_trusted_client_ip(&trusted, proxy, &headers), Some("10.0.0.1".to_string()));
+        headers.insert("X-Forwarded-For", HeaderValue::from_static("1.2.3.4, 10.0.0.1, 172.16.0.2"));
+        assert_eq!(get_trusted_client_ip(&trusted, proxy, &headers), Some("10.0.0.1".to_string()));
+    }
+
+    async fn debug_json_body(profile: RunProfile, default_pretty: bool, pretty: Option<bool>) -> String {
+        let mut properties = crate::config::config_serve::WebServeProperties::default();
+        properties.profile = profile;
+        properties.server.debug_pretty_json = Some(default_pretty);
+        let value = serde_json::json!({ "name": "alice", "roles": ["admin"] });
+        let response = debug_json_response(&properties.to_config(), pretty, &value);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "application/json");
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_debug_json_response_pretty_in_dev() {
+        assert!(debug_json_body(RunProfile::Dev, false, Some(true)).await.contains("\n  \"name\""));
+        assert!(!debug_json_body(RunProfile::Dev, false, None).await.contains('\n'));
+        assert!(!debug_json_body(RunProfile::Dev, true, Some(false)).await.contains('\n'));
+        assert!(debug_json_body(RunProfile::Dev, true, None).await.contains('\n'));
+    }
+
+    #[tokio::test]
+    async fn test_debug_json_response_ignores_pretty_query_in_prod() {
+        assert!(!debug_json_body(RunProfile::Prod, false, Some(true)).await.contains('\n'));
+        assert!(debug_json_body(RunProfile::Prod, true, Some(false)).await.contains('\n'));
+    }
 }